@@ -0,0 +1,78 @@
+//! Compares pileup over deep single-reference input with and without a full scan over entries
+//! for every column.
+//!
+//! Before the minimal entry position was tracked incrementally, `Pileup` scanned all entries
+//! to find the position of each new column. The second measurement adds the same scan on top
+//! of the current pileup, so the difference between the two is the cost of the old path.
+//!
+//! Run with `cargo bench --bench pileup_depth`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bam::pileup::{Pileup, PileupColumn};
+use bam::SamReader;
+
+const READ_LEN: usize = 100;
+const REPEATS: usize = 3;
+
+/// Creates a SAM file with `reads_per_pos` reads starting at each of `n_pos` positions,
+/// so the depth is `reads_per_pos * READ_LEN` inside the covered region.
+fn deep_sam(reads_per_pos: usize, n_pos: usize) -> Vec<u8> {
+    let seq = "A".repeat(READ_LEN);
+    let mut sam = format!("@SQ\tSN:chr1\tLN:{}\n", n_pos + READ_LEN);
+    for pos in 0..n_pos {
+        for i in 0..reads_per_pos {
+            sam += &format!(
+                "r{}_{}\t0\tchr1\t{}\t60\t{}M\t*\t0\t0\t{}\t*\n",
+                pos,
+                i,
+                pos + 1,
+                READ_LEN,
+                seq
+            );
+        }
+    }
+    sam.into_bytes()
+}
+
+/// Runs the pileup over `sam`, calls `on_column` for every column, and returns
+/// the smallest running time over `REPEATS` runs.
+fn measure<F: FnMut(&PileupColumn)>(sam: &[u8], mut on_column: F) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..REPEATS {
+        let timer = Instant::now();
+        let mut reader = SamReader::from_stream(sam).unwrap();
+        let mut total_depth = 0;
+        for column in Pileup::new(&mut reader) {
+            let column = column.unwrap();
+            on_column(&column);
+            total_depth += column.entries().len();
+        }
+        black_box(total_depth);
+        best = best.min(timer.elapsed());
+    }
+    best
+}
+
+fn main() {
+    for &reads_per_pos in &[1, 10, 50] {
+        let sam = deep_sam(reads_per_pos, 2000);
+        let incremental = measure(&sam, |_| {});
+        let full_scan = measure(&sam, |column| {
+            let min_pos = column
+                .entries()
+                .iter()
+                .map(|entry| (entry.record().ref_id(), entry.ref_pos()))
+                .min();
+            black_box(min_pos);
+        });
+        println!(
+            "depth {:>5}: incremental {:>8.1?}, with full scan {:>8.1?} ({:.2}x)",
+            reads_per_pos * READ_LEN,
+            incremental,
+            full_scan,
+            full_scan.as_secs_f64() / incremental.as_secs_f64()
+        );
+    }
+}
@@ -1,16 +1,20 @@
 //! Indexed and consecutive BAM readers.
 
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io::ErrorKind::{InvalidData, InvalidInput};
-use std::io::{BufReader, Error, Read, Result, Seek};
+use std::io::ErrorKind::{InvalidData, InvalidInput, UnexpectedEof};
+use std::io::{self, BufRead, BufReader, Error, Read, Result, Seek, Write};
 use std::path::{Path, PathBuf};
 
 use super::bgzip::{self, ReadBgzip};
 use super::bgzip::{Block, BlockError};
-use super::header::Header;
+use super::header::{Header, HeaderEntry, SortOrder};
 use super::index::{self, Index};
+use super::pileup::Pileup;
 use super::record;
-use super::RecordReader;
+use super::{FormatInfo, RecordReader};
 use index::Chunk;
 
 /// Iterator over records in a specific region.
@@ -23,7 +27,8 @@ pub struct RegionViewer<'a, R: Read + Seek> {
     pub parent: &'a mut IndexedReader<R>,
     start: i32,
     end: i32,
-    predicate: Box<dyn Fn(&record::Record) -> bool>,
+    predicate: Box<dyn Fn(&record::Record) -> bool + Send + Sync>,
+    skipped_unmapped: u64,
 }
 
 impl<'a, R: Read + Seek> RegionViewer<'a, R> {
@@ -32,20 +37,83 @@ impl<'a, R: Read + Seek> RegionViewer<'a, R> {
         self.parent.header()
     }
 
+    /// Returns an iterator over clusters of records: maximal groups of consecutive records,
+    /// where alignments overlap transitively. For example, records aligned to `[100-200)`,
+    /// `[150-300)` and `[250-350)` form a single cluster. Each cluster is a vector of records
+    /// in the order of appearance.
+    ///
+    /// Records without aligned bases are treated as records covering one base pair.
+    pub fn clusters(&mut self) -> Clusters<'_, Self> {
+        Clusters {
+            reader: self,
+            next_record: None,
+        }
+    }
+
+    /// Reads all remaining records from the region, and returns an iterator over them, sorted
+    /// by [template name](../record/struct.Record.html#method.template_name) (bytewise).
+    /// The sort is stable, so records with the same name, such as mates or secondary
+    /// alignments, keep their coordinate order.
+    ///
+    /// All records are stored in memory at the same time, so the function is intended for
+    /// bounded regions, such as a gene or a target interval, and not for whole chromosomes.
+    /// If an error occurs, the function stops and returns the error.
+    pub fn sorted_by_name(&mut self) -> Result<std::vec::IntoIter<record::Record>> {
+        let mut records = self.drain()?;
+        records.sort_by(|a, b| a.template_name().cmp(b.template_name()));
+        Ok(records.into_iter())
+    }
+
+    /// Skips `n` records from the region, and returns the number of skipped records, which is
+    /// smaller than `n` only if there are no more records. Records are filtered in the same way
+    /// as during iteration, so only records overlapping the region and satisfying the predicate
+    /// are counted.
+    pub fn skip_records(&mut self, n: usize) -> Result<usize> {
+        let mut record = record::Record::new();
+        for i in 0..n {
+            if !self.read_into(&mut record)? {
+                return Ok(i);
+            }
+        }
+        Ok(n)
+    }
+
     /// Returns [BAI index](../index/struct.Index.html).
     pub fn index(&self) -> &Index {
         self.parent.index()
     }
+
+    /// Returns the number of unmapped records that were read from the file, but skipped because
+    /// they lie outside of the region. Unmapped records, rejected by the predicate, are not counted.
+    ///
+    /// The counter is updated as the viewer proceeds, so the final value is known only after
+    /// all records in the region were read.
+    pub fn skipped_unmapped(&self) -> u64 {
+        self.skipped_unmapped
+    }
 }
 
 impl<'a, R: Read + Seek> RecordReader for RegionViewer<'a, R> {
     fn read_into(&mut self, record: &mut record::Record) -> Result<bool> {
         loop {
-            let res = record.fill_from_bam(&mut self.parent.reader);
+            let offset = if self.parent.capture_offsets {
+                self.parent.reader.virtual_offset()
+            } else {
+                None
+            };
+            let res = if self.parent.keep_raw {
+                record.fill_from_bam_keep_raw(&mut self.parent.reader)
+            } else {
+                record.fill_from_bam(&mut self.parent.reader)
+            };
             if !res.as_ref().unwrap_or(&false) {
                 record.clear();
                 return res;
             }
+            record.set_source_offset(offset);
+            if let Some(max_qual) = self.parent.max_qual {
+                record.cap_qualities(max_qual);
+            }
             // Reads are sorted, so no more reads would be in the region.
             if record.start() >= self.end {
                 record.clear();
@@ -75,13 +143,12 @@ impl<'a, R: Read + Seek> RecordReader for RegionViewer<'a, R> {
                     "Corrupted record: aln_end < aln_start",
                 ));
             }
-            if record.flag().is_mapped() {
-                if record_end > self.start {
-                    return Ok(true);
-                }
-            } else if record.start() >= self.start {
+            if record.overlaps_positions(self.start, self.end) {
                 return Ok(true);
             }
+            if !record.flag().is_mapped() {
+                self.skipped_unmapped += 1;
+            }
         }
     }
 
@@ -104,6 +171,227 @@ impl<'a, R: Read + Seek> Iterator for RegionViewer<'a, R> {
     }
 }
 
+/// Iterator over records in several regions, created by
+/// [IndexedReader::fetch_regions](struct.IndexedReader.html#method.fetch_regions) or
+/// [IndexedReader::fetch_bed](struct.IndexedReader.html#method.fetch_bed).
+/// Implements [RecordReader](../trait.RecordReader.html) trait.
+///
+/// Records are returned in the order of the regions, and each record is returned once, even
+/// if it overlaps several regions.
+pub struct RegionsViewer<'a, R: Read + Seek> {
+    parent: &'a mut IndexedReader<R>,
+    // Sorted non-overlapping regions.
+    regions: Vec<Region>,
+    // Index of the next region to fetch.
+    next_region: usize,
+    // True if records from the region `next_region - 1` are being read.
+    active: bool,
+}
+
+impl<'a, R: Read + Seek> RegionsViewer<'a, R> {
+    /// Returns [header](../header/struct.Header.html).
+    pub fn header(&self) -> &Header {
+        self.parent.header()
+    }
+
+    /// Returns the merged regions, sorted by reference id and start.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+}
+
+impl<'a, R: Read + Seek> RecordReader for RegionsViewer<'a, R> {
+    fn read_into(&mut self, record: &mut record::Record) -> Result<bool> {
+        loop {
+            if !self.active {
+                let region = match self.regions.get(self.next_region) {
+                    Some(region) => region,
+                    None => {
+                        record.clear();
+                        return Ok(false);
+                    }
+                };
+                let chunks = self.parent.index.fetch_chunks(
+                    region.ref_id(),
+                    region.start() as i32,
+                    region.end() as i32,
+                );
+                self.parent.reader.set_chunks(chunks);
+                self.next_region += 1;
+                self.active = true;
+            }
+
+            let region = &self.regions[self.next_region - 1];
+            let mut viewer = RegionViewer {
+                parent: &mut *self.parent,
+                start: region.start() as i32,
+                end: region.end() as i32,
+                predicate: Box::new(|_| true),
+                skipped_unmapped: 0,
+            };
+            if !viewer.read_into(record)? {
+                self.active = false;
+                continue;
+            }
+            // Records that overlap the previous region were already returned. A record cannot
+            // overlap an earlier region without overlapping the previous one.
+            if self.next_region >= 2 {
+                let prev = &self.regions[self.next_region - 2];
+                if prev.ref_id() == region.ref_id()
+                    && record.overlaps_positions(prev.start() as i32, prev.end() as i32)
+                {
+                    continue;
+                }
+            }
+            return Ok(true);
+        }
+    }
+
+    fn pause(&mut self) {
+        self.parent.pause();
+    }
+}
+
+/// Iterator over records.
+impl<'a, R: Read + Seek> Iterator for RegionsViewer<'a, R> {
+    type Item = Result<record::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = record::Record::new();
+        match self.read_into(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Parses a BED file into regions. Only the first three columns are used.
+fn read_bed<P: AsRef<Path>>(path: P, header: &Header) -> Result<Vec<Region>> {
+    let path = path.as_ref();
+    let stream = BufReader::new(File::open(path)?);
+    let mut regions = Vec::new();
+    for (i, line) in stream.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let error = |text: String| {
+            Error::new(
+                InvalidData,
+                format!(
+                    "Failed to parse BED file {}, line {}: {}",
+                    path.display(),
+                    i + 1,
+                    text
+                ),
+            )
+        };
+        let mut split = line.split_whitespace();
+        let (name, start, end) = match (split.next(), split.next(), split.next()) {
+            (Some(name), Some(start), Some(end)) => (name, start, end),
+            _ => return Err(error("expected at least 3 columns".to_string())),
+        };
+        let ref_id = header
+            .reference_id(name)
+            .ok_or_else(|| error(format!("unknown reference {}", name)))?;
+        let start: u32 = start
+            .parse()
+            .map_err(|_| error(format!("cannot parse start {}", start)))?;
+        let end: u32 = end
+            .parse()
+            .map_err(|_| error(format!("cannot parse end {}", end)))?;
+        if start > end {
+            return Err(error(format!("start > end ({} > {})", start, end)));
+        }
+        if start < end {
+            regions.push(Region::new(ref_id, start, end));
+        }
+    }
+    Ok(regions)
+}
+
+/// Iterator over clusters of overlapping records, created by
+/// [RegionViewer::clusters](struct.RegionViewer.html#method.clusters).
+pub struct Clusters<'v, V: RecordReader> {
+    reader: &'v mut V,
+    next_record: Option<record::Record>,
+}
+
+/// Returns the end of the record alignment (at least one base pair after the start).
+fn record_interval_end(record: &record::Record) -> i32 {
+    std::cmp::max(record.calculate_end(), record.start() + 1)
+}
+
+impl<'v, V: RecordReader> Iterator for Clusters<'v, V> {
+    type Item = Result<Vec<record::Record>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.next_record.take() {
+            Some(record) => record,
+            None => match self.reader.next()? {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        let ref_id = first.ref_id();
+        let mut max_end = record_interval_end(&first);
+        let mut cluster = vec![first];
+        loop {
+            let record = match self.reader.next() {
+                Some(Ok(record)) => record,
+                Some(Err(e)) => return Some(Err(e)),
+                None => break,
+            };
+            if record.ref_id() != ref_id || record.start() >= max_end {
+                self.next_record = Some(record);
+                break;
+            }
+            max_end = std::cmp::max(max_end, record_interval_end(&record));
+            cluster.push(record);
+        }
+        Some(Ok(cluster))
+    }
+}
+
+/// Goes over all references in the index order and returns a
+/// [RegionViewer](struct.RegionViewer.html) with all records aligned to each reference.
+/// Unmapped records without a reference are not returned.
+///
+/// As each viewer borrows the [IndexedReader](struct.IndexedReader.html), `ReferenceViewers`
+/// does not implement `Iterator`, use [next_reference](#method.next_reference) instead:
+/// ```rust
+/// let mut reader = bam::IndexedReader::from_path("in.bam").unwrap();
+/// let mut references = reader.fetch_all_by_reference();
+/// while let Some((ref_id, viewer)) = references.next_reference() {
+///     let count = viewer.count();
+///     println!("Reference {}: {} records", ref_id, count);
+/// }
+/// ```
+pub struct ReferenceViewers<'a, R: Read + Seek> {
+    parent: &'a mut IndexedReader<R>,
+    next_ref_id: u32,
+}
+
+impl<'a, R: Read + Seek> ReferenceViewers<'a, R> {
+    /// Returns the next reference id and a viewer over its records,
+    /// or `None` if there are no more references.
+    pub fn next_reference(&mut self) -> Option<(u32, RegionViewer<'_, R>)> {
+        let ref_id = self.next_ref_id;
+        let len = self.parent.header.reference_len(ref_id)?;
+        self.next_ref_id += 1;
+        Some((
+            ref_id,
+            self.parent
+                .fetch_unchecked(&Region::new(ref_id, 0, len), |_| true),
+        ))
+    }
+}
+
 /// Defines how to react to a BAI index being younger than BAM file.
 ///
 /// # Variants
@@ -111,11 +399,12 @@ impl<'a, R: Read + Seek> Iterator for RegionViewer<'a, R> {
 /// index is was modified earlier than the BAM file. `io::Error` will be raised.
 /// * `Ignore` - does nothing if the index is younger than the BAM file.
 /// * `Warn` - calls a function `Fn(&str)` and continues constructing
-/// [IndexedReader](struct.IndexedReader.html);
+///   [IndexedReader](struct.IndexedReader.html). The function should be `Send + Sync`,
+///   so that the builder can be moved between threads.
 pub enum ModificationTime {
     Error,
     Ignore,
-    Warn(Box<dyn Fn(&str)>),
+    Warn(Box<dyn Fn(&str) + Send + Sync>),
 }
 
 impl ModificationTime {
@@ -152,11 +441,85 @@ impl ModificationTime {
     }
 
     /// Create a warning strategy `ModificationTime::Warn`.
-    pub fn warn<F: Fn(&str) + 'static>(warning: F) -> Self {
+    pub fn warn<F: Fn(&str) + Send + Sync + 'static>(warning: F) -> Self {
         ModificationTime::Warn(Box::new(warning))
     }
 }
 
+/// Defines how [IndexedReader](struct.IndexedReader.html) reacts to a BAM file that is not
+/// sorted by coordinate, according to its [header](../header/struct.Header.html#method.sort_order).
+/// Fetching records relies on the coordinate order, so an index over such a file is meaningless.
+///
+/// # Variants
+/// * `Error` - [IndexedReader](struct.IndexedReader.html) will not be constructed if the header
+///   declares `SO:unsorted` or `SO:queryname`. `io::Error` will be raised (default).
+/// * `Ignore` - the sort order is not checked.
+/// * `Warn` - calls a function `Fn(&str)` and continues constructing
+///   [IndexedReader](struct.IndexedReader.html). The function should be `Send + Sync`,
+///   so that the builder can be moved between threads.
+///
+/// Files without a declared sort order (no `SO` tag or `SO:unknown`) are always accepted.
+pub enum SortOrderCheck {
+    Error,
+    Ignore,
+    Warn(Box<dyn Fn(&str) + Send + Sync>),
+}
+
+impl SortOrderCheck {
+    fn check(&self, header: &Header) -> Result<()> {
+        let order = match header.sort_order() {
+            SortOrder::Unsorted => "unsorted",
+            SortOrder::QueryName => "queryname",
+            SortOrder::Coordinate | SortOrder::Unknown => return Ok(()),
+        };
+        let message = format!(
+            "the BAM file is not sorted by coordinate (SO:{}), records cannot be fetched",
+            order
+        );
+        match &self {
+            SortOrderCheck::Ignore => Ok(()),
+            SortOrderCheck::Error => Err(Error::new(InvalidData, message)),
+            SortOrderCheck::Warn(box_fun) => {
+                box_fun(&message);
+                Ok(())
+            }
+        }
+    }
+
+    /// Create a warning strategy `SortOrderCheck::Warn`.
+    pub fn warn<F: Fn(&str) + Send + Sync + 'static>(warning: F) -> Self {
+        SortOrderCheck::Warn(Box::new(warning))
+    }
+}
+
+/// Defines how [BamReader](struct.BamReader.html) reacts to records with reference ids
+/// (or mate reference ids) that are not in the header.
+///
+/// # Variants
+/// * `Error` - `io::Error` will be raised,
+/// * `Skip` - such records will be skipped,
+/// * `Pass` - records are returned without checking reference ids (default).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidRef {
+    Error,
+    Skip,
+    Pass,
+}
+
+/// Defines how records are assigned to strands in
+/// [IndexedReader::coverage_stranded_by](struct.IndexedReader.html#method.coverage_stranded_by).
+///
+/// # Variants
+/// * `Record` - strand of the record itself (used by `coverage_stranded`),
+/// * `FirstInPair` - strand of the first mate: first mates and unpaired records use their own
+///   strand, and second mates use the strand of their mate. This way both mates of a pair are
+///   assigned to the same strand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrandAssignment {
+    Record,
+    FirstInPair,
+}
+
 /// [IndexedReader](struct.IndexedReader.html) builder. Allows to specify paths to BAM and BAI
 /// files, as well as the number of threads
 /// and an option to ignore or warn BAI modification time check.
@@ -164,6 +527,14 @@ pub struct IndexedReaderBuilder {
     bai_path: Option<PathBuf>,
     modification_time: ModificationTime,
     additional_threads: u16,
+    buffer: Cell<Option<Block>>,
+    capture_offsets: bool,
+    keep_raw: bool,
+    max_qual: Option<u8>,
+    references: Option<Vec<(String, u32)>>,
+    validate_index: bool,
+    buffer_pool: bool,
+    sort_order: SortOrderCheck,
 }
 
 impl IndexedReaderBuilder {
@@ -173,6 +544,14 @@ impl IndexedReaderBuilder {
             bai_path: None,
             modification_time: ModificationTime::Error,
             additional_threads: 0,
+            buffer: Cell::new(None),
+            capture_offsets: false,
+            keep_raw: false,
+            max_qual: None,
+            references: None,
+            validate_index: false,
+            buffer_pool: false,
+            sort_order: SortOrderCheck::Error,
         }
     }
 
@@ -195,6 +574,17 @@ impl IndexedReaderBuilder {
         self
     }
 
+    /// By default, the reader is not constructed if the BAM header declares that the records are
+    /// unsorted or sorted by query name
+    /// (see [Header::sort_order](../header/struct.Header.html#method.sort_order)).
+    ///
+    /// Enum [SortOrderCheck](enum.SortOrderCheck.html) contains options to skip
+    /// this check or raise a warning instead of returning an error.
+    pub fn sort_order(&mut self, check: SortOrderCheck) -> &mut Self {
+        self.sort_order = check;
+        self
+    }
+
     /// Sets the number of additional threads.
     ///
     /// Additional threads are used to decompress bgzip blocks, while the
@@ -205,6 +595,91 @@ impl IndexedReaderBuilder {
         self
     }
 
+    /// Sets a [block](../bgzip/struct.Block.html) that will be used to store decompressed data,
+    /// so that the reader does not need to allocate its own. The block can be reclaimed with
+    /// [IndexedReader::into_buffer](struct.IndexedReader.html#method.into_buffer) and passed
+    /// to the next reader, which saves allocations if many readers are opened one after another.
+    ///
+    /// A block allocates two buffers of approximately
+    /// [MAX_BLOCK_SIZE](../bgzip/constant.MAX_BLOCK_SIZE.html) bytes, when it is created using
+    /// [Block::new](../bgzip/struct.Block.html#method.new). Block contents are cleared.
+    /// The buffer is used by the next created reader only, and is ignored by multi-thread readers.
+    pub fn with_buffer(&mut self, block: Block) -> &mut Self {
+        self.buffer.set(Some(block));
+        self
+    }
+
+    /// Takes the [block](../bgzip/struct.Block.html) for decompressed data from a thread-local
+    /// pool, and returns it to the pool when the reader is dropped. If the pool is empty, the
+    /// reader allocates a new block. This reduces allocations when many short-lived readers are
+    /// created, for example one reader per request in a server. Default: `false`.
+    ///
+    /// The block is returned to the pool of the thread that drops the reader, and each thread keeps
+    /// a limited number of blocks (see
+    /// [SeekReader::from_stream_with_pool](../bgzip/struct.SeekReader.html#method.from_stream_with_pool)).
+    /// The option is ignored by multi-thread readers and if a buffer is set using
+    /// [with_buffer](#method.with_buffer).
+    pub fn buffer_pool(&mut self, use_pool: bool) -> &mut Self {
+        self.buffer_pool = use_pool;
+        self
+    }
+
+    /// Saves the [virtual offset](../index/struct.VirtualOffset.html) of each record, which can
+    /// be accessed with [Record::source_offset](../record/struct.Record.html#method.source_offset).
+    /// Default: `false`.
+    pub fn capture_offsets(&mut self, capture: bool) -> &mut Self {
+        self.capture_offsets = capture;
+        self
+    }
+
+    /// Saves the raw BAM block of each record, so that unmodified
+    /// ([pristine](../record/struct.Record.html#method.is_pristine)) records are written by
+    /// a [BamWriter](../bam_writer/struct.BamWriter.html) without encoding. Default: `false`.
+    ///
+    /// This speeds up filtering pipelines, but every record stores the block in addition to
+    /// the decoded fields.
+    pub fn keep_raw(&mut self, keep: bool) -> &mut Self {
+        self.keep_raw = keep;
+        self
+    }
+
+    /// Caps base qualities of all fetched records at `max` (without +33), see
+    /// [Record::cap_qualities](../record/struct.Record.html#method.cap_qualities).
+    /// By default, qualities are not changed.
+    ///
+    /// Only the qualities of the records in memory are modified: the BAM file stays the same,
+    /// and the change is lost unless the records are written out.
+    pub fn cap_qualities(&mut self, max: u8) -> &mut Self {
+        self.max_qual = Some(max);
+        self
+    }
+
+    /// Sets an external reference dictionary: pairs *(reference name, reference length)*.
+    ///
+    /// This is an escape hatch for BAM files, produced by some tools, that declare no references
+    /// in the header, while records use reference ids from an external dictionary.
+    /// If the BAM header has no references, the dictionary is added to the
+    /// [header](struct.IndexedReader.html#method.header) and is used for reference name lookups
+    /// and region bounds checks. If the BAM header has references, they should be the same as the
+    /// dictionary, otherwise the reader will not be created.
+    pub fn reference_dictionary<I>(&mut self, references: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (String, u32)>,
+    {
+        self.references = Some(references.into_iter().collect());
+        self
+    }
+
+    /// Validates the whole BAI index after loading it (see
+    /// [Index::validate](../index/struct.Index.html#method.validate)), so that a corrupted index
+    /// produces an error before it leads to incorrect fetch results. Default: `false`.
+    ///
+    /// Validation takes time linear in the size of the index.
+    pub fn validate_index(&mut self, validate: bool) -> &mut Self {
+        self.validate_index = validate;
+        self
+    }
+
     /// Creates a new [IndexedReader](struct.IndexedReader.html) from `bam_path`.
     /// If BAI path was not specified, the functions tries to open `{bam_path}.bai`.
     pub fn from_path<P: AsRef<Path>>(&self, bam_path: P) -> Result<IndexedReader<BufReader<File>>> {
@@ -217,13 +692,13 @@ impl IndexedReaderBuilder {
         self.modification_time.check(&bam_path, &bai_path)?;
 
         let bam = BufReader::new(File::open(bam_path)?);
-
-        let reader = bgzip::SeekReader::from_stream(bam, self.additional_threads)
+        let reader = self
+            .open_bgzip(bam)
             .map_err(|e| Error::new(e.kind(), format!("Failed to open BAM file: {}", e)))?;
 
         let index = Index::from_path(bai_path)
             .map_err(|e| Error::new(e.kind(), format!("Failed to open BAI index: {}", e)))?;
-        IndexedReader::new(reader, index)
+        IndexedReader::new(reader, index, self)
     }
 
     /// Creates a new [IndexedReader](struct.IndexedReader.html) from two streams.
@@ -234,15 +709,36 @@ impl IndexedReaderBuilder {
         bam_stream: R,
         bai_stream: T,
     ) -> Result<IndexedReader<R>> {
-        let reader = bgzip::SeekReader::from_stream(bam_stream, self.additional_threads)
+        let reader = self
+            .open_bgzip(bam_stream)
             .map_err(|e| Error::new(e.kind(), format!("Failed to read BAM stream: {}", e)))?;
 
         let index = Index::from_stream(bai_stream)
             .map_err(|e| Error::new(e.kind(), format!("Failed to read BAI index: {}", e)))?;
-        IndexedReader::new(reader, index)
+        IndexedReader::new(reader, index, self)
+    }
+
+    fn open_bgzip<R: Read + Seek>(&self, stream: R) -> Result<bgzip::SeekReader<R>> {
+        match self.buffer.take() {
+            None if self.buffer_pool => {
+                bgzip::SeekReader::from_stream_with_pool(stream, self.additional_threads)
+            }
+            block => {
+                bgzip::SeekReader::from_stream_with_block(stream, self.additional_threads, block)
+            }
+        }
     }
 }
 
+/// Flags of records, excluded by
+/// [IndexedReader::fetch_filtered](struct.IndexedReader.html#method.fetch_filtered):
+/// `0xf04` (unmapped, secondary, QC-failed, duplicate and supplementary records).
+pub const DEFAULT_EXCLUDED_FLAGS: u16 = record::RECORD_UNMAPPED
+    | record::SECONDARY
+    | record::RECORD_FAILS_QC
+    | record::PCR_OR_OPTICAL_DUPLICATE
+    | record::SUPPLEMENTARY;
+
 /// Genomic coordinates, used in [struct.IndexedReader.html#method.fetch] and [struct.IndexedReader.html#method.pileup].
 /// `ref_id` is 0-based, `start-end` is 0-based half-open interval.
 #[derive(Clone, Debug)]
@@ -404,6 +900,10 @@ pub struct IndexedReader<R: Read + Seek> {
     pub reader: bgzip::SeekReader<R>,
     header: Header,
     index: Index,
+    capture_offsets: bool,
+    keep_raw: bool,
+    max_qual: Option<u8>,
+    format_info: FormatInfo,
 }
 
 impl IndexedReader<BufReader<File>> {
@@ -421,17 +921,95 @@ impl IndexedReader<BufReader<File>> {
 }
 
 impl<R: Read + Seek> IndexedReader<R> {
-    fn new(mut reader: bgzip::SeekReader<R>, index: Index) -> Result<Self> {
+    fn new(
+        mut reader: bgzip::SeekReader<R>,
+        index: Index,
+        builder: &IndexedReaderBuilder,
+    ) -> Result<Self> {
+        if builder.validate_index {
+            index.validate()?;
+        }
         reader.make_consecutive();
         let header = Header::from_bam(&mut reader)?;
-        Ok(Self {
+        builder.sort_order.check(&header)?;
+        let format_info = FormatInfo::bam(&header);
+        let mut reader = Self {
             reader,
             header,
             index,
+            capture_offsets: builder.capture_offsets,
+            keep_raw: builder.keep_raw,
+            max_qual: builder.max_qual,
+            format_info,
+        };
+        if let Some(references) = &builder.references {
+            reader.set_references(references)?;
+        }
+        Ok(reader)
+    }
+
+    /// Adds external references to a header without references, or checks that
+    /// the header has the same references.
+    fn set_references(&mut self, references: &[(String, u32)]) -> Result<()> {
+        if self.header.n_references() == 0 {
+            for (name, len) in references {
+                self.header
+                    .push_entry(HeaderEntry::ref_sequence(name.clone(), *len))
+                    .map_err(|e| Error::new(InvalidInput, e))?;
+            }
+            return Ok(());
+        }
+        self.header.check_compatible(references).map_err(|e| {
+            Error::new(
+                InvalidInput,
+                format!("Reference dictionary does not match the BAM header: {}", e),
+            )
         })
     }
 
+    /// Returns an error if the region reference is out of bounds or the region ends after the reference.
+    /// Otherwise, returns the region with the end `u32::MAX` replaced by the reference length.
+    fn check_region(&self, region: &Region) -> Result<Region> {
+        match self.header.reference_len(region.ref_id()) {
+            None => Err(Error::new(
+                InvalidInput,
+                format!(
+                    "Failed to fetch records: out of bounds reference {}",
+                    region.ref_id()
+                ),
+            )),
+            Some(len) if region.end() == u32::MAX => {
+                if region.start() > len {
+                    Err(Error::new(
+                        InvalidInput,
+                        format!(
+                            "Failed to fetch records: start > reference length ({} > {})",
+                            region.start(),
+                            len
+                        ),
+                    ))
+                } else {
+                    Ok(Region::new(region.ref_id(), region.start(), len))
+                }
+            }
+            Some(len) if len < region.end() => Err(Error::new(
+                InvalidInput,
+                format!(
+                    "Failed to fetch records: end > reference length ({} > {})",
+                    region.end(),
+                    len
+                ),
+            )),
+            _ => Ok(region.clone()),
+        }
+    }
+
     /// Returns an iterator over records aligned to the [reference region](struct.Region.html).
+    ///
+    /// Returns an error if the region ends after the end of the reference, with one exception:
+    /// region end `u32::MAX` means "until the end of the reference", so
+    /// `Region::new(ref_id, start, u32::MAX)` fetches all records from `start` onward.
+    /// The same applies to other methods that take a region.
     pub fn fetch<'a>(&'a mut self, region: &Region) -> Result<RegionViewer<'a, R>> {
         self.fetch_by(region, |_| true)
     }
@@ -440,48 +1018,192 @@ impl<R: Read + Seek> IndexedReader<R> {
     ///
     /// Records will be filtered by `predicate`. It helps to slightly reduce fetching time,
     /// as some records will be removed without allocating new memory and without calculating
-    /// alignment length.
+    /// alignment length. The predicate should be `Send + Sync`, so the viewer can be sent to
+    /// another thread.
     pub fn fetch_by<'a, F>(
         &'a mut self,
         region: &Region,
         predicate: F,
     ) -> Result<RegionViewer<'a, R>>
     where
-        F: 'static + Fn(&record::Record) -> bool,
+        F: 'static + Fn(&record::Record) -> bool + Send + Sync,
     {
-        match self.header.reference_len(region.ref_id()) {
-            None => {
-                return Err(Error::new(
-                    InvalidInput,
-                    format!(
-                        "Failed to fetch records: out of bounds reference {}",
-                        region.ref_id()
-                    ),
-                ))
-            }
-            Some(len) if len < region.end() => {
-                return Err(Error::new(
-                    InvalidInput,
-                    format!(
-                        "Failed to fetch records: end > reference length ({} > {})",
-                        region.end(),
-                        len
-                    ),
-                ))
+        let region = self.check_region(region)?;
+        Ok(self.fetch_unchecked(&region, predicate))
+    }
+
+    /// Returns an iterator over records aligned to `ref_id:start-end` (0-based half-open interval),
+    /// filtered by `predicate`, same as [fetch_by](#method.fetch_by). In addition to the record,
+    /// the predicate receives the record start relative to the region: `record.start() - start`,
+    /// which is negative for records starting before the region.
+    ///
+    /// For example, the following code fetches only records that start in the first half
+    /// of the region:
+    /// ```rust
+    /// let mut reader = bam::IndexedReader::from_path("in.bam").unwrap();
+    /// let viewer = reader.fetch_by_pos(0, 1000, 2000, |_, offset| offset >= 0 && offset < 500)
+    ///     .unwrap();
+    /// ```
+    pub fn fetch_by_pos<'a, F>(
+        &'a mut self,
+        ref_id: u32,
+        start: u32,
+        end: u32,
+        predicate: F,
+    ) -> Result<RegionViewer<'a, R>>
+    where
+        F: 'static + Fn(&record::Record, i32) -> bool + Send + Sync,
+    {
+        let region_start = start as i32;
+        self.fetch_by(&Region::new(ref_id, start, end), move |record| {
+            predicate(record, record.start() - region_start)
+        })
+    }
+
+    /// Returns an iterator over records aligned to `ref_id:start-end` (0-based half-open interval),
+    /// excluding records with any of the [DEFAULT_EXCLUDED_FLAGS](constant.DEFAULT_EXCLUDED_FLAGS.html):
+    /// unmapped, secondary, supplementary, QC-failed records and PCR or optical duplicates.
+    ///
+    /// Use [fetch](#method.fetch) to get all records, or [fetch_by](#method.fetch_by) to use
+    /// a custom filter.
+    pub fn fetch_filtered<'a>(
+        &'a mut self,
+        ref_id: u32,
+        start: u32,
+        end: u32,
+    ) -> Result<RegionViewer<'a, R>> {
+        self.fetch_by(&Region::new(ref_id, start, end), |record| {
+            record.flag().no_bits(DEFAULT_EXCLUDED_FLAGS)
+        })
+    }
+
+    /// Returns an iterator over records that cover a single reference position `ref_id:pos`
+    /// (0-based), same as fetching the region `pos..pos + 1`.
+    ///
+    /// A mapped record covers the position if the position is between the record start and the
+    /// [alignment end](../record/struct.Record.html#method.calculate_end), so records where
+    /// `pos` falls inside a deletion or a reference skip are returned as well. To check what
+    /// is aligned to the position, use a [pileup](../pileup/struct.Pileup.html) or
+    /// [Record::aligned_pairs](../record/struct.Record.html#method.aligned_pairs).
+    pub fn fetch_pos<'a>(&'a mut self, ref_id: u32, pos: u32) -> Result<RegionViewer<'a, R>> {
+        self.fetch(&Region::new(ref_id, pos, pos.saturating_add(1)))
+    }
+
+    /// Returns an iterator over records aligned to `ref_id:start-end` (0-based half-open interval)
+    /// with mapping quality at least `min_mapq`.
+    ///
+    /// Same as [fetch_by](#method.fetch_by) with a MAPQ predicate: records with lower mapping
+    /// quality are skipped before their alignment end is calculated, and the remaining records
+    /// are checked for overlap with the region as usual.
+    pub fn fetch_min_mapq<'a>(
+        &'a mut self,
+        ref_id: u32,
+        start: u32,
+        end: u32,
+        min_mapq: u8,
+    ) -> Result<RegionViewer<'a, R>> {
+        self.fetch_by(&Region::new(ref_id, start, end), move |record| {
+            record.mapq() >= min_mapq
+        })
+    }
+
+    /// Returns an iterator over records, overlapping any of the `regions`.
+    ///
+    /// Regions are sorted, and overlapping or adjacent regions on the same reference are merged,
+    /// for example `[100-200)` and `[200-300)` become `[100-300)`. The records are returned
+    /// in the order of the merged regions, and a record overlapping several regions is
+    /// returned only once. Records are checked for overlap in the same way as in
+    /// [fetch](#method.fetch).
+    ///
+    /// Returns an error if any of the regions is out of bounds. Region end `u32::MAX` means
+    /// the end of the reference.
+    pub fn fetch_regions<'a>(&'a mut self, regions: &[Region]) -> Result<RegionsViewer<'a, R>> {
+        let mut sorted = regions
+            .iter()
+            .map(|region| self.check_region(region))
+            .collect::<Result<Vec<_>>>()?;
+        sorted.sort_by_key(|region| (region.ref_id(), region.start()));
+        let mut merged: Vec<Region> = Vec::with_capacity(sorted.len());
+        for region in sorted {
+            match merged.last_mut() {
+                Some(last) if last.ref_id() == region.ref_id() && region.start() <= last.end() => {
+                    if region.end() > last.end() {
+                        last.set_end(region.end());
+                    }
+                }
+                _ => merged.push(region),
             }
-            _ => {}
         }
+        Ok(RegionsViewer {
+            parent: self,
+            regions: merged,
+            next_region: 0,
+            active: false,
+        })
+    }
+
+    /// Returns an iterator over records, overlapping any of the intervals from a BED file.
+    ///
+    /// BED intervals are 0-based half-open, only the first three columns (reference name, start
+    /// and end) are used, and empty lines as well as lines starting with `#`, `track` or
+    /// `browser` are skipped. Reference names should be present in the header.
+    /// Intervals are merged and records are deduplicated as in
+    /// [fetch_regions](#method.fetch_regions).
+    ///
+    /// ```rust
+    /// let mut reader = bam::IndexedReader::from_path("in.bam").unwrap();
+    /// for record in reader.fetch_bed("targets.bed").unwrap() {
+    ///     let record = record.unwrap();
+    ///     // Do something with the record.
+    /// }
+    /// ```
+    pub fn fetch_bed<P: AsRef<Path>>(&mut self, bed_path: P) -> Result<RegionsViewer<'_, R>> {
+        let regions = read_bed(bed_path, &self.header)?;
+        self.fetch_regions(&regions)
+    }
 
+    /// Same as [fetch_by](#method.fetch_by), but does not check that the region is valid.
+    fn fetch_unchecked<'a, F>(&'a mut self, region: &Region, predicate: F) -> RegionViewer<'a, R>
+    where
+        F: 'static + Fn(&record::Record) -> bool + Send + Sync,
+    {
         let chunks =
             self.index
                 .fetch_chunks(region.ref_id(), region.start() as i32, region.end() as i32);
         self.reader.set_chunks(chunks);
-        Ok(RegionViewer {
+        RegionViewer {
             parent: self,
             start: region.start() as i32,
             end: region.end() as i32,
             predicate: Box::new(predicate),
-        })
+            skipped_unmapped: 0,
+        }
+    }
+
+    /// Returns an iterator over all records aligned to the reference `ref_id` (0-based).
+    pub fn fetch_ref<'a>(&'a mut self, ref_id: u32) -> Result<RegionViewer<'a, R>> {
+        let len = self.header.reference_len(ref_id).ok_or_else(|| {
+            Error::new(
+                InvalidInput,
+                format!(
+                    "Failed to fetch records: out of bounds reference {}",
+                    ref_id
+                ),
+            )
+        })?;
+        Ok(self.fetch_unchecked(&Region::new(ref_id, 0, len), |_| true))
+    }
+
+    /// Returns [ReferenceViewers](struct.ReferenceViewers.html), which goes over references
+    /// one by one and returns records aligned to each of them. See
+    /// [ReferenceViewers](struct.ReferenceViewers.html) for an example.
+    /// References without records in the index can be skipped using
+    /// [Index::populated_references](../index/struct.Index.html#method.populated_references).
+    pub fn fetch_all_by_reference(&mut self) -> ReferenceViewers<'_, R> {
+        ReferenceViewers {
+            parent: self,
+            next_ref_id: 0,
+        }
     }
 
 
@@ -492,30 +1214,9 @@ impl<R: Read + Seek> IndexedReader<R> {
         predicate: F,
     ) -> Result<RegionViewer<'a, R>>
     where
-        F: 'static + Fn(&record::Record) -> bool,
+        F: 'static + Fn(&record::Record) -> bool + Send + Sync,
     {
-        match self.header.reference_len(region.ref_id()) {
-            None => {
-                return Err(Error::new(
-                    InvalidInput,
-                    format!(
-                        "Failed to fetch records: out of bounds reference {}",
-                        region.ref_id()
-                    ),
-                ))
-            }
-            Some(len) if len < region.end() => {
-                return Err(Error::new(
-                    InvalidInput,
-                    format!(
-                        "Failed to fetch records: end > reference length ({} > {})",
-                        region.end(),
-                        len
-                    ),
-                ))
-            }
-            _ => {}
-        }
+        self.check_region(region)?;
 
         let chunks =
             self.index
@@ -526,20 +1227,232 @@ impl<R: Read + Seek> IndexedReader<R> {
             start: std::i32::MIN,
             end: std::i32::MAX,
             predicate: Box::new(predicate),
+            skipped_unmapped: 0,
         })
     }
 
-    /// Returns an iterator over all records from the start of the BAM file.
-    pub fn full<'a>(&'a mut self) -> RegionViewer<'a, R> {
-        self.full_by(|_| true)
+    /// Returns chunks of the BAM file that [fetch](#method.fetch) would read for the region
+    /// `ref_id:start-end` (0-based half-open interval), as pairs of raw
+    /// [virtual offsets](../index/struct.VirtualOffset.html) `(begin, end)`.
+    /// Neither the BAM file nor the reader state are touched, so the chunks can be used to
+    /// issue readahead requests or for debugging.
+    ///
+    /// The region is checked in the same way as in [fetch](#method.fetch).
+    pub fn region_chunks(&self, ref_id: u32, start: u32, end: u32) -> Result<Vec<(u64, u64)>> {
+        let region = self.check_region(&Region::new(ref_id, start, end))?;
+        Ok(self
+            .index
+            .fetch_chunks(region.ref_id(), region.start() as i32, region.end() as i32)
+            .into_iter()
+            .map(|chunk| (chunk.start().raw(), chunk.end().raw()))
+            .collect())
     }
 
-    /// Returns an iterator over all records from the start of the BAM file.
+    /// Estimates the number of compressed bytes that [fetch](#method.fetch) would read
+    /// for the region `ref_id:start-end`: the sum of distances between compressed offsets
+    /// (`block_offset`) of the [region chunks](#method.region_chunks) ends and starts.
     ///
-    /// Records will be filtered by `predicate`, which allows to skip some records without allocating new memory.
-    pub fn full_by<'a, F>(&'a mut self, predicate: F) -> RegionViewer<'a, R>
+    /// The estimate does not include the last block of each chunk, where the chunk ends, so it
+    /// can be lower than the actual size by at most 64 KiB per chunk. For example, a chunk
+    /// within a single block has estimated size 0.
+    pub fn region_compressed_size(&self, ref_id: u32, start: u32, end: u32) -> Result<u64> {
+        Ok(self
+            .region_chunks(ref_id, start, end)?
+            .into_iter()
+            .map(|(begin, end)| {
+                index::VirtualOffset::from_raw(end).block_offset()
+                    - index::VirtualOffset::from_raw(begin).block_offset()
+            })
+            .sum())
+    }
+
+    /// Returns `true` if there is at least one record aligned to the region
+    /// `ref_id:start-end` (0-based half-open interval).
+    ///
+    /// If the index contains no chunks for the region, returns `false` without reading the BAM file.
+    /// Otherwise, reads records until the first record overlapping the region.
+    pub fn has_records(&mut self, ref_id: u32, start: u32, end: u32) -> Result<bool> {
+        let region = self.check_region(&Region::new(ref_id, start, end))?;
+        let chunks =
+            self.index
+                .fetch_chunks(region.ref_id(), region.start() as i32, region.end() as i32);
+        if chunks.is_empty() {
+            return Ok(false);
+        }
+        self.reader.set_chunks(chunks);
+        let mut viewer = RegionViewer {
+            parent: self,
+            start: region.start() as i32,
+            end: region.end() as i32,
+            predicate: Box::new(|_| true),
+            skipped_unmapped: 0,
+        };
+        viewer.read_into(&mut record::Record::new())
+    }
+
+    /// Computes per-base depth over the region `ref_id:start-end` (0-based half-open interval)
+    /// and writes it to `out` in the BEDGRAPH format. Each line has format
+    /// `chrom\tstart\tend\tdepth`, where consecutive positions with the same depth are merged,
+    /// and positions with zero depth are not written.
+    ///
+    /// Depth is calculated using [Pileup](../pileup/struct.Pileup.html) with
+    /// [skipped deletions](../pileup/struct.Pileup.html#method.skip_deletions), so only
+    /// records with a base aligned to a position are counted.
+    pub fn write_bedgraph<W: Write>(
+        &mut self,
+        ref_id: u32,
+        start: u32,
+        end: u32,
+        out: &mut W,
+    ) -> Result<()> {
+        let chrom = match self.header.reference_name(ref_id) {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(Error::new(
+                    InvalidInput,
+                    format!("Failed to write bedgraph: no reference with id {}", ref_id),
+                ))
+            }
+        };
+        let mut viewer = self.fetch(&Region::new(ref_id, start, end))?;
+        let mut pileup = Pileup::new(&mut viewer);
+        pileup.skip_deletions(true);
+
+        // Current run of positions [run_start, run_end) with the same depth.
+        let mut run: Option<(u32, u32, usize)> = None;
+        for column in pileup {
+            let column = column?;
+            let pos = column.ref_pos();
+            if pos < start || pos >= end {
+                continue;
+            }
+            let depth = column.entries().len();
+            if let Some((run_start, run_end, run_depth)) = run {
+                if run_end == pos && run_depth == depth {
+                    run = Some((run_start, pos + 1, depth));
+                    continue;
+                }
+                writeln!(out, "{}\t{}\t{}\t{}", chrom, run_start, run_end, run_depth)?;
+            }
+            run = Some((pos, pos + 1, depth));
+        }
+        if let Some((run_start, run_end, run_depth)) = run {
+            writeln!(out, "{}\t{}\t{}\t{}", chrom, run_start, run_end, run_depth)?;
+        }
+        Ok(())
+    }
+
+    /// Returns physical depth for each position of the region `ref_id:start-end` (0-based
+    /// half-open interval): *i*-th element stores the depth at position `start + i`.
+    ///
+    /// Same as in [write_bedgraph](#method.write_bedgraph), only positions with a base aligned
+    /// to them are counted, but if both mates of a proper pair (flags `0x1` and `0x2`, mates on
+    /// the same reference) overlap a position, the fragment is counted once. Unmapped,
+    /// secondary and supplementary records are skipped, mates are matched by their names.
+    pub fn fragment_coverage(&mut self, ref_id: u32, start: u32, end: u32) -> Result<Vec<u32>> {
+        let region = self.check_region(&Region::new(ref_id, start, end))?;
+        let start = region.start();
+        let end = region.end();
+        let mut depth = vec![0_u32; region.len() as usize];
+        let mut viewer = self.fetch_by(&region, |record| {
+            let flag = record.flag();
+            flag.is_mapped() && !flag.is_secondary() && !flag.is_supplementary()
+        })?;
+
+        // Positions covered by the first mate of a proper pair, until the second mate is found.
+        let mut first_mates: HashMap<Vec<u8>, Vec<u32>> = HashMap::new();
+        let mut record = record::Record::new();
+        while viewer.read_into(&mut record)? {
+            // Matching pairs are sorted by reference position.
+            let positions: Vec<u32> = record
+                .matching_pairs()
+                .map(|(_, ref_pos)| ref_pos)
+                .filter(|&ref_pos| ref_pos >= start && ref_pos < end)
+                .collect();
+            let flag = record.flag();
+            let proper_pair = flag.is_paired()
+                && flag.all_segments_aligned()
+                && record.mate_ref_id() == record.ref_id();
+            let mate_positions = if proper_pair {
+                first_mates.remove(record.name())
+            } else {
+                None
+            };
+
+            for &pos in positions.iter() {
+                if let Some(mate_positions) = &mate_positions {
+                    if mate_positions.binary_search(&pos).is_ok() {
+                        continue;
+                    }
+                }
+                depth[(pos - start) as usize] += 1;
+            }
+            if proper_pair && mate_positions.is_none() {
+                first_mates.insert(record.name().to_vec(), positions);
+            }
+        }
+        Ok(depth)
+    }
+
+    /// Returns forward and reverse coverage of the reference `ref_id`: *i*-th elements of the
+    /// vectors store the number of forward and reverse strand records with a base aligned to the
+    /// position *i*. Records are assigned to a strand using
+    /// [is_reverse_strand](../record/struct.Flag.html#method.is_reverse_strand), use
+    /// [coverage_stranded_by](#method.coverage_stranded_by) to assign second mates to the strand
+    /// of the first mate.
+    ///
+    /// Unmapped, secondary and supplementary records are skipped, and deletions, reference skips
+    /// and insertions do not contribute to the coverage.
+    pub fn coverage_stranded(&mut self, ref_id: u32) -> Result<(Vec<u32>, Vec<u32>)> {
+        self.coverage_stranded_by(ref_id, StrandAssignment::Record)
+    }
+
+    /// Same as [coverage_stranded](#method.coverage_stranded), but records are assigned to
+    /// strands according to [StrandAssignment](enum.StrandAssignment.html).
+    /// For example, paired-end stranded RNA-seq libraries use
+    /// [FirstInPair](enum.StrandAssignment.html#variant.FirstInPair).
+    pub fn coverage_stranded_by(
+        &mut self,
+        ref_id: u32,
+        assignment: StrandAssignment,
+    ) -> Result<(Vec<u32>, Vec<u32>)> {
+        let mut viewer = self.fetch_ref(ref_id)?;
+        let len = viewer.parent.header.reference_len(ref_id).unwrap_or(0) as usize;
+        let mut forward = vec![0_u32; len];
+        let mut reverse = vec![0_u32; len];
+        let mut record = record::Record::new();
+        while viewer.read_into(&mut record)? {
+            let flag = record.flag();
+            if !flag.is_mapped() || flag.is_secondary() || flag.is_supplementary() {
+                continue;
+            }
+            let is_reverse = match assignment {
+                StrandAssignment::FirstInPair if flag.is_paired() && flag.last_in_pair() => {
+                    flag.mate_is_reverse_strand()
+                }
+                _ => flag.is_reverse_strand(),
+            };
+            let depth = if is_reverse { &mut reverse } else { &mut forward };
+            for (_, ref_pos) in record.matching_pairs() {
+                if let Some(value) = depth.get_mut(ref_pos as usize) {
+                    *value += 1;
+                }
+            }
+        }
+        Ok((forward, reverse))
+    }
+
+    /// Returns an iterator over all records from the start of the BAM file.
+    pub fn full<'a>(&'a mut self) -> RegionViewer<'a, R> {
+        self.full_by(|_| true)
+    }
+
+    /// Returns an iterator over all records from the start of the BAM file.
+    ///
+    /// Records will be filtered by `predicate`, which allows to skip some records without allocating new memory.
+    pub fn full_by<'a, F>(&'a mut self, predicate: F) -> RegionViewer<'a, R>
     where
-        F: 'static + Fn(&record::Record) -> bool,
+        F: 'static + Fn(&record::Record) -> bool + Send + Sync,
     {
         if let Some(offset) = self.index.start_offset() {
             self.reader
@@ -550,20 +1463,50 @@ impl<R: Read + Seek> IndexedReader<R> {
             start: std::i32::MIN,
             end: std::i32::MAX,
             predicate: Box::new(predicate),
+            skipped_unmapped: 0,
         }
     }
 
-    /// Returns an iterator over all records from the start of the BAM file.
+    /// Returns an iterator over all records in the bgzip `chunks`, without checking
+    /// their alignment positions.
     pub fn chunk<'a>(&'a mut self, chunks: Vec<Chunk>) -> RegionViewer<'a, R> {
         self.chunk_by(chunks, |_| true)
     }
 
-    /// Returns an iterator over all records from the start of the BAM file.
+    /// Returns an iterator over records in the bgzip `chunks`, that are aligned to the `region`.
+    /// The BAI index is not used, so the chunks can come from an external index. Chunks are sorted
+    /// and merged before reading. To read all records from the chunks use [chunk](#method.chunk).
+    pub fn fetch_chunks_raw<'a>(
+        &'a mut self,
+        mut chunks: Vec<Chunk>,
+        region: &Region,
+    ) -> Result<RegionViewer<'a, R>> {
+        let region = self.check_region(region)?;
+        chunks.sort();
+        let mut merged: Vec<Chunk> = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            match merged.last_mut() {
+                Some(last) if last.can_combine(&chunk) => *last = last.combine(&chunk),
+                _ => merged.push(chunk),
+            }
+        }
+        self.reader.set_chunks(merged);
+        Ok(RegionViewer {
+            parent: self,
+            start: region.start() as i32,
+            end: region.end() as i32,
+            predicate: Box::new(|_| true),
+            skipped_unmapped: 0,
+        })
+    }
+
+    /// Returns an iterator over all records in the bgzip `chunks`, without checking
+    /// their alignment positions.
     ///
     /// Records will be filtered by `predicate`, which allows to skip some records without allocating new memory.
     pub fn chunk_by<'a, F>(&'a mut self, chunks: Vec<Chunk>, predicate: F) -> RegionViewer<'a, R>
     where
-        F: 'static + Fn(&record::Record) -> bool,
+        F: 'static + Fn(&record::Record) -> bool + Send + Sync,
     {
         self.reader.set_chunks(chunks);
         RegionViewer {
@@ -571,6 +1514,7 @@ impl<R: Read + Seek> IndexedReader<R> {
             start: std::i32::MIN,
             end: std::i32::MAX,
             predicate: Box::new(predicate),
+            skipped_unmapped: 0,
         }
     }
 
@@ -584,7 +1528,7 @@ impl<R: Read + Seek> IndexedReader<R> {
     /// Records will be filtered by `predicate`, which allows to skip some records without allocating new memory.
     pub fn unmapped_by<'a, F>(&'a mut self, predicate: F) -> RegionViewer<'a, R>
     where
-        F: 'static + Fn(&record::Record) -> bool,
+        F: 'static + Fn(&record::Record) -> bool + Send + Sync,
     {
         if let Some(offset) = self.index.end_offset() {
             self.reader
@@ -595,25 +1539,734 @@ impl<R: Read + Seek> IndexedReader<R> {
             start: -1,
             end: 0,
             predicate: Box::new(predicate),
+            skipped_unmapped: 0,
         }
     }
 
+    /// Reads a single record that starts at the virtual offset `voffset` (see
+    /// [VirtualOffset::raw](../index/struct.VirtualOffset.html#method.raw)), for example an offset
+    /// saved with [capture_offsets](struct.IndexedReaderBuilder.html#method.capture_offsets).
+    ///
+    /// Returns an `InvalidInput` error if there is no bgzip block at the offset, or if the offset
+    /// points outside of the block contents. Returns an `InvalidData` error if the offset is not
+    /// on a record boundary: the decoded record block size should be at least 32 bytes, the
+    /// remaining file should contain the whole record, and the record should be valid.
+    pub fn read_at(&mut self, voffset: u64) -> Result<record::Record> {
+        let offset = index::VirtualOffset::from_raw(voffset);
+        self.reader
+            .set_chunks(vec![index::Chunk::new(offset, index::VirtualOffset::MAX)]);
+        let block_size = match self.reader.next() {
+            Ok(block) => block.uncompressed_size() as usize,
+            Err(BlockError::EndOfStream) => {
+                return Err(Error::new(
+                    InvalidInput,
+                    format!("Failed to read record: no block at offset {}", offset),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if offset.contents_offset() as usize >= block_size {
+            return Err(Error::new(
+                InvalidInput,
+                format!(
+                    "Failed to read record: offset {} is outside of the block ({} bytes)",
+                    offset, block_size
+                ),
+            ));
+        }
+
+        let not_a_record = |text: String| {
+            Error::new(
+                InvalidData,
+                format!("Failed to read record at offset {}: {}", offset, text),
+            )
+        };
+        let mut size_bytes = [0_u8; 4];
+        self.reader
+            .read_exact(&mut size_bytes)
+            .map_err(|_| not_a_record("cannot read record block size".to_string()))?;
+        let record_size = i32::from_le_bytes(size_bytes);
+        if record_size < 32 {
+            return Err(not_a_record(format!("invalid block size {}", record_size)));
+        }
+        let mut raw = size_bytes.to_vec();
+        (&mut self.reader)
+            .take(record_size as u64)
+            .read_to_end(&mut raw)?;
+        if raw.len() != record_size as usize + 4 {
+            return Err(not_a_record(format!(
+                "block size is {}, but only {} bytes remain",
+                record_size,
+                raw.len() - 4
+            )));
+        }
+
+        let mut record = record::Record::new();
+        record
+            .fill_from_bam(&mut &raw[..])
+            .map_err(|e| not_a_record(e.to_string()))?;
+        let n_refs = self.header.n_references() as i32;
+        if record.ref_id() >= n_refs || record.mate_ref_id() >= n_refs {
+            return Err(not_a_record("reference id is out of bounds".to_string()));
+        }
+        if self.capture_offsets {
+            record.set_source_offset(Some(offset));
+        }
+        Ok(record)
+    }
+
     /// Returns [header](../header/struct.Header.html).
     pub fn header(&self) -> &Header {
         &self.header
     }
 
+    /// Returns the [container format](../struct.FormatInfo.html) of the BAM file, captured while
+    /// parsing the header.
+    pub fn format_info(&self) -> FormatInfo {
+        self.format_info
+    }
+
     /// Returns [BAI index](../index/struct.Index.html).
     pub fn index(&self) -> &Index {
         &self.index
     }
 
+    /// Returns the total number of compressed bytes loaded from the BAM file, summed over all
+    /// fetched regions (including the header). Overlapping regions may be counted several times,
+    /// so compare the value to the file size only for a single consecutive pass
+    /// ([full](#method.full) or [fetch](#method.fetch) over the whole file).
+    pub fn bytes_processed(&self) -> u64 {
+        self.reader.bytes_processed()
+    }
+
     /// Pauses multi-thread reader until the next read operation. Does nothing to a single-thread reader.
     ///
     /// Use with caution: pausing and unpausing takes some time.
     pub fn pause(&mut self) {
         self.reader.pause();
     }
+
+    /// Consumes the reader and returns the block used for decompression, which can be passed to
+    /// [IndexedReaderBuilder::with_buffer](struct.IndexedReaderBuilder.html#method.with_buffer).
+    /// Returns `None` for multi-thread readers.
+    pub fn into_buffer(self) -> Option<Block> {
+        self.reader.into_block()
+    }
+}
+
+/// Several indexed BAM files, presented as a single dataset.
+///
+/// All files should have the same references (names and lengths) in the same order.
+/// [fetch](#method.fetch) returns a [MergedViewer](struct.MergedViewer.html) that merges
+/// records from all files in the coordinate order:
+/// ```rust
+/// let mut reader = bam::bam_reader::VirtualIndexedReader::from_path_pairs(vec![
+///     ("in1.bam", "in1.bam.bai"),
+///     ("in2.bam", "in2.bam.bai"),
+/// ]).unwrap();
+/// for record in reader.fetch(&bam::Region::new(0, 1000, 2000)).unwrap() {
+///     let record = record.unwrap();
+///     // Do something with the record.
+/// }
+/// ```
+pub struct VirtualIndexedReader<R: Read + Seek> {
+    readers: Vec<IndexedReader<R>>,
+}
+
+impl VirtualIndexedReader<BufReader<File>> {
+    /// Opens pairs of BAM and BAI files.
+    pub fn from_path_pairs<I, P, Q>(pairs: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (P, Q)>,
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let readers = pairs
+            .into_iter()
+            .map(|(bam_path, bai_path)| {
+                IndexedReaderBuilder::new()
+                    .bai_path(bai_path)
+                    .from_path(bam_path)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::from_readers(readers)
+    }
+}
+
+impl<R: Read + Seek> VirtualIndexedReader<R> {
+    /// Creates a virtual reader from already opened readers. Returns an error if `readers`
+    /// is empty, or if the references of any reader do not match the references of the first one.
+    pub fn from_readers(readers: Vec<IndexedReader<R>>) -> Result<Self> {
+        let first = readers.first().ok_or_else(|| {
+            Error::new(InvalidInput, "Virtual reader requires at least one BAM file")
+        })?;
+        let refs: Vec<(String, u32)> = first
+            .header()
+            .reference_names()
+            .iter()
+            .cloned()
+            .zip(first.header().reference_lengths().iter().cloned())
+            .collect();
+        for (i, reader) in readers.iter().enumerate().skip(1) {
+            reader.header().check_compatible(&refs).map_err(|e| {
+                Error::new(
+                    InvalidData,
+                    format!(
+                        "References of BAM file #{} do not match references of the first file: {}",
+                        i, e
+                    ),
+                )
+            })?;
+        }
+        Ok(Self { readers })
+    }
+
+    /// Returns [header](../header/struct.Header.html) of the first file.
+    pub fn header(&self) -> &Header {
+        self.readers[0].header()
+    }
+
+    /// Returns the underlying readers.
+    pub fn readers(&self) -> &[IndexedReader<R>] {
+        &self.readers
+    }
+
+    /// Returns an iterator over records from all files, aligned to the
+    /// [reference region](struct.Region.html).
+    pub fn fetch<'a>(&'a mut self, region: &Region) -> Result<MergedViewer<'a, R>> {
+        self.fetch_by(region, |_| true)
+    }
+
+    /// Returns an iterator over records from all files, aligned to the
+    /// [reference region](struct.Region.html) and satisfying `predicate`.
+    /// See [IndexedReader::fetch_by](struct.IndexedReader.html#method.fetch_by).
+    pub fn fetch_by<'a, F>(
+        &'a mut self,
+        region: &Region,
+        predicate: F,
+    ) -> Result<MergedViewer<'a, R>>
+    where
+        F: 'static + Fn(&record::Record) -> bool + Send + Sync + Clone,
+    {
+        let viewers = self
+            .readers
+            .iter_mut()
+            .map(|reader| reader.fetch_by(region, predicate.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(MergedViewer {
+            buffers: viewers.iter().map(|_| record::Record::new()).collect(),
+            viewers,
+            heap: BinaryHeap::new(),
+            started: false,
+        })
+    }
+}
+
+/// Iterator over records from several [region viewers](struct.RegionViewer.html),
+/// merged by their start. Records with the same start are returned in the order of the files.
+/// Created by [VirtualIndexedReader::fetch](struct.VirtualIndexedReader.html#method.fetch).
+pub struct MergedViewer<'a, R: Read + Seek> {
+    viewers: Vec<RegionViewer<'a, R>>,
+    // Next record from each viewer.
+    buffers: Vec<record::Record>,
+    // Pairs (record start, viewer index) for viewers with a record in the buffer.
+    heap: BinaryHeap<Reverse<(i32, usize)>>,
+    started: bool,
+}
+
+impl<'a, R: Read + Seek> MergedViewer<'a, R> {
+    /// Reads the next record from the viewer `i` into its buffer.
+    fn refill(&mut self, i: usize) -> Result<()> {
+        if self.viewers[i].read_into(&mut self.buffers[i])? {
+            self.heap.push(Reverse((self.buffers[i].start(), i)));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R: Read + Seek> RecordReader for MergedViewer<'a, R> {
+    fn read_into(&mut self, record: &mut record::Record) -> Result<bool> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.viewers.len() {
+                if let Err(e) = self.refill(i) {
+                    record.clear();
+                    return Err(e);
+                }
+            }
+        }
+        let i = match self.heap.pop() {
+            Some(Reverse((_, i))) => i,
+            None => {
+                record.clear();
+                return Ok(false);
+            }
+        };
+        std::mem::swap(record, &mut self.buffers[i]);
+        if let Err(e) = self.refill(i) {
+            record.clear();
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn pause(&mut self) {
+        for viewer in self.viewers.iter_mut() {
+            viewer.pause();
+        }
+    }
+}
+
+impl<'a, R: Read + Seek> Iterator for MergedViewer<'a, R> {
+    type Item = Result<record::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = record::Record::new();
+        match self.read_into(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Iterator over records with reference names, created by
+/// [BamReader::with_names](struct.BamReader.html#method.with_names).
+pub struct WithNames<'h, R: Read> {
+    reader: BamReader<R>,
+    header: &'h Header,
+}
+
+impl<'h, R: Read> Iterator for WithNames<'h, R> {
+    type Item = Result<(Option<&'h str>, record::Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match Iterator::next(&mut self.reader)? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        let ref_name = if record.ref_id() < 0 {
+            None
+        } else {
+            match self.header.reference_name(record.ref_id() as u32) {
+                Some(name) => Some(name),
+                None => {
+                    return Some(Err(Error::new(
+                        InvalidData,
+                        "Record has a reference id not in the header",
+                    )))
+                }
+            }
+        };
+        Some(Ok((ref_name, record)))
+    }
+}
+
+/// Iterator over a random subset of records, created by
+/// [BamReader::subsample](struct.BamReader.html#method.subsample).
+pub struct Subsample<R: Read> {
+    reader: BamReader<R>,
+    fraction: f64,
+    seed: u64,
+}
+
+impl<R: Read> Subsample<R> {
+    /// Returns a pseudo-random number in `[0, 1)`, defined by the name and the seed.
+    fn name_value(&self, name: &[u8]) -> f64 {
+        // FNV-1a hash, followed by the SplitMix64 finalizer to mix the bits.
+        let mut hash = 0xcbf2_9ce4_8422_2325_u64 ^ self.seed;
+        for &byte in name {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        hash ^= hash >> 31;
+        (hash >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+impl<R: Read> Iterator for Subsample<R> {
+    type Item = Result<record::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = record::Record::new();
+        loop {
+            match self.reader.read_into(&mut record) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+            if self.name_value(record.template_name()) < self.fraction {
+                return Some(Ok(record));
+            }
+        }
+    }
+}
+
+/// Iterator over records with a limited fraction of soft-clipped bases, created by
+/// [BamReader::max_soft_clip_fraction](struct.BamReader.html#method.max_soft_clip_fraction).
+pub struct SoftClipFilter<R: Read> {
+    reader: BamReader<R>,
+    max_fraction: f64,
+}
+
+impl<R: Read> Iterator for SoftClipFilter<R> {
+    type Item = Result<record::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = record::Record::new();
+        loop {
+            match self.reader.read_into(&mut record) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+            let seq_len = record.sequence().len();
+            if seq_len == 0 {
+                return Some(Ok(record));
+            }
+            let cigar = record.cigar();
+            let clipping = cigar.soft_clipping(true) + cigar.soft_clipping(false);
+            if f64::from(clipping) / seq_len as f64 <= self.max_fraction {
+                return Some(Ok(record));
+            }
+        }
+    }
+}
+
+/// Iterator over records with UMI-based duplicate marking, created by
+/// [BamReader::dedup_umi](struct.BamReader.html#method.dedup_umi).
+pub struct UmiDedup<R: Read> {
+    reader: BamReader<R>,
+    umi_tag: [u8; 2],
+    drop_duplicates: bool,
+    // Processed records that start at the same position.
+    ready: VecDeque<record::Record>,
+    // The first record at the next position.
+    pending: Option<record::Record>,
+    finished: bool,
+}
+
+impl<R: Read> UmiDedup<R> {
+    /// If `drop` is true, duplicates are removed instead of being marked with the flag `0x400`.
+    /// Default: `false`.
+    pub fn drop_duplicates(&mut self, drop: bool) -> &mut Self {
+        self.drop_duplicates = drop;
+        self
+    }
+
+    /// Returns the deduplication key `(UMI, is_reverse_strand)`, or `None` if the record
+    /// does not take part in the deduplication.
+    fn key(&self, record: &record::Record) -> Option<(Vec<u8>, bool)> {
+        let flag = record.flag();
+        if !flag.is_mapped() || flag.is_secondary() || flag.is_supplementary() {
+            return None;
+        }
+        let mut umi = Vec::new();
+        match record.tags().get(&self.umi_tag)? {
+            record::tags::TagValue::String(value, _) => umi.extend_from_slice(value),
+            value => value
+                .write_sam(&mut umi)
+                .expect("Writing to a vector should not fail"),
+        }
+        Some((umi, flag.is_reverse_strand()))
+    }
+
+    /// Reads all records with the same start as the next record, and marks or removes duplicates.
+    fn fill(&mut self) -> Result<()> {
+        let first = match self.pending.take() {
+            Some(record) => record,
+            None => {
+                let mut record = record::Record::new();
+                if !self.reader.read_into(&mut record)? {
+                    self.finished = true;
+                    return Ok(());
+                }
+                record
+            }
+        };
+        // Records without a reference are not deduplicated, so the unmapped tail of a sorted
+        // file is passed through one record at a time, and is not collected into one group.
+        if first.ref_id() < 0 {
+            self.ready.push_back(first);
+            return Ok(());
+        }
+        let pos = (first.ref_id(), first.start());
+        let mut group = vec![first];
+        loop {
+            let mut record = record::Record::new();
+            if !self.reader.read_into(&mut record)? {
+                break;
+            }
+            let rec_pos = (record.ref_id(), record.start());
+            if rec_pos == pos {
+                group.push(record);
+                continue;
+            }
+            if rec_pos.0 >= 0 && rec_pos < pos {
+                return Err(Error::new(
+                    InvalidData,
+                    "Cannot deduplicate records: input file is unsorted",
+                ));
+            }
+            self.pending = Some(record);
+            break;
+        }
+
+        let mut seen = HashSet::new();
+        for mut record in group {
+            if let Some(key) = self.key(&record) {
+                if !seen.insert(key) {
+                    if self.drop_duplicates {
+                        continue;
+                    }
+                    record.flag_mut().set_duplicate(true);
+                }
+            }
+            self.ready.push_back(record);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for UmiDedup<R> {
+    type Item = Result<record::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.ready.pop_front() {
+                return Some(Ok(record));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Sequenced fragment: a pair of mates or a single record, created by
+/// [BamReader::fragments](struct.BamReader.html#method.fragments).
+pub struct Fragment {
+    first: record::Record,
+    second: Option<record::Record>,
+    ref_id: i32,
+    start: i32,
+    end: i32,
+}
+
+impl Fragment {
+    fn new(first: record::Record, second: Option<record::Record>) -> Self {
+        let mut fragment = Fragment {
+            first,
+            second,
+            ref_id: -1,
+            start: -1,
+            end: -1,
+        };
+        let mut mapped = std::iter::once(&fragment.first)
+            .chain(&fragment.second)
+            .filter(|record| record.flag().is_mapped());
+        let (ref_id, start, end) = match mapped.next() {
+            Some(record) => {
+                let mut span = (record.ref_id(), record.start(), record.calculate_end());
+                if let Some(mate) = mapped.next().filter(|mate| mate.ref_id() == span.0) {
+                    span.1 = std::cmp::min(span.1, mate.start());
+                    span.2 = std::cmp::max(span.2, mate.calculate_end());
+                }
+                span
+            }
+            None => (fragment.first.ref_id(), fragment.first.start(), fragment.first.start()),
+        };
+        fragment.ref_id = ref_id;
+        fragment.start = start;
+        fragment.end = end;
+        fragment
+    }
+
+    /// Returns the reference id of the fragment, or -1 if all its records are unmapped.
+    pub fn ref_id(&self) -> i32 {
+        self.ref_id
+    }
+
+    /// Returns the 0-based start of the fragment: the smallest start of its mapped records.
+    pub fn start(&self) -> i32 {
+        self.start
+    }
+
+    /// Returns the 0-based exclusive end of the fragment: the largest
+    /// [alignment end](../record/struct.Record.html#method.calculate_end) of its mapped records.
+    pub fn end(&self) -> i32 {
+        self.end
+    }
+
+    /// Returns the first record: the first mate for a pair, and the only record for a singleton.
+    pub fn first(&self) -> &record::Record {
+        &self.first
+    }
+
+    /// Returns the second mate, or `None` for a singleton fragment.
+    pub fn second(&self) -> Option<&record::Record> {
+        self.second.as_ref()
+    }
+
+    /// Returns `true` if the fragment contains both mates.
+    pub fn is_pair(&self) -> bool {
+        self.second.is_some()
+    }
+
+    /// Consumes the fragment and returns its records.
+    pub fn into_records(self) -> (record::Record, Option<record::Record>) {
+        (self.first, self.second)
+    }
+}
+
+/// Iterator over fragments, created by
+/// [BamReader::fragments](struct.BamReader.html#method.fragments).
+pub struct Fragments<R: Read> {
+    reader: BamReader<R>,
+    // Primary record that was read, but not yet assigned to a fragment.
+    pending: Option<record::Record>,
+}
+
+impl<R: Read> Fragments<R> {
+    /// Returns the next primary record.
+    fn next_primary(&mut self) -> Result<Option<record::Record>> {
+        if let Some(record) = self.pending.take() {
+            return Ok(Some(record));
+        }
+        let mut record = record::Record::new();
+        while self.reader.read_into(&mut record)? {
+            let flag = record.flag();
+            if !flag.is_secondary() && !flag.is_supplementary() {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Checks if two records are the first and the last mates of the same template.
+fn are_mates(first: &record::Record, second: &record::Record) -> bool {
+    let (flag1, flag2) = (first.flag(), second.flag());
+    flag1.is_paired()
+        && flag2.is_paired()
+        && flag1.first_in_pair() != flag1.last_in_pair()
+        && flag1.first_in_pair() == flag2.last_in_pair()
+        && flag1.last_in_pair() == flag2.first_in_pair()
+        && first.template_name() == second.template_name()
+}
+
+impl<R: Read> Iterator for Fragments<R> {
+    type Item = Result<Fragment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.next_primary() {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        if !record.flag().is_paired() {
+            return Some(Ok(Fragment::new(record, None)));
+        }
+        let mate = match self.next_primary() {
+            Ok(mate) => mate,
+            Err(e) => return Some(Err(e)),
+        };
+        match mate {
+            Some(mate) if are_mates(&record, &mate) => Some(Ok(if record.flag().first_in_pair() {
+                Fragment::new(record, Some(mate))
+            } else {
+                Fragment::new(mate, Some(record))
+            })),
+            mate => {
+                self.pending = mate;
+                Some(Ok(Fragment::new(record, None)))
+            }
+        }
+    }
+}
+
+/// Iterator over records that skips corrupted records, created by
+/// [BamReader::skip_errors](struct.BamReader.html#method.skip_errors).
+///
+/// After an error, the iterator moves to the start of the next bgzip block, and searches the block
+/// for the first position, where a valid record (see
+/// [Record::validate](../record/struct.Record.html#method.validate)) starts and ends in the same
+/// block. Blocks without such positions are skipped.
+///
+/// Resynchronization is best-effort: all records between the error and the found position are
+/// lost, and a random sequence of bytes can still be recognized as a record.
+/// A corrupted block is skipped if its header is intact, otherwise, the rest of the file
+/// is usually lost. IO errors stop the iteration. Multi-threaded readers may stop after
+/// a corrupted block, so use `additional_threads = 0` to recover as much as possible.
+pub struct SkipErrors<R: Read, F> {
+    reader: BamReader<R>,
+    on_error: F,
+}
+
+impl<R: Read, F: FnMut(&Error)> SkipErrors<R, F> {
+    /// Moves to the first valid record in the next bgzip blocks.
+    /// Returns `false` if there are no valid records until the end of the file.
+    fn resync(&mut self) -> bool {
+        self.reader.peeked_size = None;
+        let mut record = record::Record::new();
+        loop {
+            let block = match self.reader.reader.next() {
+                Ok(block) => block,
+                Err(BlockError::EndOfStream) => return false,
+                Err(e @ BlockError::Corrupted(_)) => {
+                    (self.on_error)(&e.into());
+                    continue;
+                }
+                Err(e) => {
+                    (self.on_error)(&e.into());
+                    return false;
+                }
+            };
+
+            let header = &self.reader.header;
+            let data = block.uncompressed_data();
+            let start = (0..data.len().saturating_sub(4)).find(|&i| {
+                let size = i32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+                if size < 0 || size as usize > data.len() - i - 4 {
+                    return false;
+                }
+                let mut stream = &data[i..i + 4 + size as usize];
+                matches!(record.fill_from_bam(&mut stream), Ok(true))
+                    && stream.is_empty()
+                    && record.validate(header).is_ok()
+            });
+            if let Some(start) = start {
+                self.reader.reader.set_contents_offset(start);
+                return true;
+            }
+        }
+    }
+}
+
+impl<R: Read, F: FnMut(&Error)> Iterator for SkipErrors<R, F> {
+    type Item = record::Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = record::Record::new();
+        loop {
+            match self.reader.read_into(&mut record) {
+                Ok(true) => return Some(record),
+                Ok(false) => return None,
+                Err(e) => {
+                    (self.on_error)(&e);
+                    if !self.resync() {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
 }
 
 /// BAM file reader. In contrast to [IndexedReader](struct.IndexedReader.html) the `BamReader`
@@ -656,6 +2309,15 @@ impl<R: Read + Seek> IndexedReader<R> {
 pub struct BamReader<R: Read> {
     pub reader: bgzip::ConsecutiveReader<R>,
     header: Header,
+    // Block size of the next record, read by `peek_record_size`.
+    peeked_size: Option<[u8; 4]>,
+    // Virtual offset of the record with the peeked size.
+    peeked_offset: index::VirtualOffset,
+    on_invalid_ref: InvalidRef,
+    capture_offsets: bool,
+    keep_raw: bool,
+    max_qual: Option<u8>,
+    format_info: FormatInfo,
 }
 
 impl BamReader<File> {
@@ -669,6 +2331,38 @@ impl BamReader<File> {
             .map_err(|e| Error::new(e.kind(), format!("Failed to open BAM file: {}", e)))?;
         Self::from_stream(stream, additional_threads)
     }
+
+    /// Creates BAM file reader from an open file descriptor, such as a pipe end.
+    ///
+    /// The reader takes ownership of the descriptor and closes it when dropped,
+    /// so the descriptor should not be closed or used by the caller afterwards.
+    ///
+    /// # Safety
+    /// `fd` must be an open file descriptor that is not owned by another object.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(
+        fd: std::os::unix::io::RawFd,
+        additional_threads: u16,
+    ) -> Result<Self> {
+        use std::os::unix::io::FromRawFd;
+        Self::from_stream(File::from_raw_fd(fd), additional_threads)
+    }
+
+    /// Creates BAM file reader from an open file handle.
+    ///
+    /// The reader takes ownership of the handle and closes it when dropped,
+    /// so the handle should not be closed or used by the caller afterwards.
+    ///
+    /// # Safety
+    /// `handle` must be an open file handle that is not owned by another object.
+    #[cfg(windows)]
+    pub unsafe fn from_raw_handle(
+        handle: std::os::windows::io::RawHandle,
+        additional_threads: u16,
+    ) -> Result<Self> {
+        use std::os::windows::io::FromRawHandle;
+        Self::from_stream(File::from_raw_handle(handle), additional_threads)
+    }
 }
 
 impl<R: Read> BamReader<R> {
@@ -678,7 +2372,18 @@ impl<R: Read> BamReader<R> {
     pub fn from_stream(stream: R, additional_threads: u16) -> Result<Self> {
         let mut reader = bgzip::ConsecutiveReader::from_stream(stream, additional_threads);
         let header = Header::from_bam(&mut reader)?;
-        Ok(Self { reader, header })
+        let format_info = FormatInfo::bam(&header);
+        Ok(Self {
+            reader,
+            header,
+            peeked_size: None,
+            peeked_offset: index::VirtualOffset::MIN,
+            on_invalid_ref: InvalidRef::Pass,
+            capture_offsets: false,
+            keep_raw: false,
+            max_qual: None,
+            format_info,
+        })
     }
 
     pub fn from_stream_no_header(
@@ -687,7 +2392,22 @@ impl<R: Read> BamReader<R> {
         additional_threads: u16,
     ) -> Result<Self> {
         let reader = bgzip::ConsecutiveReader::from_stream(stream, additional_threads);
-        Ok(Self { reader, header })
+        let format_info = FormatInfo {
+            bgzf: true,
+            bam_magic: false,
+            header_text_len: None,
+        };
+        Ok(Self {
+            reader,
+            header,
+            peeked_size: None,
+            peeked_offset: index::VirtualOffset::MIN,
+            on_invalid_ref: InvalidRef::Pass,
+            capture_offsets: false,
+            keep_raw: false,
+            max_qual: None,
+            format_info,
+        })
     }
 
     /// Returns [header](../header/struct.Header.html).
@@ -695,6 +2415,322 @@ impl<R: Read> BamReader<R> {
         &self.header
     }
 
+    /// Returns the [container format](../struct.FormatInfo.html) of the input, captured while
+    /// parsing the header. For a reader created with
+    /// [from_stream_no_header](#method.from_stream_no_header), the magic string and the header
+    /// text were not read, so `bam_magic` is `false` and `header_text_len` is `None`.
+    pub fn format_info(&self) -> FormatInfo {
+        self.format_info
+    }
+
+    /// Returns the number of compressed bytes consumed from the underlying stream, including
+    /// the header. Compare it to the file size to report progress of a long scan; for streaming
+    /// inputs the total size may be unknown. With additional threads, blocks are read in advance,
+    /// so the value can run slightly ahead of the returned records.
+    pub fn bytes_processed(&self) -> u64 {
+        self.reader.bytes_processed()
+    }
+
+    /// Sets the behavior for records with reference ids or mate reference ids,
+    /// that are not in the header (see [InvalidRef](enum.InvalidRef.html)).
+    /// Records skipped with [skip_record](#method.skip_record) are not checked.
+    pub fn on_invalid_ref(&mut self, policy: InvalidRef) -> &mut Self {
+        self.on_invalid_ref = policy;
+        self
+    }
+
+    /// Saves the [virtual offset](../index/struct.VirtualOffset.html) of each record, which can
+    /// be accessed with [Record::source_offset](../record/struct.Record.html#method.source_offset).
+    /// Default: `false`.
+    ///
+    /// Offsets are counted from the start of the stream, so they are correct only if the reader
+    /// was created at the start of the BAM file.
+    pub fn capture_offsets(&mut self, capture: bool) -> &mut Self {
+        self.capture_offsets = capture;
+        self
+    }
+
+    /// Saves the raw BAM block of each record, so that unmodified records are copied by
+    /// a [BamWriter](../bam_writer/struct.BamWriter.html) directly (see
+    /// [Record::is_pristine](../record/struct.Record.html#method.is_pristine)).
+    /// Default: `false`.
+    ///
+    /// Use it for pass-through filtering; records take about twice as much memory.
+    pub fn keep_raw(&mut self, keep: bool) -> &mut Self {
+        self.keep_raw = keep;
+        self
+    }
+
+    /// Caps base qualities of all records at `max` (without +33) as they are read, see
+    /// [Record::cap_qualities](../record/struct.Record.html#method.cap_qualities).
+    /// By default, qualities are not changed.
+    ///
+    /// The quality buffers of the records are modified in place, so the original values are lost,
+    /// while the file stays the same unless the records are written to it.
+    pub fn cap_qualities(&mut self, max: u8) -> &mut Self {
+        self.max_qual = Some(max);
+        self
+    }
+
+    /// Consumes the reader and returns an iterator over pairs *(reference name, record)*.
+    /// Reference names are taken from `header`, and are `None` for records without a reference.
+    ///
+    /// As the reader is consumed, clone the header first if it is the header of this reader:
+    /// ```rust
+    /// let reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+    /// let header = reader.header().clone();
+    /// for entry in reader.with_names(&header) {
+    ///     let (ref_name, record) = entry.unwrap();
+    ///     println!("{}\t{}", ref_name.unwrap_or("*"), record.start() + 1);
+    /// }
+    /// ```
+    pub fn with_names(self, header: &Header) -> WithNames<'_, R> {
+        WithNames {
+            reader: self,
+            header,
+        }
+    }
+
+    /// Consumes the reader and returns an iterator over records, that does not stop at corrupted
+    /// records or bgzip blocks. Instead, each error is passed to `on_error`, and the reader tries to
+    /// find the next valid record (see [SkipErrors](struct.SkipErrors.html)).
+    ///
+    /// ```rust
+    /// let reader = bam::BamReader::from_path("damaged.bam", 0).unwrap();
+    /// let mut n_errors = 0;
+    /// for record in reader.skip_errors(|e| { eprintln!("{}", e); n_errors += 1; }) {
+    ///     // Process the record.
+    /// }
+    /// ```
+    pub fn skip_errors<F: FnMut(&Error)>(self, on_error: F) -> SkipErrors<R, F> {
+        SkipErrors {
+            reader: self,
+            on_error,
+        }
+    }
+
+    /// Returns the size of the next record in bytes (`block_size` in the BAM specification,
+    /// which does not include 4 bytes of the size itself), without decoding the record.
+    /// Returns `None` if there are no more records, and an `UnexpectedEof` error if the stream
+    /// ends inside the 4-byte size.
+    ///
+    /// The size is stored in the reader, so the following
+    /// [read_into](../trait.RecordReader.html#method.read_into) reads the whole record, and
+    /// consecutive calls to `peek_record_size` return the same value. Use
+    /// [skip_record](#method.skip_record) to skip the record without decoding it.
+    pub fn peek_record_size(&mut self) -> Result<Option<u32>> {
+        if self.peeked_size.is_none() {
+            self.peeked_offset = self.reader.virtual_offset();
+            match record::read_block_size(&mut self.reader)? {
+                Some(size) => self.peeked_size = Some(size),
+                None => return Ok(None),
+            }
+        }
+        let size = i32::from_le_bytes(self.peeked_size.unwrap());
+        if size < 0 {
+            Err(Error::new(InvalidData, "Corrupted record: Negative block size"))
+        } else {
+            Ok(Some(size as u32))
+        }
+    }
+
+    /// Skips the next record without decoding it. Returns `false` if there are no more records.
+    pub fn skip_record(&mut self) -> Result<bool> {
+        let size = match self.peek_record_size()? {
+            Some(value) => u64::from(value),
+            None => return Ok(false),
+        };
+        self.peeked_size = None;
+        let skipped = io::copy(&mut (&mut self.reader).take(size), &mut io::sink())?;
+        if skipped < size {
+            return Err(Error::new(
+                UnexpectedEof,
+                "Corrupted record: unexpected end of file",
+            ));
+        }
+        Ok(true)
+    }
+
+    /// Consumes the reader and returns an iterator over a random subset of records, where each
+    /// record is kept with probability `fraction`.
+    ///
+    /// The decision is made by hashing the record [template name](../record/struct.Record.html#method.template_name)
+    /// together with `seed`, so both mates of a pair are either kept or discarded,
+    /// and the same `seed` always produces the same subset.
+    ///
+    /// Panics if `fraction` is not within `[0, 1]`.
+    pub fn subsample(self, fraction: f64, seed: u64) -> Subsample<R> {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "Subsampling fraction should be within [0, 1]"
+        );
+        Subsample {
+            reader: self,
+            fraction,
+            seed,
+        }
+    }
+
+    /// Consumes the reader and returns an iterator over records, where soft-clipped bases make up
+    /// at most `max_fraction` of the sequence: `(left_soft_clip + right_soft_clip) / seq_len`.
+    /// Records with the fraction exactly equal to `max_fraction` are kept.
+    ///
+    /// Soft clipping is calculated using
+    /// [Cigar::soft_clipping](../record/cigar/struct.Cigar.html#method.soft_clipping).
+    /// Records without a sequence are always kept.
+    ///
+    /// Panics if `max_fraction` is not within `[0, 1]`.
+    pub fn max_soft_clip_fraction(self, max_fraction: f64) -> SoftClipFilter<R> {
+        assert!(
+            (0.0..=1.0).contains(&max_fraction),
+            "Soft clipping fraction should be within [0, 1]"
+        );
+        SoftClipFilter {
+            reader: self,
+            max_fraction,
+        }
+    }
+
+    /// Consumes the reader and returns an iterator over records, where PCR duplicates are
+    /// detected using unique molecular identifiers (UMIs), stored in the tag `umi_tag`
+    /// (for example `RX`). The input should be sorted by coordinate.
+    ///
+    /// Among the records with the same reference id, start and strand, only the first record
+    /// with each UMI is kept, and the rest are marked as duplicates (flag `0x400`), or removed if
+    /// [drop_duplicates](struct.UmiDedup.html#method.drop_duplicates) is set.
+    /// Records without the `umi_tag`, as well as unmapped, secondary and supplementary records,
+    /// are returned unchanged. Only records with the same start are buffered at any time.
+    pub fn dedup_umi(self, umi_tag: [u8; 2]) -> UmiDedup<R> {
+        UmiDedup {
+            reader: self,
+            umi_tag,
+            drop_duplicates: false,
+            ready: VecDeque::new(),
+            pending: None,
+            finished: false,
+        }
+    }
+
+    /// Consumes the reader and returns an iterator over [fragments](struct.Fragment.html):
+    /// pairs of mates, collapsed into a single item, and single records. The input should be
+    /// grouped by name (for example, sorted by query name or collated), so that the primary
+    /// records of two mates follow each other. Secondary and supplementary records are skipped.
+    ///
+    /// Two consecutive primary records form a pair if both are paired, have the same
+    /// [template name](../record/struct.Record.html#method.template_name), and one of them is
+    /// the first and the other is the last in pair. Single-end reads and orphaned mates
+    /// become singleton fragments.
+    ///
+    /// Improper pairs are still collapsed. If only one mate is mapped, the fragment spans
+    /// that mate, and if the mates are mapped to different references, the fragment spans
+    /// the first mate only.
+    ///
+    /// ```rust
+    /// let reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+    /// for fragment in reader.fragments() {
+    ///     let fragment = fragment.unwrap();
+    ///     println!("{}: {}-{}", fragment.ref_id(), fragment.start(), fragment.end());
+    /// }
+    /// ```
+    pub fn fragments(self) -> Fragments<R> {
+        Fragments {
+            reader: self,
+            pending: None,
+        }
+    }
+
+    /// Consumes the reader and returns the total number of reference positions covered by the
+    /// records ([reference_span](../record/struct.Record.html#method.reference_span)) for each
+    /// reference sequence. The output is indexed by reference id and has length equal to the
+    /// number of references in the header.
+    ///
+    /// Unmapped and secondary records are skipped, use
+    /// [aligned_bases_per_ref_by](#method.aligned_bases_per_ref_by) to select records differently.
+    pub fn aligned_bases_per_ref(self) -> Result<Vec<u64>> {
+        self.aligned_bases_per_ref_by(|record| !record.flag().is_secondary())
+    }
+
+    /// Same as [aligned_bases_per_ref](#method.aligned_bases_per_ref), but counts only
+    /// mapped records that satisfy `predicate`.
+    ///
+    /// Returns an error if a mapped record has a reference id, absent from the header.
+    pub fn aligned_bases_per_ref_by<F>(mut self, mut predicate: F) -> Result<Vec<u64>>
+    where
+        F: FnMut(&record::Record) -> bool,
+    {
+        let mut sums = vec![0_u64; self.header.n_references()];
+        let mut record = record::Record::new();
+        while self.read_into(&mut record)? {
+            if !record.flag().is_mapped() || !predicate(&record) {
+                continue;
+            }
+            let ref_id = record.ref_id();
+            let sum = if ref_id >= 0 { sums.get_mut(ref_id as usize) } else { None };
+            let sum = sum.ok_or_else(|| {
+                Error::new(
+                    InvalidData,
+                    format!(
+                        "Record {} has reference id {}, absent from the header",
+                        String::from_utf8_lossy(record.name()),
+                        ref_id
+                    ),
+                )
+            })?;
+            *sum += u64::from(record.reference_span());
+        }
+        Ok(sums)
+    }
+
+    /// Consumes the reader and returns the extent of mapped records on each reference sequence:
+    /// the smallest start and the largest
+    /// [alignment end](../record/struct.Record.html#method.calculate_end) (0-based half-open),
+    /// or `None` for references without mapped records. The output is indexed by reference id
+    /// and has length equal to the number of references in the header.
+    ///
+    /// The file is read once and the input does not need to be sorted.
+    /// Returns an error if a mapped record has a reference id, absent from the header.
+    pub fn genomic_extent(mut self) -> Result<Vec<Option<(u32, u32)>>> {
+        let mut extents = vec![None; self.header.n_references()];
+        let mut record = record::Record::new();
+        while self.read_into(&mut record)? {
+            if !record.flag().is_mapped() {
+                continue;
+            }
+            let ref_id = record.ref_id();
+            let extent = if ref_id >= 0 { extents.get_mut(ref_id as usize) } else { None };
+            let extent = extent.ok_or_else(|| {
+                Error::new(
+                    InvalidData,
+                    format!(
+                        "Record {} has reference id {}, absent from the header",
+                        String::from_utf8_lossy(record.name()),
+                        ref_id
+                    ),
+                )
+            })?;
+            let start = record.start() as u32;
+            let end = record.calculate_end() as u32;
+            *extent = Some(match *extent {
+                Some((min_start, max_end)) => {
+                    (std::cmp::min(min_start, start), std::cmp::max(max_end, end))
+                }
+                None => (start, end),
+            });
+        }
+        Ok(extents)
+    }
+
+    /// Skips `n` records without decoding them, and returns the number of skipped records,
+    /// which is smaller than `n` only if the file has ended.
+    pub fn skip_records(&mut self, n: usize) -> Result<usize> {
+        for i in 0..n {
+            if !self.skip_record()? {
+                return Ok(i);
+            }
+        }
+        Ok(n)
+    }
+
     pub fn next(&mut self) -> std::result::Result<&Block, BlockError> {
         self.reader.next()
     }
@@ -702,11 +2738,50 @@ impl<R: Read> BamReader<R> {
 
 impl<R: Read> RecordReader for BamReader<R> {
     fn read_into(&mut self, record: &mut record::Record) -> Result<bool> {
-        let res = record.fill_from_bam(&mut self.reader);
-        if !res.as_ref().unwrap_or(&false) {
-            record.clear();
+        loop {
+            let offset = match (self.capture_offsets, self.peeked_size.is_some()) {
+                (false, _) => None,
+                (true, true) => Some(self.peeked_offset),
+                (true, false) => Some(self.reader.virtual_offset()),
+            };
+            let res = match (self.peeked_size.take(), self.keep_raw) {
+                (Some(size), false) => {
+                    record.fill_from_bam(&mut (&size[..]).chain(&mut self.reader))
+                }
+                (Some(size), true) => {
+                    record.fill_from_bam_keep_raw(&mut (&size[..]).chain(&mut self.reader))
+                }
+                (None, false) => record.fill_from_bam(&mut self.reader),
+                (None, true) => record.fill_from_bam_keep_raw(&mut self.reader),
+            };
+            if !res.as_ref().unwrap_or(&false) {
+                record.clear();
+                return res;
+            }
+            record.set_source_offset(offset);
+            if let Some(max_qual) = self.max_qual {
+                record.cap_qualities(max_qual);
+            }
+            if self.on_invalid_ref == InvalidRef::Pass {
+                return res;
+            }
+
+            let n_refs = self.header.n_references() as i32;
+            if record.ref_id() < n_refs && record.mate_ref_id() < n_refs {
+                return res;
+            }
+            if self.on_invalid_ref == InvalidRef::Error {
+                let err = Error::new(
+                    InvalidData,
+                    format!(
+                        "Record {} has a reference id not in the header",
+                        String::from_utf8_lossy(record.name())
+                    ),
+                );
+                record.clear();
+                return Err(err);
+            }
         }
-        res
     }
 
     fn pause(&mut self) {
@@ -727,3 +2802,43 @@ impl<R: Read> Iterator for BamReader<R> {
         }
     }
 }
+
+/// Reader of uncompressed BAM files, which start with `BAM\1` instead of BGZF blocks.
+/// Used by [open](../fn.open.html).
+pub(crate) struct UncompressedReader<R: Read> {
+    stream: R,
+}
+
+impl<R: Read> UncompressedReader<R> {
+    /// Parses the header and returns it together with the reader over the remaining records.
+    pub(crate) fn from_stream(mut stream: R) -> Result<(Header, Self)> {
+        let header = Header::from_bam(&mut stream)?;
+        Ok((header, Self { stream }))
+    }
+}
+
+impl<R: Read> RecordReader for UncompressedReader<R> {
+    fn read_into(&mut self, record: &mut record::Record) -> Result<bool> {
+        let res = record.fill_from_bam(&mut self.stream);
+        if !res.as_ref().unwrap_or(&false) {
+            record.clear();
+        }
+        res
+    }
+
+    /// Does nothing, as the reader does not use additional threads.
+    fn pause(&mut self) {}
+}
+
+impl<R: Read> Iterator for UncompressedReader<R> {
+    type Item = Result<record::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = record::Record::new();
+        match self.read_into(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
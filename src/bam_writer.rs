@@ -1,17 +1,78 @@
 //! BAM writer.
 
-use std::fs::File;
-use std::io::{Result, Write};
-use std::path::Path;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::ErrorKind::InvalidInput;
+use std::io::{BufWriter, Error, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use super::bgzip;
 use super::{Header, Record, RecordWriter};
 
+/// Defines how [BamWriter](struct.BamWriter.html) rewrites record names
+/// (see [normalize_names](struct.BamWriterBuilder.html#method.normalize_names)).
+///
+/// # Variants
+/// * `Keep` - names are written as is (default),
+/// * `StripPairSuffix` - `/1` is removed from the end of first-in-pair record names,
+///   and `/2` from the end of last-in-pair record names,
+/// * `AddPairSuffix` - `/1` is appended to first-in-pair record names and `/2` to last-in-pair
+///   record names, unless the names already end with it.
+///
+/// Records that are neither first nor last in pair, or both, are never changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameMode {
+    Keep,
+    StripPairSuffix,
+    AddPairSuffix,
+}
+
+impl NameMode {
+    /// Writes the normalized name of `record` into `name` and returns `true`,
+    /// or returns `false` if the name should stay unchanged.
+    fn normalize(self, record: &Record, name: &mut Vec<u8>) -> Result<bool> {
+        let flag = record.flag();
+        let suffix: &[u8] = match (flag.first_in_pair(), flag.last_in_pair()) {
+            (true, false) => b"/1",
+            (false, true) => b"/2",
+            _ => return Ok(false),
+        };
+        let old_name = record.name();
+        match self {
+            NameMode::Keep => Ok(false),
+            NameMode::StripPairSuffix if old_name.ends_with(suffix) => {
+                name.clear();
+                name.extend_from_slice(&old_name[..old_name.len() - suffix.len()]);
+                Ok(true)
+            }
+            NameMode::AddPairSuffix if !old_name.ends_with(suffix) => {
+                if old_name.len() + suffix.len() > 254 {
+                    return Err(Error::new(
+                        InvalidInput,
+                        format!(
+                            "Cannot add pair suffix to record name {}: name is too long",
+                            String::from_utf8_lossy(old_name)
+                        ),
+                    ));
+                }
+                name.clear();
+                name.extend_from_slice(old_name);
+                name.extend_from_slice(suffix);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
 /// Builder of the [BamWriter](struct.BamWriter.html).
 pub struct BamWriterBuilder {
     write_header: bool,
     level: u8,
     additional_threads: u16,
+    name_mode: NameMode,
 }
 
 impl BamWriterBuilder {
@@ -20,6 +81,7 @@ impl BamWriterBuilder {
             write_header: true,
             level: 6,
             additional_threads: 0,
+            name_mode: NameMode::Keep,
         }
     }
 
@@ -29,7 +91,8 @@ impl BamWriterBuilder {
         self
     }
 
-    /// Specify compression level from 0 to 9 (6 by default).
+    /// Specify compression level from 0 to 9 (6 by default). Level 0 produces uncompressed
+    /// bgzip blocks, see [bgzip::WriterBuilder](../bgzip/write/struct.WriterBuilder.html#method.compression_level).
     pub fn compression_level(&mut self, level: u8) -> &mut Self {
         assert!(level <= 9, "Maximal compression level is 9");
         self.level = level;
@@ -46,6 +109,16 @@ impl BamWriterBuilder {
         self
     }
 
+    /// Rewrites record names on write according to their first/last-in-pair flags
+    /// (see [NameMode](enum.NameMode.html)). Default: `NameMode::Keep`.
+    ///
+    /// Records themselves are not modified. Writing a record returns an error if its name
+    /// becomes longer than 254 bytes.
+    pub fn normalize_names(&mut self, mode: NameMode) -> &mut Self {
+        self.name_mode = mode;
+        self
+    }
+
     /// Creates a BAM writer from a file and a header.
     pub fn from_path<P: AsRef<Path>>(
         &mut self,
@@ -66,7 +139,13 @@ impl BamWriterBuilder {
             header.write_bam(&mut writer)?;
         }
         writer.flush_contents()?;
-        Ok(BamWriter { writer, header })
+        Ok(BamWriter {
+            writer,
+            header,
+            started: self.write_header,
+            name_mode: self.name_mode,
+            name: Vec::new(),
+        })
     }
 }
 
@@ -74,9 +153,15 @@ impl BamWriterBuilder {
 /// [BamWriterBuilder](struct.BamWriterBuilder.html).
 ///
 /// Use [RecordWriter](../trait.RecordWriter.html) trait to write records.
+/// [Pristine](../record/struct.Record.html#method.is_pristine) records are copied as is.
 pub struct BamWriter<W: Write> {
     writer: bgzip::Writer<W>,
     header: Header,
+    // True if the header or any records were written.
+    started: bool,
+    name_mode: NameMode,
+    // Buffer for normalized record names.
+    name: Vec<u8>,
 }
 
 impl BamWriter<File> {
@@ -102,6 +187,41 @@ impl<W: Write> BamWriter<W> {
         &self.header
     }
 
+    /// Writes `header` to the output, and replaces the writer [header](#method.header) with it.
+    /// The header text is copied exactly as it was stored in the input BAM file (see
+    /// [Header::text](../header/struct.Header.html#method.text)), so that order, formatting
+    /// and `@RG`, `@PG` and `@CO` lines are preserved. Headers that were not loaded from a BAM
+    /// file are written in the same way as when creating the writer.
+    ///
+    /// Note that the original text does not reflect changes to the header made after loading,
+    /// while the reference sequences are always taken from the header itself.
+    ///
+    /// The writer should be created without a header
+    /// (see [write_header](struct.BamWriterBuilder.html#method.write_header)), and the function
+    /// returns an error if the header or any records were already written.
+    ///
+    /// ```rust
+    /// let reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+    /// let mut writer = bam::BamWriter::build()
+    ///     .write_header(false)
+    ///     .from_path("out.bam", bam::Header::new())
+    ///     .unwrap();
+    /// writer.write_header_from(reader.header()).unwrap();
+    /// ```
+    pub fn write_header_from(&mut self, header: &Header) -> Result<()> {
+        if self.started {
+            return Err(Error::new(
+                InvalidInput,
+                "Cannot write BAM header: the header or records were already written",
+            ));
+        }
+        header.write_bam_raw(&mut self.writer)?;
+        self.writer.flush_contents()?;
+        self.header = header.clone();
+        self.started = true;
+        Ok(())
+    }
+
     /// Consumes the writer and returns inner stream.
     pub fn take_stream(self) -> W {
         self.writer.take_stream()
@@ -121,8 +241,16 @@ impl<W: Write> BamWriter<W> {
 
 impl<W: Write> RecordWriter for BamWriter<W> {
     fn write(&mut self, record: &Record) -> Result<()> {
-        record.write_bam(&mut self.writer)?;
+        if self.name_mode.normalize(record, &mut self.name)? {
+            record.write_bam_with_name(&self.name, &mut self.writer)?;
+        } else {
+            match record.pristine_block() {
+                Some(block) => self.writer.write_all(block)?,
+                None => record.write_bam(&mut self.writer)?,
+            }
+        }
         self.writer.end_context();
+        self.started = true;
         Ok(())
     }
 
@@ -134,3 +262,247 @@ impl<W: Write> RecordWriter for BamWriter<W> {
         self.writer.flush()
     }
 }
+
+/// Builder of the [SortingWriter](struct.SortingWriter.html).
+pub struct SortingWriterBuilder {
+    memory_limit: usize,
+    tmp_dir: PathBuf,
+    bam_builder: BamWriterBuilder,
+}
+
+impl SortingWriterBuilder {
+    pub fn new() -> Self {
+        Self {
+            memory_limit: 512 << 20,
+            tmp_dir: std::env::temp_dir(),
+            bam_builder: BamWriterBuilder::new(),
+        }
+    }
+
+    /// Specify the approximate amount of memory in bytes, used to store records before saving
+    /// them into a temporary file (512 MiB by default).
+    pub fn memory_limit(&mut self, bytes: usize) -> &mut Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Specify the directory for temporary files (`std::env::temp_dir()` by default).
+    pub fn tmp_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.tmp_dir = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Specify compression level of the output file from 0 to 9 (6 by default),
+    /// see [BamWriterBuilder::compression_level](struct.BamWriterBuilder.html#method.compression_level).
+    /// Temporary files always use level 1.
+    pub fn compression_level(&mut self, level: u8) -> &mut Self {
+        self.bam_builder.compression_level(level);
+        self
+    }
+
+    /// Specify the number of additional threads, used to compress the output file,
+    /// see [BamWriterBuilder::additional_threads](struct.BamWriterBuilder.html#method.additional_threads).
+    pub fn additional_threads(&mut self, additional_threads: u16) -> &mut Self {
+        self.bam_builder.additional_threads(additional_threads);
+        self
+    }
+
+    /// Creates a sorting BAM writer from a file and a header.
+    pub fn from_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        header: Header,
+    ) -> Result<SortingWriter<File>> {
+        let stream = File::create(path)?;
+        self.from_stream(stream, header)
+    }
+
+    /// Creates a sorting BAM writer from a stream and a header.
+    pub fn from_stream<W: Write>(
+        &mut self,
+        stream: W,
+        header: Header,
+    ) -> Result<SortingWriter<W>> {
+        static WRITER_ID: AtomicUsize = AtomicUsize::new(0);
+        let tmp_prefix = format!(
+            "bam_sort.{}.{}",
+            std::process::id(),
+            WRITER_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        Ok(SortingWriter {
+            writer: self.bam_builder.from_stream(stream, header)?,
+            memory_limit: self.memory_limit,
+            tmp_dir: self.tmp_dir.clone(),
+            tmp_prefix,
+            records: Vec::new(),
+            records_size: 0,
+            runs: Vec::new(),
+            finished: false,
+        })
+    }
+}
+
+impl Default for SortingWriterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// BAM writer, that sorts records by coordinate: by reference id, then by start, and then
+/// by strand (forward strand first). Records with reference id -1 go to the end.
+/// Records with equal coordinates keep the input order.
+///
+/// Records are stored in memory until they reach the
+/// [memory limit](struct.SortingWriterBuilder.html#method.memory_limit), after which they are
+/// sorted and saved into a temporary bgzip file in the
+/// [temporary directory](struct.SortingWriterBuilder.html#method.tmp_dir).
+/// All records are written into the output on [finish](../trait.RecordWriter.html#tymethod.finish),
+/// where the temporary files are merged and removed. [Flush](../trait.RecordWriter.html#tymethod.flush)
+/// does not write stored records.
+///
+/// The header is written without changes, so you may want to set `SO:coordinate` in the header line.
+/// ```rust
+/// let mut writer = bam::bam_writer::SortingWriter::build()
+///     .memory_limit(1 << 30)
+///     .from_path("sorted.bam", reader.header().clone()).unwrap();
+/// for record in reader {
+///     writer.write(&record.unwrap()).unwrap();
+/// }
+/// writer.finish().unwrap();
+/// ```
+pub struct SortingWriter<W: Write> {
+    writer: BamWriter<W>,
+    memory_limit: usize,
+    tmp_dir: PathBuf,
+    // Prefix of the temporary file names, unique for the writer.
+    tmp_prefix: String,
+    records: Vec<Record>,
+    // Approximate size of the stored records in bytes.
+    records_size: usize,
+    // Temporary files with sorted records.
+    runs: Vec<PathBuf>,
+    finished: bool,
+}
+
+impl SortingWriter<File> {
+    /// Creates a [SortingWriterBuilder](struct.SortingWriterBuilder.html).
+    pub fn build() -> SortingWriterBuilder {
+        SortingWriterBuilder::new()
+    }
+}
+
+/// Key used to sort records by coordinate.
+fn coordinate_key(record: &Record) -> (u32, i32, bool) {
+    (
+        record.ref_id() as u32,
+        record.start(),
+        record.flag().is_reverse_strand(),
+    )
+}
+
+impl<W: Write> SortingWriter<W> {
+    /// Returns BAM header.
+    pub fn header(&self) -> &Header {
+        self.writer.header()
+    }
+
+    /// Sorts stored records and saves them into a new temporary file.
+    fn save_run(&mut self) -> Result<()> {
+        self.records.sort_by_key(coordinate_key);
+        let path = self
+            .tmp_dir
+            .join(format!("{}.{}.tmp.bgz", self.tmp_prefix, self.runs.len()));
+        let file = BufWriter::new(File::create(&path)?);
+        self.runs.push(path);
+        let mut run_writer = bgzip::Writer::build()
+            .compression_level(1)
+            .from_stream(file);
+        for record in self.records.drain(..) {
+            record.write_bam(&mut run_writer)?;
+            run_writer.end_context();
+        }
+        run_writer.finish()?;
+        self.records_size = 0;
+        Ok(())
+    }
+
+    /// Merges all temporary files into the output.
+    fn merge_runs(&mut self) -> Result<()> {
+        let mut readers = Vec::with_capacity(self.runs.len());
+        let mut heap = BinaryHeap::new();
+        let mut next_records = Vec::with_capacity(self.runs.len());
+        for (i, path) in self.runs.iter().enumerate() {
+            let mut reader = bgzip::ConsecutiveReader::from_path(path, 0)?;
+            let mut record = Record::new();
+            if record.fill_from_bam(&mut reader)? {
+                heap.push(Reverse((coordinate_key(&record), i)));
+            }
+            readers.push(reader);
+            next_records.push(record);
+        }
+
+        while let Some(Reverse((_, i))) = heap.pop() {
+            self.writer.write(&next_records[i])?;
+            if next_records[i].fill_from_bam(&mut readers[i])? {
+                heap.push(Reverse((coordinate_key(&next_records[i]), i)));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_runs(&mut self) {
+        for path in self.runs.drain(..) {
+            let _ignore = fs::remove_file(path);
+        }
+    }
+}
+
+impl<W: Write> RecordWriter for SortingWriter<W> {
+    fn write(&mut self, record: &Record) -> Result<()> {
+        self.records_size += std::mem::size_of::<Record>()
+            + record.name().len()
+            + 4 * record.cigar().len()
+            + record.sequence().raw().len()
+            + record.qualities().raw().len()
+            + record.tags().raw().len();
+        self.records.push(record.clone());
+        if self.records_size >= self.memory_limit {
+            self.save_run()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.runs.is_empty() {
+            self.records.sort_by_key(coordinate_key);
+            for record in self.records.drain(..) {
+                self.writer.write(&record)?;
+            }
+        } else {
+            if !self.records.is_empty() {
+                self.save_run()?;
+            }
+            let res = self.merge_runs();
+            self.remove_runs();
+            res?;
+        }
+        self.writer.finish()
+    }
+
+    /// Flushes records that are already written to the output. Stored records
+    /// are written only on [finish](#method.finish).
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for SortingWriter<W> {
+    fn drop(&mut self) {
+        let _ignore = self.finish();
+        self.remove_runs();
+    }
+}
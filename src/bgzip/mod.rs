@@ -103,6 +103,30 @@ fn analyze_extra_fields(extra_fields: &[u8]) -> Result<u16, BlockError> {
     ))
 }
 
+/// Reads the header and extra fields of the next block, and returns the total block size.
+/// The stream is left after the extra fields. Returns `None` if the stream has ended.
+fn read_block_size<R: Read>(stream: &mut R) -> Result<Option<usize>, BlockError> {
+    let mut buffer = vec![0; HEADER_SIZE + MIN_EXTRA_SIZE];
+    match stream.read_exact(&mut buffer) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(BlockError::from(e)),
+    }
+    let extra_len = analyze_header(&buffer)? as usize;
+    if extra_len > MIN_EXTRA_SIZE {
+        buffer.resize(HEADER_SIZE + extra_len, 0);
+        stream.read_exact(&mut buffer[HEADER_SIZE + MIN_EXTRA_SIZE..])?;
+    }
+    let block_size = analyze_extra_fields(&buffer[HEADER_SIZE..])? as usize + 1;
+    if block_size < buffer.len() + FOOTER_SIZE {
+        return Err(BlockError::Corrupted(format!(
+            "Block size {} is too small",
+            block_size
+        )));
+    }
+    Ok(Some(block_size))
+}
+
 /// Enum that describes the block state.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BlockState {
@@ -175,6 +199,16 @@ impl Block {
         }
     }
 
+    /// Creates an empty block without allocating memory.
+    fn unallocated() -> Self {
+        Self {
+            uncompressed: Vec::new(),
+            compressed: Vec::new(),
+            buffer: Vec::new(),
+            offset: None,
+        }
+    }
+
     /// Resets a block (clears both compressed and uncompressed data).
     pub fn reset(&mut self) {
         self.uncompressed.clear();
@@ -493,13 +527,13 @@ impl Block {
 
 struct ObjectPool<T> {
     objects: Vec<T>,
-    constructor: Box<dyn Fn() -> T>,
+    constructor: Box<dyn Fn() -> T + Send + Sync>,
     taken: u64,
     brought: u64,
 }
 
 impl<T> ObjectPool<T> {
-    pub fn new<F: 'static + Fn() -> T>(constructor: F) -> Self {
+    pub fn new<F: 'static + Fn() -> T + Send + Sync>(constructor: F) -> Self {
         Self {
             objects: vec![],
             constructor: Box::new(constructor),
@@ -525,5 +559,5 @@ impl<T> ObjectPool<T> {
 const SLEEP_TIME: Duration = Duration::from_nanos(50);
 const TIMEOUT: Duration = Duration::from_secs(10);
 
-pub use read::{ConsecutiveReader, ReadBgzip, SeekReader};
+pub use read::{pooled_blocks, ConsecutiveReader, ReadBgzip, SeekReader};
 pub use write::{Writer, WriterBuilder};
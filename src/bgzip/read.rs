@@ -7,6 +7,7 @@
 //! Use [ReadBgzip](trait.ReadBgzip.html) trait if you wish to read blocks directly
 //! (not via `io::Read`).
 
+use std::cell::RefCell;
 use std::cmp::{max, min};
 use std::collections::VecDeque;
 use std::fs::File;
@@ -17,7 +18,7 @@ use std::sync::{Arc, Mutex, Weak};
 use std::thread;
 use std::time::Duration;
 
-use super::{Block, BlockError, ObjectPool};
+use super::{read_block_size, Block, BlockError, ObjectPool};
 use super::{SLEEP_TIME, TIMEOUT};
 use crate::index::{Chunk, VirtualOffset};
 
@@ -150,6 +151,8 @@ pub struct JumpingReadBlock<R: Read + Seek> {
     chunks: Vec<Chunk>,
     index: usize,
     started: bool,
+    // Total size of all loaded blocks, including blocks from previous chunks.
+    bytes_read: u64,
 }
 
 impl<R: Read + Seek> JumpingReadBlock<R> {
@@ -161,6 +164,7 @@ impl<R: Read + Seek> JumpingReadBlock<R> {
             chunks: Vec::new(),
             index: 0,
             started: false,
+            bytes_read: 0,
         })
     }
 
@@ -213,6 +217,14 @@ impl<R: Read + Seek> JumpingReadBlock<R> {
         &self.chunks
     }
 
+    /// Scans the whole stream and returns the offsets of all blocks. The stream position
+    /// is restored afterwards.
+    fn block_boundaries(&mut self) -> io::Result<Vec<u64>> {
+        let res = scan_blocks(&mut self.stream);
+        self.stream.seek(SeekFrom::Start(self.offset))?;
+        res
+    }
+
     fn take_stream(self) -> R {
         self.stream
     }
@@ -227,9 +239,11 @@ impl<R: Read + Seek> ReadBlock for JumpingReadBlock<R> {
             }
             block.reset();
             block.load(Some(self.offset), &mut self.stream)?;
-            self.offset += block
+            let block_size = block
                 .block_size()
                 .expect("Block size should be already defined") as u64;
+            self.offset += block_size;
+            self.bytes_read += block_size;
             Ok(())
         } else {
             Err(BlockError::EndOfStream)
@@ -237,24 +251,90 @@ impl<R: Read + Seek> ReadBlock for JumpingReadBlock<R> {
     }
 }
 
-trait DecompressBlock<T: ReadBlock> {
+/// Returns the offsets of all blocks in the stream, reading only block headers.
+fn scan_blocks<R: Read + Seek>(stream: &mut R) -> io::Result<Vec<u64>> {
+    let mut boundaries = Vec::new();
+    let mut offset = stream.seek(SeekFrom::Start(0))?;
+    loop {
+        match read_block_size(stream) {
+            Ok(Some(block_size)) => {
+                boundaries.push(offset);
+                offset += block_size as u64;
+                stream.seek(SeekFrom::Start(offset))?;
+            }
+            Ok(None) => return Ok(boundaries),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+trait DecompressBlock<T: ReadBlock>: Send {
     fn decompress_next(&mut self, reader: &mut T) -> Result<&Block, BlockError>;
     fn get_current(&self) -> Option<&Block>;
     fn reset_queue(&mut self);
     fn pause(&mut self);
+    /// Returns the inner block, if it can be reused.
+    fn into_block(self: Box<Self>) -> Option<Block>;
+}
+
+/// Maximal number of blocks, stored in a thread-local pool.
+const MAX_POOLED_BLOCKS: usize = 64;
+
+thread_local! {
+    static BLOCK_POOL: RefCell<Vec<Block>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns the number of blocks, currently stored in the thread-local pool of the current thread.
+///
+/// Blocks are taken from the pool and returned to it by readers, created with
+/// [SeekReader::from_stream_with_pool](struct.SeekReader.html#method.from_stream_with_pool).
+pub fn pooled_blocks() -> usize {
+    BLOCK_POOL.with(|pool| pool.borrow().len())
 }
 
 struct SingleThread {
     block: Block,
     was_error: bool,
+    // Return the block to the thread-local pool on drop.
+    pooled: bool,
 }
 
 impl SingleThread {
     fn new() -> Self {
+        Self::with_block(Block::new())
+    }
+
+    fn with_block(mut block: Block) -> Self {
+        block.reset();
         Self {
-            block: Block::new(),
+            block,
             was_error: true,
+            pooled: false,
+        }
+    }
+
+    /// Takes a block from the thread-local pool, or allocates a new block if the pool is empty.
+    fn from_pool() -> Self {
+        let block = BLOCK_POOL.with(|pool| pool.borrow_mut().pop());
+        let mut res = block.map(Self::with_block).unwrap_or_else(Self::new);
+        res.pooled = true;
+        res
+    }
+}
+
+impl Drop for SingleThread {
+    fn drop(&mut self) {
+        if !self.pooled {
+            return;
         }
+        let block = std::mem::replace(&mut self.block, Block::unallocated());
+        // The pool may be already destroyed if the thread is exiting.
+        let _ = BLOCK_POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED_BLOCKS {
+                pool.push(block);
+            }
+        });
     }
 }
 
@@ -278,6 +358,11 @@ impl<T: ReadBlock> DecompressBlock<T> for SingleThread {
     fn reset_queue(&mut self) {}
 
     fn pause(&mut self) {}
+
+    fn into_block(mut self: Box<Self>) -> Option<Block> {
+        self.pooled = false;
+        Some(std::mem::replace(&mut self.block, Block::unallocated()))
+    }
 }
 
 struct MultiThread {
@@ -475,6 +560,10 @@ impl<T: ReadBlock> DecompressBlock<T> for MultiThread {
     fn pause(&mut self) {
         self.finish.store(true, Relaxed);
     }
+
+    fn into_block(self: Box<Self>) -> Option<Block> {
+        None
+    }
 }
 
 impl Drop for MultiThread {
@@ -534,12 +623,46 @@ impl SeekReader<File> {
 impl<R: Read + Seek> SeekReader<R> {
     /// Opens a reader from a stream.
     pub fn from_stream(stream: R, additional_threads: u16) -> io::Result<Self> {
-        let reader = JumpingReadBlock::new(stream)?;
+        Self::from_stream_with_block(stream, additional_threads, None)
+    }
+
+    /// Opens a reader from a stream. If `additional_threads` is 0, `block` (if present)
+    /// is used to store decompressed data instead of allocating a new block.
+    /// The block can be reclaimed later using [into_block](#method.into_block).
+    pub fn from_stream_with_block(
+        stream: R,
+        additional_threads: u16,
+        block: Option<Block>,
+    ) -> io::Result<Self> {
         let decompressor: Box<dyn DecompressBlock<_>> = if additional_threads == 0 {
-            Box::new(SingleThread::new())
+            Box::new(block.map(SingleThread::with_block).unwrap_or_else(SingleThread::new))
+        } else {
+            Box::new(MultiThread::new(additional_threads))
+        };
+        Self::with_decompressor(stream, decompressor)
+    }
+
+    /// Opens a reader from a stream. If `additional_threads` is 0, the reader takes a block from
+    /// the thread-local pool (or allocates a new one if the pool is empty), and returns the block
+    /// to the pool of the dropping thread when the reader is dropped. This saves allocations
+    /// when many short-lived readers are created.
+    ///
+    /// The pool keeps at most 64 blocks per thread, see [pooled_blocks](fn.pooled_blocks.html).
+    /// Blocks, reclaimed with [into_block](#method.into_block), are not returned to the pool.
+    pub fn from_stream_with_pool(stream: R, additional_threads: u16) -> io::Result<Self> {
+        let decompressor: Box<dyn DecompressBlock<_>> = if additional_threads == 0 {
+            Box::new(SingleThread::from_pool())
         } else {
             Box::new(MultiThread::new(additional_threads))
         };
+        Self::with_decompressor(stream, decompressor)
+    }
+
+    fn with_decompressor(
+        stream: R,
+        decompressor: Box<dyn DecompressBlock<JumpingReadBlock<R>>>,
+    ) -> io::Result<Self> {
+        let reader = JumpingReadBlock::new(stream)?;
         Ok(Self {
             decompressor,
             reader,
@@ -586,9 +709,65 @@ impl<R: Read + Seek> SeekReader<R> {
         self.reader.take_stream()
     }
 
+    /// Consumes the reader and returns the block used for decompression, so it can be
+    /// reused in another reader. Returns `None` for multi-thread readers.
+    pub fn into_block(self) -> Option<Block> {
+        self.decompressor.into_block()
+    }
+
     pub fn contents_offset(&self) -> usize {
         self.contents_offset
     }
+
+    /// Returns the [virtual offset](../index/struct.VirtualOffset.html) of the next byte
+    /// that will be read, or `None` if there are no more bytes in the current chunks.
+    ///
+    /// If the current block or chunk is exhausted, the function returns the start
+    /// of the next block or chunk.
+    pub fn virtual_offset(&self) -> Option<VirtualOffset> {
+        let chunks = self.reader.chunks();
+        if !self.started {
+            return chunks.first().map(Chunk::start);
+        }
+        let block = self.current()?;
+        let block_offset = block.offset()?;
+        let chunk_end = chunks.get(self.chunks_index)?.end();
+        let contents_end = if block_offset < chunk_end.block_offset() {
+            block.uncompressed_size() as usize
+        } else {
+            chunk_end.contents_offset() as usize
+        };
+        if self.contents_offset < contents_end {
+            Some(VirtualOffset::new(block_offset, self.contents_offset as u16))
+        } else if block_offset >= chunk_end.block_offset() {
+            chunks.get(self.chunks_index + 1).map(Chunk::start)
+        } else {
+            let block_size = block.block_size()?;
+            Some(VirtualOffset::new(block_offset + u64::from(block_size), 0))
+        }
+    }
+
+    /// Returns the total size of compressed blocks loaded from the stream, summed over all chunks
+    /// since the reader was created. Blocks read twice (for example, by two different `set_chunks`
+    /// calls) are counted twice.
+    ///
+    /// Multi-thread reader loads blocks in advance, so the value can slightly exceed the size
+    /// of the blocks that were actually returned.
+    pub fn bytes_processed(&self) -> u64 {
+        self.reader.bytes_read
+    }
+
+    /// Returns the offsets of all blocks in the file, including the EOF block. Any of these
+    /// offsets can be used as a start of a new reader (for example, using
+    /// [from_offset](#method.from_offset) with zero contents offset), so a file can be split
+    /// between several workers without an index.
+    ///
+    /// The function scans the whole file from the beginning: it reads only block headers and
+    /// does not decompress anything, but still requires one seek per block, which can be slow
+    /// for large or remote files. The current reading position is not changed.
+    pub fn block_boundaries(&mut self) -> io::Result<Vec<u64>> {
+        self.reader.block_boundaries()
+    }
 }
 
 impl<R: Read + Seek> ReadBgzip for SeekReader<R> {
@@ -724,6 +903,40 @@ impl<R: Read> ConsecutiveReader<R> {
     pub fn contents_offset(&self) -> usize {
         self.contents_offset
     }
+
+    /// Returns the [virtual offset](../index/struct.VirtualOffset.html) of the next byte
+    /// that will be read. Offsets are counted from the position of the stream when the reader
+    /// was created.
+    ///
+    /// If the current block is exhausted, the function returns the start of the next block.
+    pub fn virtual_offset(&self) -> VirtualOffset {
+        let block = match self.current() {
+            Some(block) if self.started => block,
+            _ => return VirtualOffset::new(self.reader.offset, 0),
+        };
+        let block_offset = block.offset().expect("Block offset should be already defined");
+        if self.contents_offset < block.uncompressed_size() as usize {
+            VirtualOffset::new(block_offset, self.contents_offset as u16)
+        } else {
+            let block_size = block.block_size().expect("Block size should be already defined");
+            VirtualOffset::new(block_offset + u64::from(block_size), 0)
+        }
+    }
+
+    /// Returns the number of compressed bytes consumed from the underlying stream.
+    /// Together with the file size, this value can be used to report progress. For streaming
+    /// inputs (such as stdin) the total size may be unknown.
+    ///
+    /// Multi-thread reader loads blocks in advance, so the value can slightly exceed the size
+    /// of the blocks that were actually returned.
+    pub fn bytes_processed(&self) -> u64 {
+        self.reader.offset
+    }
+
+    /// Moves the reading position inside the current block.
+    pub(crate) fn set_contents_offset(&mut self, contents_offset: usize) {
+        self.contents_offset = contents_offset;
+    }
 }
 
 impl<R: Read> ReadBgzip for ConsecutiveReader<R> {
@@ -362,6 +362,10 @@ impl WriterBuilder {
 
     /// Specify compression level from 0 to 9, where 0 represents no compression,
     /// and 9 represents maximal compression. The builder uses 6 as default.
+    ///
+    /// With level 0 the data is still split into valid bgzip blocks, so the output can be read
+    /// by any BGZF reader. This saves CPU time when the stream is immediately consumed by another
+    /// process, for example in a pipe.
     pub fn compression_level(&mut self, level: u8) -> &mut Self {
         assert!(level <= 9, "Maximal compression level is 9");
         self.compression = flate2::Compression::new(level as u32);
@@ -0,0 +1,117 @@
+//! Content digests of records. The module is available with the `digest` feature.
+//!
+//! [DigestReader](struct.DigestReader.html) wraps any [RecordReader](../trait.RecordReader.html)
+//! and computes a SHA-256 digest of the records as they are read. The digest depends only on
+//! the alignment contents, so the same records stored with different compression levels,
+//! block boundaries or in SAM and BAM formats produce the same digest:
+//! ```rust
+//! let reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+//! let header = reader.header().clone();
+//! let mut reader = bam::digest::DigestReader::new(reader, header);
+//! for record in &mut reader {
+//!     let record = record.unwrap();
+//!     // Do something with the record.
+//! }
+//! println!("{} records, digest {}", reader.n_records(), reader.content_digest_hex());
+//! ```
+
+use std::io;
+
+use sha2::{Digest, Sha256};
+
+use super::{Header, Record, RecordReader};
+
+/// Record reader that computes a SHA-256 digest of the records it returns.
+///
+/// The digest is computed over the SAM lines of the records, as written by
+/// [Record::write_sam](../record/struct.Record.html#method.write_sam), each followed by `\n`,
+/// in the order in which they are read. Therefore, it includes all eleven mandatory SAM fields
+/// (name, flag, reference name, 1-based position, MAPQ, CIGAR, mate reference name,
+/// where the same reference is written as `=`, mate position, template length, sequence and
+/// qualities) and all tags in their stored order. Integer tags are written as `i` regardless of
+/// their BAM type, and the bin is not included. The header is not a part of the digest.
+///
+/// As a result, the digest equals the SHA-256 of the SAM file without the header, written by
+/// this crate, and usually equals `samtools view in.bam | sha256sum`. Tools may format float
+/// tags differently, in which case the digests differ.
+///
+/// The reader implements [RecordReader](../trait.RecordReader.html) and `Iterator`.
+/// Records that fail to be read are not included in the digest.
+pub struct DigestReader<R: RecordReader> {
+    reader: R,
+    header: Header,
+    hasher: Sha256,
+    line: Vec<u8>,
+    n_records: u64,
+}
+
+impl<R: RecordReader> DigestReader<R> {
+    /// Wraps a record reader. `header` is needed to write reference names, and should be
+    /// the header of the reader.
+    pub fn new(reader: R, header: Header) -> Self {
+        DigestReader {
+            reader,
+            header,
+            hasher: Sha256::new(),
+            line: Vec::new(),
+            n_records: 0,
+        }
+    }
+
+    /// Returns the SHA-256 digest of all records read so far. The function can be called
+    /// at any moment, and the reading can continue afterwards.
+    pub fn content_digest(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+
+    /// Returns the [content digest](#method.content_digest) as a lowercase hexadecimal string.
+    pub fn content_digest_hex(&self) -> String {
+        self.content_digest()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Returns the number of records included in the digest.
+    pub fn n_records(&self) -> u64 {
+        self.n_records
+    }
+
+    /// Consumes the `DigestReader` and returns the inner reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: RecordReader> RecordReader for DigestReader<R> {
+    fn read_into(&mut self, record: &mut Record) -> io::Result<bool> {
+        if !self.reader.read_into(record)? {
+            return Ok(false);
+        }
+        self.line.clear();
+        if let Err(e) = record.write_sam(&mut self.line, &self.header) {
+            record.clear();
+            return Err(e);
+        }
+        self.hasher.update(&self.line);
+        self.n_records += 1;
+        Ok(true)
+    }
+
+    fn pause(&mut self) {
+        self.reader.pause();
+    }
+}
+
+impl<R: RecordReader> Iterator for DigestReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = Record::new();
+        match self.read_into(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -7,6 +7,8 @@ use std::string::String;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use super::bgzip;
+
 pub type TagName = [u8; 2];
 
 /// A single tag in a line.
@@ -87,6 +89,23 @@ impl EntryType {
     }
 }
 
+/// Sort order of the records, declared in the `SO` tag of the `@HD` header line.
+///
+/// # Variants
+/// * `Unknown` - the tag value is `unknown`, an unrecognized value, or the tag is missing,
+/// * `Unsorted` - the tag value is `unsorted`,
+/// * `QueryName` - the tag value is `queryname`,
+/// * `Coordinate` - the tag value is `coordinate`.
+///
+/// Tag values are compared case-insensitively.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortOrder {
+    Unknown,
+    Unsorted,
+    QueryName,
+    Coordinate,
+}
+
 /// A single header line.
 ///
 /// You can create a new entry using [header_line](#method.header_line),
@@ -267,6 +286,18 @@ pub struct Header {
     ref_names: Vec<String>,
     ref_lengths: Vec<u32>,
     ref_ids: HashMap<String, u32>,
+    comments: Vec<String>,
+    text: String,
+}
+
+/// Removes line ending from a header line. Trailing whitespaces are kept in `@CO` lines,
+/// so that the comments are preserved exactly.
+pub(crate) fn trim_line_end(line: &str) -> &str {
+    if line.starts_with("@CO") {
+        line.trim_end_matches(['\n', '\r'])
+    } else {
+        line.trim_end()
+    }
 }
 
 impl Header {
@@ -277,6 +308,8 @@ impl Header {
             ref_names: Vec::new(),
             ref_lengths: Vec::new(),
             ref_ids: HashMap::new(),
+            comments: Vec::new(),
+            text: String::new(),
         }
     }
 
@@ -317,9 +350,29 @@ impl Header {
 
     /// Pushes a new comment.
     pub fn push_comment(&mut self, comment: String) {
+        self.comments.push(comment.clone());
         self.lines.push(HeaderLine::Comment(comment));
     }
 
+    /// Pushes a new comment, same as [push_comment](#method.push_comment).
+    /// The comment will be written as `@CO\t{text}`.
+    pub fn add_comment(&mut self, text: &str) {
+        self.push_comment(text.to_string());
+    }
+
+    /// Returns the header text exactly as it was stored in the BAM file
+    /// (see [from_bam](#method.from_bam)), including all line endings and padding.
+    /// The text is not updated when the header is modified, and is empty for headers
+    /// that were not loaded from a BAM file.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the text of all `@CO` lines (without `@CO\t`), in the order of appearance.
+    pub fn comments(&self) -> &[String] {
+        &self.comments
+    }
+
     /// Pushes a lines to the header.
     pub fn push_line(&mut self, line: &str) -> Result<()> {
         if line.starts_with("@CO") {
@@ -354,12 +407,26 @@ impl Header {
 
     /// Writes header in an uncompressed BAM format.
     pub fn write_bam<W: Write>(&self, writer: &mut W) -> Result<()> {
-        writer.write_all(&[b'B', b'A', b'M', 1])?;
         let mut header_text = Vec::new();
         self.write_text(&mut header_text)
             .expect("Failed to write BAM text to a vector");
+        self.write_bam_with_text(writer, &header_text)
+    }
+
+    /// Writes header in an uncompressed BAM format, using the original [text](#method.text)
+    /// if it is not empty.
+    pub(crate) fn write_bam_raw<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.text.is_empty() {
+            self.write_bam(writer)
+        } else {
+            self.write_bam_with_text(writer, self.text.as_bytes())
+        }
+    }
+
+    fn write_bam_with_text<W: Write>(&self, writer: &mut W, header_text: &[u8]) -> Result<()> {
+        writer.write_all(&[b'B', b'A', b'M', 1])?;
         writer.write_i32::<LittleEndian>(header_text.len() as i32)?;
-        writer.write_all(&header_text)?;
+        writer.write_all(header_text)?;
         writer.write_i32::<LittleEndian>(self.ref_names.len() as i32)?;
         for (name, len) in self.ref_names.iter().zip(self.ref_lengths.iter()) {
             writer.write_i32::<LittleEndian>(name.len() as i32 + 1)?;
@@ -391,13 +458,15 @@ impl Header {
             .map_err(|_| Error::new(InvalidData, "BAM header is not in UTF-8"))?;
         let mut header = Header::new();
 
-        for line in text.split('\n') {
-            let line = line.trim_end();
+        // Header text can be padded with null symbols.
+        for line in text.trim_end_matches('\0').split('\n') {
+            let line = trim_line_end(line);
             if line.is_empty() {
                 continue;
             }
             header.push_line(line)?;
         }
+        header.text = text;
 
         let n_refs = stream.read_i32::<LittleEndian>()?;
         if n_refs < 0 {
@@ -453,29 +522,59 @@ impl Header {
         Ok(header)
     }
 
+    /// Parses the header of a compressed BAM file (BGZF), without reading any records.
+    ///
+    /// The function consumes only the prefix of the stream: bgzip blocks that contain the header
+    /// text and the list of references. The rest of the stream is not read and does not have
+    /// to be present or valid, so it is enough to download the first few blocks of a remote
+    /// file (for example, using a [RemoteReader](../remote/struct.RemoteReader.html) with the
+    /// `http` feature) to get the list of references.
+    ///
+    /// The last consumed block may also contain records, so the stream position after the
+    /// function is not necessarily the start of the first record.
+    pub fn from_bam_prefix<R: Read>(stream: &mut R) -> Result<Self> {
+        let mut reader = bgzip::ConsecutiveReader::from_stream(stream, 0);
+        Header::from_bam(&mut reader)
+    }
+
     /// Returns the number of reference sequences in the BAM file.
     pub fn n_references(&self) -> usize {
         self.ref_names.len()
     }
 
+    /// Returns the [sort order](enum.SortOrder.html), declared in the first `@HD` line.
+    /// Returns `SortOrder::Unknown` if there is no `@HD` line or it has no `SO` tag.
+    pub fn sort_order(&self) -> SortOrder {
+        let value = self.lines.iter().find_map(|line| match line {
+            HeaderLine::Entry(entry) if entry.entry_type() == EntryType::HeaderLine => {
+                Some(entry.get(b"SO"))
+            }
+            _ => None,
+        });
+        match value.flatten().map(str::to_ascii_lowercase).as_deref() {
+            Some("unsorted") => SortOrder::Unsorted,
+            Some("queryname") => SortOrder::QueryName,
+            Some("coordinate") => SortOrder::Coordinate,
+            _ => SortOrder::Unknown,
+        }
+    }
+
+    /// Returns `true` if there is a reference with `ref_id` (0-based), in other words,
+    /// if `ref_id < n_references()`.
+    pub fn has_reference(&self, ref_id: u32) -> bool {
+        (ref_id as usize) < self.ref_names.len()
+    }
+
     /// Returns the name of the reference with `ref_id` (0-based).
     /// Returns None if there is no such reference
     pub fn reference_name(&self, ref_id: u32) -> Option<&str> {
-        if ref_id as usize > self.ref_names.len() {
-            None
-        } else {
-            Some(&self.ref_names[ref_id as usize])
-        }
+        self.ref_names.get(ref_id as usize).map(String::as_str)
     }
 
     /// Returns the length of the reference with `ref_id` (0-based).
     /// Returns None if there is no such reference
     pub fn reference_len(&self, ref_id: u32) -> Option<u32> {
-        if ref_id as usize > self.ref_lengths.len() {
-            None
-        } else {
-            Some(self.ref_lengths[ref_id as usize])
-        }
+        self.ref_lengths.get(ref_id as usize).cloned()
     }
 
     /// Returns reference id from its name, if possible.
@@ -492,4 +591,51 @@ impl Header {
     pub fn reference_lengths(&self) -> &[u32] {
         &self.ref_lengths
     }
+
+    /// Checks that the header references are the same as `refs`: pairs
+    /// *(reference name, reference length)*, for example, loaded from a FASTA index.
+    /// The references should have the same names and lengths, and should appear in the same order.
+    ///
+    /// Returns an error describing the first discrepancy: a name mismatch, a length mismatch,
+    /// or a different number of references.
+    pub fn check_compatible(&self, refs: &[(String, u32)]) -> std::result::Result<(), String> {
+        for (i, (name, len)) in refs.iter().enumerate() {
+            let (header_name, header_len) = match self.ref_names.get(i) {
+                Some(header_name) => (header_name, self.ref_lengths[i]),
+                None => {
+                    return Err(format!(
+                        "Header contains {} references, but {} are expected \
+                        (first missing reference #{}: {})",
+                        self.ref_names.len(),
+                        refs.len(),
+                        i,
+                        name
+                    ))
+                }
+            };
+            if header_name != name {
+                return Err(format!(
+                    "Reference #{} has name {} in the header, but {} is expected",
+                    i, header_name, name
+                ));
+            }
+            if header_len != *len {
+                return Err(format!(
+                    "Reference #{} ({}) has length {} in the header, but {} is expected",
+                    i, name, header_len, len
+                ));
+            }
+        }
+        if self.ref_names.len() > refs.len() {
+            return Err(format!(
+                "Header contains {} references, but {} are expected \
+                (first unexpected reference #{}: {})",
+                self.ref_names.len(),
+                refs.len(),
+                refs.len(),
+                self.ref_names[refs.len()]
+            ));
+        }
+        Ok(())
+    }
 }
@@ -193,21 +193,25 @@ const WINDOW_SIZE: u32 = 16384;
 pub struct LinearIndex {
     /// each element stores the index and offset of the first interval with such offset.
     intervals: Vec<(u32, VirtualOffset)>,
+    /// raw offsets for all windows, as stored in the index.
+    offsets: Vec<u64>,
 }
 
 impl LinearIndex {
     fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
         let n_intervals = stream.read_i32::<LittleEndian>()? as u32;
         let mut intervals = Vec::new();
+        let mut offsets = Vec::with_capacity(n_intervals as usize);
         for i in 0..n_intervals {
             let offset = VirtualOffset::from_stream(stream)?;
+            offsets.push(offset.raw());
             match intervals.last() {
                 Some((_, prev_offset)) if *prev_offset == offset => {}
                 _ => intervals.push((i, offset)),
             }
         }
         intervals.shrink_to_fit();
-        Ok(LinearIndex { intervals })
+        Ok(LinearIndex { intervals, offsets })
     }
 
     /// Returns true if the linear index is empty.
@@ -226,6 +230,14 @@ impl LinearIndex {
         &self.intervals
     }
 
+    /// Returns raw virtual offsets for all windows: *i*-th element stores the smallest virtual offset
+    /// of a record overlapping the window [16384 * i, 16384 * (i + 1)).
+    ///
+    /// Unlike [intervals](#method.intervals), consecutive windows with the same offset are not merged.
+    pub fn offsets(&self) -> &[u64] {
+        &self.offsets
+    }
+
     /// Retuns an offset *x*, such that for a region with genomic coordinates [start-end)
     /// we only need to visit chunks with end offset > *x*.
     pub fn min_end_offset(&self, start: i32) -> VirtualOffset {
@@ -363,6 +375,73 @@ impl Index {
         Index::from_stream(b)
     }
 
+    /// Walks the whole index and checks that
+    /// * all bin ids are valid,
+    /// * chunks within each bin have `start < end`, are sorted and do not overlap,
+    /// * non-zero linear index offsets do not decrease.
+    ///
+    /// Returns an `InvalidData` error that describes the first violation, together with the
+    /// reference id and the number of bins and chunks in the reference.
+    pub fn validate(&self) -> Result<()> {
+        for (ref_id, reference) in self.references.iter().enumerate() {
+            let n_chunks: usize = reference.bins.values().map(|bin| bin.chunks.len()).sum();
+            let invalid = |problem: String| {
+                Error::new(
+                    InvalidData,
+                    format!(
+                        "Invalid BAI index: reference {} ({} bins, {} chunks): {}",
+                        ref_id,
+                        reference.bins.len(),
+                        n_chunks,
+                        problem
+                    ),
+                )
+            };
+
+            let mut bin_ids: Vec<u32> = reference.bins.keys().cloned().collect();
+            bin_ids.sort_unstable();
+            for bin_id in bin_ids {
+                if bin_id == SUMMARY_BIN {
+                    continue;
+                }
+                if bin_id > MAX_BIN {
+                    return Err(invalid(format!("bin id {} > {}", bin_id, MAX_BIN)));
+                }
+                let chunks = &reference.bins[&bin_id].chunks;
+                for (i, chunk) in chunks.iter().enumerate() {
+                    if chunk.end <= chunk.start {
+                        return Err(invalid(format!(
+                            "bin {} has chunk {} with end <= start",
+                            bin_id, chunk
+                        )));
+                    }
+                    if i > 0 && chunk.start < chunks[i - 1].end {
+                        return Err(invalid(format!(
+                            "bin {} has unsorted or overlapping chunks {} and {}",
+                            bin_id,
+                            chunks[i - 1],
+                            chunk
+                        )));
+                    }
+                }
+            }
+
+            let mut prev_offset = 0;
+            for (i, &offset) in reference.linear_index.offsets.iter().enumerate() {
+                if offset != 0 && offset < prev_offset {
+                    return Err(invalid(format!(
+                        "linear index offset for window {} decreases ({} < {})",
+                        i,
+                        VirtualOffset::from_raw(offset),
+                        VirtualOffset::from_raw(prev_offset)
+                    )));
+                }
+                prev_offset = max(prev_offset, offset);
+            }
+        }
+        Ok(())
+    }
+
     /// Fetches [chunks](struct.Chunk.html) of the BAM file that contain all records for a given region.
     pub fn fetch_by_bins(&self, ref_id: u32, bin_ids: Vec<u32>) -> Vec<Chunk> {
         let mut chunks = Vec::new();
@@ -455,11 +534,42 @@ impl Index {
         None
     }
 
+    /// Returns raw virtual offsets from the linear index of the reference `ref_id`,
+    /// one for each 16384bp window (see [LinearIndex::offsets](struct.LinearIndex.html#method.offsets)).
+    ///
+    /// An offset can be converted into [VirtualOffset](struct.VirtualOffset.html) using
+    /// [from_raw](struct.VirtualOffset.html#method.from_raw), for example, to find the position in
+    /// the compressed file (`block_offset`) before reading a region. Windows after the last
+    /// record are not stored. Panics if `ref_id` is out of range.
+    pub fn linear_offsets(&self, ref_id: u32) -> &[u64] {
+        self.references[ref_id as usize].linear_index.offsets()
+    }
+
     /// Returns all [references](struct.Reference.html) present in the BAI index.
     pub fn references(&self) -> &[Reference] {
         &self.references
     }
 
+    /// Returns ids of the references that have any chunks in the index, in increasing order.
+    /// Only the index is used, so the function can be called before reading the BAM file,
+    /// for example, to skip empty references when processing references in parallel.
+    ///
+    /// The result reflects the index, and does not guarantee that fetching a reference returns
+    /// any records, for example, when all of them are removed by a filter.
+    pub fn populated_references(&self) -> Vec<u32> {
+        self.references
+            .iter()
+            .enumerate()
+            .filter(|(_, reference)| {
+                reference
+                    .bins
+                    .iter()
+                    .any(|(&bin_id, bin)| bin_id != SUMMARY_BIN && !bin.chunks.is_empty())
+            })
+            .map(|(ref_id, _)| ref_id as u32)
+            .collect()
+    }
+
     /// Returns the number of unmapped records, if present in the index.
     pub fn n_unmapped(&self) -> Option<u64> {
         self.n_unmapped
@@ -3,20 +3,37 @@
 //!
 //! ## Overview
 //!
-//! Currently, there are three readers and two writers:
+//! Currently, there are four readers and three writers:
 //! * [bam::IndexedReader](bam_reader/struct.IndexedReader.html) - fetches records from
 //! random genomic regions.
+//! * [bam::bam_reader::VirtualIndexedReader](bam_reader/struct.VirtualIndexedReader.html) -
+//!   fetches records from several indexed BAM files, merged in the coordinate order.
 //! * [bam::BamReader](bam_reader/struct.BamReader.html) - reads a BAM file consecutively.
 //! * [bam::SamReader](sam/struct.SamReader.html) - reads a SAM file consecutively.
 //! * [bam::BamWriter](bam_writer/struct.BamWriter.html) - writes a BAM file.
+//! * [bam::bam_writer::SortingWriter](bam_writer/struct.SortingWriter.html) - writes a BAM file,
+//!   sorted by coordinate, from unsorted records.
 //! * [bam::SamWriter](sam/struct.SamWriter.html) - writes a SAM file.
 //!
 //! BAM readers and writers have single-thread and multi-thread modes.
+//! If the input format is not known in advance, [bam::open](fn.open.html) opens either
+//! a SAM or a BAM file.
 //!
 //! You can construct pileups from all readers using [Pileup](pileup/struct.Pileup.html).
 //!
 //! The [bgzip](bgzip/index.html) module to interact directly with bgzip files (BGZF).
 //!
+//! The [stats](stats/index.html) module calculates summary statistics over records.
+//!
+//! With the `http` feature, the [remote](remote/index.html) module allows to read
+//! BAM files over HTTP and HTTPS.
+//!
+//! With the `json` feature, records can be converted to and from JSON using
+//! [Record::to_json](record/struct.Record.html#method.to_json).
+//!
+//! With the `digest` feature, the [digest](digest/index.html) module computes checksums
+//! of record contents while reading.
+//!
 //! The crate also allows to conviniently work with SAM/BAM [records](record/struct.Record.html)
 //! and their fields, such as [CIGAR](record/cigar/struct.Cigar.html) or
 //! [tags](record/tags/struct.TagViewer.html).
@@ -109,11 +126,16 @@ extern crate flate2;
 pub mod bam_reader;
 pub mod bam_writer;
 pub mod bgzip;
+#[cfg(feature = "digest")]
+pub mod digest;
 pub mod header;
 pub mod index;
 pub mod pileup;
 pub mod record;
+#[cfg(feature = "http")]
+pub mod remote;
 pub mod sam;
+pub mod stats;
 
 pub use bam_reader::BamReader;
 pub use bam_reader::IndexedReader;
@@ -126,7 +148,9 @@ pub use record::Record;
 pub use sam::SamReader;
 pub use sam::SamWriter;
 
-use std::io;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
 
 /// A trait for reading BAM/SAM records.
 ///
@@ -159,6 +183,21 @@ pub trait RecordReader: Iterator<Item = io::Result<Record>> {
     /// If the function returns an error, the record is cleared.
     fn read_into(&mut self, record: &mut Record) -> io::Result<bool>;
 
+    /// Reads all remaining records into a vector. For a
+    /// [RegionViewer](bam_reader/struct.RegionViewer.html), only the rest of the region is read.
+    ///
+    /// If an error occurs, the function stops and returns the error, and records read before
+    /// the error are lost.
+    fn drain(&mut self) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        let mut record = Record::new();
+        while self.read_into(&mut record)? {
+            records.push(record);
+            record = Record::new();
+        }
+        Ok(records)
+    }
+
     /// Pauses multi-thread reader until the next read operation. Does nothing to a single-thread reader.
     ///
     /// Use with caution: pausing and unpausing takes some time.
@@ -176,3 +215,229 @@ pub trait RecordWriter {
     /// Flushes contents.
     fn flush(&mut self) -> io::Result<()>;
 }
+
+/// Reads all records from `reader`, modifies them in place with `f`, and writes the records,
+/// for which `f` returned `true`, to `writer`. A single record is used for all reads, so the
+/// function does not allocate memory per record.
+///
+/// ```rust
+/// let mut reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+/// let mut writer = bam::BamWriter::from_path("out.bam", reader.header().clone()).unwrap();
+/// // Remove duplicates and mark the remaining records with a tag.
+/// bam::transform(&mut reader, &mut writer, |record| {
+///     record.tags_mut().push_char(b"XD", b'N');
+///     !record.flag().is_duplicate()
+/// }).unwrap();
+/// ```
+///
+/// The writer is not [finished](trait.RecordWriter.html#method.finish) by the function.
+pub fn transform<R, W, F>(reader: &mut R, writer: &mut W, mut f: F) -> io::Result<()>
+where
+    R: RecordReader + ?Sized,
+    W: RecordWriter + ?Sized,
+    F: FnMut(&mut Record) -> bool,
+{
+    let mut record = Record::new();
+    while reader.read_into(&mut record)? {
+        if f(&mut record) {
+            writer.write(&record)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads all records from `reader` and writes them to `out` in FASTQ format (see
+/// [Record::write_fastq](record/struct.Record.html#method.write_fastq)), optionally
+/// quality-trimming 3' ends with the threshold `min_qual`. Reverse strand reads are written
+/// in their original orientation. Secondary and supplementary records are skipped, so every
+/// read is written once. Returns the number of written reads.
+///
+/// Paired reads get `/1` and `/2` suffixes, so if mates follow each other in the input
+/// (for example, the file is sorted by query name), the output is an interleaved FASTQ file.
+/// To keep mates in sync, completely trimmed reads are written with an empty sequence,
+/// and records without a sequence produce an error.
+///
+/// ```rust
+/// let mut reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+/// let mut output = std::io::BufWriter::new(std::fs::File::create("out.fastq").unwrap());
+/// bam::export_fastq(&mut reader, &mut output, Some(20)).unwrap();
+/// ```
+pub fn export_fastq<R, W>(reader: &mut R, out: &mut W, min_qual: Option<u8>) -> io::Result<u64>
+where
+    R: RecordReader + ?Sized,
+    W: Write,
+{
+    let mut record = Record::new();
+    let mut count = 0;
+    while reader.read_into(&mut record)? {
+        if record.flag().is_secondary() || record.flag().is_supplementary() {
+            continue;
+        }
+        record.write_fastq(out, min_qual)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Opens a SAM or a BAM file, depending on its contents, and returns its header and a reader
+/// over its records.
+///
+/// The format is determined by the first bytes of the file: gzip magic number means a
+/// BGZF-compressed BAM file (opened with a single-thread [BamReader](bam_reader/struct.BamReader.html)),
+/// `BAM\1` means an uncompressed BAM file, such as the output of `samtools view -u`,
+/// and anything else is read as a plain-text SAM file using [SamReader](sam/struct.SamReader.html).
+/// To inspect the format without opening a reader, use [sniff](fn.sniff.html).
+///
+/// The returned trait object only provides
+/// [RecordReader](trait.RecordReader.html) and `Iterator` methods: if you need methods
+/// specific to a concrete reader, open it directly.
+///
+/// ```rust
+/// let (header, reader) = bam::open("in.bam").unwrap();
+/// for record in reader {
+///     let record = record.unwrap();
+///     // Do something with the record.
+/// }
+/// ```
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Header, Box<dyn RecordReader>)> {
+    let mut stream = BufReader::new(File::open(path)?);
+    match detect_format(stream.fill_buf()?) {
+        // BamReader reports an error for gzip files without BGZF blocks.
+        DetectedFormat::Bgzf | DetectedFormat::Gzip => {
+            let reader = BamReader::from_stream(stream, 0)?;
+            Ok((reader.header().clone(), Box::new(reader)))
+        }
+        DetectedFormat::UncompressedBam => {
+            let (header, reader) = bam_reader::UncompressedReader::from_stream(stream)?;
+            Ok((header, Box::new(reader)))
+        }
+        DetectedFormat::Text => {
+            let reader = SamReader::from_stream(stream)?;
+            Ok((reader.header().clone(), Box::new(reader)))
+        }
+    }
+}
+
+/// Input format, detected from the first bytes of a file by [open](fn.open.html)
+/// and [sniff](fn.sniff.html).
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DetectedFormat {
+    /// A BGZF block: a gzip member with the `BC` extra subfield.
+    Bgzf,
+    /// Any other gzip member.
+    Gzip,
+    /// Uncompressed BAM file, starting with `BAM\1`.
+    UncompressedBam,
+    /// Anything else, read as a plain-text SAM file.
+    Text,
+}
+
+/// Detects the input format from the first buffered bytes of a file.
+fn detect_format(prefix: &[u8]) -> DetectedFormat {
+    if prefix.len() >= 16
+        && prefix.starts_with(&[0x1f, 0x8b, 8])
+        && prefix[3] & 4 != 0
+        && &prefix[12..14] == b"BC"
+    {
+        DetectedFormat::Bgzf
+    } else if prefix.starts_with(&[0x1f, 0x8b]) {
+        DetectedFormat::Gzip
+    } else if prefix.starts_with(b"BAM\x01") {
+        DetectedFormat::UncompressedBam
+    } else {
+        DetectedFormat::Text
+    }
+}
+
+/// Container format of a SAM/BAM input, as opposed to the contents of its
+/// [header](header/struct.Header.html).
+///
+/// Returned by [sniff](fn.sniff.html) and by
+/// [BamReader::format_info](bam_reader/struct.BamReader.html#method.format_info).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatInfo {
+    /// The input starts with a BGZF block (a gzip member with the `BC` extra subfield).
+    pub bgzf: bool,
+    /// The (decompressed) input starts with the BAM magic string `BAM\1`.
+    pub bam_magic: bool,
+    /// Length of the header text (`l_text`), if the input has a valid BAM magic string.
+    pub header_text_len: Option<u32>,
+}
+
+impl FormatInfo {
+    /// Format info of a BGZF-compressed BAM file with the parsed `header`.
+    pub(crate) fn bam(header: &Header) -> Self {
+        FormatInfo {
+            bgzf: true,
+            bam_magic: true,
+            header_text_len: Some(header.text().len() as u32),
+        }
+    }
+
+    /// Returns `true` if the input is a BGZF-compressed BAM file.
+    pub fn is_bam(&self) -> bool {
+        self.bgzf && self.bam_magic
+    }
+}
+
+/// Inspects the first bytes of a file and returns its [container format](struct.FormatInfo.html),
+/// using the same rules as [open](fn.open.html), but without parsing the header or reading
+/// records. Useful to report on input files in diagnostic tools.
+///
+/// For BGZF files, only the first block is decompressed. Other gzip files are reported with
+/// `bgzf: false` and are not decompressed, so `bam_magic` is `false` for them.
+/// Uncompressed BAM files are reported with `bgzf: false` and `bam_magic: true`.
+///
+/// ```rust
+/// let info = bam::sniff("in.bam").unwrap();
+/// println!("BGZF: {}, BAM magic: {}, header text: {:?} bytes",
+///     info.bgzf, info.bam_magic, info.header_text_len);
+/// ```
+pub fn sniff<P: AsRef<Path>>(path: P) -> io::Result<FormatInfo> {
+    let mut stream = BufReader::new(File::open(path)?);
+    let format = detect_format(stream.fill_buf()?);
+    let bgzf = format == DetectedFormat::Bgzf;
+
+    let mut start = [0_u8; 8];
+    let start_len = match format {
+        DetectedFormat::Bgzf => {
+            let mut reader = bgzip::ConsecutiveReader::from_stream(stream, 0);
+            read_up_to(&mut reader, &mut start)?
+        }
+        DetectedFormat::Gzip => 0,
+        DetectedFormat::UncompressedBam | DetectedFormat::Text => {
+            read_up_to(&mut stream, &mut start)?
+        }
+    };
+
+    let bam_magic = start_len >= 4 && &start[..4] == b"BAM\x01";
+    let header_text_len = if bam_magic && start_len == 8 {
+        let l_text = i32::from_le_bytes([start[4], start[5], start[6], start[7]]);
+        if l_text >= 0 {
+            Some(l_text as u32)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    Ok(FormatInfo {
+        bgzf,
+        bam_magic,
+        header_text_len,
+    })
+}
+
+/// Reads until `buf` is full or the stream ends, and returns the number of read bytes.
+fn read_up_to<R: io::Read>(stream: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match stream.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
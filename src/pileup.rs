@@ -5,11 +5,17 @@
 //! * [Pileup column](struct.PileupColumn.html) - contains information about all records that overlap a certain
 //! reference position,
 //! * [Pileup entry](struct.PileupEntry.html) - a single record that overlaps a certain reference position.
+//!
+//! [Read tracks](struct.ReadTracks.html) present the same information in the row-major order:
+//! one [track](struct.ReadTrack.html) per record.
 
 use std::cmp::min;
+use std::collections::HashMap;
 use std::io;
 use std::rc::Rc;
 
+use super::record::cigar::Operation;
+use super::record::ReferenceFetch;
 use super::{Record, RecordReader};
 
 /// Type of the record sequence, matching a single reference position.
@@ -37,16 +43,24 @@ pub struct PileupEntry {
     ref_pos: u32,
     cigar_index: usize,
     cigar_remaining: u32,
+    // Qualities of the whole record, capped near indels, if enabled in the pileup.
+    baq_qualities: Option<Rc<[u8]>>,
 }
 
 impl PileupEntry {
     /// Creates a new `PileupEntry` from a mapped `Record`.
-    fn new(record: Rc<Record>) -> Self {
+    /// Returns an error if the record CIGAR does not contain reference-consuming operations.
+    fn new(record: Rc<Record>) -> io::Result<Self> {
         let ref_pos = record.start();
         let mut cigar_index = 0;
         let mut query_pos = 0;
         let cigar_remaining = loop {
-            let (len, op) = record.cigar().at(cigar_index);
+            let (len, op) = record.cigar().get(cigar_index).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Corrupted record: CIGAR cannot contain only insertions",
+                )
+            })?;
             if op.consumes_ref() {
                 break len;
             }
@@ -55,10 +69,6 @@ impl PileupEntry {
             }
             cigar_index += 1;
         };
-        assert!(
-            cigar_index < record.cigar().len(),
-            "CIGAR cannot contain only insertions"
-        );
         let aln_query_end = record.aligned_query_end();
 
         let mut res = PileupEntry {
@@ -70,9 +80,10 @@ impl PileupEntry {
             ref_pos: ref_pos as u32,
             cigar_index,
             cigar_remaining,
+            baq_qualities: None,
         };
         res.update_query_end();
-        res
+        Ok(res)
     }
 
     fn update_query_end(&mut self) {
@@ -162,7 +173,27 @@ impl PileupEntry {
         }
     }
 
-    /// Returns the current reference position.
+    /// If the [reference position](#method.ref_pos) is inside a deletion or a reference skip
+    /// (CIGAR operations `D` and `N`), returns the operation and its full length.
+    /// The full length is returned for every position inside the operation, including the first one.
+    /// Returns `None` otherwise.
+    pub fn indel_context(&self) -> Option<(Operation, u32)> {
+        match self.record.cigar().at(self.cigar_index) {
+            (len, op @ Operation::Deletion) | (len, op @ Operation::Skip) => Some((op, len)),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of reference positions remaining in the current deletion or reference
+    /// skip, including the current position (equal to the full length at the first position).
+    /// Returns `None` if the position is not inside a deletion.
+    pub fn indel_remaining(&self) -> Option<u32> {
+        self.indel_context().map(|_| self.cigar_remaining)
+    }
+
+    /// Returns the current reference position (0-based). It is the same as the
+    /// [reference position](struct.PileupColumn.html#method.ref_pos) of the column,
+    /// containing the entry.
     pub fn ref_pos(&self) -> u32 {
         self.ref_pos
     }
@@ -191,6 +222,43 @@ impl PileupEntry {
         }
     }
 
+    /// Returns qualities in the same region as [qualities](#method.qualities), adjusted using
+    /// a simplified BAQ (see [Pileup::cap_qualities_near_indels](struct.Pileup.html#method.cap_qualities_near_indels)).
+    /// Returns `None` if the adjustment was not enabled, or if the record has no qualities.
+    pub fn baq_qualities(&self) -> Option<&[u8]> {
+        self.baq_qualities
+            .as_ref()
+            .map(|qual| &qual[self.query_start as usize..self.query_end as usize])
+    }
+
+    /// Calculates record qualities, capped at `max_qual` in the `window` around insertions
+    /// and deletions.
+    fn calculate_baq(&mut self, window: u32, max_qual: u8) {
+        if !self.record.qualities().available() {
+            return;
+        }
+        let mut qual = self.record.qualities().raw().to_vec();
+        let mut cap = |start: u32, end: u32| {
+            let start = start.saturating_sub(window) as usize;
+            let end = min(end.saturating_add(window) as usize, qual.len());
+            for q in qual[min(start, end)..end].iter_mut() {
+                *q = min(*q, max_qual);
+            }
+        };
+        let mut query_pos = 0;
+        for (len, op) in self.record.cigar().iter() {
+            match op {
+                Operation::Insertion => cap(query_pos, query_pos + len),
+                Operation::Deletion => cap(query_pos, query_pos),
+                _ => {}
+            }
+            if op.consumes_query() {
+                query_pos += len;
+            }
+        }
+        self.baq_qualities = Some(Rc::from(qual));
+    }
+
     /// Returns true if the record alignment starts at the [reference position](#method.ref_pos).
     pub fn is_aln_start(&self) -> bool {
         self.ref_pos == self.record.start() as u32
@@ -242,9 +310,13 @@ pub struct Pileup<'a, I: Iterator<Item = io::Result<Record>>> {
     read_filter: Box<dyn Fn(&Record) -> bool>,
     entries: Vec<PileupEntry>,
     error: Option<io::Error>,
+    skip_deletions: bool,
+    baq: Option<(u32, u8)>,
 
     last_ref_id: u32,
     last_ref_pos: u32,
+    // Minimal (ref_id, ref_pos) over all entries, or (u32::MAX, u32::MAX) if there are no entries.
+    min_pos: (u32, u32),
 }
 
 impl<'a, I: Iterator<Item = io::Result<Record>>> Pileup<'a, I> {
@@ -269,13 +341,91 @@ impl<'a, I: Iterator<Item = io::Result<Record>>> Pileup<'a, I> {
             read_filter: Box::new(read_filter),
             entries: Vec::new(),
             error: None,
+            skip_deletions: false,
+            baq: None,
             last_ref_id: 0,
             last_ref_pos: 0,
+            min_pos: (u32::MAX, u32::MAX),
         };
         res.read_next();
         res
     }
 
+    /// If `skip` is true, pileup columns will not contain entries with
+    /// [AlnType::Deletion](enum.AlnType.html) (deletions and reference skips), and columns
+    /// that only contain such entries will not be reported. In that case the number of
+    /// [entries](struct.PileupColumn.html#method.entries) reflects only the records with a base
+    /// aligned to the reference position. By default, deletions are not skipped.
+    pub fn skip_deletions(&mut self, skip: bool) -> &mut Self {
+        self.skip_deletions = skip;
+        self
+    }
+
+    /// Enables a simplified BAQ (base alignment quality): base qualities at most `window` bases
+    /// away from an insertion or a deletion are capped at `max_qual`.
+    /// Adjusted qualities are available through
+    /// [PileupEntry::baq_qualities](struct.PileupEntry.html#method.baq_qualities),
+    /// and [qualities](struct.PileupEntry.html#method.qualities) stay unchanged.
+    ///
+    /// For an insertion, the inserted bases are capped, and the window is counted from both ends,
+    /// and for a deletion, from the bases around it. Window boundaries are measured in read
+    /// bases, and soft clipping and reference skips (`N`) are not treated as indels.
+    ///
+    /// This is a heuristic, and not the BAQ from samtools: it does not use the reference and
+    /// does not realign the reads, so misaligned bases far from the indels are not affected,
+    /// and true bases near the indels are downweighted as well.
+    pub fn cap_qualities_near_indels(&mut self, window: u32, max_qual: u8) -> &mut Self {
+        self.baq = Some((window, max_qual));
+        for entry in self.entries.iter_mut() {
+            entry.calculate_baq(window, max_qual);
+        }
+        self
+    }
+
+    /// Creates a pileup that only yields columns at the `positions` (pairs `(ref_id, ref_pos)`,
+    /// 0-based), see [PositionsPileup](struct.PositionsPileup.html).
+    /// Positions without any records are not reported.
+    ///
+    /// Returns an error if `positions` are not sorted, or contain duplicates.
+    pub fn at_positions(
+        record_iter: &'a mut I,
+        positions: Vec<(u32, u32)>,
+    ) -> io::Result<PositionsPileup<'a, I>> {
+        if positions.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Pileup positions should be sorted and should not contain duplicates",
+            ));
+        }
+        Ok(PositionsPileup {
+            pileup: Self::new(record_iter),
+            positions: positions.into_iter(),
+        })
+    }
+
+    /// Converts the pileup into a naive variant scanner, that yields only positions where
+    /// the records disagree with the reference (see [VariantScan](struct.VariantScan.html)).
+    ///
+    /// The reference nucleotide at each position is fetched from the
+    /// [reference](../record/trait.ReferenceFetch.html), positions with a reference nucleotide
+    /// other than A, C, G and T are skipped. If the reference cannot be fetched, the scanner
+    /// returns the error and continues from the next position.
+    /// A position is reported if its depth is at least `min_depth`, and the fraction of
+    /// non-reference nucleotides is at least `min_fraction`.
+    pub fn variant_scan<F: ReferenceFetch>(
+        self,
+        reference: F,
+        min_depth: u32,
+        min_fraction: f64,
+    ) -> VariantScan<'a, I, F> {
+        VariantScan {
+            pileup: self,
+            reference,
+            min_depth,
+            min_fraction,
+        }
+    }
+
     fn record_passes(&self, record: &Record) -> bool {
         if !record.flag().is_mapped() {
             return false;
@@ -284,6 +434,39 @@ impl<'a, I: Iterator<Item = io::Result<Record>>> Pileup<'a, I> {
         (self.read_filter)(record)
     }
 
+    /// Reads all records that start before or at the `target` position, and moves all entries
+    /// to the `target` position or after it, without constructing pileup columns.
+    fn skip_to(&mut self, target: (u32, u32)) {
+        while self.last_ref_id < u32::MAX && (self.last_ref_id, self.last_ref_pos) <= target {
+            self.read_next();
+        }
+        let mut min_pos = (u32::MAX, u32::MAX);
+        self.entries.retain_mut(|entry| {
+            let rec_ref_id = entry.record.ref_id() as u32;
+            if rec_ref_id < target.0
+                || (rec_ref_id == target.0 && entry.record.calculate_end() as u32 <= target.1)
+            {
+                return false;
+            }
+            while (rec_ref_id, entry.ref_pos) < target {
+                if !entry.move_forward() {
+                    return false;
+                }
+            }
+            min_pos = min(min_pos, (rec_ref_id, entry.ref_pos));
+            true
+        });
+        self.min_pos = min_pos;
+    }
+
+    /// Removes all entries and stops reading records after an error.
+    fn stop(&mut self) -> io::Error {
+        self.entries.clear();
+        self.min_pos = (u32::MAX, u32::MAX);
+        self.last_ref_id = u32::MAX;
+        self.error.take().unwrap()
+    }
+
     fn read_next(&mut self) {
         if self.last_ref_id == std::u32::MAX || self.error.is_some() {
             return;
@@ -308,7 +491,19 @@ impl<'a, I: Iterator<Item = io::Result<Record>>> Pileup<'a, I> {
                     }
                     self.last_ref_id = rec_ref_id;
                     self.last_ref_pos = rec_start;
-                    self.entries.push(PileupEntry::new(Rc::new(record)));
+                    match PileupEntry::new(Rc::new(record)) {
+                        Ok(mut entry) => {
+                            if let Some((window, max_qual)) = self.baq {
+                                entry.calculate_baq(window, max_qual);
+                            }
+                            self.min_pos = min(self.min_pos, (rec_ref_id, entry.ref_pos));
+                            self.entries.push(entry);
+                        }
+                        Err(e) => {
+                            self.error = Some(e);
+                            self.last_ref_id = u32::MAX;
+                        }
+                    }
                 }
                 Some(Err(e)) => {
                     self.error = Some(e);
@@ -324,40 +519,43 @@ impl<'a, R: RecordReader> Iterator for Pileup<'a, R> {
     type Item = io::Result<PileupColumn>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.error.is_some() {
-            self.entries.clear();
-            self.last_ref_id = std::u32::MAX;
-            return Some(Err(self.error.take().unwrap()));
-        }
-
-        let mut new_ref_id = std::u32::MAX;
-        let mut new_ref_pos = std::u32::MAX;
-        while new_ref_id == std::u32::MAX
-            && (!self.entries.is_empty() || self.last_ref_id < std::u32::MAX)
-        {
-            for entry in self.entries.iter() {
-                let rec_ref_id = entry.record.ref_id() as u32;
-                if rec_ref_id < new_ref_id {
-                    new_ref_id = rec_ref_id;
-                    new_ref_pos = entry.ref_pos;
-                } else if rec_ref_id == new_ref_id {
-                    new_ref_pos = min(new_ref_pos, entry.ref_pos);
+        loop {
+            let mut column = match self.next_column()? {
+                Ok(column) => column,
+                Err(e) => return Some(Err(e)),
+            };
+            if self.skip_deletions {
+                column
+                    .entries
+                    .retain(|entry| entry.aln_type() != AlnType::Deletion);
+                if column.entries.is_empty() {
+                    continue;
                 }
             }
+            return Some(Ok(column));
+        }
+    }
+}
 
-            while self.last_ref_id < std::u32::MAX
-                    // new_ref_id == last_ref_id or new_ref_id == u32::MAX, same with pos.
-                    && self.last_ref_id <= new_ref_id && self.last_ref_pos <= new_ref_pos
-            {
-                self.read_next();
-            }
-            if self.error.is_some() {
-                self.entries.clear();
-                self.last_ref_id = std::u32::MAX;
-                return Some(Err(self.error.take().unwrap()));
-            }
+impl<'a, I: Iterator<Item = io::Result<Record>>> Pileup<'a, I> {
+    /// Returns the next column with all entries, including deletions.
+    fn next_column(&mut self) -> Option<io::Result<PileupColumn>> {
+        if self.error.is_some() {
+            return Some(Err(self.stop()));
         }
 
+        // The minimal position is updated when new records are read, so all records
+        // that start at the minimal position are read.
+        while self.last_ref_id < u32::MAX && (self.last_ref_id, self.last_ref_pos) <= self.min_pos {
+            self.read_next();
+        }
+        if self.error.is_some() {
+            return Some(Err(self.stop()));
+        }
+        let (new_ref_id, new_ref_pos) = self.min_pos;
+
+        // Entries are moved forward, and the new minimal position is calculated at the same time.
+        let mut min_pos = (u32::MAX, u32::MAX);
         let mut entries = Vec::new();
         for i in (0..self.entries.len()).rev() {
             let entry = &mut self.entries[i];
@@ -367,6 +565,7 @@ impl<'a, R: RecordReader> Iterator for Pileup<'a, R> {
                 if !entry.move_forward() {
                     std::mem::drop(entry);
                     self.entries.swap_remove(i);
+                    continue;
                 }
             } else {
                 assert!(
@@ -374,7 +573,9 @@ impl<'a, R: RecordReader> Iterator for Pileup<'a, R> {
                     "Record is to the left of the new pileup position"
                 );
             }
+            min_pos = min(min_pos, (rec_ref_id, entry.ref_pos));
         }
+        self.min_pos = min_pos;
 
         if entries.is_empty() {
             None
@@ -388,6 +589,50 @@ impl<'a, R: RecordReader> Iterator for Pileup<'a, R> {
     }
 }
 
+/// Iterator over [pileup columns](struct.PileupColumn.html) at specific positions.
+/// Can be created using [Pileup::at_positions](struct.Pileup.html#method.at_positions).
+///
+/// Records between the positions are moved forward without constructing pileup columns,
+/// which makes it faster than filtering all columns of a [Pileup](struct.Pileup.html).
+/// ```rust
+/// let mut reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+/// let positions = vec![(0, 1000), (0, 2500), (1, 300)];
+/// for column in bam::Pileup::at_positions(&mut reader, positions).unwrap() {
+///     let column = column.unwrap();
+///     println!("{}:{} {:?}", column.ref_id(), column.ref_pos() + 1, column.base_counts());
+/// }
+/// ```
+pub struct PositionsPileup<'a, I: Iterator<Item = io::Result<Record>>> {
+    pileup: Pileup<'a, I>,
+    positions: std::vec::IntoIter<(u32, u32)>,
+}
+
+impl<'a, R: RecordReader> Iterator for PositionsPileup<'a, R> {
+    type Item = io::Result<PileupColumn>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.pileup.error.is_some() {
+                return self.pileup.next_column();
+            }
+            if self.pileup.entries.is_empty() && self.pileup.last_ref_id == u32::MAX {
+                return None;
+            }
+            let target = self.positions.next()?;
+            self.pileup.skip_to(target);
+            if self.pileup.error.is_some()
+                || self
+                    .pileup
+                    .entries
+                    .iter()
+                    .any(|entry| (entry.record.ref_id() as u32, entry.ref_pos) == target)
+            {
+                return self.pileup.next_column();
+            }
+        }
+    }
+}
+
 /// Pileup column that stores all records that overlap a specific reference position.
 #[derive(Clone)]
 pub struct PileupColumn {
@@ -410,6 +655,15 @@ impl PileupColumn {
         })
     }
 
+    /// Returns an iterator over the names of the records, in the order of the
+    /// [entries](#method.entries). The names are borrowed from the records, so that reads
+    /// covering several positions can be tracked without allocations.
+    pub fn read_names(&self) -> ReadNames<'_> {
+        ReadNames {
+            entries: self.entries.iter(),
+        }
+    }
+
     /// Returns 0-based reference id.
     pub fn ref_id(&self) -> u32 {
         self.ref_id
@@ -419,4 +673,357 @@ impl PileupColumn {
     pub fn ref_pos(&self) -> u32 {
         self.ref_pos
     }
+
+    /// Returns the number of entries with nucleotides A, C, G and T (in this order)
+    /// aligned to the reference position. Deletions, entries without sequence and other nucleotides
+    /// are not counted. For insertions, only the first nucleotide is counted.
+    pub fn base_counts(&self) -> [u32; 4] {
+        let mut counts = [0; 4];
+        for entry in self.entries.iter() {
+            if let Some(index) = entry
+                .sequence()
+                .and_then(|mut seq| seq.next())
+                .and_then(base_index)
+            {
+                counts[index] += 1;
+            }
+        }
+        counts
+    }
+
+    /// Returns the most common nucleotide among the [base counts](#method.base_counts),
+    /// or `None` if no entries, or fewer than `min_depth` entries, have a nucleotide A, C, G or T.
+    /// Ties are broken in the order A, C, G, T, so the result does not depend on the order
+    /// of the entries.
+    pub fn consensus(&self, min_depth: u32) -> Option<u8> {
+        let counts = self.base_counts();
+        let depth: u32 = counts.iter().sum();
+        if depth == 0 || depth < min_depth {
+            return None;
+        }
+        let mut best = 0;
+        for i in 1..4 {
+            if counts[i] > counts[best] {
+                best = i;
+            }
+        }
+        Some(b"ACGT"[best])
+    }
+
+    /// Counts entries by their [alignment type](enum.AlnType.html) and finds the most common
+    /// inserted sequence, see [IndelSummary](struct.IndelSummary.html). Each entry is
+    /// counted once, so insertions are not counted as matches, and reference skips (`N`) are
+    /// counted as deletions.
+    pub fn indel_summary(&self) -> IndelSummary {
+        let mut summary = IndelSummary {
+            matches: 0,
+            insertions: 0,
+            deletions: 0,
+            top_insertion: None,
+        };
+        let mut inserted: HashMap<Vec<u8>, u32> = HashMap::new();
+        for entry in self.entries.iter() {
+            match entry.aln_type() {
+                AlnType::Match => summary.matches += 1,
+                AlnType::Deletion => summary.deletions += 1,
+                AlnType::Insertion(_) => {
+                    summary.insertions += 1;
+                    if let Some(seq) = entry.sequence() {
+                        *inserted.entry(seq.skip(1).collect()).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+        // Ties are broken by the smallest sequence, so that the result does not depend on
+        // the order of the entries.
+        summary.top_insertion = inserted
+            .into_iter()
+            .max_by(|(seq1, count1), (seq2, count2)| count1.cmp(count2).then(seq2.cmp(seq1)));
+        summary
+    }
+}
+
+/// Summary of the alignment types in a [pileup column](struct.PileupColumn.html), created by
+/// [PileupColumn::indel_summary](struct.PileupColumn.html#method.indel_summary).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndelSummary {
+    /// Number of entries with a single aligned base ([AlnType::Match](enum.AlnType.html)).
+    pub matches: u32,
+    /// Number of entries with a base followed by an insertion.
+    pub insertions: u32,
+    /// Number of entries with a deletion or a reference skip at the position.
+    pub deletions: u32,
+    /// The most common inserted sequence (without the preceding aligned base), and the number
+    /// of entries with it. Entries without sequence are not taken into account.
+    /// `None` if there are no insertions with known sequence.
+    pub top_insertion: Option<(Vec<u8>, u32)>,
+}
+
+/// Iterator over record names of a [pileup column](struct.PileupColumn.html).
+/// Created by [PileupColumn::read_names](struct.PileupColumn.html#method.read_names).
+pub struct ReadNames<'a> {
+    entries: std::slice::Iter<'a, PileupEntry>,
+}
+
+impl<'a> Iterator for ReadNames<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next().map(|entry| entry.record.name())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for ReadNames<'a> {}
+
+/// Nucleotides in the order used by [base_counts](struct.PileupColumn.html#method.base_counts).
+pub const BASES: [u8; 4] = *b"ACGT";
+
+fn base_index(nt: u8) -> Option<usize> {
+    match nt {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// Builds a draft consensus sequence from pileup columns, for example, from a
+/// [Pileup](struct.Pileup.html) over a fetched region. Returns the
+/// [consensus](struct.PileupColumn.html#method.consensus) nucleotide of each column, and its depth
+/// (the sum of [base counts](struct.PileupColumn.html#method.base_counts), so deletions are not
+/// counted). Ties are broken in the order A, C, G, T.
+///
+/// Columns with depth below `min_depth` (at least 1) are skipped if `fill_gaps` is `false`.
+/// If `fill_gaps` is `true`, they are written as `N`, and so are the reference positions
+/// without any columns between the first and the last column on the same reference.
+/// In that case, the sequence covers all positions from the first to the last column, and
+/// the depth of positions without columns is 0. Columns on different references are appended
+/// one after another.
+///
+/// Insertions are not added to the consensus, take a look at
+/// [PileupColumn::indel_summary](struct.PileupColumn.html#method.indel_summary) for that.
+///
+/// ```rust
+/// let mut reader = bam::IndexedReader::from_path("in.bam").unwrap();
+/// let mut viewer = reader.fetch(&bam::Region::new(0, 1000, 1500)).unwrap();
+/// let pileup = bam::Pileup::new(&mut viewer);
+/// let (seq, depth) = bam::pileup::region_consensus(pileup, 3, true).unwrap();
+/// println!("{} ({} positions)", String::from_utf8_lossy(&seq), depth.len());
+/// ```
+pub fn region_consensus<C>(
+    pileup: C,
+    min_depth: u32,
+    fill_gaps: bool,
+) -> io::Result<(Vec<u8>, Vec<u32>)>
+where
+    C: IntoIterator<Item = io::Result<PileupColumn>>,
+{
+    let min_depth = std::cmp::max(min_depth, 1);
+    let mut seq = Vec::new();
+    let mut depths = Vec::new();
+    let mut last_pos: Option<(u32, u32)> = None;
+    for column in pileup {
+        let column = column?;
+        let depth = column.base_counts().iter().sum();
+        let nt = column.consensus(min_depth);
+        if fill_gaps {
+            if let Some((ref_id, ref_pos)) = last_pos {
+                if ref_id == column.ref_id() && ref_pos + 1 < column.ref_pos() {
+                    let gap = (column.ref_pos() - ref_pos - 1) as usize;
+                    seq.resize(seq.len() + gap, b'N');
+                    depths.resize(depths.len() + gap, 0);
+                }
+            }
+            last_pos = Some((column.ref_id(), column.ref_pos()));
+            seq.push(nt.unwrap_or(b'N'));
+            depths.push(depth);
+        } else if let Some(nt) = nt {
+            seq.push(nt);
+            depths.push(depth);
+        }
+    }
+    Ok((seq, depths))
+}
+
+/// Iterator over pileup positions where the records disagree with the reference.
+/// Can be created using [Pileup::variant_scan](struct.Pileup.html#method.variant_scan).
+///
+/// Each item is a tuple `(ref_id, ref_pos, ref_nt, base_counts)`, where `ref_nt` is an upper-case
+/// reference nucleotide, and `base_counts` are the counts of A, C, G and T
+/// (see [PileupColumn::base_counts](struct.PileupColumn.html#method.base_counts)).
+/// The depth of a position is the sum of `base_counts`.
+/// ```rust
+/// let mut reader = bam::BamReader::from_path("in.bam", 0).unwrap();
+/// // Reference sequences in the order of the header references.
+/// let references: Vec<Vec<u8>> = load_references();
+/// let scan = bam::Pileup::new(&mut reader).variant_scan(&references, 10, 0.2);
+/// for site in scan {
+///     let (ref_id, ref_pos, ref_nt, counts) = site.unwrap();
+///     println!("{}:{} {} {:?}", ref_id, ref_pos + 1, ref_nt as char, counts);
+/// }
+/// ```
+pub struct VariantScan<'a, I: Iterator<Item = io::Result<Record>>, F> {
+    pileup: Pileup<'a, I>,
+    reference: F,
+    min_depth: u32,
+    min_fraction: f64,
+}
+
+impl<'a, R, F> Iterator for VariantScan<'a, R, F>
+where
+    R: RecordReader,
+    F: ReferenceFetch,
+{
+    type Item = io::Result<(u32, u32, u8, [u32; 4])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let column = match self.pileup.next()? {
+                Ok(column) => column,
+                Err(e) => return Some(Err(e)),
+            };
+            let ref_pos = column.ref_pos();
+            let ref_nt = match self.reference.fetch(column.ref_id(), ref_pos, ref_pos + 1) {
+                Ok(seq) => match seq.first() {
+                    Some(nt) => nt.to_ascii_uppercase(),
+                    None => continue,
+                },
+                Err(e) => return Some(Err(e)),
+            };
+            let ref_index = match base_index(ref_nt) {
+                Some(index) => index,
+                None => continue,
+            };
+
+            let counts = column.base_counts();
+            let depth: u32 = counts.iter().sum();
+            if depth == 0 || depth < self.min_depth {
+                continue;
+            }
+            let alt_count = depth - counts[ref_index];
+            if alt_count > 0 && alt_count as f64 >= self.min_fraction * depth as f64 {
+                return Some(Ok((column.ref_id(), column.ref_pos(), ref_nt, counts)));
+            }
+        }
+    }
+}
+
+/// Alignment of a single record, presented as a row of reference positions. Row-major
+/// counterpart of the [pileup columns](struct.PileupColumn.html), useful for read-centric
+/// visualization. Can be created using [ReadTracks](struct.ReadTracks.html).
+///
+/// Each position is represented by a tuple `(ref_pos, aln_type, base)`, where
+/// [aln_type](enum.AlnType.html) is the same as in [PileupEntry](struct.PileupEntry.html),
+/// and `base` is the record nucleotide aligned to the reference position (`None` for deletions,
+/// reference skips and records without sequence). As in the pileup, insertions are attached to the
+/// preceding matching position, and insertions before the first match or after a deletion are
+/// not reported.
+#[derive(Clone)]
+pub struct ReadTrack {
+    record: Rc<Record>,
+    positions: Vec<(u32, AlnType, Option<u8>)>,
+}
+
+impl ReadTrack {
+    /// Creates a track from a mapped record.
+    pub fn new(record: Rc<Record>) -> Self {
+        let mut positions: Vec<(u32, AlnType, Option<u8>)> = Vec::new();
+        let seq = record.sequence();
+        for (query_pos, ref_pos, _) in record.aligned_triples() {
+            match (query_pos, ref_pos) {
+                (Some(query_pos), Some(ref_pos)) => {
+                    let base = if seq.available() {
+                        Some(seq.at(query_pos as usize))
+                    } else {
+                        None
+                    };
+                    positions.push((ref_pos, AlnType::Match, base));
+                }
+                (None, Some(ref_pos)) => positions.push((ref_pos, AlnType::Deletion, None)),
+                (Some(_), None) => match positions.last_mut() {
+                    Some((_, aln_type @ AlnType::Match, _)) => *aln_type = AlnType::Insertion(1),
+                    Some((_, AlnType::Insertion(len), _)) => *len += 1,
+                    _ => {}
+                },
+                (None, None) => {}
+            }
+        }
+        Self { record, positions }
+    }
+
+    /// Returns a smart pointer to the [record](../record/struct.Record.html).
+    pub fn record(&self) -> &Rc<Record> {
+        &self.record
+    }
+
+    /// Returns all positions of the track, sorted by the reference position.
+    pub fn positions(&self) -> &[(u32, AlnType, Option<u8>)] {
+        &self.positions
+    }
+
+    /// Returns positions of the track within the reference interval `start..end` (0-based,
+    /// half-open), for example, to render the track in a window.
+    pub fn positions_in(&self, start: u32, end: u32) -> &[(u32, AlnType, Option<u8>)] {
+        let first = self.positions.partition_point(|(pos, _, _)| *pos < start);
+        let last = self.positions.partition_point(|(pos, _, _)| *pos < end);
+        &self.positions[first..last.max(first)]
+    }
+}
+
+/// Iterator over [read tracks](struct.ReadTrack.html): each mapped record produces exactly one
+/// track, in the order of the input records.
+/// ```rust
+/// let mut reader = bam::IndexedReader::from_path("in.bam").unwrap();
+/// let mut viewer = reader.fetch(&bam::Region::new(0, 1000, 1100)).unwrap();
+/// for track in bam::pileup::ReadTracks::new(&mut viewer) {
+///     let track = track.unwrap();
+///     let row: String = track.positions_in(1000, 1100).iter()
+///         .map(|(_, _, base)| base.map(|nt| nt as char).unwrap_or('-'))
+///         .collect();
+///     println!("{:?}: {}", track.record().name(), row);
+/// }
+/// ```
+pub struct ReadTracks<'a, I: Iterator<Item = io::Result<Record>>> {
+    record_iter: &'a mut I,
+    read_filter: Box<dyn Fn(&Record) -> bool>,
+}
+
+impl<'a, I: Iterator<Item = io::Result<Record>>> ReadTracks<'a, I> {
+    /// Creates read tracks from an iterator over `io::Result<Record>`.
+    /// Unlike [Pileup](struct.Pileup.html), records do not need to be sorted.
+    pub fn new(record_iter: &'a mut I) -> Self {
+        Self::with_filter(record_iter, |_| true)
+    }
+
+    /// Same as [new](#method.new), but only records that pass `read_filter` produce tracks.
+    pub fn with_filter<F: 'static + Fn(&Record) -> bool>(
+        record_iter: &'a mut I,
+        read_filter: F,
+    ) -> Self {
+        Self {
+            record_iter,
+            read_filter: Box::new(read_filter),
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = io::Result<Record>>> Iterator for ReadTracks<'a, I> {
+    type Item = io::Result<ReadTrack>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.record_iter.next()? {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            if record.flag().is_mapped() && (self.read_filter)(&record) {
+                return Some(Ok(ReadTrack::new(Rc::new(record))));
+            }
+        }
+    }
 }
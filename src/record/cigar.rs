@@ -105,7 +105,7 @@ impl Operation {
         }
     }
 
-    /// Returns `true` if the operation consumes only reference (I or S).
+    /// Returns `true` if the operation consumes only query (I or S).
     pub fn is_insertion(self) -> bool {
         match self {
             Operation::Insertion | Operation::Soft => true,
@@ -113,7 +113,7 @@ impl Operation {
         }
     }
 
-    /// Returns `true` if the operation consumes only query (D or N).
+    /// Returns `true` if the operation consumes only reference (D or N).
     pub fn is_deletion(self) -> bool {
         match self {
             Operation::Deletion | Operation::Skip => true,
@@ -223,7 +223,23 @@ impl Cigar {
         (v >> 4, Operation::from(v & 0xf))
     }
 
-    /// Returns an iterator over typles `(length, operation)`.
+    /// Returns a pair `(length, operation)` by its index. In contrast to [at](#method.at),
+    /// returns `None` if the index is out of bounds or the operation is not recognized.
+    pub fn get(&self, index: usize) -> Option<(u32, Operation)> {
+        let v = *self.0.get(index)?;
+        if v & 0xf > 8 {
+            None
+        } else {
+            Some((v >> 4, Operation::from(v & 0xf)))
+        }
+    }
+
+    /// Returns `true` if all operations in the CIGAR are recognized (have codes 0-8).
+    pub(crate) fn operations_valid(&self) -> bool {
+        self.0.iter().all(|value| value & 0xf <= 8)
+    }
+
+    /// Returns an iterator over tuples `(length, operation)`. Same as `for (len, op) in &cigar`.
     pub fn iter<'a>(&'a self) -> CigarIter<'a> {
         CigarIter {
             parent: self,
@@ -279,6 +295,11 @@ impl Cigar {
         self.0.shrink_to_fit();
     }
 
+    /// Reserves capacity for at least `additional` more operations.
+    pub fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+
     /// Writes to `f` in a human readable format. Write `*` if empty.
     pub fn write_readable<W: Write>(&self, f: &mut W) -> io::Result<()> {
         if self.is_empty() {
@@ -303,6 +324,18 @@ impl Cigar {
         }
     }
 
+    /// Returns aligned triples (considering that reference starts at position `r_pos`).
+    #[doc(hidden)]
+    pub fn aligned_triples(&self, r_pos: u32) -> AlignedTriples<'_> {
+        AlignedTriples {
+            raw_iter: self.0.iter(),
+            q_pos: 0,
+            r_pos,
+            remaining_len: 0,
+            operation: Operation::AlnMatch,
+        }
+    }
+
     /// Returns matching pairs (considering that reference starts at position `r_pos`).
     #[doc(hidden)]
     pub fn matching_pairs(&self, r_pos: u32) -> MatchingPairs {
@@ -352,7 +385,7 @@ impl Cigar {
     }
 }
 
-/// Double-ended iterator over CIGAR operations `(usize, Operation)`.
+/// Double-ended iterator over CIGAR operations `(u32, Operation)`.
 #[derive(Clone)]
 pub struct CigarIter<'a> {
     parent: &'a Cigar,
@@ -393,6 +426,15 @@ impl<'a> ExactSizeIterator for CigarIter<'a> {}
 
 impl<'a> std::iter::FusedIterator for CigarIter<'a> {}
 
+impl<'a> IntoIterator for &'a Cigar {
+    type Item = (u32, Operation);
+    type IntoIter = CigarIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 /// Iterator over pairs `(Option<u32>, Option<u32>)`.
 /// The first element represents a sequence index, and the second element represents a
 /// reference index. If the current operation is an insertion or a deletion, the respective
@@ -437,6 +479,54 @@ impl<'a> Iterator for AlignedPairs<'a> {
 
 impl<'a> std::iter::FusedIterator for AlignedPairs<'a> {}
 
+/// Iterator over triples `(Option<u32>, Option<u32>, Operation)`: a sequence index, a reference
+/// index and the CIGAR operation. Matches produce both indices, insertions produce only
+/// the sequence index, and deletions and reference skips produce only the reference index.
+/// Soft and hard clipping and padding are skipped, although soft clipping still shifts
+/// sequence indices.
+#[derive(Clone)]
+pub struct AlignedTriples<'a> {
+    raw_iter: Iter<'a, u32>,
+    q_pos: u32,
+    r_pos: u32,
+    remaining_len: u32,
+    operation: Operation,
+}
+
+impl<'a> Iterator for AlignedTriples<'a> {
+    type Item = (Option<u32>, Option<u32>, Operation);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use Operation::*;
+
+        while self.remaining_len == 0 {
+            let v = self.raw_iter.next()?;
+            self.operation = Operation::from(v & 0xf);
+            match self.operation {
+                Soft => self.q_pos += v >> 4,
+                Hard | Padding => {}
+                _ => self.remaining_len = v >> 4,
+            }
+        }
+        self.remaining_len -= 1;
+        let q_pos = if self.operation.consumes_query() {
+            self.q_pos += 1;
+            Some(self.q_pos - 1)
+        } else {
+            None
+        };
+        let r_pos = if self.operation.consumes_ref() {
+            self.r_pos += 1;
+            Some(self.r_pos - 1)
+        } else {
+            None
+        };
+        Some((q_pos, r_pos, self.operation))
+    }
+}
+
+impl<'a> std::iter::FusedIterator for AlignedTriples<'a> {}
+
 /// Iterator over pairs `(u32, u32)`.
 /// The first element represents a sequence index, and the second element represents a
 /// reference index. This iterator skips insertions and deletions.
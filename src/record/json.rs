@@ -0,0 +1,180 @@
+//! JSON representation of records. The module is available with the `json` feature.
+
+use std::io::ErrorKind::InvalidData;
+use std::io::{self, Error};
+
+use serde::{Deserialize, Serialize};
+
+use super::{flag_to_string, Record};
+use crate::header::Header;
+
+/// Plain record fields, in the order they are written to JSON.
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    name: String,
+    flag: u16,
+    #[serde(default, skip_deserializing)]
+    flags: Vec<String>,
+    #[serde(default)]
+    ref_name: Option<String>,
+    #[serde(default)]
+    pos: i32,
+    #[serde(default = "default_mapq")]
+    mapq: u8,
+    #[serde(default = "missing")]
+    cigar: String,
+    #[serde(default)]
+    mate_ref_name: Option<String>,
+    #[serde(default)]
+    mate_pos: i32,
+    #[serde(default)]
+    tlen: i32,
+    #[serde(default = "missing")]
+    seq: String,
+    #[serde(default = "missing")]
+    qual: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+fn default_mapq() -> u8 {
+    255
+}
+
+fn missing() -> String {
+    "*".to_string()
+}
+
+/// Converts bytes written by a `write_readable` function into a string.
+fn readable<F: FnOnce(&mut Vec<u8>) -> io::Result<()>>(write: F) -> String {
+    let mut buffer = Vec::new();
+    write(&mut buffer).expect("Writing to a vector should not fail");
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+fn ref_name(ref_id: i32, header: &Header) -> Option<String> {
+    if ref_id < 0 {
+        None
+    } else {
+        header.reference_name(ref_id as u32).map(str::to_string)
+    }
+}
+
+fn invalid(text: &str) -> Error {
+    Error::new(InvalidData, format!("Cannot parse JSON record: {}", text))
+}
+
+/// Converts a reference name into a reference id, `None` means no reference.
+fn find_ref_id(name: Option<&str>, header: &Header) -> io::Result<i32> {
+    match name {
+        None | Some("*") => Ok(-1),
+        Some(name) => header
+            .reference_id(name)
+            .map(|id| id as i32)
+            .ok_or_else(|| invalid(&format!("Reference '{}' is not in the header", name))),
+    }
+}
+
+/// Converts a 1-based position into a 0-based start.
+fn start(key: &str, pos: i32) -> io::Result<i32> {
+    if pos < 0 {
+        Err(invalid(&format!("{} < 0", key)))
+    } else {
+        Ok(pos - 1)
+    }
+}
+
+impl Record {
+    /// Converts the record into a single-line JSON object with the following keys:
+    /// * `name` - record name,
+    /// * `flag` - numeric flag, and `flags` - array of flag names
+    ///   (see [flag_to_string](fn.flag_to_string.html)),
+    /// * `ref_name`, `pos` - reference name (`null` if the record has no reference) and 1-based
+    ///   position, same as *RNAME* and *POS* in SAM,
+    /// * `mapq`, `cigar`,
+    /// * `mate_ref_name`, `mate_pos`, `tlen` - same as *RNEXT*, *PNEXT* and *TLEN*,
+    ///   but `mate_ref_name` always contains the name,
+    /// * `seq`, `qual` - sequence and qualities in SAM format (`"*"` if absent),
+    /// * `tags` - array of tags in SAM format, for example `["NM:i:1", "MD:Z:10A3"]`.
+    ///
+    /// The record can be parsed back using [from_json](#method.from_json).
+    pub fn to_json(&self, header: &Header) -> String {
+        let json = JsonRecord {
+            name: String::from_utf8_lossy(&self.name).into_owned(),
+            flag: self.flag.0,
+            flags: flag_to_string(self.flag.0)
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+            ref_name: ref_name(self.ref_id, header),
+            pos: self.start + 1,
+            mapq: self.mapq,
+            cigar: readable(|buffer| self.cigar.write_readable(buffer)),
+            mate_ref_name: ref_name(self.mate_ref_id, header),
+            mate_pos: self.mate_start + 1,
+            tlen: self.template_len,
+            seq: readable(|buffer| self.seq.write_readable(buffer)),
+            qual: readable(|buffer| self.qual.write_readable(buffer)),
+            tags: self
+                .tags
+                .iter()
+                .map(|(name, value)| {
+                    readable(|buffer| {
+                        buffer.extend_from_slice(&[name[0], name[1], b':']);
+                        value.write_sam(buffer)
+                    })
+                })
+                .collect(),
+        };
+        serde_json::to_string(&json).expect("Converting a record to JSON should not fail")
+    }
+
+    /// Parses a record from a JSON object, produced by [to_json](#method.to_json).
+    ///
+    /// Keys `name` and `flag` are required. Missing `ref_name` and `mate_ref_name` mean no
+    /// reference, missing `pos`, `mate_pos` and `tlen` are 0, missing `mapq` is 255, and missing
+    /// `cigar`, `seq` and `qual` are `"*"`. Key `flags` as well as unknown keys are ignored.
+    /// The record is checked in the same way as a SAM line
+    /// (see [fill_from_sam](#method.fill_from_sam)), and additionally, the name and the tags
+    /// should not contain tabs, so that the record can be written to SAM.
+    pub fn from_json(text: &str, header: &Header) -> io::Result<Record> {
+        let json: JsonRecord = serde_json::from_str(text).map_err(|e| invalid(&e.to_string()))?;
+        if json.name.contains('\t') || json.tags.iter().any(|tag| tag.contains('\t')) {
+            return Err(invalid("name and tags should not contain tabs"));
+        }
+
+        let mut record = Record::new();
+        record.set_name(json.name.bytes());
+        record.set_flag(json.flag);
+        let ref_id = find_ref_id(json.ref_name.as_deref(), header)?;
+        record.set_ref_id(ref_id);
+        record.set_start(start("pos", json.pos)?);
+        record.set_mapq(json.mapq);
+        if json.cigar != "*" {
+            record.set_cigar(json.cigar.bytes()).map_err(|e| invalid(&e))?;
+        }
+        if record.flag().is_mapped() && record.cigar.is_empty() {
+            return Err(invalid("Mapped read has an empty CIGAR"));
+        }
+        match json.mate_ref_name.as_deref() {
+            Some("=") => record.set_mate_ref_id(ref_id),
+            name => record.set_mate_ref_id(find_ref_id(name, header)?),
+        }
+        record.set_mate_start(start("mate_pos", json.mate_pos)?);
+        record.set_template_len(json.tlen);
+
+        let seq = if json.seq == "*" { "" } else { &json.seq };
+        let qual = if json.qual == "*" { "" } else { &json.qual };
+        if qual.bytes().any(|q| q < 33) {
+            return Err(invalid("Qualities should contain characters from '!' onwards"));
+        }
+        record
+            .set_seq_qual(seq.bytes(), qual.bytes().map(|q| q - 33))
+            .map_err(|e| invalid(&e))?;
+        for tag in json.tags.iter() {
+            record.tags.push_sam(tag)?;
+        }
+        Ok(record)
+    }
+}
@@ -9,6 +9,8 @@ use std::str::from_utf8;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 pub mod cigar;
+#[cfg(feature = "json")]
+mod json;
 pub mod sequence;
 pub mod tags;
 
@@ -244,6 +246,88 @@ impl fmt::Display for Flag {
     }
 }
 
+/// Flag bits and their names, as used by `samtools flags`.
+const FLAG_NAMES: [(u16, &str); 12] = [
+    (RECORD_PAIRED, "PAIRED"),
+    (ALL_SEGMENTS_ALIGNED, "PROPER_PAIR"),
+    (RECORD_UNMAPPED, "UNMAP"),
+    (MATE_UNMAPPED, "MUNMAP"),
+    (RECORD_REVERSE_STRAND, "REVERSE"),
+    (MATE_REVERSE_STRAND, "MREVERSE"),
+    (FIRST_IN_PAIR, "READ1"),
+    (LAST_IN_PAIR, "READ2"),
+    (SECONDARY, "SECONDARY"),
+    (RECORD_FAILS_QC, "QCFAIL"),
+    (PCR_OR_OPTICAL_DUPLICATE, "DUP"),
+    (SUPPLEMENTARY, "SUPPLEMENTARY"),
+];
+
+/// Converts the flag into a comma-separated list of names, same as `samtools flags`,
+/// for example `99` is converted into `"PAIRED,PROPER_PAIR,MREVERSE,READ1"`.
+/// Bits without a name are written as a single hexadecimal number at the end,
+/// and the flag 0 is converted into an empty string.
+///
+/// The string can be parsed back using [flag_from_string](fn.flag_from_string.html).
+pub fn flag_to_string(flag: u16) -> String {
+    let mut names: Vec<String> = FLAG_NAMES
+        .iter()
+        .filter(|(bit, _)| flag & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let unnamed = flag & 0xf000;
+    if unnamed != 0 {
+        names.push(format!("{:#x}", unnamed));
+    }
+    names.join(",")
+}
+
+/// Parses a comma-separated list of flag names (see [flag_to_string](fn.flag_to_string.html)).
+/// Names are case-insensitive, and the list can also contain decimal or hexadecimal (starting
+/// with `0x`) numbers, so both `"PAIRED,READ1"` and `"0x41"` are parsed into 65.
+pub fn flag_from_string(text: &str) -> Result<u16, String> {
+    let mut flag = 0;
+    for item in text
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        let value = if let Some(hex) = item.strip_prefix("0x").or_else(|| item.strip_prefix("0X")) {
+            u16::from_str_radix(hex, 16).ok()
+        } else if item.bytes().all(|b| b.is_ascii_digit()) {
+            item.parse().ok()
+        } else {
+            FLAG_NAMES
+                .iter()
+                .find(|(_, name)| name.eq_ignore_ascii_case(item))
+                .map(|(bit, _)| *bit)
+        };
+        flag |= value.ok_or_else(|| format!("Cannot parse flag: unexpected value {:?}", item))?;
+    }
+    Ok(flag)
+}
+
+/// Reads the 4-byte block size that precedes each BAM record. Returns `None` if the stream
+/// ends before the first byte, and an `UnexpectedEof` error if it ends inside the block size.
+pub(crate) fn read_block_size<R: Read>(stream: &mut R) -> io::Result<Option<[u8; 4]>> {
+    let mut buf = [0_u8; 4];
+    let mut len = 0;
+    while len < 4 {
+        match stream.read(&mut buf[len..]) {
+            Ok(0) if len == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    UnexpectedEof,
+                    "Corrupted record: unexpected end of file inside the block size",
+                ))
+            }
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(buf))
+}
+
 pub(crate) unsafe fn resize<T>(v: &mut Vec<T>, new_len: usize) {
     if v.capacity() < new_len {
         v.reserve(new_len - v.len());
@@ -287,6 +371,138 @@ impl<'a> NextToErr<'a> for std::str::Split<'a, char> {
     }
 }
 
+/// Returns the length of a read after BWA-like quality trimming of its 3' end.
+/// Qualities are in ASCII format (with +33).
+fn trimmed_len(qual: &[u8], min_qual: u8) -> usize {
+    let mut sum = 0_i32;
+    let mut max_sum = 0_i32;
+    let mut len = qual.len();
+    for (i, &q) in qual.iter().enumerate().rev() {
+        sum += i32::from(min_qual) - i32::from(q.saturating_sub(33));
+        if sum < 0 {
+            break;
+        }
+        if sum > max_sum {
+            max_sum = sum;
+            len = i;
+        }
+    }
+    len
+}
+
+/// A source of reference sequences, used by
+/// [Record::count_mismatches](struct.Record.html#method.count_mismatches) and
+/// [Pileup::variant_scan](../pileup/struct.Pileup.html#method.variant_scan).
+///
+/// The trait is implemented for `Vec<T>`, where `T: AsRef<[u8]>` is the sequence of
+/// the reference with the corresponding id, for example for `Vec<Vec<u8>>` or `Vec<String>`,
+/// and for references to other implementations.
+pub trait ReferenceFetch {
+    /// Returns bases `start..end` (0-based, half-open) of the reference sequence `ref_id`
+    /// as ASCII letters. Returns an error if the reference is unknown or is too short.
+    fn fetch(&self, ref_id: u32, start: u32, end: u32) -> io::Result<Vec<u8>>;
+}
+
+impl<T: ReferenceFetch + ?Sized> ReferenceFetch for &T {
+    fn fetch(&self, ref_id: u32, start: u32, end: u32) -> io::Result<Vec<u8>> {
+        (**self).fetch(ref_id, start, end)
+    }
+}
+
+impl<T: AsRef<[u8]>> ReferenceFetch for Vec<T> {
+    fn fetch(&self, ref_id: u32, start: u32, end: u32) -> io::Result<Vec<u8>> {
+        let seq = self.get(ref_id as usize).map(AsRef::as_ref).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Reference sequence {} is not available", ref_id),
+            )
+        })?;
+        seq.get(start as usize..end as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Region {}-{} is out of bounds for reference sequence {} (length {})",
+                        start,
+                        end,
+                        ref_id,
+                        seq.len()
+                    ),
+                )
+            })
+    }
+}
+
+/// Checks if a nucleotide is anything other than A, C, G or T (case-insensitive).
+fn is_ambiguous(nt: u8) -> bool {
+    !matches!(nt.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T')
+}
+
+/// A single alignment from the `SA` tag, returned by
+/// [Record::supplementary_alignments](struct.Record.html#method.supplementary_alignments).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupplementaryAlignment {
+    /// Reference name.
+    pub ref_name: String,
+    /// 0-based start of the alignment (the `SA` tag stores a 1-based position).
+    pub start: i32,
+    /// `true` if the alignment is on the reverse strand.
+    pub is_reverse_strand: bool,
+    /// CIGAR in the SAM format.
+    pub cigar: String,
+    /// Mapping quality.
+    pub mapq: u8,
+    /// Edit distance (number of mismatches and gaps).
+    pub edit_distance: u32,
+}
+
+impl SupplementaryAlignment {
+    /// Parses a single `SA` entry `rname,pos,strand,CIGAR,mapQ,NM`.
+    fn parse(entry: &[u8]) -> Option<Self> {
+        let entry = from_utf8(entry).ok()?;
+        let fields: Vec<_> = entry.split(',').collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        let pos: i32 = fields[1].parse().ok()?;
+        if pos < 1 {
+            return None;
+        }
+        let is_reverse_strand = match fields[2] {
+            "+" => false,
+            "-" => true,
+            _ => return None,
+        };
+        Some(Self {
+            ref_name: fields[0].to_string(),
+            start: pos - 1,
+            is_reverse_strand,
+            cigar: fields[3].to_string(),
+            mapq: fields[4].parse().ok()?,
+            edit_distance: fields[5].parse().ok()?,
+        })
+    }
+}
+
+/// Relative orientation of two mates in a pair, returned by
+/// [Record::pair_orientation](struct.Record.html#method.pair_orientation).
+///
+/// # Variants
+/// * `FR` - the leftmost mate is on the forward strand, and the rightmost mate is on the reverse
+///   strand (mates point towards each other),
+/// * `RF` - the leftmost mate is on the reverse strand, and the rightmost mate is on the forward
+///   strand (mates point away from each other),
+/// * `FF` - both mates are on the forward strand (tandem),
+/// * `RR` - both mates are on the reverse strand (tandem).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairOrientation {
+    FR,
+    RF,
+    FF,
+    RR,
+}
+
 /// BAM Record.
 ///
 /// Allows to get and set name, [sequence](sequence/struct.Sequence.html),
@@ -316,6 +532,12 @@ pub struct Record {
     seq: Sequence,
     qual: Qualities,
     tags: tags::TagViewer,
+    // Virtual offset of the record in the BAM file, if it was saved by the reader.
+    source_offset: Option<index::VirtualOffset>,
+    // The BAM block the record was read from, including the block size. Only saved if enabled
+    // in the reader, and only valid while `pristine` is true.
+    raw: Vec<u8>,
+    pristine: bool,
 }
 
 const BIN_UNKNOWN: u16 = std::u16::MAX;
@@ -340,9 +562,29 @@ impl Record {
             seq: Sequence::new(),
             qual: Qualities::new(),
             tags: tags::TagViewer::new(),
+            source_offset: None,
+            raw: Vec::new(),
+            pristine: false,
         }
     }
 
+    /// Creates an empty record with preallocated inner vectors. This is useful when reading long
+    /// records (for example, ONT or PacBio reads), as the first
+    /// [read_into](../trait.RecordReader.html#method.read_into) calls would not need to reallocate.
+    ///
+    /// # Arguments
+    /// * seq_cap - number of nucleotides, reserved for both sequence and qualities,
+    /// * cigar_cap - number of CIGAR operations,
+    /// * aux_cap - number of bytes, reserved for tags in raw (BAM) format.
+    pub fn with_capacity(seq_cap: usize, cigar_cap: usize, aux_cap: usize) -> Record {
+        let mut record = Record::new();
+        record.seq.reserve(seq_cap);
+        record.qual.reserve(seq_cap);
+        record.cigar.reserve(cigar_cap);
+        record.tags.reserve(aux_cap);
+        record
+    }
+
     /// Clears the record.
     pub fn clear(&mut self) {
         self.ref_id = -1;
@@ -360,6 +602,9 @@ impl Record {
         self.seq.clear();
         self.qual.clear();
         self.tags.clear();
+        self.source_offset = None;
+        self.raw.clear();
+        self.pristine = false;
     }
 
     fn corrupt(&mut self, text: &str) -> io::Error {
@@ -380,21 +625,18 @@ impl Record {
     /// Fills the record from a `stream` of uncompressed BAM contents.
     /// Returns `false`, if the file ended and the record was not read.
     pub(crate) fn fill_from_bam<R: Read>(&mut self, stream: &mut R) -> io::Result<bool> {
+        self.pristine = false;
         self.name.clear();
-        let block_size = match stream.read_i32::<LittleEndian>() {
-            Ok(value) => {
+        self.source_offset = None;
+        let block_size = match read_block_size(stream)? {
+            Some(size) => {
+                let value = i32::from_le_bytes(size);
                 if value < 0 {
                     return Err(self.corrupt("Negative block size"));
                 }
                 value as usize
             }
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    return Ok(false);
-                } else {
-                    return Err(e);
-                }
-            }
+            None => return Ok(false),
         };
 
         let ref_id = stream.read_i32::<LittleEndian>()?;
@@ -441,6 +683,14 @@ impl Record {
         self.set_mate_start(mate_start);
         self.set_template_len(stream.read_i32::<LittleEndian>()?);
 
+        let seq_len = qual_len.div_ceil(2);
+        let remaining_size = match block_size.checked_sub(
+            32 + name_len as usize + 4 * cigar_len as usize + seq_len + qual_len,
+        ) {
+            Some(value) => value,
+            None => return Err(self.corrupt("Block size is smaller than record fields")),
+        };
+
         unsafe {
             resize(&mut self.name, name_len as usize - 1);
         }
@@ -448,19 +698,76 @@ impl Record {
         let _null_symbol = stream.read_u8()?;
 
         self.cigar.fill_from(stream, cigar_len as usize)?;
+        if !self.cigar.operations_valid() {
+            return Err(self.corrupt("Unexpected CIGAR operation"));
+        }
         self.seq.fill_from(stream, qual_len)?;
         self.qual.fill_from(stream, qual_len)?;
-
-        let seq_len = (qual_len + 1) / 2;
-        let remaining_size =
-            block_size - 32 - name_len as usize - 4 * cigar_len as usize - seq_len - qual_len;
         self.tags.fill_from(stream, remaining_size)?;
         self.replace_cigar_if_needed()?;
         Ok(true)
     }
 
+    /// Same as [fill_from_bam](#method.fill_from_bam), but also stores the whole BAM block,
+    /// so that the record can be written without encoding while it is
+    /// [pristine](#method.is_pristine).
+    pub(crate) fn fill_from_bam_keep_raw<R: Read>(&mut self, stream: &mut R) -> io::Result<bool> {
+        self.pristine = false;
+        let mut raw = std::mem::take(&mut self.raw);
+        raw.clear();
+        let size = match read_block_size(stream)? {
+            Some(size) => size,
+            None => {
+                self.raw = raw;
+                return Ok(false);
+            }
+        };
+        let block_size = i32::from_le_bytes(size);
+        if block_size < 0 {
+            return Err(self.corrupt("Negative block size"));
+        }
+        raw.extend_from_slice(&size);
+        stream.take(block_size as u64).read_to_end(&mut raw)?;
+        if raw.len() != 4 + block_size as usize {
+            return Err(io::Error::new(
+                UnexpectedEof,
+                "Corrupted record: unexpected end of file",
+            ));
+        }
+
+        let res = self.fill_from_bam(&mut &raw[..]);
+        self.raw = raw;
+        if let Ok(true) = res {
+            self.pristine = true;
+        }
+        res
+    }
+
+    /// Returns `true` if the record was read from a BAM file with the raw block saved (see
+    /// [BamReader::keep_raw](../bam_reader/struct.BamReader.html#method.keep_raw) and
+    /// [IndexedReaderBuilder::keep_raw](../bam_reader/struct.IndexedReaderBuilder.html#method.keep_raw)),
+    /// and was not modified since. A [BamWriter](../bam_writer/struct.BamWriter.html) copies
+    /// pristine records directly, without encoding them again.
+    ///
+    /// Any function that can modify the record, including [flag_mut](#method.flag_mut) and
+    /// [tags_mut](#method.tags_mut), makes the record not pristine, even if nothing was
+    /// actually changed.
+    pub fn is_pristine(&self) -> bool {
+        self.pristine
+    }
+
+    /// Returns the saved BAM block if the record is [pristine](#method.is_pristine).
+    pub(crate) fn pristine_block(&self) -> Option<&[u8]> {
+        if self.pristine {
+            Some(&self.raw)
+        } else {
+            None
+        }
+    }
+
     /// Fills the record from SAM. If an error is return, the record may be corrupted.
     pub fn fill_from_sam(&mut self, line: &str, header: &Header) -> io::Result<()> {
+        self.pristine = false;
         let mut split = line.split('\t');
         self.set_name(split.try_next("record name (QNAME)")?.bytes());
         let flag = split.try_next("flag")?;
@@ -556,16 +863,12 @@ impl Record {
 
     /// Replace Cigar by CG tag if Cigar has placeholder *kSmN*.
     fn replace_cigar_if_needed(&mut self) -> io::Result<()> {
-        if !self.cigar.is_empty()
+        // Other CIGARs starting with kS (for example, a fully soft-clipped read) are regular CIGARs.
+        if self.cigar.len() == 2
             && self.cigar.at(0) == (self.seq.len() as u32, cigar::Operation::Soft)
+            && self.cigar.at(1).1 == cigar::Operation::Skip
         {
-            if self.cigar.len() != 2 {
-                return Err(self.corrupt("Record contains invalid Cigar"));
-            }
-            let (len, op) = self.cigar.at(1);
-            if op != cigar::Operation::Skip {
-                return Err(self.corrupt("Record contains invalid Cigar"));
-            }
+            let (len, _) = self.cigar.at(1);
             self.end.set(self.start + len as i32);
 
             let cigar_arr = match self.tags.get(b"CG") {
@@ -602,6 +905,20 @@ impl Record {
         &self.name
     }
 
+    /// Returns record name without a pair suffix (`/1`, `/2`, `.1` or `.2`), if present.
+    /// Other dots in the name are kept, for example `a.b.10` stays unchanged. Use it to match
+    /// mates with different suffixes, [name](#method.name) still returns the original name.
+    pub fn template_name(&self) -> &[u8] {
+        let len = self.name.len();
+        if len > 2 {
+            match &self.name[len - 2..] {
+                b"/1" | b"/2" | b".1" | b".2" => return &self.name[..len - 2],
+                _ => {}
+            }
+        }
+        &self.name
+    }
+
     /// Returns record sequence. You can check if sequence is present in the record using
     /// [sequence().available()](sequence/struct.Sequence.html#method.available).
     pub fn sequence(&self) -> &Sequence {
@@ -614,6 +931,14 @@ impl Record {
         &self.qual
     }
 
+    /// Caps base qualities at `max` (without +33), see
+    /// [Qualities::cap](sequence/struct.Qualities.html#method.cap). Missing qualities stay missing.
+    pub fn cap_qualities(&mut self, max: u8) {
+        if self.qual.cap(max) {
+            self.pristine = false;
+        }
+    }
+
     /// Returns record CIGAR (can be empty).
     pub fn cigar(&self) -> &Cigar {
         &self.cigar
@@ -624,6 +949,16 @@ impl Record {
         self.ref_id
     }
 
+    /// Returns the name of the record reference, using the `header`.
+    /// Returns `None` if the reference id is -1 or is not in the header.
+    pub fn ref_name<'h>(&self, header: &'h Header) -> Option<&'h str> {
+        if self.ref_id < 0 {
+            None
+        } else {
+            header.reference_name(self.ref_id as u32)
+        }
+    }
+
     /// Returns 0-based left-most aligned reference position. Same as *POS - 1* in SAM specification.
     /// Returns -1 for unmapped records.
     pub fn start(&self) -> i32 {
@@ -635,10 +970,15 @@ impl Record {
     /// Consecutive calculations take O(1).
     /// If the record was fetched from a specific region, it should have `end` already calculated.
     ///
-    /// Returns zero for unmapped records.
+    /// A mapped record without reference-consuming Cigar operations (for example `50S`, `10I`
+    /// or an empty Cigar) has a zero-length span, and the function returns `start`.
+    /// For overlap checks, such records are treated as covering the single position `start`,
+    /// same as for the [BAI bin](#method.expected_bin).
+    ///
+    /// Returns zero for unmapped records without Cigar.
     pub fn calculate_end(&self) -> i32 {
         if self.cigar.is_empty() {
-            return 0;
+            return if self.flag.is_mapped() { self.start } else { 0 };
         }
 
         let end = self.end.get();
@@ -651,6 +991,34 @@ impl Record {
         end
     }
 
+    /// Returns 1-based left-most aligned reference position, same as *POS* in SAM output.
+    /// Returns 0 for unmapped records without a position.
+    pub fn pos1(&self) -> i64 {
+        self.start as i64 + 1
+    }
+
+    /// Returns 1-based inclusive end of the alignment, so the record covers reference positions
+    /// `pos1..=end1` (1-based). Returns 0 for unmapped records.
+    ///
+    /// Numerically equal to [calculate_end](#method.calculate_end) (0-based exclusive end).
+    pub fn end1(&self) -> i64 {
+        if self.flag.is_mapped() {
+            self.calculate_end() as i64
+        } else {
+            0
+        }
+    }
+
+    /// Returns the number of reference positions covered by the alignment
+    /// (sum of `M`, `=`, `X`, `D` and `N` operation lengths). Returns zero for unmapped records.
+    pub fn reference_span(&self) -> u32 {
+        if self.flag.is_mapped() {
+            self.cigar.calculate_ref_len()
+        } else {
+            0
+        }
+    }
+
     /// Returns query length. The function returns the length of the sequence if it is present.
     /// Otherwise, the function returns the length calculated from the CIGAR.
     /// Unmapped records without sequence would get length 0.
@@ -680,6 +1048,182 @@ impl Record {
         }
     }
 
+    /// Returns a pair `(five_prime, three_prime)`, which shows if the alignment is clipped
+    /// (soft or hard clipping) at the 5' and 3' ends of the read. The ends are taken in the read
+    /// orientation: for a forward strand record the 5' end is the left-most (smallest reference
+    /// coordinate) end of the alignment, and for a reverse strand record, it is the right-most end.
+    ///
+    /// Returns `(false, false)` for unmapped records.
+    pub fn clip_reference_ends(&self) -> (bool, bool) {
+        if !self.flag.is_mapped() {
+            return (false, false);
+        }
+        let clipped =
+            |left_side| self.cigar.soft_clipping(left_side) + self.cigar.hard_clipping(left_side) > 0;
+        let (left, right) = (clipped(true), clipped(false));
+        if self.flag.is_reverse_strand() {
+            (right, left)
+        } else {
+            (left, right)
+        }
+    }
+
+    /// Returns the fraction of the query aligned to the reference: the number of bases in `M`,
+    /// `=`, `X` and `I` operations, divided by the full query length, which includes soft and hard
+    /// clipping. Values well below 1.0 indicate heavily clipped, partial alignments.
+    ///
+    /// Returns 0.0 for unmapped records and for records with empty query.
+    pub fn query_aligned_fraction(&self) -> f64 {
+        if !self.flag.is_mapped() {
+            return 0.0;
+        }
+        let full_len =
+            self.query_len() + self.cigar.hard_clipping(true) + self.cigar.hard_clipping(false);
+        if full_len == 0 {
+            return 0.0;
+        }
+        let aligned: u32 = self
+            .cigar
+            .iter()
+            .filter(|(_len, op)| op.consumes_query() && *op != cigar::Operation::Soft)
+            .map(|(len, _op)| len)
+            .sum();
+        f64::from(aligned) / f64::from(full_len)
+    }
+
+    /// Compares two records field by field: name, flag, reference ids and positions of the record
+    /// and its mate, MAPQ, template length, CIGAR, sequence, qualities, and the tags.
+    /// Tags are compared as a set, so the records, that differ only in the order of tags,
+    /// are equal. Tag values are compared exactly, including integer types (for example,
+    /// `NM:i:1` stored as `i8` and as `i32` are considered different).
+    pub fn content_eq(&self, other: &Record) -> bool {
+        self.name == other.name
+            && self.flag.0 == other.flag.0
+            && self.ref_id == other.ref_id
+            && self.start == other.start
+            && self.mate_ref_id == other.mate_ref_id
+            && self.mate_start == other.mate_start
+            && self.mapq == other.mapq
+            && self.template_len == other.template_len
+            && self.cigar.raw() == other.cigar.raw()
+            && self.seq.len() == other.seq.len()
+            && self.seq.raw() == other.seq.raw()
+            && self.qual.raw() == other.qual.raw()
+            && self.tags.eq_unordered(&other.tags)
+    }
+
+    /// Returns `true` if the record overlaps the interval `ref_id:start-end` (0-based half-open).
+    /// The function uses the same rules as
+    /// [IndexedReader::fetch](../bam_reader/struct.IndexedReader.html#method.fetch):
+    /// a mapped record overlaps the interval if it starts before `end` and its
+    /// [alignment end](#method.calculate_end) is after `start`, so records that start before
+    /// the interval are included. Mapped records with a zero-length span are treated as covering
+    /// their start position. Unmapped records with a position overlap the interval if they
+    /// start within it.
+    ///
+    /// As in `fetch`, `end = u32::MAX` means "until the end of the reference".
+    pub fn overlaps(&self, ref_id: u32, start: u32, end: u32) -> bool {
+        // Record positions fit in i32, so larger values behave the same as i32::MAX.
+        let clamp = |pos: u32| std::cmp::min(pos, i32::MAX as u32) as i32;
+        self.ref_id >= 0
+            && self.ref_id as u32 == ref_id
+            && self.overlaps_positions(clamp(start), clamp(end))
+    }
+
+    /// Checks if the record overlaps `[start, end)` on its reference.
+    pub(crate) fn overlaps_positions(&self, start: i32, end: i32) -> bool {
+        if self.start >= end {
+            false
+        } else if self.flag.is_mapped() {
+            std::cmp::max(self.calculate_end(), self.start + 1) > start
+        } else {
+            self.start >= start
+        }
+    }
+
+    /// Returns the part of the read covered by the record in the original read orientation:
+    /// (start of the part, full read length, sequence of the part).
+    fn query_part(&self) -> Option<(usize, usize, Vec<u8>)> {
+        if !self.flag.is_mapped() || !self.seq.available() {
+            return None;
+        }
+        let left = self.cigar.hard_clipping(true) as usize;
+        let right = self.cigar.hard_clipping(false) as usize;
+        let full_len = left + self.seq.len() + right;
+        if self.flag.is_reverse_strand() {
+            Some((right, full_len, self.seq.rev_compl(..).collect()))
+        } else {
+            Some((left, full_len, self.seq.to_vec()))
+        }
+    }
+
+    /// Reconstructs the complete read sequence in the original read orientation from a
+    /// hard-clipped `primary` alignment and this supplementary alignment, or vice versa.
+    ///
+    /// The [supplementary alignments](#method.supplementary_alignments) of this record should
+    /// contain an entry with the reference name (taken from `header`), the position and the
+    /// strand of `primary`, and both records should have the same name. The records are placed
+    /// in the original read using their hard clipping, and together they should cover the whole
+    /// read. Otherwise, or if any of the records is unmapped or has no sequence, the function
+    /// returns `None`. If the records overlap, the sequence of `primary` is used.
+    pub fn full_query_from_sa(&self, primary: &Record, header: &Header) -> Option<Vec<u8>> {
+        if self.name() != primary.name() || !primary.flag.is_mapped() {
+            return None;
+        }
+        let primary_ref = primary.ref_name(header)?;
+        let found = self.supplementary_alignments()?.iter().any(|alignment| {
+            alignment.ref_name == primary_ref
+                && alignment.start == primary.start()
+                && alignment.is_reverse_strand == primary.flag.is_reverse_strand()
+        });
+        if !found {
+            return None;
+        }
+
+        let (start, full_len, query) = self.query_part()?;
+        let (primary_start, primary_full_len, primary_query) = primary.query_part()?;
+        if full_len != primary_full_len {
+            return None;
+        }
+        let mut res = vec![None; full_len];
+        for (i, &nt) in query.iter().enumerate() {
+            res[start + i] = Some(nt);
+        }
+        for (i, &nt) in primary_query.iter().enumerate() {
+            res[primary_start + i] = Some(nt);
+        }
+        res.into_iter().collect()
+    }
+
+    /// Returns `true` if the record has an `SA` tag (other alignments of a chimeric read)
+    /// with a string value. In that case,
+    /// [supplementary_alignments](#method.supplementary_alignments) returns `Some`.
+    pub fn is_split_read(&self) -> bool {
+        matches!(self.tags.get(b"SA"), Some(tags::TagValue::String(_, _)))
+    }
+
+    /// Returns `true` if the record is a part of a chimeric alignment: the record is
+    /// [split](#method.is_split_read) or is marked as supplementary (flag `0x800`).
+    pub fn is_chimeric(&self) -> bool {
+        self.flag.is_supplementary() || self.is_split_read()
+    }
+
+    /// Parses the `SA` tag: semicolon-separated entries `rname,pos,strand,CIGAR,mapQ,NM`.
+    /// Returns `None` if the record has no `SA` tag, or the tag is not a string
+    /// (see [is_split_read](#method.is_split_read)). Malformed entries are skipped.
+    pub fn supplementary_alignments(&self) -> Option<Vec<SupplementaryAlignment>> {
+        let sa = match self.tags.get(b"SA")? {
+            tags::TagValue::String(value, _) => value,
+            _ => return None,
+        };
+        Some(
+            sa.split(|&c| c == b';')
+                .filter(|entry| !entry.is_empty())
+                .filter_map(SupplementaryAlignment::parse)
+                .collect(),
+        )
+    }
+
     /// Returns BAI bin. If the bin is unknown and the end has not been calculated,
     /// the bin will be calculated in `O(n_cigar)`, otherwise `O(1)`.
     ///
@@ -689,12 +1233,32 @@ impl Record {
         if bin != BIN_UNKNOWN {
             return bin;
         }
-        let end = self.calculate_end();
-        let bin = index::region_to_bin(self.start, end) as u16;
+        let bin = self.expected_bin();
         self.bin.set(bin);
         bin
     }
 
+    /// Returns BAI bin stored in the record: either the bin read from the BAM file, or the bin
+    /// saved by [calculate_bin](#method.calculate_bin). Returns `None` if the bin is unknown
+    /// (for example, for records read from SAM files or modified after reading).
+    pub fn bin(&self) -> Option<u16> {
+        match self.bin.get() {
+            BIN_UNKNOWN => None,
+            bin => Some(bin),
+        }
+    }
+
+    /// Calculates BAI bin from the record start and end, ignoring the stored [bin](#method.bin).
+    /// Records without aligned bases are treated as if they cover one base.
+    ///
+    /// A stored bin, that is different from the expected bin, is a sign of a corrupted file,
+    /// and would make the record invisible for some regions fetched by the
+    /// [IndexedReader](../bam_reader/struct.IndexedReader.html).
+    pub fn expected_bin(&self) -> u16 {
+        let end = std::cmp::max(self.calculate_end(), self.start + 1);
+        index::region_to_bin(self.start, end) as u16
+    }
+
     /// Returns record MAPQ.
     pub fn mapq(&self) -> u8 {
         self.mapq
@@ -706,6 +1270,16 @@ impl Record {
         self.mate_ref_id
     }
 
+    /// Returns the name of the pair record reference, using the `header`.
+    /// Returns `None` if the mate reference id is -1 or is not in the header.
+    pub fn mate_ref_name<'h>(&self, header: &'h Header) -> Option<&'h str> {
+        if self.mate_ref_id < 0 {
+            None
+        } else {
+            header.reference_name(self.mate_ref_id as u32)
+        }
+    }
+
     /// Returns 0-based left-most aligned reference position for the pair record.
     /// Same as *PNEXT - 1* in SAM specification.
     /// Returns -1 for unmapped records and records without a pair.
@@ -718,6 +1292,60 @@ impl Record {
         self.template_len
     }
 
+    /// Returns the 0-based midpoint of the sequenced fragment: `start + TLEN / 2`.
+    ///
+    /// The midpoint is defined only for the leftmost record in a properly aligned pair
+    /// (flags `0x1` and `0x2`) with non-zero template length, so each fragment is counted once,
+    /// and the function returns `None` for the rightmost mate and for all other records.
+    /// A record is the leftmost mate if its template length is positive, or, if both mates start
+    /// at the same position, if it is the first in pair (flag `0x40`), regardless of the sign.
+    pub fn fragment_midpoint(&self) -> Option<i64> {
+        let flag = self.flag();
+        if !flag.is_paired() || !flag.all_segments_aligned() || self.template_len == 0 {
+            return None;
+        }
+        let leftmost = if self.start == self.mate_start {
+            flag.first_in_pair()
+        } else {
+            self.template_len > 0
+        };
+        if leftmost {
+            Some(i64::from(self.start) + i64::from(self.template_len.abs() / 2))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the [relative orientation](enum.PairOrientation.html) of the record and its mate,
+    /// based on the strand flags and on the leftmost aligned positions of both mates.
+    /// The function returns the same value for both records in a pair.
+    ///
+    /// Returns `None` if the record is not paired, if the record or its mate is unmapped, or
+    /// if the mates are aligned to different references. Proper pair flag is not required.
+    ///
+    /// If both mates start at the same position, the record on the forward strand is treated
+    /// as the leftmost one, so such pairs on different strands are classified as `FR`.
+    pub fn pair_orientation(&self) -> Option<PairOrientation> {
+        let flag = self.flag();
+        if !flag.is_paired()
+            || !flag.is_mapped()
+            || !flag.mate_is_mapped()
+            || self.ref_id < 0
+            || self.ref_id != self.mate_ref_id
+        {
+            return None;
+        }
+        let forward = !flag.is_reverse_strand();
+        let mate_forward = !flag.mate_is_reverse_strand();
+        Some(match (forward, mate_forward) {
+            (true, true) => PairOrientation::FF,
+            (false, false) => PairOrientation::RR,
+            (true, false) if self.start <= self.mate_start => PairOrientation::FR,
+            (false, true) if self.mate_start <= self.start => PairOrientation::FR,
+            _ => PairOrientation::RF,
+        })
+    }
+
     /// Returns [TagViewer](tags/struct.TagViewer.html), which provides operations of tags.
     pub fn tags(&self) -> &tags::TagViewer {
         &self.tags
@@ -725,9 +1353,27 @@ impl Record {
 
     /// Returns mutable [TagViewer](tags/struct.TagViewer.html), which provides operations of tags.
     pub fn tags_mut(&mut self) -> &mut tags::TagViewer {
+        self.pristine = false;
         &mut self.tags
     }
 
+    /// Returns the [virtual offset](../index/struct.VirtualOffset.html) of the record in
+    /// the BAM file it was read from. The offset is saved only if the reader was configured
+    /// to capture offsets (see
+    /// [BamReader::capture_offsets](../bam_reader/struct.BamReader.html#method.capture_offsets) and
+    /// [IndexedReaderBuilder::capture_offsets](../bam_reader/struct.IndexedReaderBuilder.html#method.capture_offsets)),
+    /// otherwise the function returns `None`.
+    ///
+    /// The offset can be used to read the record again using
+    /// [SeekReader::from_offset](../bgzip/struct.SeekReader.html#method.from_offset).
+    pub fn source_offset(&self) -> Option<index::VirtualOffset> {
+        self.source_offset
+    }
+
+    pub(crate) fn set_source_offset(&mut self, offset: Option<index::VirtualOffset>) {
+        self.source_offset = offset;
+    }
+
     /// Write the record in SAM format to `f`. The function needs
     /// [header](../header/struct.Header.html), as the record itself does not store reference
     /// names.
@@ -775,15 +1421,157 @@ impl Record {
         writeln!(f)
     }
 
+    /// Writes the record in FASTQ format to `f`, in the original read orientation: sequences of
+    /// reverse strand records are reverse complemented and their qualities are reversed.
+    /// The read name is the [template name](#method.template_name), followed by `/1` or `/2`
+    /// for paired records that are first or last in pair.
+    ///
+    /// If `min_qual` is set, the 3' end of the read is quality-trimmed using the same algorithm
+    /// as `bwa aln -q`: the read is cut at the position that maximizes the sum of
+    /// `min_qual - quality` over the removed bases. A read can be trimmed completely, in which
+    /// case an entry with an empty sequence is written. Records without qualities are not
+    /// trimmed, and all their qualities are written as `!`.
+    ///
+    /// Returns an error if the record has no sequence.
+    pub fn write_fastq<W: Write>(&self, f: &mut W, min_qual: Option<u8>) -> io::Result<()> {
+        if !self.seq.available() {
+            return Err(io::Error::new(
+                InvalidData,
+                format!(
+                    "Cannot write record {} in FASTQ format: the record has no sequence",
+                    String::from_utf8_lossy(&self.name)
+                ),
+            ));
+        }
+        let reverse = self.flag.is_reverse_strand();
+        let seq: Vec<u8> = if reverse {
+            self.seq.rev_compl(..).collect()
+        } else {
+            self.seq.to_vec()
+        };
+        let mut qual = if self.qual.available() {
+            self.qual.to_ascii(33)
+        } else {
+            vec![b'!'; seq.len()]
+        };
+        if reverse {
+            qual.reverse();
+        }
+        let len = match min_qual {
+            Some(min_qual) if self.qual.available() => trimmed_len(&qual, min_qual),
+            _ => seq.len(),
+        };
+
+        f.write_u8(b'@')?;
+        f.write_all(self.template_name())?;
+        if self.flag.is_paired() {
+            if self.flag.first_in_pair() {
+                f.write_all(b"/1")?;
+            } else if self.flag.last_in_pair() {
+                f.write_all(b"/2")?;
+            }
+        }
+        f.write_u8(b'\n')?;
+        f.write_all(&seq[..len])?;
+        f.write_all(b"\n+\n")?;
+        f.write_all(&qual[..len])?;
+        writeln!(f)
+    }
+
+    /// Checks that the record is consistent with the `header` and with itself. Returns a message
+    /// describing the first found problem. The function checks that
+    /// * reference ids are -1 or within the header bounds,
+    /// * start is within the reference, and the CIGAR does not go beyond the reference end,
+    /// * CIGAR query length, sequence and qualities lengths match (when present),
+    /// * BAI bin is correct (if the bin is already known, for example if the record was read from a BAM file).
+    pub fn validate(&self, header: &Header) -> Result<(), String> {
+        let problem = |text: String| {
+            Err(format!(
+                "Record {}: {}",
+                String::from_utf8_lossy(&self.name),
+                text
+            ))
+        };
+        let n_refs = header.n_references() as i32;
+        if self.ref_id < -1 || self.ref_id >= n_refs {
+            return problem(format!("reference id {} is out of bounds", self.ref_id));
+        }
+        if self.mate_ref_id < -1 || self.mate_ref_id >= n_refs {
+            return problem(format!(
+                "mate reference id {} is out of bounds",
+                self.mate_ref_id
+            ));
+        }
+        if self.start < -1 {
+            return problem(format!("start {} is negative", self.start));
+        }
+
+        if self.ref_id >= 0 {
+            let len = i64::from(header.reference_len(self.ref_id as u32).unwrap());
+            if i64::from(self.start) >= len {
+                return problem(format!(
+                    "start {} is beyond the reference length {}",
+                    self.start, len
+                ));
+            }
+            // Computed in i64, as the end does not always fit in i32.
+            let end = i64::from(self.start) + i64::from(self.cigar.calculate_ref_len());
+            if self.start >= 0 && end > len {
+                return problem(format!(
+                    "alignment end {} is beyond the reference length {}",
+                    end, len
+                ));
+            }
+        }
+
+        if self.qual.available() && self.qual.len() != self.seq.len() {
+            return problem(format!(
+                "sequence length {} does not match qualities length {}",
+                self.seq.len(),
+                self.qual.len()
+            ));
+        }
+        if self.seq.available() && !self.cigar.is_empty() {
+            let query_len = self.cigar.calculate_query_len() as usize;
+            if query_len != self.seq.len() {
+                return problem(format!(
+                    "sequence length {} does not match CIGAR query length {}",
+                    self.seq.len(),
+                    query_len
+                ));
+            }
+        }
+
+        if let Some(bin) = self.bin() {
+            let expected_bin = self.expected_bin();
+            if bin != expected_bin {
+                return problem(format!(
+                    "BAI bin {} does not match the expected bin {}",
+                    bin, expected_bin
+                ));
+            }
+        }
+        Ok(())
+    }
+
     /// Writes a record in BAM format.
     pub fn write_bam<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        self.write_bam_with_name(&self.name, stream)
+    }
+
+    /// Writes a record in BAM format, replacing its name with `name` (at most 254 bytes).
+    pub(crate) fn write_bam_with_name<W: Write>(
+        &self,
+        name: &[u8],
+        stream: &mut W,
+    ) -> io::Result<()> {
         let raw_cigar_len = if self.cigar.len() <= 0xffff {
             4 * self.cigar.len()
         } else {
             16 + 4 * self.cigar.len()
         };
         let total_block_len = 32
-            + self.name.len()
+            + name.len()
             + 1
             + raw_cigar_len
             + self.seq.raw().len()
@@ -793,7 +1581,7 @@ impl Record {
 
         stream.write_i32::<LittleEndian>(self.ref_id)?;
         stream.write_i32::<LittleEndian>(self.start)?;
-        stream.write_u8(self.name.len() as u8 + 1)?;
+        stream.write_u8(name.len() as u8 + 1)?;
         stream.write_u8(self.mapq)?;
         stream.write_u16::<LittleEndian>(self.calculate_bin())?;
 
@@ -809,7 +1597,7 @@ impl Record {
         stream.write_i32::<LittleEndian>(self.mate_start)?;
         stream.write_i32::<LittleEndian>(self.template_len)?;
 
-        stream.write_all(&self.name)?;
+        stream.write_all(name)?;
         stream.write_u8(0)?;
         if self.cigar.len() <= 0xffff {
             for &el in self.cigar.raw() {
@@ -845,8 +1633,36 @@ impl Record {
         Ok(())
     }
 
+    /// Returns the record in BAM format (starting with the block size), as written by
+    /// [write_bam](#method.write_bam). The record can be restored using
+    /// [from_bytes](#method.from_bytes).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_bam(&mut bytes).expect("Writing to Vec<u8> cannot fail");
+        bytes
+    }
+
+    /// Reads a record from `bytes` in BAM format (starting with the block size), for example,
+    /// produced by [to_bytes](#method.to_bytes). Returns an error if `bytes` do not contain
+    /// exactly one valid record.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Record> {
+        let mut record = Record::new();
+        let mut stream = bytes;
+        if !record.fill_from_bam(&mut stream)? {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Cannot read record: input is empty",
+            ));
+        }
+        if !stream.is_empty() {
+            return Err(record.corrupt("Unexpected bytes after the end of the record"));
+        }
+        Ok(record)
+    }
+
     /// Sets record name (only first 254 letters will be used).
     pub fn set_name<T: IntoIterator<Item = u8>>(&mut self, name: T) {
+        self.pristine = false;
         self.name.clear();
         self.name.extend(name.into_iter().take(254));
     }
@@ -865,16 +1681,19 @@ impl Record {
     /// You can change it directly `record.flag_mut().0 |= RECORD_PAIRED`. You can also you
     /// [set_flag](#method.set_flag).
     pub fn flag_mut(&mut self) -> &mut Flag {
+        self.pristine = false;
         &mut self.flag
     }
 
     pub fn set_flag(&mut self, flag: u16) {
+        self.pristine = false;
         self.flag.0 = flag;
     }
 
     /// Sets reference id. Panics if less than -1. This function does not update record flag.
     pub fn set_ref_id(&mut self, ref_id: i32) {
         assert!(ref_id >= -1, "Reference id < -1");
+        self.pristine = false;
         self.ref_id = ref_id;
     }
 
@@ -883,6 +1702,7 @@ impl Record {
     /// If the end position was already calculated, it is updated.
     pub fn set_start(&mut self, start: i32) {
         assert!(start >= -1, "Start < -1");
+        self.pristine = false;
         let difference = start - self.start;
         self.start = start;
         if self.end.get() != 0 {
@@ -892,6 +1712,7 @@ impl Record {
     }
 
     pub fn set_mapq(&mut self, mapq: u8) {
+        self.pristine = false;
         self.mapq = mapq;
     }
 
@@ -899,16 +1720,19 @@ impl Record {
     /// Panics if argument is less than -1. This function does not update record flag.
     pub fn set_mate_ref_id(&mut self, mate_ref_id: i32) {
         assert!(mate_ref_id >= -1, "Mate reference id < -1");
+        self.pristine = false;
         self.mate_ref_id = mate_ref_id;
     }
 
     /// Sets record 0-based start of the mate record. Panics if less than -1.
     pub fn set_mate_start(&mut self, mate_start: i32) {
         assert!(mate_start >= -1, "Mate start < -1");
+        self.pristine = false;
         self.mate_start = mate_start;
     }
 
     pub fn set_template_len(&mut self, template_len: i32) {
+        self.pristine = false;
         self.template_len = template_len;
     }
 
@@ -926,6 +1750,7 @@ impl Record {
         T: IntoIterator<Item = u8>,
         U: IntoIterator<Item = u8>,
     {
+        self.pristine = false;
         self.seq.clear();
         if let Err(e) = self.seq.extend_from_text(sequence) {
             self.seq.clear();
@@ -968,6 +1793,7 @@ impl Record {
     where
         U: IntoIterator<Item = u8>,
     {
+        self.pristine = false;
         self.seq.clear();
         self.qual.clear();
         self.qual.extend_from_raw(qualities);
@@ -997,6 +1823,7 @@ impl Record {
 
     /// Sets record cigar from u8 iterator. This resets end position and BAI bin.
     pub fn set_cigar<I: IntoIterator<Item = u8>>(&mut self, cigar: I) -> Result<(), String> {
+        self.pristine = false;
         self.end.set(0);
         self.bin.set(BIN_UNKNOWN);
         self.cigar.clear();
@@ -1005,6 +1832,7 @@ impl Record {
 
     /// Sets raw record cigar. This resets end position and BAI bin.
     pub fn set_raw_cigar<I: IntoIterator<Item = u32>>(&mut self, cigar: I) {
+        self.pristine = false;
         self.end.set(0);
         self.bin.set(BIN_UNKNOWN);
         self.cigar.clear();
@@ -1021,6 +1849,20 @@ impl Record {
         self.cigar.aligned_pairs(self.start as u32)
     }
 
+    /// Returns an iterator over triples `(Option<u32>, Option<u32>, Operation)`, containing a
+    /// sequence index, a reference index and the corresponding CIGAR operation.
+    /// Matches produce both indices, insertions produce `(Some, None)`, and deletions and
+    /// reference skips produce `(None, Some)`.
+    ///
+    /// Unlike [aligned_pairs](#method.aligned_pairs), soft-clipped positions are skipped.
+    /// Sequence indices still count soft-clipped bases, so they can be used to access the
+    /// [sequence](#method.sequence) and the [qualities](#method.qualities).
+    ///
+    /// If the record is unmapped, returns an empty iterator.
+    pub fn aligned_triples(&self) -> cigar::AlignedTriples<'_> {
+        self.cigar.aligned_triples(self.start as u32)
+    }
+
     /// Returns an iterator over pairs `(u32, u32)`.
     /// The first element represents a sequence index, and the second element represents a
     /// reference index. This iterator skips insertions and deletions.
@@ -1030,6 +1872,120 @@ impl Record {
         self.cigar.matching_pairs(self.start as u32)
     }
 
+    /// Counts substitutions between the record sequence and the `reference` by comparing
+    /// sequence and reference bases at all [matching pairs](#method.matching_pairs)
+    /// (`M`, `=` and `X` operations), case-insensitive. Insertions, deletions and clipped bases
+    /// are not counted, so unlike the `NM` tag the result only contains mismatches.
+    ///
+    /// Positions, where the sequence or the reference has an ambiguous base (anything other than
+    /// A, C, G or T, for example N or R), are counted as matches. Sequence bases `=` always match
+    /// the reference. Use [count_mismatches_by](#method.count_mismatches_by) to count ambiguous
+    /// bases differently.
+    ///
+    /// Returns 0 for unmapped records, and an error if the record has no sequence or the
+    /// reference cannot be fetched.
+    pub fn count_mismatches<F: ReferenceFetch>(&self, reference: &F) -> io::Result<u32> {
+        self.count_mismatches_by(reference, true)
+    }
+
+    /// Same as [count_mismatches](#method.count_mismatches), but if `ambiguous_match` is false,
+    /// ambiguous bases are compared literally: for example, N matches only N, and R is a mismatch
+    /// with both A and G.
+    pub fn count_mismatches_by<F: ReferenceFetch>(
+        &self,
+        reference: &F,
+        ambiguous_match: bool,
+    ) -> io::Result<u32> {
+        if !self.flag.is_mapped() || self.ref_id < 0 {
+            return Ok(0);
+        }
+        if !self.seq.available() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Cannot count mismatches for record {}: the record has no sequence",
+                    String::from_utf8_lossy(&self.name)
+                ),
+            ));
+        }
+        let start = self.start as u32;
+        let ref_seq = reference.fetch(self.ref_id as u32, start, self.calculate_end() as u32)?;
+        let mut mismatches = 0;
+        for (seq_pos, ref_pos) in self.matching_pairs() {
+            let seq_nt = self.seq.at(seq_pos as usize);
+            let ref_nt = ref_seq[(ref_pos - start) as usize];
+            if seq_nt == b'='
+                || (ambiguous_match && (is_ambiguous(seq_nt) || is_ambiguous(ref_nt)))
+                || seq_nt.eq_ignore_ascii_case(&ref_nt)
+            {
+                continue;
+            }
+            mismatches += 1;
+        }
+        Ok(mismatches)
+    }
+
+    /// Returns the number of aligned columns `(M/=/X, I + D)`, where the second value is the
+    /// total length of insertions and deletions.
+    fn alignment_columns(&self) -> (u32, u32) {
+        let mut matches = 0;
+        let mut indels = 0;
+        for (len, op) in self.cigar.iter() {
+            match op {
+                cigar::Operation::AlnMatch
+                | cigar::Operation::SeqMatch
+                | cigar::Operation::SeqMismatch => matches += len,
+                cigar::Operation::Insertion | cigar::Operation::Deletion => indels += len,
+                _ => {}
+            }
+        }
+        (matches, indels)
+    }
+
+    /// Returns the alignment identity: `matches / (matches + mismatches + I + D)`, where matches
+    /// and mismatches are calculated by comparing the sequence with the `reference` (see
+    /// [count_mismatches](#method.count_mismatches), ambiguous bases count as matches),
+    /// and `I` and `D` are total lengths of insertions and deletions. Clipping and reference
+    /// skips (`N`) are not included.
+    ///
+    /// Returns `None` for unmapped records and records without `M`, `=`, `X`, `I` or `D`
+    /// operations, and an error if the record has no sequence or the reference cannot be fetched.
+    /// The [identity_from_nm](#method.identity_from_nm) does not need the reference and is faster,
+    /// but relies on the `NM` tag.
+    pub fn identity<F: ReferenceFetch>(&self, reference: &F) -> io::Result<Option<f64>> {
+        let (matches, indels) = self.alignment_columns();
+        if !self.flag.is_mapped() || matches + indels == 0 {
+            return Ok(None);
+        }
+        let mismatches = self.count_mismatches(reference)?;
+        Ok(Some(f64::from(matches - mismatches) / f64::from(matches + indels)))
+    }
+
+    /// Returns the alignment identity, calculated from the `NM` tag (edit distance) and the
+    /// Cigar: `(M + I + D - NM) / (M + I + D)`, where `M` is the total length of `M`, `=` and `X`
+    /// operations. If the `NM` tag is correct, the value is the same as for
+    /// [identity](#method.identity), except that the `NM` tag usually counts ambiguous bases
+    /// as mismatches.
+    ///
+    /// Returns `None` for unmapped records, records without the `NM` tag (or with a non-integer
+    /// tag), records without `M`, `=`, `X`, `I` or `D` operations, and records with `NM` greater
+    /// than `M + I + D`.
+    pub fn identity_from_nm(&self) -> Option<f64> {
+        if !self.flag.is_mapped() {
+            return None;
+        }
+        let edit_distance = match self.tags.get(b"NM")? {
+            tags::TagValue::Int(value, _) if value >= 0 => value as u64,
+            _ => return None,
+        };
+        let (matches, indels) = self.alignment_columns();
+        let columns = u64::from(matches + indels);
+        if columns == 0 || edit_distance > columns {
+            return None;
+        }
+        Some((columns - edit_distance) as f64 / columns as f64)
+    }
+
     /// Returns an iterator over [AlignmentEntry](struct.AlignmentEntry.html), which stores information
     /// about a single position in the record-reference alignment.
     ///
@@ -7,8 +7,9 @@ use byteorder::WriteBytesExt;
 
 use super::{resize, write_iterator};
 
-/// Converts nucleotide to BAM u4 (for example `b'T'` -> `8`).
-fn nt_to_raw(nt: u8) -> Result<u8, String> {
+/// Converts nucleotide to BAM u4 (for example `b'T'` -> `8`). Lowercase nucleotides are
+/// converted in the same way as uppercase. Inverse of [nibble_to_base](fn.nibble_to_base.html).
+pub fn base_to_nibble(nt: u8) -> Result<u8, String> {
     match nt {
         b'=' => Ok(0),
         b'A' | b'a' => Ok(1),
@@ -30,6 +31,12 @@ fn nt_to_raw(nt: u8) -> Result<u8, String> {
     }
 }
 
+/// Converts BAM u4 into a nucleotide (for example `8` -> `b'T'`), using the order
+/// `=ACMGRSVTWYHKDBN`. Panics if `nibble` is bigger than 15.
+pub fn nibble_to_base(nibble: u8) -> u8 {
+    b"=ACMGRSVTWYHKDBN"[nibble as usize]
+}
+
 /// Wrapper around raw sequence, stored as an `[u8; (len + 1) / 2]`. Each four bits encode a
 /// nucleotide in the following order: `=ACMGRSVTWYHKDBN`.
 #[derive(Clone)]
@@ -65,12 +72,17 @@ impl Sequence {
         self.raw.shrink_to_fit();
     }
 
+    /// Reserves capacity for at least `additional` more nucleotides.
+    pub fn reserve(&mut self, additional: usize) {
+        self.raw.reserve(additional / 2 + 1);
+    }
+
     /// Pushes a single nucleotide to the end.
     pub fn push(&mut self, nt: u8) -> Result<(), String> {
         if self.len % 2 == 0 {
-            self.raw.push(nt_to_raw(nt)? << 4);
+            self.raw.push(base_to_nibble(nt)? << 4);
         } else {
-            self.raw[self.len / 2] |= nt_to_raw(nt)?;
+            self.raw[self.len / 2] |= base_to_nibble(nt)?;
         }
         self.len += 1;
         Ok(())
@@ -104,6 +116,16 @@ impl Sequence {
         &self.raw
     }
 
+    /// Returns the sequence in the packed BAM encoding, with two nucleotides per byte.
+    /// Nucleotide `2 * i` is stored in the high nibble of the byte `i`, and nucleotide `2 * i + 1`
+    /// in the low nibble. If the sequence length is odd, the last low nibble should be zero.
+    ///
+    /// Nibbles can be converted to nucleotides using [nibble_to_base](fn.nibble_to_base.html).
+    /// Same as [raw](#method.raw).
+    pub fn packed(&self) -> &[u8] {
+        &self.raw
+    }
+
     /// Returns full length of the sequence, O(1).
     pub fn len(&self) -> usize {
         self.len
@@ -138,7 +160,7 @@ impl Sequence {
         } else {
             self.raw[index / 2] & 0x0f
         };
-        b"=ACMGRSVTWYHKDBN"[nt as usize]
+        nibble_to_base(nt)
     }
 
     /// Returns a nucleotide at the position `index`, represented by a single byte, O(1).
@@ -381,6 +403,26 @@ impl Qualities {
         self.raw.iter().map(|qual| qual + 33).collect()
     }
 
+    /// Returns vector with `base` added to each quality, for example `to_ascii(33)` gives
+    /// the same result as [to_readable](#method.to_readable), and `to_ascii(64)` produces
+    /// old Illumina qualities. Sums above 255 are capped at 255. Returns an empty vector if
+    /// qualities are not available, O(n).
+    pub fn to_ascii(&self, base: u8) -> Vec<u8> {
+        if !self.available() {
+            return Vec::new();
+        }
+        self.raw.iter().map(|qual| qual.saturating_add(base)).collect()
+    }
+
+    /// Returns the mean quality (without +33), or `None` if qualities are not available, O(n).
+    pub fn mean(&self) -> Option<f64> {
+        if !self.available() {
+            return None;
+        }
+        let sum: u64 = self.raw.iter().map(|&qual| u64::from(qual)).sum();
+        Some(sum as f64 / self.raw.len() as f64)
+    }
+
     /// Writes to `f` in human readable format (qual + 33). Writes `*` if empty.
     pub fn write_readable<W: Write>(&self, f: &mut W) -> io::Result<()> {
         if !self.available() {
@@ -394,6 +436,22 @@ impl Qualities {
         self.raw.clear();
     }
 
+    /// Caps each quality at `max` (without +33). Returns `true` if any quality has changed.
+    /// Does nothing if qualities are not available, O(n).
+    pub fn cap(&mut self, max: u8) -> bool {
+        if !self.available() {
+            return false;
+        }
+        let mut changed = false;
+        for qual in self.raw.iter_mut() {
+            if *qual > max {
+                *qual = max;
+                changed = true;
+            }
+        }
+        changed
+    }
+
     /// Extends the qualities from raw qualities (without + 33).
     pub fn extend_from_raw<I: IntoIterator<Item = u8>>(&mut self, qualities: I) {
         self.raw.extend(qualities);
@@ -403,4 +461,9 @@ impl Qualities {
     pub fn shrink_to_fit(&mut self) {
         self.raw.shrink_to_fit();
     }
+
+    /// Reserves capacity for at least `additional` more qualities.
+    pub fn reserve(&mut self, additional: usize) {
+        self.raw.reserve(additional);
+    }
 }
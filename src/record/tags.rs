@@ -144,8 +144,9 @@ impl<'a> IntArrayView<'a> {
         self.int_type.parse_raw(&self.raw[start..end])
     }
 
-    /// Returns iterator over values (converted into `i64`).
-    pub fn iter<'b: 'a>(&'b self) -> IntArrayViewIter<'b> {
+    /// Returns iterator over values (converted into `i64`). The iterator borrows the tag data,
+    /// not the view, so it can outlive the view.
+    pub fn iter(&self) -> IntArrayViewIter<'a> {
         IntArrayViewIter {
             chunks: self.raw.chunks(self.int_type.size_of()),
             int_type: self.int_type,
@@ -616,6 +617,11 @@ impl TagViewer {
         self.lengths.shrink_to_fit();
     }
 
+    /// Reserves capacity for at least `additional` more bytes of raw tags.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.raw.reserve(additional);
+    }
+
     pub(crate) fn fill_from<R: Read>(&mut self, stream: &mut R, length: usize) -> io::Result<()> {
         unsafe {
             resize(&mut self.raw, length);
@@ -661,6 +667,20 @@ impl TagViewer {
         None
     }
 
+    /// Returns an iterator over the values of an integer array tag with `name` (B-type tag with
+    /// subtype `c`, `C`, `s`, `S`, `i` or `I`), converted into `i64`. Values are decoded from
+    /// the little-endian raw tag on the fly, without copying the array. Takes `O(n_tags)`.
+    ///
+    /// Returns `None` if there is no such tag, or if the tag is not an integer array.
+    /// The element type can be found using [get](#method.get) and
+    /// [IntArrayView::int_type](struct.IntArrayView.html#method.int_type).
+    pub fn tag_array_iter<'a>(&'a self, name: &TagName) -> Option<IntArrayViewIter<'a>> {
+        match self.get(name) {
+            Some(TagValue::IntArray(array_view)) => Some(array_view.iter()),
+            _ => None,
+        }
+    }
+
     /// Iterate over tuples `(name, tag_value)`, where `name: [u8; 2]` and `tag_value: TagValue`.
     pub fn iter<'a>(&'a self) -> TagIter<'a> {
         TagIter {
@@ -891,6 +911,29 @@ impl TagViewer {
     pub fn raw_lengths(&self) -> &[u32] {
         &self.lengths
     }
+
+    /// Returns raw tags (including names and types), sorted in lexicographic order.
+    fn sorted_raw_tags(&self) -> Vec<&[u8]> {
+        let mut start = 0;
+        let mut res: Vec<_> = self
+            .lengths
+            .iter()
+            .map(|&tag_len| {
+                let tag = &self.raw[start..start + tag_len as usize];
+                start += tag_len as usize;
+                tag
+            })
+            .collect();
+        res.sort_unstable();
+        res
+    }
+
+    /// Checks if two viewers contain the same tags, possibly in a different order.
+    pub(crate) fn eq_unordered(&self, other: &TagViewer) -> bool {
+        self.raw.len() == other.raw.len()
+            && self.lengths.len() == other.lengths.len()
+            && self.sorted_raw_tags() == other.sorted_raw_tags()
+    }
 }
 
 /// Iterator over tags.
@@ -0,0 +1,245 @@
+//! Reading remote files over HTTP. The module is available with the `http` feature.
+//!
+//! [RemoteReader](struct.RemoteReader.html) implements `Read` and `Seek` using HTTP range
+//! requests, and can be used as a BAM stream for
+//! [IndexedReaderBuilder::from_streams](../bam_reader/struct.IndexedReaderBuilder.html#method.from_streams)
+//! or for any other reader:
+//! ```rust
+//! let bam_stream = bam::remote::RemoteReader::new("http://example.com/in.bam").unwrap();
+//! let bai_stream = bam::remote::RemoteReader::new("http://example.com/in.bam.bai").unwrap();
+//! let mut reader = bam::IndexedReader::build()
+//!     .from_streams(bam_stream, bai_stream).unwrap();
+//! ```
+//!
+//! Requests are sent using [ureq](https://docs.rs/ureq), so both `http://` and `https://` urls
+//! are supported, redirects are followed, and connections are reused between requests.
+
+use std::cmp::min;
+use std::io::ErrorKind::{InvalidData, InvalidInput, Other, UnexpectedEof};
+use std::io::{self, Error, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+/// Default size of a single range request, in bytes.
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 20;
+/// Default number of blocks stored in the cache.
+pub const DEFAULT_CACHE_BLOCKS: usize = 16;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Remote file, accessed using HTTP range requests. Implements `Read` and `Seek`.
+///
+/// The file is downloaded in blocks of a fixed size, and several recently used blocks are cached,
+/// so small consecutive reads and seeks within a block do not produce new requests.
+pub struct RemoteReader {
+    agent: ureq::Agent,
+    url: String,
+    len: u64,
+    pos: u64,
+    block_size: u64,
+    // Recently used blocks (block index, contents), the most recent block goes first.
+    cache: Vec<(u64, Vec<u8>)>,
+    cache_blocks: usize,
+}
+
+impl RemoteReader {
+    /// Opens a remote file with the default block size and cache size
+    /// ([DEFAULT_BLOCK_SIZE](constant.DEFAULT_BLOCK_SIZE.html) and
+    /// [DEFAULT_CACHE_BLOCKS](constant.DEFAULT_CACHE_BLOCKS.html)).
+    ///
+    /// The `url` should start with `http://` or `https://`.
+    pub fn new(url: &str) -> io::Result<Self> {
+        Self::with_cache(url, DEFAULT_BLOCK_SIZE, DEFAULT_CACHE_BLOCKS)
+    }
+
+    /// Opens a remote file, that will be downloaded in blocks of `block_size` bytes,
+    /// and at most `cache_blocks` blocks will be stored at the same time.
+    ///
+    /// Panics if `block_size` or `cache_blocks` is zero.
+    pub fn with_cache(url: &str, block_size: usize, cache_blocks: usize) -> io::Result<Self> {
+        assert!(block_size > 0, "Block size should be positive");
+        assert!(cache_blocks > 0, "Cache should contain at least one block");
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(Error::new(
+                InvalidInput,
+                format!("Url should start with http:// or https:// ({})", url),
+            ));
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(TIMEOUT)
+            .timeout_read(TIMEOUT)
+            .timeout_write(TIMEOUT)
+            .build();
+        let mut reader = RemoteReader {
+            agent,
+            url: url.to_string(),
+            len: 0,
+            pos: 0,
+            block_size: block_size as u64,
+            cache: Vec::with_capacity(cache_blocks),
+            cache_blocks,
+        };
+        // The first request also finds the length of the file.
+        let (len, contents) = reader.fetch(0)?;
+        reader.len = len;
+        reader.cache.push((0, contents));
+        Ok(reader)
+    }
+
+    /// Returns the length of the remote file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the remote file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Downloads the block with index `block`, returns the length of the file and the block contents.
+    fn fetch(&self, block: u64) -> io::Result<(u64, Vec<u8>)> {
+        let start = block * self.block_size;
+        let end = start + self.block_size - 1;
+        let response = match self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, end))
+            .set("Accept-Encoding", "identity")
+            .call()
+        {
+            Ok(response) => response,
+            // Requested range is outside of an empty file.
+            Err(ureq::Error::Status(416, _)) if start == 0 => return Ok((0, Vec::new())),
+            Err(ureq::Error::Status(status, _)) => {
+                return Err(Error::new(
+                    Other,
+                    format!("HTTP request failed with status {}", status),
+                ))
+            }
+            Err(e) => return Err(Error::new(Other, e)),
+        };
+        let status = response.status();
+        let content_range = response.header("Content-Range").map(str::to_string);
+        let mut body = Vec::new();
+        // Read at most one byte more than a block, to detect servers that ignore the range.
+        response
+            .into_reader()
+            .take(self.block_size + 1)
+            .read_to_end(&mut body)?;
+
+        match status {
+            206 => {
+                let (range_start, range_end, len) = content_range
+                    .as_deref()
+                    .and_then(parse_content_range)
+                    .ok_or_else(|| {
+                        Error::new(InvalidData, "HTTP response: invalid Content-Range header")
+                    })?;
+                if range_start != start || range_end < range_start || range_end > end {
+                    return Err(Error::new(
+                        InvalidData,
+                        format!(
+                            "HTTP response: requested bytes {}-{}, but received bytes {}-{}",
+                            start, end, range_start, range_end
+                        ),
+                    ));
+                }
+                if body.len() as u64 != range_end - range_start + 1 {
+                    return Err(Error::new(
+                        InvalidData,
+                        format!(
+                            "HTTP response: Content-Range has {} bytes, but the body has {} bytes",
+                            range_end - range_start + 1,
+                            body.len()
+                        ),
+                    ));
+                }
+                Ok((len, body))
+            }
+            // The server ignored the range, which is fine only if the whole file fits in the block.
+            200 if start == 0 && body.len() as u64 <= self.block_size => {
+                Ok((body.len() as u64, body))
+            }
+            200 => Err(Error::new(
+                InvalidData,
+                "HTTP server does not support range requests",
+            )),
+            status => Err(Error::new(
+                Other,
+                format!("HTTP request failed with status {}", status),
+            )),
+        }
+    }
+
+    /// Returns the block with index `block`, using the cache if possible.
+    fn block(&mut self, block: u64) -> io::Result<&[u8]> {
+        match self.cache.iter().position(|(index, _)| *index == block) {
+            Some(0) => {}
+            Some(i) => {
+                let entry = self.cache.remove(i);
+                self.cache.insert(0, entry);
+            }
+            None => {
+                let (_, contents) = self.fetch(block)?;
+                if self.cache.len() == self.cache_blocks {
+                    self.cache.pop();
+                }
+                self.cache.insert(0, (block, contents));
+            }
+        }
+        Ok(&self.cache[0].1)
+    }
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = self.block_size;
+        let pos = self.pos;
+        let contents = self.block(pos / block_size)?;
+        let offset = (pos % block_size) as usize;
+        if offset >= contents.len() {
+            return Err(Error::new(UnexpectedEof, "HTTP response is shorter than expected"));
+        }
+        let count = min(buf.len(), contents.len() - offset);
+        buf[..count].copy_from_slice(&contents[offset..offset + count]);
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for RemoteReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(value) => Some(value),
+            SeekFrom::End(value) => offset_pos(self.len, value),
+            SeekFrom::Current(value) => offset_pos(self.pos, value),
+        };
+        match new_pos {
+            Some(value) => {
+                self.pos = value;
+                Ok(value)
+            }
+            None => Err(Error::new(
+                InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn offset_pos(pos: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        pos.checked_add(offset as u64)
+    } else {
+        pos.checked_sub(offset.unsigned_abs())
+    }
+}
+
+/// Parses `Content-Range` header value `bytes start-end/len` into `(start, end, len)`.
+fn parse_content_range(value: &str) -> Option<(u64, u64, u64)> {
+    let (range, len) = value.strip_prefix("bytes ")?.trim().split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?, len.parse().ok()?))
+}
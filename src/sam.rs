@@ -16,6 +16,7 @@ use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Result, Write};
 use std::path::Path;
 
+use super::header::trim_line_end;
 use super::{Header, Record, RecordReader, RecordWriter};
 
 /// Builder of the [SamWriter](struct.SamWriter.html).
@@ -177,7 +178,7 @@ impl<R: BufRead> SamReader<R> {
                 break;
             };
             if buffer.starts_with('@') {
-                header.push_line(buffer.trim_end())?;
+                header.push_line(trim_line_end(&buffer))?;
             } else {
                 break;
             }
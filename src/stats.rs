@@ -0,0 +1,87 @@
+//! Summary statistics over records.
+
+use std::io;
+
+use super::{Record, RecordReader};
+
+/// Reads all records from `reader` and returns the mean base quality (without +33) for each
+/// read cycle. Cycle is the index of a base in the sequence as it was sequenced, so base
+/// qualities of reverse strand records are taken in reverse order. Hard clipped bases are not
+/// present in the record and are not counted, while soft clipped bases are.
+///
+/// The output has length equal to the longest quality string. Records without qualities
+/// are ignored.
+///
+/// To calculate qualities over a region use the output of
+/// [IndexedReader::fetch](../bam_reader/struct.IndexedReader.html#method.fetch):
+/// ```rust
+/// let mut reader = bam::IndexedReader::from_path("in.bam").unwrap();
+/// let mut viewer = reader.fetch(&bam::Region::new(0, 1000, 2000)).unwrap();
+/// let means = bam::stats::mean_quality_by_cycle(&mut viewer).unwrap();
+/// ```
+pub fn mean_quality_by_cycle<R: RecordReader>(reader: &mut R) -> io::Result<Vec<f64>> {
+    let mut sums: Vec<u64> = Vec::new();
+    let mut counts: Vec<u64> = Vec::new();
+    let mut record = Record::new();
+    while reader.read_into(&mut record)? {
+        if !record.qualities().available() {
+            continue;
+        }
+        let qual = record.qualities().raw();
+        if qual.len() > sums.len() {
+            sums.resize(qual.len(), 0);
+            counts.resize(qual.len(), 0);
+        }
+        let reverse = record.flag().is_reverse_strand();
+        for (i, &q) in qual.iter().enumerate() {
+            let cycle = if reverse { qual.len() - 1 - i } else { i };
+            sums[cycle] += u64::from(q);
+            counts[cycle] += 1;
+        }
+    }
+    Ok(sums
+        .iter()
+        .zip(&counts)
+        .map(|(&sum, &count)| sum as f64 / count as f64)
+        .collect())
+}
+
+/// Reads all records from `reader` and counts G/C nucleotides in the aligned
+/// parts of the records (CIGAR operations `M`, `=` and `X`). Inserted, soft clipped
+/// and hard clipped bases are not counted, as well as ambiguous nucleotides (such as N).
+///
+/// Returns a pair *(GC bases, total A/C/G/T bases)*. Unmapped records and
+/// records without sequence are ignored.
+pub fn gc_content<R: RecordReader>(reader: &mut R) -> io::Result<(u64, u64)> {
+    let mut gc_bases = 0;
+    let mut total_bases = 0;
+    let mut record = Record::new();
+    while reader.read_into(&mut record)? {
+        if !record.flag().is_mapped() || !record.sequence().available() {
+            continue;
+        }
+        let mut query_pos = 0;
+        for (len, op) in record.cigar() {
+            let len = len as usize;
+            if op.is_match() {
+                for nt in record
+                    .sequence()
+                    .subseq_acgtn_only(query_pos..query_pos + len)
+                {
+                    match nt {
+                        b'G' | b'C' => {
+                            gc_bases += 1;
+                            total_bases += 1;
+                        }
+                        b'A' | b'T' => total_bases += 1,
+                        _ => {}
+                    }
+                }
+            }
+            if op.consumes_query() {
+                query_pos += len;
+            }
+        }
+    }
+    Ok((gc_bases, total_bases))
+}
@@ -2,7 +2,7 @@ extern crate bam;
 extern crate rand;
 
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Instant;
@@ -553,3 +553,3771 @@ fn ind_bam_to_bam_multithread() {
         test_ind_bam_to_bam(entry_str, 2, &mut log);
     }
 }
+
+/// Compresses raw uncompressed BAM records (without a header) into a bgzip stream.
+fn compress_raw_records(raw: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    let mut writer = bam::bgzip::Writer::build().from_stream(&mut stream);
+    writer.write_all(raw).unwrap();
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+    stream
+}
+
+fn single_ref_header() -> bam::Header {
+    let mut header = bam::Header::new();
+    header
+        .push_entry(bam::header::HeaderEntry::ref_sequence(
+            "chr1".to_string(),
+            1000,
+        ))
+        .unwrap();
+    header
+}
+
+/// Returns a raw BAM record `r1` aligned to `chr1:11` with CIGAR `4M`, and the offset of its CIGAR.
+fn raw_test_record() -> (Vec<u8>, usize) {
+    let mut record = bam::Record::new();
+    record.set_name("r1".bytes());
+    record.set_ref_id(0);
+    record.set_start(10);
+    record.set_cigar("4M".bytes()).unwrap();
+    record
+        .set_seq_qual("ACGT".bytes(), [30_u8, 30, 30, 30].iter().cloned())
+        .unwrap();
+    let mut raw = Vec::new();
+    record.write_bam(&mut raw).unwrap();
+    // Fixed fields (36 bytes including block size) and name with a null symbol.
+    (raw, 36 + 3)
+}
+
+#[test]
+fn corrupted_cigar() {
+    let (mut raw, cigar_offset) = raw_test_record();
+    raw[cigar_offset] |= 0xf;
+    let stream = compress_raw_records(&raw);
+    let mut reader =
+        bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0).unwrap();
+    assert!(reader.read_into(&mut bam::Record::new()).is_err());
+
+    // n_cigar_op is larger than the number of stored operations.
+    for n_cigar_op in 2..20_u16 {
+        let (mut raw, _) = raw_test_record();
+        raw[16..18].copy_from_slice(&n_cigar_op.to_le_bytes());
+        for truncate in 0..raw.len() - 4 {
+            let stream = compress_raw_records(&raw[..raw.len() - truncate]);
+            let mut reader =
+                bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0)
+                    .unwrap();
+            assert!(reader.read_into(&mut bam::Record::new()).is_err());
+        }
+    }
+
+    let cigar = bam::record::Cigar::from_raw(&[4 << 4, 2 << 4 | 1, 3 << 4 | 0xf]);
+    assert_eq!(cigar.get(0), Some((4, bam::record::cigar::Operation::AlnMatch)));
+    assert_eq!(cigar.get(1), Some((2, bam::record::cigar::Operation::Insertion)));
+    assert_eq!(cigar.get(2), None);
+    assert_eq!(cigar.get(3), None);
+}
+
+#[test]
+fn pileup_insertion_only_cigar() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\nr1\t0\tchr1\t11\t60\t4I\t*\t0\t0\tACGT\t*\n";
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let mut pileup = bam::Pileup::new(&mut reader);
+    assert!(pileup.next().unwrap().is_err());
+    assert!(pileup.next().is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn bam_reader_from_raw_fd() {
+    use std::os::unix::io::IntoRawFd;
+
+    let (raw, _) = raw_test_record();
+    let path = std::env::temp_dir().join(format!("bam_crate_raw_fd_{}.bam", std::process::id()));
+    {
+        let mut writer = bam::bgzip::Writer::from_path(&path).unwrap();
+        single_ref_header().write_bam(&mut writer).unwrap();
+        writer.write_all(&raw).unwrap();
+        writer.finish().unwrap();
+    }
+
+    let fd = File::open(&path).unwrap().into_raw_fd();
+    let reader = unsafe { bam::BamReader::from_raw_fd(fd, 0) }.unwrap();
+    assert_eq!(reader.header().reference_name(0), Some("chr1"));
+    let records: Vec<_> = reader.map(Result::unwrap).collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].name(), b"r1");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn pileup_variant_scan() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t8M\t*\t0\t0\tACGTACGT\t*\n\
+        r2\t0\tchr1\t2\t60\t6M\t*\t0\t0\tCGAACG\t*\n\
+        r3\t0\tchr1\t3\t60\t4M\t*\t0\t0\tGAAC\t*\n";
+    let reference = vec!["ACGTACGTACGT"];
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let sites: Vec<_> = bam::Pileup::new(&mut reader)
+        .variant_scan(&reference, 2, 0.5)
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(sites, vec![(0, 3, b'T', [2, 0, 0, 1])]);
+
+    // Positions after the end of the reference are reported as errors.
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let sites: Vec<_> = bam::Pileup::new(&mut reader)
+        .variant_scan(vec!["acgTAC"], 1, 0.5)
+        .collect();
+    assert_eq!(sites.len(), 3);
+    assert_eq!(sites[0].as_ref().unwrap(), &(0, 3, b'T', [2, 0, 0, 1]));
+    assert!(sites[1..].iter().all(Result::is_err));
+}
+
+#[test]
+fn header_comments_round_trip() {
+    let mut header = single_ref_header();
+    header.add_comment("first comment");
+    header.add_comment("second\tcomment with trailing spaces  ");
+    header.add_comment("");
+    let expected = header.comments().to_vec();
+    assert_eq!(expected.len(), 3);
+
+    let mut bam = Vec::new();
+    header.write_bam(&mut bam).unwrap();
+    let parsed = bam::Header::from_bam(&mut &bam[..]).unwrap();
+    assert_eq!(parsed.comments(), &expected[..]);
+
+    let mut sam = Vec::new();
+    header.write_text(&mut sam).unwrap();
+    let reader = bam::SamReader::from_stream(&sam[..]).unwrap();
+    assert_eq!(reader.header().comments(), &expected[..]);
+}
+
+/// Creates a single-block BAM file with `records` (sorted by coordinate) and a matching BAI index.
+fn indexed_test_bam(header: &bam::Header, records: &[bam::Record]) -> (Vec<u8>, Vec<u8>) {
+    let mut raw = Vec::new();
+    header.write_bam(&mut raw).unwrap();
+    indexed_test_bam_from_raw(raw, header.n_references(), records)
+}
+
+/// Same as `indexed_test_bam`, but takes uncompressed BAM header, and the number of references
+/// in the index.
+fn indexed_test_bam_from_raw(
+    mut raw: Vec<u8>,
+    n_refs: usize,
+    records: &[bam::Record],
+) -> (Vec<u8>, Vec<u8>) {
+    let mut bins = vec![std::collections::BTreeMap::<u32, Vec<(u64, u64)>>::new(); n_refs];
+    let mut first_offsets = vec![None; n_refs];
+    for record in records {
+        let start_offset = raw.len() as u64;
+        record.write_bam(&mut raw).unwrap();
+        let ref_id = record.ref_id() as usize;
+        bins[ref_id]
+            .entry(record.calculate_bin() as u32)
+            .or_insert_with(Vec::new)
+            .push((start_offset, raw.len() as u64));
+        first_offsets[ref_id].get_or_insert(start_offset);
+    }
+    assert!(raw.len() < 0xff00, "Test BAM file should fit into a single block");
+
+    let mut bai = b"BAI\x01".to_vec();
+    bai.extend_from_slice(&(n_refs as i32).to_le_bytes());
+    for (ref_bins, first_offset) in bins.iter().zip(&first_offsets) {
+        bai.extend_from_slice(&(ref_bins.len() as i32).to_le_bytes());
+        for (bin_id, chunks) in ref_bins {
+            bai.extend_from_slice(&bin_id.to_le_bytes());
+            bai.extend_from_slice(&(chunks.len() as i32).to_le_bytes());
+            for (start, end) in chunks {
+                bai.extend_from_slice(&start.to_le_bytes());
+                bai.extend_from_slice(&end.to_le_bytes());
+            }
+        }
+        match first_offset {
+            Some(offset) => {
+                bai.extend_from_slice(&1_i32.to_le_bytes());
+                bai.extend_from_slice(&offset.to_le_bytes());
+            }
+            None => bai.extend_from_slice(&0_i32.to_le_bytes()),
+        }
+    }
+    bai.extend_from_slice(&0_u64.to_le_bytes());
+    (compress_raw_records(&raw), bai)
+}
+
+fn two_ref_header() -> bam::Header {
+    let mut header = single_ref_header();
+    header
+        .push_entry(bam::header::HeaderEntry::ref_sequence(
+            "chr2".to_string(),
+            1000,
+        ))
+        .unwrap();
+    header
+}
+
+/// Creates a mapped record with CIGAR `4M`.
+fn mapped_record(name: &str, ref_id: i32, start: i32) -> bam::Record {
+    let mut record = bam::Record::new();
+    record.set_name(name.bytes());
+    record.set_ref_id(ref_id);
+    record.set_start(start);
+    record.set_cigar("4M".bytes()).unwrap();
+    record
+        .set_seq_qual("ACGT".bytes(), [30_u8, 30, 30, 30].iter().cloned())
+        .unwrap();
+    record
+}
+
+#[test]
+fn indexed_reader_has_records() {
+    let header = two_ref_header();
+    let records = vec![mapped_record("r1", 0, 100), mapped_record("r2", 0, 500)];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+
+    assert!(reader.has_records(0, 0, 1000).unwrap());
+    assert!(reader.has_records(0, 102, 103).unwrap());
+    assert!(reader.has_records(0, 490, 510).unwrap());
+    assert!(!reader.has_records(0, 200, 300).unwrap());
+    assert!(!reader.has_records(0, 0, 100).unwrap());
+    assert!(!reader.has_records(1, 0, 1000).unwrap());
+    assert!(reader.has_records(2, 0, 10).is_err());
+    assert!(reader.has_records(0, 0, 1001).is_err());
+    assert_eq!(reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().count(), 2);
+}
+
+#[test]
+fn indexed_reader_fetch_all_by_reference() {
+    let header = two_ref_header();
+    let records = vec![
+        mapped_record("r1", 0, 100),
+        mapped_record("r2", 0, 500),
+        mapped_record("r3", 1, 10),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+
+    let mut names = Vec::new();
+    let mut references = reader.fetch_all_by_reference();
+    while let Some((ref_id, viewer)) = references.next_reference() {
+        for record in viewer {
+            let record = record.unwrap();
+            assert_eq!(record.ref_id(), ref_id as i32);
+            names.push((ref_id, record.name().to_vec()));
+        }
+    }
+    assert_eq!(
+        names,
+        vec![(0, b"r1".to_vec()), (0, b"r2".to_vec()), (1, b"r3".to_vec())]
+    );
+
+    assert_eq!(reader.fetch_ref(1).unwrap().count(), 1);
+    assert!(reader.fetch_ref(2).is_err());
+}
+
+#[test]
+fn record_template_name() {
+    let mut record = bam::Record::new();
+    for &(name, template) in &[
+        ("read/1", "read"),
+        ("read/2", "read"),
+        ("read.1", "read"),
+        ("read.2", "read"),
+        ("SRR1.17/1", "SRR1.17"),
+        ("a.b.c", "a.b.c"),
+        ("read.10", "read.10"),
+        ("read.3", "read.3"),
+        ("read/12", "read/12"),
+        ("read_1", "read_1"),
+        ("/1", "/1"),
+        ("r", "r"),
+    ] {
+        record.set_name(name.bytes());
+        assert_eq!(record.template_name(), template.as_bytes(), "name {}", name);
+        assert_eq!(record.name(), name.as_bytes());
+    }
+}
+
+#[test]
+fn bgzip_level_zero_round_trip() {
+    use std::io::Read;
+
+    let mut rng = rand::thread_rng();
+    let data: Vec<u8> = (0..300_000).map(|_| rng.gen()).collect();
+    for &threads in &[0, 2] {
+        let mut stream = Vec::new();
+        let mut writer = bam::bgzip::Writer::build()
+            .compression_level(0)
+            .additional_threads(threads)
+            .from_stream(&mut stream);
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+        std::mem::drop(writer);
+        assert!(stream.len() > data.len());
+
+        let mut reader = bam::bgzip::ConsecutiveReader::from_stream(&stream[..], threads);
+        let mut decompressed = Vec::new();
+        reader.read_to_end(&mut decompressed).unwrap();
+        assert!(decompressed == data, "Level 0 round trip failed ({} threads)", threads);
+    }
+}
+
+#[test]
+fn bam_level_zero_round_trip() {
+    let header = single_ref_header();
+    let records: Vec<_> = (0..1000)
+        .map(|i| mapped_record(&format!("r{}", i), 0, i % 990))
+        .collect();
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::build()
+        .compression_level(0)
+        .from_stream(&mut stream, header)
+        .unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let read: Vec<_> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(read.len(), records.len());
+    for (record, expected) in read.iter().zip(&records) {
+        let mut buf1 = Vec::new();
+        let mut buf2 = Vec::new();
+        record.write_bam(&mut buf1).unwrap();
+        expected.write_bam(&mut buf2).unwrap();
+        assert_eq!(buf1, buf2);
+    }
+}
+
+#[test]
+fn record_validate() {
+    let header = single_ref_header();
+    let mut record = mapped_record("r1", 0, 10);
+    assert_eq!(record.validate(&header), Ok(()));
+    assert_eq!(bam::Record::new().validate(&header), Ok(()));
+
+    record.set_ref_id(1);
+    assert!(record.validate(&header).unwrap_err().contains("reference id"));
+    record.set_ref_id(0);
+    record.set_start(1000);
+    assert!(record.validate(&header).unwrap_err().contains("start"));
+    record.set_start(998);
+    assert!(record.validate(&header).unwrap_err().contains("alignment end"));
+    record.set_start(10);
+    record.set_cigar("3M".bytes()).unwrap();
+    assert!(record.validate(&header).unwrap_err().contains("CIGAR query length"));
+    record.set_cigar("2M2I".bytes()).unwrap();
+    assert_eq!(record.validate(&header), Ok(()));
+
+    // Records read from a BAM file have a bin, which should match the alignment.
+    let (mut raw, _) = raw_test_record();
+    let stream = compress_raw_records(&raw);
+    let mut reader =
+        bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0).unwrap();
+    let record = Iterator::next(&mut reader).unwrap().unwrap();
+    assert_eq!(record.validate(&header), Ok(()));
+
+    raw[14] ^= 1;
+    let stream = compress_raw_records(&raw);
+    let mut reader =
+        bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0).unwrap();
+    let record = Iterator::next(&mut reader).unwrap().unwrap();
+    assert!(record.validate(&header).unwrap_err().contains("BAI bin"));
+}
+
+#[test]
+fn bam_reader_with_names() {
+    let header = two_ref_header();
+    let mut unmapped = bam::Record::new();
+    unmapped.set_name("u1".bytes());
+    unmapped.flag_mut().set_mapped(false);
+    let records = vec![
+        mapped_record("r1", 0, 100),
+        mapped_record("r2", 1, 10),
+        unmapped,
+    ];
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let header = reader.header().clone();
+    let names: Vec<_> = reader
+        .with_names(&header)
+        .map(|entry| {
+            let (ref_name, record) = entry.unwrap();
+            (ref_name, record.name().to_vec())
+        })
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            (Some("chr1"), b"r1".to_vec()),
+            (Some("chr2"), b"r2".to_vec()),
+            (None, b"u1".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn bam_reader_peek_record_size() {
+    let records = vec![
+        mapped_record("r1", 0, 10),
+        mapped_record("long_read_name", 0, 20),
+        mapped_record("r3", 0, 30),
+    ];
+    let mut raw = Vec::new();
+    let mut sizes = Vec::new();
+    for record in &records {
+        let prev_len = raw.len();
+        record.write_bam(&mut raw).unwrap();
+        sizes.push((raw.len() - prev_len - 4) as u32);
+    }
+    let stream = compress_raw_records(&raw);
+    let mut reader =
+        bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0).unwrap();
+
+    assert_eq!(reader.peek_record_size().unwrap(), Some(sizes[0]));
+    assert_eq!(reader.peek_record_size().unwrap(), Some(sizes[0]));
+    let mut record = bam::Record::new();
+    assert!(reader.read_into(&mut record).unwrap());
+    assert_eq!(record.name(), b"r1");
+
+    assert_eq!(reader.peek_record_size().unwrap(), Some(sizes[1]));
+    assert!(reader.skip_record().unwrap());
+    assert!(reader.read_into(&mut record).unwrap());
+    assert_eq!(record.name(), b"r3");
+
+    assert_eq!(reader.peek_record_size().unwrap(), None);
+    assert!(!reader.skip_record().unwrap());
+    assert!(!reader.read_into(&mut record).unwrap());
+}
+
+#[test]
+fn skip_records() {
+    let header = single_ref_header();
+    let records: Vec<_> = (0..10)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 100 * i))
+        .collect();
+    let mut raw = Vec::new();
+    for record in &records {
+        record.write_bam(&mut raw).unwrap();
+    }
+    let stream = compress_raw_records(&raw);
+    let mut reader =
+        bam::BamReader::from_stream_no_header(&stream[..], header.clone(), 0).unwrap();
+    assert_eq!(reader.skip_records(3).unwrap(), 3);
+    let mut record = bam::Record::new();
+    assert!(reader.read_into(&mut record).unwrap());
+    assert_eq!(record.name(), b"r3");
+    assert_eq!(reader.skip_records(10).unwrap(), 6);
+    assert_eq!(reader.skip_records(1).unwrap(), 0);
+
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let mut viewer = reader.fetch(&bam::Region::new(0, 250, 750)).unwrap();
+    assert_eq!(viewer.skip_records(2).unwrap(), 2);
+    assert_eq!(viewer.next().unwrap().unwrap().name(), b"r5");
+    assert_eq!(viewer.skip_records(5).unwrap(), 2);
+}
+
+#[test]
+fn bam_reader_subsample() {
+    let mut raw = Vec::new();
+    for i in 0..2000 {
+        for &suffix in &["/1", "/2"] {
+            let record = mapped_record(&format!("read{}{}", i, suffix), 0, i % 990);
+            record.write_bam(&mut raw).unwrap();
+        }
+    }
+    let stream = compress_raw_records(&raw);
+    let subsample = |fraction, seed| -> Vec<Vec<u8>> {
+        bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0)
+            .unwrap()
+            .subsample(fraction, seed)
+            .map(|record| record.unwrap().name().to_vec())
+            .collect()
+    };
+
+    let names = subsample(0.25, 42);
+    assert!(names.len() > 800 && names.len() < 1200, "Kept {} records", names.len());
+    // Mates are kept together.
+    assert_eq!(names.len() % 2, 0);
+    for pair in names.chunks(2) {
+        assert_eq!(pair[0][..pair[0].len() - 2], pair[1][..pair[1].len() - 2]);
+    }
+    assert_eq!(subsample(0.25, 42), names);
+    assert_ne!(subsample(0.25, 43), names);
+    assert_eq!(subsample(0.0, 42).len(), 0);
+    assert_eq!(subsample(1.0, 42).len(), 4000);
+}
+
+#[test]
+fn record_ref_names() {
+    let header = two_ref_header();
+    let mut record = mapped_record("r1", 0, 10);
+    assert_eq!(record.ref_name(&header), Some("chr1"));
+    assert_eq!(record.mate_ref_name(&header), None);
+    record.set_mate_ref_id(1);
+    record.set_mate_start(20);
+    assert_eq!(record.mate_ref_name(&header), Some("chr2"));
+    assert_eq!(record.mate_start(), 20);
+    record.set_mate_ref_id(2);
+    assert_eq!(record.mate_ref_name(&header), None);
+    record.set_ref_id(-1);
+    assert_eq!(record.ref_name(&header), None);
+}
+
+#[test]
+fn pileup_indel_context() {
+    use bam::record::cigar::Operation;
+
+    let sam = "@SQ\tSN:chr1\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t2M3D1M4N2M\t*\t0\t0\tACGTA\t*\n";
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let contexts: Vec<_> = bam::Pileup::new(&mut reader)
+        .map(|column| {
+            let column = column.unwrap();
+            let entry = &column.entries()[0];
+            (column.ref_pos(), entry.indel_context(), entry.indel_remaining())
+        })
+        .collect();
+    let deletion = Some((Operation::Deletion, 3));
+    let skip = Some((Operation::Skip, 4));
+    assert_eq!(
+        contexts,
+        vec![
+            (0, None, None),
+            (1, None, None),
+            (2, deletion, Some(3)),
+            (3, deletion, Some(2)),
+            (4, deletion, Some(1)),
+            (5, None, None),
+            (6, skip, Some(4)),
+            (7, skip, Some(3)),
+            (8, skip, Some(2)),
+            (9, skip, Some(1)),
+            (10, None, None),
+            (11, None, None),
+        ]
+    );
+}
+
+#[test]
+fn indexed_reader_fetch_chunks_raw() {
+    use bam::index::{Chunk, VirtualOffset};
+
+    let header = single_ref_header();
+    let records: Vec<_> = (0..6)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 100 * i))
+        .collect();
+    let mut offsets = Vec::new();
+    let mut raw = Vec::new();
+    header.write_bam(&mut raw).unwrap();
+    for record in &records {
+        offsets.push(raw.len() as u16);
+        record.write_bam(&mut raw).unwrap();
+    }
+    offsets.push(raw.len() as u16);
+
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let chunk = |i: usize, j: usize| {
+        Chunk::new(VirtualOffset::new(0, offsets[i]), VirtualOffset::new(0, offsets[j]))
+    };
+    // Unsorted and adjacent chunks covering records 1-4.
+    let chunks = vec![chunk(3, 5), chunk(1, 2), chunk(2, 3)];
+    let names: Vec<_> = reader
+        .fetch_chunks_raw(chunks.clone(), &bam::Region::new(0, 150, 1000))
+        .unwrap()
+        .map(|record| record.unwrap().name().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"r2".to_vec(), b"r3".to_vec(), b"r4".to_vec()]);
+    assert!(reader
+        .fetch_chunks_raw(chunks, &bam::Region::new(1, 0, 10))
+        .is_err());
+}
+
+#[test]
+fn header_text_verbatim() {
+    let text = "@HD\tVN:1.6\tSO:coordinate\r\n@SQ\tSN:chr1\tLN:1000\tM5:abc\n\n@CO\tx  \n\0\0";
+    let mut raw = b"BAM\x01".to_vec();
+    raw.extend_from_slice(&(text.len() as i32).to_le_bytes());
+    raw.extend_from_slice(text.as_bytes());
+    raw.extend_from_slice(&1_i32.to_le_bytes());
+    raw.extend_from_slice(&5_i32.to_le_bytes());
+    raw.extend_from_slice(b"chr1\0");
+    raw.extend_from_slice(&1000_i32.to_le_bytes());
+
+    let header = bam::Header::from_bam(&mut &raw[..]).unwrap();
+    assert_eq!(header.text(), text);
+    assert_eq!(header.reference_name(0), Some("chr1"));
+    assert_eq!(header.clone().text(), text);
+    assert_eq!(bam::Header::new().text(), "");
+}
+
+/// Starts an HTTP server on a local port, that serves `contents` with range requests,
+/// and returns the url.
+#[cfg(feature = "http")]
+fn serve_http(contents: Vec<u8>) -> String {
+    serve_http_with(contents, |start, end| (start, end)).0
+}
+
+/// Starts an HTTP server on a local port, that serves `contents` at `/file` with range requests,
+/// and redirects `/old` to `/file`. Returns the url of `/file` and the number of accepted
+/// connections.
+///
+/// The response body contains the requested bytes, but `Content-Range` contains the range
+/// returned by `content_range(start, end)` for the requested range `start-end`.
+#[cfg(feature = "http")]
+fn serve_http_with<F>(
+    contents: Vec<u8>,
+    content_range: F,
+) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>)
+where
+    F: Fn(usize, usize) -> (usize, usize) + Send + Sync + 'static,
+{
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/file", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    let contents = Arc::new(contents);
+    let content_range = Arc::new(content_range);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let contents = contents.clone();
+            let content_range = content_range.clone();
+            // Serves requests over the same connection until the client closes it.
+            std::thread::spawn(move || loop {
+                let mut request = Vec::new();
+                let mut buf = [0_u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(count) => request.extend_from_slice(&buf[..count]),
+                    }
+                }
+                let request = String::from_utf8(request).unwrap();
+                if request.starts_with("GET /old ") {
+                    let response =
+                        "HTTP/1.1 302 Found\r\nLocation: /file\r\nContent-Length: 0\r\n\r\n";
+                    stream.write_all(response.as_bytes()).unwrap();
+                    continue;
+                }
+                let range = request
+                    .lines()
+                    .find(|line| line.to_ascii_lowercase().starts_with("range: bytes="))
+                    .unwrap()["Range: bytes=".len()..]
+                    .to_string();
+                let mut split = range.split('-');
+                let start: usize = split.next().unwrap().parse().unwrap();
+                let end: usize = split.next().unwrap().parse().unwrap();
+                let end = std::cmp::min(end + 1, contents.len());
+                let (range_start, range_end) = content_range(start, end - 1);
+                write!(
+                    stream,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n\
+                    Content-Length: {}\r\n\r\n",
+                    range_start,
+                    range_end,
+                    contents.len(),
+                    end - start
+                )
+                .unwrap();
+                stream.write_all(&contents[start..end]).unwrap();
+            });
+        }
+    });
+    (url, connections)
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn remote_reader() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let data: Vec<u8> = (0..10_000_u32).map(|i| (i % 251) as u8).collect();
+    let url = serve_http(data.clone());
+    let mut reader = bam::remote::RemoteReader::with_cache(&url, 1000, 2).unwrap();
+    assert_eq!(reader.len(), data.len() as u64);
+    let mut buf = vec![0_u8; 2500];
+    reader.seek(SeekFrom::Start(1500)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert!(buf[..] == data[1500..4000]);
+    reader.seek(SeekFrom::End(-100)).unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest[..] == data[9900..]);
+    assert!(bam::remote::RemoteReader::new("ftp://localhost/file").is_err());
+
+    // Redirects are followed, and all requests use the same connection.
+    let (url, connections) = serve_http_with(data.clone(), |start, end| (start, end));
+    let mut reader = bam::remote::RemoteReader::with_cache(&url.replace("/file", "/old"), 1000, 2)
+        .unwrap();
+    let mut all = Vec::new();
+    reader.read_to_end(&mut all).unwrap();
+    assert!(all == data);
+    assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // Responses with a range other than the requested one are rejected.
+    let (url, _) = serve_http_with(data.clone(), |start, end| (start + 1, end));
+    let err = bam::remote::RemoteReader::with_cache(&url, 1000, 2).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let (url, _) = serve_http_with(data.clone(), |start, end| (start, end - 1));
+    let err = bam::remote::RemoteReader::with_cache(&url, 1000, 2).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let header = single_ref_header();
+    let records: Vec<_> = (0..50)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 10 * i))
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let bam_stream = bam::remote::RemoteReader::with_cache(&serve_http(bam), 512, 4).unwrap();
+    let bai_stream = bam::remote::RemoteReader::new(&serve_http(bai)).unwrap();
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(bam_stream, bai_stream)
+        .unwrap();
+    assert_eq!(reader.fetch(&bam::Region::new(0, 100, 200)).unwrap().count(), 10);
+}
+
+#[test]
+fn record_reference_span() {
+    let mut record = mapped_record("r1", 0, 100);
+    for &(cigar, span) in &[
+        ("4M", 4),
+        ("2S2M", 2),
+        ("1M2I1M", 2),
+        ("2M5D2M", 9),
+        ("1=10N1X2H2M", 14),
+        ("3I1M", 1),
+    ] {
+        record.set_cigar(cigar.bytes()).unwrap();
+        assert_eq!(record.reference_span(), span, "CIGAR {}", cigar);
+        assert_eq!(record.calculate_end() - record.start(), span as i32);
+    }
+    record.flag_mut().set_mapped(false);
+    assert_eq!(record.reference_span(), 0);
+    assert_eq!(bam::Record::new().reference_span(), 0);
+}
+
+#[test]
+fn bam_reader_invalid_ref() {
+    use bam::bam_reader::InvalidRef;
+
+    let mut with_bad_mate = mapped_record("r2", 0, 20);
+    with_bad_mate.set_mate_ref_id(3);
+    let records = vec![
+        mapped_record("r1", 0, 10),
+        with_bad_mate,
+        mapped_record("r3", 2, 30),
+        mapped_record("r4", 0, 40),
+    ];
+    let mut raw = Vec::new();
+    for record in &records {
+        record.write_bam(&mut raw).unwrap();
+    }
+    let stream = compress_raw_records(&raw);
+    let reader = |policy| {
+        let mut reader =
+            bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0).unwrap();
+        reader.on_invalid_ref(policy);
+        reader
+    };
+
+    let names = |reader: bam::BamReader<&[u8]>| -> Vec<Vec<u8>> {
+        reader.map(|record| record.unwrap().name().to_vec()).collect()
+    };
+    assert_eq!(names(reader(InvalidRef::Pass)).len(), 4);
+    assert_eq!(
+        names(reader(InvalidRef::Skip)),
+        vec![b"r1".to_vec(), b"r4".to_vec()]
+    );
+    let results: Vec<_> = reader(InvalidRef::Error).collect();
+    assert_eq!(results.len(), 4);
+    assert!(results[0].is_ok() && results[3].is_ok());
+    assert!(results[1].is_err() && results[2].is_err());
+}
+
+#[test]
+fn cigar_iter() {
+    use bam::record::cigar::Operation;
+
+    let mut record = mapped_record("r1", 0, 10);
+    record.set_cigar("2S3M1D1M".bytes()).unwrap();
+    let mut ops = Vec::new();
+    for (len, op) in record.cigar() {
+        ops.push((len, op));
+    }
+    assert_eq!(
+        ops,
+        vec![
+            (2, Operation::Soft),
+            (3, Operation::AlnMatch),
+            (1, Operation::Deletion),
+            (1, Operation::AlnMatch),
+        ]
+    );
+    let ref_len: u32 = record
+        .cigar()
+        .iter()
+        .filter(|(_, op)| op.consumes_ref())
+        .map(|(len, _)| len)
+        .sum();
+    assert_eq!(ref_len, record.cigar().calculate_ref_len());
+    assert_eq!(record.cigar().iter().rev().next(), Some((1, Operation::AlnMatch)));
+    assert_eq!(record.cigar().iter().len(), record.cigar().len());
+}
+
+#[test]
+fn stats_mean_quality_by_cycle() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\t#+5?\n\
+        r2\t16\tchr1\t3\t60\t4M\t*\t0\t0\tACGT\t#+5?\n\
+        r3\t0\tchr1\t5\t60\t2M\t*\t0\t0\tAC\t?5\n\
+        r4\t0\tchr1\t10\t60\t2M\t*\t0\t0\tAC\t*\n\
+        r5\t0\tchr1\t20\t60\t6M\t*\t0\t0\tACGTAC\t??????\n";
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let means = bam::stats::mean_quality_by_cycle(&mut reader).unwrap();
+    // Qualities: # = 2, + = 10, 5 = 20, ? = 30.
+    let expected = [
+        (2.0 + 30.0 + 30.0 + 30.0) / 4.0,
+        (10.0 + 20.0 + 20.0 + 30.0) / 4.0,
+        (20.0 + 10.0 + 30.0) / 3.0,
+        (30.0 + 2.0 + 30.0) / 3.0,
+        30.0,
+        30.0,
+    ];
+    assert_eq!(means.len(), expected.len());
+    for (mean, exp) in means.iter().zip(&expected) {
+        assert!((mean - exp).abs() < 1e-9, "{:?} != {:?}", means, expected);
+    }
+}
+
+#[test]
+fn write_sam_missing_seq_qual() {
+    let header = single_ref_header();
+    let sam_columns = |record: &bam::Record| -> Vec<String> {
+        let mut line = Vec::new();
+        record.write_sam(&mut line, &header).unwrap();
+        String::from_utf8(line)
+            .unwrap()
+            .trim_end()
+            .split('\t')
+            .map(str::to_string)
+            .collect()
+    };
+
+    let mut record = mapped_record("r1", 0, 10);
+    record
+        .set_seq_qual("ACGT".bytes(), std::iter::repeat(0xff).take(4))
+        .unwrap();
+    assert!(!record.qualities().available());
+    assert_eq!(&sam_columns(&record)[9..11], &["ACGT", "*"]);
+
+    // Same after BAM round trip, where missing qualities are stored as 0xff.
+    let mut raw = Vec::new();
+    record.write_bam(&mut raw).unwrap();
+    let stream = compress_raw_records(&raw);
+    let mut reader =
+        bam::BamReader::from_stream_no_header(&stream[..], single_ref_header(), 0).unwrap();
+    let mut record = Iterator::next(&mut reader).unwrap().unwrap();
+    assert_eq!(&sam_columns(&record)[9..11], &["ACGT", "*"]);
+
+    record.set_seq_qual(std::iter::empty(), std::iter::empty()).unwrap();
+    assert_eq!(&sam_columns(&record)[9..11], &["*", "*"]);
+}
+
+#[test]
+fn region_viewer_clusters() {
+    let header = two_ref_header();
+    let mut long_record = mapped_record("r2", 0, 102);
+    long_record.set_cigar("2M10D2M".bytes()).unwrap();
+    let records = vec![
+        mapped_record("r1", 0, 100),
+        long_record,
+        mapped_record("r3", 0, 110),
+        mapped_record("r4", 0, 116),
+        mapped_record("r5", 0, 200),
+        mapped_record("r6", 1, 200),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let mut viewer = reader.full();
+    let clusters: Vec<Vec<Vec<u8>>> = viewer
+        .clusters()
+        .map(|cluster| {
+            cluster
+                .unwrap()
+                .iter()
+                .map(|record| record.name().to_vec())
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        clusters,
+        vec![
+            vec![b"r1".to_vec(), b"r2".to_vec(), b"r3".to_vec()],
+            vec![b"r4".to_vec()],
+            vec![b"r5".to_vec()],
+            vec![b"r6".to_vec()],
+        ]
+    );
+}
+
+#[test]
+fn indexed_reader_reuse_buffer() {
+    let header = single_ref_header();
+    let records: Vec<_> = (0..10)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 50 * i))
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+
+    let mut buffer = Some(bam::bgzip::Block::new());
+    for _ in 0..3 {
+        let mut builder = bam::IndexedReader::build();
+        builder.with_buffer(buffer.take().unwrap());
+        let mut reader = builder
+            .from_streams(std::io::Cursor::new(bam.clone()), &bai[..])
+            .unwrap();
+        assert_eq!(reader.fetch(&bam::Region::new(0, 100, 300)).unwrap().count(), 4);
+        buffer = reader.into_buffer();
+        assert!(buffer.is_some());
+    }
+
+    let mut reader = bam::IndexedReader::build()
+        .additional_threads(1)
+        .with_buffer(buffer.take().unwrap())
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    assert_eq!(reader.fetch(&bam::Region::new(0, 100, 300)).unwrap().count(), 4);
+    assert!(reader.into_buffer().is_none());
+}
+
+#[test]
+fn stats_gc_content() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tGCAT\t*\n\
+        r2\t0\tchr1\t3\t60\t2S2M2I1D2M1H\t*\t0\t0\tGGATGGCN\t*\n\
+        r3\t4\t*\t0\t0\t*\t*\t0\t0\tGGGG\t*\n\
+        r4\t0\tchr1\t5\t60\t3=1X\t*\t0\t0\tCCNA\t*\n";
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let (gc, total) = bam::stats::gc_content(&mut reader).unwrap();
+    // r1: GCAT -> 2/4, r2: aligned AT and CN -> 1/3, r4: CC_A -> 2/3.
+    assert_eq!((gc, total), (5, 10));
+}
+
+#[test]
+fn indexed_reader_fetch_filtered() {
+    use bam::record::{
+        PCR_OR_OPTICAL_DUPLICATE, RECORD_FAILS_QC, RECORD_REVERSE_STRAND, SECONDARY,
+        SUPPLEMENTARY,
+    };
+
+    let header = single_ref_header();
+    let flags = [
+        0,
+        SECONDARY,
+        RECORD_FAILS_QC,
+        PCR_OR_OPTICAL_DUPLICATE,
+        SUPPLEMENTARY,
+        RECORD_REVERSE_STRAND,
+    ];
+    let records: Vec<_> = flags
+        .iter()
+        .enumerate()
+        .map(|(i, &flag)| {
+            let mut record = mapped_record(&format!("r{}", i), 0, 10 * i as i32);
+            record.set_flag(flag);
+            record
+        })
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let names: Vec<_> = reader
+        .fetch_filtered(0, 0, 1000)
+        .unwrap()
+        .map(|record| record.unwrap().name().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"r0".to_vec(), b"r5".to_vec()]);
+    assert_eq!(reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().count(), 6);
+    assert_eq!(bam::bam_reader::DEFAULT_EXCLUDED_FLAGS, 0xf04);
+}
+
+fn random_record<R: Rng>(rng: &mut R) -> bam::Record {
+    let mut record = bam::Record::new();
+    let name_len = rng.gen_range(1, 30);
+    record.set_name((0..name_len).map(|_| rng.gen_range(b'!', b'~' + 1)));
+    record.set_mapq(rng.gen());
+    record.set_template_len(rng.gen_range(-1000, 1000));
+
+    let mut cigar = String::new();
+    if rng.gen_bool(0.2) {
+        record.flag_mut().set_mapped(false);
+    } else {
+        record.set_ref_id(rng.gen_range(0, 2));
+        record.set_start(rng.gen_range(0, 900));
+        for _ in 0..rng.gen_range(1, 6) {
+            let op = ['M', 'I', 'D', 'S', '=', 'X'][rng.gen_range(0, 6)];
+            cigar.push_str(&format!("{}{}", rng.gen_range(1, 20), op));
+        }
+    }
+    record.set_cigar(cigar.bytes()).unwrap();
+
+    let seq_len = if cigar.is_empty() {
+        rng.gen_range(0, 50)
+    } else {
+        record.cigar().calculate_query_len() as usize
+    };
+    let seq: Vec<u8> = (0..seq_len).map(|_| b"ACGTN"[rng.gen_range(0, 5)]).collect();
+    if rng.gen_bool(0.8) {
+        let qual: Vec<u8> = (0..seq_len).map(|_| rng.gen_range(0, 60)).collect();
+        record.set_seq_qual(seq, qual).unwrap();
+    } else {
+        record.set_seq_qual(seq, std::iter::empty()).unwrap();
+    }
+
+    if rng.gen_bool(0.5) {
+        record.tags_mut().push_num(b"NM", rng.gen::<u16>());
+    }
+    if rng.gen_bool(0.5) {
+        record.tags_mut().push_string(b"XS", b"value");
+    }
+    record
+}
+
+#[test]
+fn record_bytes_round_trip() {
+    let header = two_ref_header();
+    let mut rng = rand::thread_rng();
+    for _ in 0..1000 {
+        let record = random_record(&mut rng);
+        let bytes = record.to_bytes();
+        let restored = bam::Record::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.to_bytes(), bytes);
+
+        let mut sam1 = Vec::new();
+        let mut sam2 = Vec::new();
+        record.write_sam(&mut sam1, &header).unwrap();
+        restored.write_sam(&mut sam2, &header).unwrap();
+        assert_eq!(sam1, sam2);
+    }
+
+    let bytes = mapped_record("r1", 0, 10).to_bytes();
+    assert!(bam::Record::from_bytes(&[]).is_err());
+    assert!(bam::Record::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    let mut extended = bytes.clone();
+    extended.push(0);
+    assert!(bam::Record::from_bytes(&extended).is_err());
+}
+
+#[test]
+fn pileup_at_positions() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t3M2I3M\t*\t0\t0\tACGTTACG\t*\n\
+        r2\t0\tchr1\t3\t60\t2M2D2M\t*\t0\t0\tGTAC\t*\n\
+        r3\t0\tchr1\t20\t60\t4M\t*\t0\t0\tTTTT\t*\n\
+        r4\t0\tchr2\t5\t60\t3M\t*\t0\t0\tCCC\t*\n";
+    let summarize = |column: std::io::Result<bam::pileup::PileupColumn>| {
+        let mut column = column.unwrap();
+        column.sort();
+        let names: Vec<_> = column
+            .entries()
+            .iter()
+            .map(|entry| entry.record().name().to_vec())
+            .collect();
+        (column.ref_id(), column.ref_pos(), names, column.base_counts())
+    };
+
+    let positions = vec![(0, 0), (0, 3), (0, 5), (0, 10), (0, 21), (1, 0), (1, 6), (1, 500)];
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let expected: Vec<_> = bam::Pileup::new(&mut reader)
+        .map(summarize)
+        .filter(|(ref_id, ref_pos, _, _)| positions.contains(&(*ref_id, *ref_pos)))
+        .collect();
+    assert_eq!(expected.len(), 5);
+
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let observed: Vec<_> = bam::Pileup::at_positions(&mut reader, positions)
+        .unwrap()
+        .map(summarize)
+        .collect();
+    assert_eq!(observed, expected);
+
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    assert!(bam::Pileup::at_positions(&mut reader, vec![(0, 5), (0, 3)]).is_err());
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    assert!(bam::Pileup::at_positions(&mut reader, vec![(1, 5), (0, 10)]).is_err());
+}
+
+#[test]
+fn index_linear_offsets() {
+    use bam::index::VirtualOffset;
+
+    let first = VirtualOffset::new(100, 5).raw();
+    let second = VirtualOffset::new(2500, 0).raw();
+    let mut bai = b"BAI\x01".to_vec();
+    bai.extend_from_slice(&2_i32.to_le_bytes());
+    bai.extend_from_slice(&0_i32.to_le_bytes());
+    bai.extend_from_slice(&3_i32.to_le_bytes());
+    for offset in &[first, first, second] {
+        bai.extend_from_slice(&offset.to_le_bytes());
+    }
+    bai.extend_from_slice(&0_i32.to_le_bytes());
+    bai.extend_from_slice(&0_i32.to_le_bytes());
+
+    let index = bam::index::Index::from_stream(&bai[..]).unwrap();
+    assert_eq!(index.linear_offsets(0), &[first, first, second]);
+    assert_eq!(index.references()[0].linear_index().intervals().len(), 2);
+    assert_eq!(
+        VirtualOffset::from_raw(index.linear_offsets(0)[2]).block_offset(),
+        2500
+    );
+    assert!(index.linear_offsets(1).is_empty());
+}
+
+#[test]
+fn record_pos1_end1() {
+    let header = single_ref_header();
+    let sam_pos = |record: &bam::Record| -> i64 {
+        let mut sam = Vec::new();
+        record.write_sam(&mut sam, &header).unwrap();
+        let line = String::from_utf8(sam).unwrap();
+        line.split('\t').nth(3).unwrap().parse().unwrap()
+    };
+
+    let mut record = mapped_record("r1", 0, 0);
+    assert_eq!((record.pos1(), record.end1()), (1, 4));
+    assert_eq!(record.pos1(), sam_pos(&record));
+
+    record = mapped_record("r2", 0, 99);
+    record.set_cigar("2M3D2M".bytes()).unwrap();
+    assert_eq!((record.pos1(), record.end1()), (100, 106));
+    assert_eq!(record.pos1(), sam_pos(&record));
+
+    let mut unmapped = bam::Record::new();
+    unmapped.set_name("r3".bytes());
+    unmapped.flag_mut().set_mapped(false);
+    assert_eq!((unmapped.pos1(), unmapped.end1()), (0, 0));
+    assert_eq!(unmapped.pos1(), sam_pos(&unmapped));
+}
+
+#[test]
+fn record_flag_strings() {
+    use bam::record::{flag_from_string, flag_to_string};
+
+    assert_eq!(flag_to_string(99), "PAIRED,PROPER_PAIR,MREVERSE,READ1");
+    assert_eq!(flag_to_string(0), "");
+    assert_eq!(flag_to_string(0x1804), "UNMAP,SUPPLEMENTARY,0x1000");
+    assert_eq!(
+        flag_from_string("PAIRED,PROPER_PAIR,MREVERSE,READ1"),
+        Ok(99)
+    );
+    assert_eq!(flag_from_string("paired, read1"), Ok(65));
+    assert_eq!(flag_from_string("0x41"), Ok(65));
+    assert_eq!(flag_from_string("64,PAIRED"), Ok(65));
+    assert_eq!(flag_from_string(""), Ok(0));
+    assert!(flag_from_string("PAIRED,UNKNOWN").is_err());
+    assert!(flag_from_string("0x10000").is_err());
+
+    for flag in 0..=std::u16::MAX {
+        assert_eq!(flag_from_string(&flag_to_string(flag)), Ok(flag));
+    }
+}
+
+#[test]
+fn pileup_skip_deletions() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t2M2D2M\t*\t0\t0\tACGT\t*\n\
+        r2\t0\tchr1\t2\t60\t2M\t*\t0\t0\tCA\t*\n";
+    let depths = |skip: bool| -> Vec<(u32, usize)> {
+        let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+        let mut pileup = bam::Pileup::new(&mut reader);
+        pileup.skip_deletions(skip);
+        pileup
+            .map(|column| {
+                let column = column.unwrap();
+                (column.ref_pos(), column.entries().len())
+            })
+            .collect()
+    };
+    assert_eq!(
+        depths(false),
+        vec![(0, 1), (1, 2), (2, 2), (3, 1), (4, 1), (5, 1)]
+    );
+    assert_eq!(depths(true), vec![(0, 1), (1, 2), (2, 1), (4, 1), (5, 1)]);
+}
+
+#[test]
+fn indexed_reader_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<bam::IndexedReader<std::io::BufReader<File>>>();
+    assert_send::<bam::bam_reader::RegionViewer<std::io::BufReader<File>>>();
+    assert_send::<bam::bam_reader::IndexedReaderBuilder>();
+    assert_send::<bam::BamReader<File>>();
+
+    let header = single_ref_header();
+    let records: Vec<_> = (0..5)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 100 * i))
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut builder = bam::IndexedReader::build();
+    builder.modification_time(bam::bam_reader::ModificationTime::warn(|e| eprintln!("{}", e)));
+    let mut reader = builder
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let handle = std::thread::spawn(move || {
+        let viewer = reader
+            .fetch_by(&bam::Region::new(0, 0, 1000), |record| record.start() >= 200)
+            .unwrap();
+        std::thread::scope(|scope| scope.spawn(move || viewer.count()).join().unwrap())
+    });
+    assert_eq!(handle.join().unwrap(), 3);
+}
+
+#[test]
+fn record_clip_reference_ends() {
+    let mut record = mapped_record("r1", 0, 10);
+    assert_eq!(record.clip_reference_ends(), (false, false));
+
+    record.set_cigar("1S3M".bytes()).unwrap();
+    assert_eq!(record.clip_reference_ends(), (true, false));
+    record.flag_mut().set_strand(false);
+    assert_eq!(record.clip_reference_ends(), (false, true));
+
+    record.set_cigar("5H4M".bytes()).unwrap();
+    assert_eq!(record.clip_reference_ends(), (false, true));
+    record.set_cigar("3H1S2M1S".bytes()).unwrap();
+    assert_eq!(record.clip_reference_ends(), (true, true));
+
+    record.flag_mut().set_mapped(false);
+    assert_eq!(record.clip_reference_ends(), (false, false));
+}
+
+#[test]
+fn bam_reader_skip_errors() {
+    let header = single_ref_header();
+    let mut raw_header = Vec::new();
+    header.write_bam(&mut raw_header).unwrap();
+    let raw_records = |range: std::ops::Range<i32>| {
+        let mut raw = Vec::new();
+        for i in range {
+            mapped_record(&format!("r{}", i), 0, 10 * i)
+                .write_bam(&mut raw)
+                .unwrap();
+        }
+        raw
+    };
+    // Compresses records into a separate block, without the empty blocks at the end.
+    let eof_len = compress_raw_records(&[]).len();
+    let block = |raw: &[u8]| {
+        let mut compressed = compress_raw_records(raw);
+        compressed.truncate(compressed.len() - eof_len);
+        compressed
+    };
+    let read_names = |mut bam: Vec<u8>| {
+        bam.extend(compress_raw_records(&[]));
+        let reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+        let mut n_errors = 0;
+        let names: Vec<_> = reader
+            .skip_errors(|_| n_errors += 1)
+            .map(|record| String::from_utf8(record.name().to_vec()).unwrap())
+            .collect();
+        (names, n_errors)
+    };
+
+    // Record r4 in the third block has a negative block size.
+    let mut corrupted = raw_records(3..4);
+    let r4_start = corrupted.len();
+    corrupted.extend(raw_records(4..6));
+    corrupted[r4_start + 3] = 0xff;
+    let mut bam = block(&raw_header);
+    bam.extend(block(&raw_records(0..3)));
+    bam.extend(block(&corrupted));
+    bam.extend(block(&raw_records(6..8)));
+    let (names, n_errors) = read_names(bam);
+    assert_eq!(names, vec!["r0", "r1", "r2", "r3", "r6", "r7"]);
+    assert_eq!(n_errors, 1);
+
+    // The third block cannot be decompressed.
+    let mut bam = block(&raw_header);
+    bam.extend(block(&raw_records(0..3)));
+    let block_start = bam.len();
+    bam.extend(block(&raw_records(3..6)));
+    bam[block_start + 30] ^= 0xff;
+    bam.extend(block(&raw_records(6..8)));
+    let (names, n_errors) = read_names(bam);
+    assert_eq!(names, vec!["r0", "r1", "r2", "r6", "r7"]);
+    assert!(n_errors >= 1);
+}
+
+#[test]
+fn pileup_cap_qualities_near_indels() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t3M2I3M\t*\t0\t0\tACGTTACG\tIIIIIIII\n\
+        r2\t0\tchr1\t11\t60\t2M2D2M\t*\t0\t0\tGTAC\tIIII\n\
+        r3\t0\tchr1\t21\t60\t2M\t*\t0\t0\tGT\t*\n";
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let mut pileup = bam::Pileup::new(&mut reader);
+    pileup.cap_qualities_near_indels(1, 10);
+    let qualities: Vec<_> = pileup
+        .map(|column| {
+            let column = column.unwrap();
+            let entry = &column.entries()[0];
+            (column.ref_pos(), entry.baq_qualities().map(|qual| qual.to_vec()))
+        })
+        .collect();
+    assert_eq!(
+        qualities,
+        vec![
+            (0, Some(vec![40])),
+            (1, Some(vec![40])),
+            (2, Some(vec![10, 10, 10])),
+            (3, Some(vec![10])),
+            (4, Some(vec![40])),
+            (5, Some(vec![40])),
+            (10, Some(vec![40])),
+            (11, Some(vec![10])),
+            (12, Some(vec![])),
+            (13, Some(vec![])),
+            (14, Some(vec![10])),
+            (15, Some(vec![40])),
+            (20, None),
+            (21, None),
+        ]
+    );
+
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    assert!(bam::Pileup::new(&mut reader)
+        .all(|column| column.unwrap().entries()[0].baq_qualities().is_none()));
+}
+
+#[test]
+fn record_expected_bin() {
+    let record = mapped_record("r1", 0, 100);
+    assert_eq!(record.bin(), None);
+    assert_eq!(record.expected_bin(), 4681);
+    let mut bytes = record.to_bytes();
+    assert_eq!(bam::Record::from_bytes(&bytes).unwrap().bin(), Some(4681));
+
+    bytes[14..16].copy_from_slice(&4682_u16.to_le_bytes());
+    let tampered = bam::Record::from_bytes(&bytes).unwrap();
+    assert_eq!(tampered.bin(), Some(4682));
+    assert_eq!(tampered.expected_bin(), 4681);
+    assert!(tampered.validate(&single_ref_header()).is_err());
+
+    let mut long = mapped_record("r2", 0, 16382);
+    long.set_cigar("4M".bytes()).unwrap();
+    assert_eq!(long.expected_bin(), 585);
+    let mut unmapped = mapped_record("r3", 0, 100);
+    unmapped.flag_mut().set_mapped(false);
+    unmapped.set_cigar(std::iter::empty()).unwrap();
+    assert_eq!(unmapped.expected_bin(), 4681);
+    assert_eq!(unmapped.calculate_bin(), 4681);
+    assert_eq!(bam::Record::new().expected_bin(), 4680);
+}
+
+#[test]
+fn sorting_writer() {
+    let header = two_ref_header();
+    let mut rng = rand::thread_rng();
+    let records: Vec<_> = (0..500).map(|_| random_record(&mut rng)).collect();
+    let key = |record: &bam::Record| {
+        (
+            record.ref_id() as u32,
+            record.start(),
+            record.flag().is_reverse_strand(),
+        )
+    };
+    let mut expected: Vec<_> = records.iter().collect();
+    expected.sort_by_key(|record| key(record));
+    let expected: Vec<_> = expected.iter().map(|record| record.to_bytes()).collect();
+
+    let tmp_dir = std::env::temp_dir().join(format!("bam_sorting_writer_{}", std::process::id()));
+    std::fs::create_dir_all(&tmp_dir).unwrap();
+    for &memory_limit in &[1 << 30, 4096] {
+        let mut output = Vec::new();
+        let mut writer = bam::bam_writer::SortingWriter::build()
+            .memory_limit(memory_limit)
+            .tmp_dir(&tmp_dir)
+            .from_stream(&mut output, header.clone())
+            .unwrap();
+        for record in records.iter() {
+            writer.write(record).unwrap();
+        }
+        if memory_limit == 4096 {
+            assert!(std::fs::read_dir(&tmp_dir).unwrap().count() > 1);
+        }
+        writer.finish().unwrap();
+        std::mem::drop(writer);
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+        let reader = bam::BamReader::from_stream(&output[..], 0).unwrap();
+        let observed: Vec<_> = reader.map(|record| record.unwrap().to_bytes()).collect();
+        assert_eq!(observed, expected);
+    }
+    std::fs::remove_dir(&tmp_dir).unwrap();
+}
+
+#[test]
+fn bytes_processed() {
+    let header = single_ref_header();
+    let records: Vec<_> = (0..5)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 100 * i))
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+
+    let mut reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    let after_header = reader.bytes_processed();
+    assert!(after_header > 0);
+    assert_eq!(reader.by_ref().count(), 5);
+    assert!(reader.bytes_processed() > after_header);
+    assert!(reader.bytes_processed() <= bam.len() as u64);
+
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam.clone()), std::io::Cursor::new(bai))
+        .unwrap();
+    let after_header = reader.bytes_processed();
+    assert_eq!(reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().count(), 5);
+    let after_fetch = reader.bytes_processed();
+    assert!(after_fetch > after_header);
+    // The second fetch reads the same blocks again.
+    assert_eq!(reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().count(), 5);
+    assert_eq!(reader.bytes_processed() - after_fetch, after_fetch - after_header);
+}
+
+#[test]
+fn open_sam_or_bam() {
+    let header = single_ref_header();
+    let records: Vec<_> = (0..3)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 10 * i))
+        .collect();
+    let dir = std::env::temp_dir();
+    let bam_path = dir.join(format!("bam_crate_open_{}.bam", std::process::id()));
+    let sam_path = dir.join(format!("bam_crate_open_{}.sam", std::process::id()));
+    let raw_path = dir.join(format!("bam_crate_open_{}.raw.bam", std::process::id()));
+    let (bam, _) = indexed_test_bam(&header, &records);
+    std::fs::write(&bam_path, &bam).unwrap();
+    let mut writer = bam::SamWriter::from_path(&sam_path, header.clone()).unwrap();
+    for record in records.iter() {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    let mut raw = Vec::new();
+    header.write_bam(&mut raw).unwrap();
+    for record in records.iter() {
+        record.write_bam(&mut raw).unwrap();
+    }
+    std::fs::write(&raw_path, &raw).unwrap();
+
+    for path in [&bam_path, &sam_path, &raw_path] {
+        let (opened_header, reader) = bam::open(path).unwrap();
+        assert_eq!(opened_header.reference_names(), header.reference_names());
+        let names: Vec<_> = reader.map(|record| record.unwrap().name().to_vec()).collect();
+        assert_eq!(names, vec![b"r0".to_vec(), b"r1".to_vec(), b"r2".to_vec()]);
+    }
+
+    // Truncated uncompressed header.
+    std::fs::write(&raw_path, b"BAM\x01").unwrap();
+    assert!(bam::open(&raw_path).is_err());
+    std::fs::remove_file(&bam_path).unwrap();
+    std::fs::remove_file(&sam_path).unwrap();
+    std::fs::remove_file(&raw_path).unwrap();
+}
+
+#[test]
+fn record_full_query_from_sa() {
+    let header = two_ref_header();
+    // Full read: ACGTTTGACC.
+    let mut primary = mapped_record("r1", 0, 100);
+    primary.set_cigar("6M4H".bytes()).unwrap();
+    primary.set_seq_qual("ACGTTT".bytes(), [30_u8; 6].iter().cloned()).unwrap();
+    let mut supplementary = mapped_record("r1", 0, 500);
+    supplementary.flag_mut().set_supplementary(true);
+    supplementary.set_cigar("6H4M".bytes()).unwrap();
+    supplementary.set_seq_qual("GACC".bytes(), [30_u8; 4].iter().cloned()).unwrap();
+    assert_eq!(supplementary.full_query_from_sa(&primary, &header), None);
+
+    supplementary.tags_mut().push_string(b"SA", b"chr1,101,+,6M4H,60,0;");
+    assert_eq!(
+        supplementary.full_query_from_sa(&primary, &header),
+        Some(b"ACGTTTGACC".to_vec())
+    );
+
+    // Primary alignment at the same position and strand of another reference.
+    primary.set_ref_id(1);
+    assert_eq!(supplementary.full_query_from_sa(&primary, &header), None);
+    primary.set_ref_id(0);
+
+    // Supplementary alignment on the reverse strand.
+    supplementary.flag_mut().set_strand(false);
+    supplementary.set_cigar("4M6H".bytes()).unwrap();
+    supplementary.set_seq_qual("GGTC".bytes(), [30_u8; 4].iter().cloned()).unwrap();
+    assert_eq!(
+        supplementary.full_query_from_sa(&primary, &header),
+        Some(b"ACGTTTGACC".to_vec())
+    );
+
+    // The records do not cover the whole read.
+    supplementary.set_cigar("3M7H".bytes()).unwrap();
+    supplementary.set_seq_qual("GTC".bytes(), [30_u8; 3].iter().cloned()).unwrap();
+    assert_eq!(supplementary.full_query_from_sa(&primary, &header), None);
+    // Wrong strand in the SA tag.
+    primary.flag_mut().set_strand(false);
+    assert_eq!(supplementary.full_query_from_sa(&primary, &header), None);
+}
+
+#[test]
+fn header_from_bam_prefix() {
+    let header = two_ref_header();
+    let mut raw_header = Vec::new();
+    header.write_bam(&mut raw_header).unwrap();
+    let eof_len = compress_raw_records(&[]).len();
+    let mut bam = compress_raw_records(&raw_header);
+    bam.truncate(bam.len() - eof_len);
+    let header_len = bam.len();
+    // Garbage instead of the records.
+    bam.extend(vec![0xab; 100]);
+
+    let mut stream = &bam[..];
+    let parsed = bam::Header::from_bam_prefix(&mut stream).unwrap();
+    assert_eq!(parsed.reference_names(), header.reference_names());
+    assert_eq!(parsed.reference_len(1), header.reference_len(1));
+    assert_eq!(stream.len(), bam.len() - header_len);
+
+    assert!(bam::Header::from_bam_prefix(&mut &bam[header_len..]).is_err());
+}
+
+#[test]
+fn record_content_eq() {
+    let mut record1 = mapped_record("r1", 0, 100);
+    record1.tags_mut().push_num(b"NM", 1_i32);
+    record1.tags_mut().push_string(b"MD", b"2A1");
+    record1.tags_mut().push_char(b"XT", b'U');
+    let mut record2 = mapped_record("r1", 0, 100);
+    record2.tags_mut().push_char(b"XT", b'U');
+    record2.tags_mut().push_num(b"NM", 1_i32);
+    record2.tags_mut().push_string(b"MD", b"2A1");
+    assert!(record1.content_eq(&record2));
+    assert!(record2.content_eq(&record1));
+
+    let mut record3 = record2.clone();
+    record3.tags_mut().remove(b"XT");
+    assert!(!record1.content_eq(&record3));
+    record3.tags_mut().push_char(b"XT", b'R');
+    assert!(!record1.content_eq(&record3));
+
+    let mut record4 = record2.clone();
+    record4.set_start(101);
+    assert!(!record1.content_eq(&record4));
+    let mut record5 = record2.clone();
+    record5.set_cigar("2M2S".bytes()).unwrap();
+    assert!(!record1.content_eq(&record5));
+}
+
+#[test]
+fn bam_reader_aligned_bases_per_ref() {
+    let header = two_ref_header();
+    let mut records = vec![
+        mapped_record("r1", 0, 100),
+        mapped_record("r2", 0, 200),
+        mapped_record("r3", 1, 50),
+        mapped_record("r4", 1, 60),
+        mapped_record("r5", 1, 70),
+    ];
+    records[1].set_cigar("2M3D2M".bytes()).unwrap();
+    records[3].flag_mut().set_secondary(true);
+    records[4].flag_mut().set_mapped(false);
+    let (bam, _) = indexed_test_bam(&header, &records);
+
+    let reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    assert_eq!(reader.aligned_bases_per_ref().unwrap(), vec![11, 4]);
+    let reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    assert_eq!(
+        reader.aligned_bases_per_ref_by(|_| true).unwrap(),
+        vec![11, 8]
+    );
+}
+
+#[test]
+fn pileup_entry_ref_pos() {
+    let sam = "@SQ\tSN:chr1\tLN:1000\n\
+        r1\t0\tchr1\t1\t60\t2M2D2M\t*\t0\t0\tACGT\t*\n\
+        r2\t0\tchr1\t3\t60\t2M\t*\t0\t0\tCA\t*\n";
+    let mut reader = bam::SamReader::from_stream(sam.as_bytes()).unwrap();
+    let entries: Vec<_> = bam::Pileup::new(&mut reader)
+        .flat_map(|column| column.unwrap().entries().to_vec())
+        .collect();
+    let positions: Vec<_> = entries.iter().map(|entry| entry.ref_pos()).collect();
+    assert_eq!(positions, vec![0, 1, 2, 2, 3, 3, 4, 5]);
+}
+
+#[test]
+fn virtual_indexed_reader() {
+    let header = two_ref_header();
+    let open = |records: &[bam::Record]| {
+        let (bam, bai) = indexed_test_bam(&header, records);
+        bam::IndexedReader::build()
+            .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+            .unwrap()
+    };
+    let reader1 = open(&[
+        mapped_record("a1", 0, 100),
+        mapped_record("a2", 0, 300),
+        mapped_record("a3", 1, 100),
+    ]);
+    let reader2 = open(&[
+        mapped_record("b1", 0, 50),
+        mapped_record("b2", 0, 300),
+        mapped_record("b3", 0, 400),
+    ]);
+    let mut reader =
+        bam::bam_reader::VirtualIndexedReader::from_readers(vec![reader1, reader2]).unwrap();
+    let names: Vec<_> = reader
+        .fetch(&bam::Region::new(0, 0, 350))
+        .unwrap()
+        .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+        .collect();
+    assert_eq!(names, vec!["b1", "a1", "a2", "b2"]);
+    let count = reader
+        .fetch_by(&bam::Region::new(0, 0, 1000), |record| record.start() >= 300)
+        .unwrap()
+        .count();
+    assert_eq!(count, 3);
+
+    let reader1 = open(&[mapped_record("a1", 0, 100)]);
+    let (bam, bai) = indexed_test_bam(&single_ref_header(), &[mapped_record("c1", 0, 100)]);
+    let reader3 = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+    let res = bam::bam_reader::VirtualIndexedReader::from_readers(vec![reader1, reader3]);
+    let err = res.err().unwrap().to_string();
+    assert!(err.starts_with("References of BAM file #1 do not match references of the first file"));
+    assert!(err.contains("Header contains 1 references, but 2 are expected"));
+}
+
+#[test]
+fn record_source_offset() {
+    use std::io::Read;
+
+    let header = single_ref_header();
+    let mut raw_header = Vec::new();
+    header.write_bam(&mut raw_header).unwrap();
+    let records: Vec<_> = (0..6)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 10 * i))
+        .collect();
+    let raw_records = |records: &[bam::Record]| {
+        let mut raw = Vec::new();
+        for record in records {
+            record.write_bam(&mut raw).unwrap();
+        }
+        raw
+    };
+    // Header and records are split into three blocks.
+    let eof_len = compress_raw_records(&[]).len();
+    let mut bam = Vec::new();
+    let mut block_starts = Vec::new();
+    for raw in [raw_header, raw_records(&records[..3]), raw_records(&records[3..])] {
+        block_starts.push(bam.len() as u64);
+        let mut compressed = compress_raw_records(&raw);
+        compressed.truncate(compressed.len() - eof_len);
+        bam.extend(compressed);
+    }
+    bam.extend(compress_raw_records(&[]));
+
+    let mut reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    assert!(Iterator::next(&mut reader).unwrap().unwrap().source_offset().is_none());
+    let mut reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    reader.capture_offsets(true);
+    let record_len = records[0].to_bytes().len() as u16;
+    assert_eq!(reader.peek_record_size().unwrap(), Some(u32::from(record_len) - 4));
+    let offsets: Vec<_> = reader
+        .map(|record| record.unwrap().source_offset().unwrap())
+        .collect();
+    let expected: Vec<_> = (0..6_u16)
+        .map(|i| {
+            bam::index::VirtualOffset::new(block_starts[1 + i as usize / 3], i % 3 * record_len)
+        })
+        .collect();
+    assert!(offsets == expected);
+
+    // Records can be read again from the offsets.
+    let mut seek_reader =
+        bam::bgzip::SeekReader::from_stream(std::io::Cursor::new(bam.clone()), 0).unwrap();
+    for (record, &offset) in records.iter().zip(&offsets) {
+        seek_reader.from_offset(offset);
+        let mut raw = vec![0_u8; record_len as usize];
+        seek_reader.read_exact(&mut raw).unwrap();
+        assert!(bam::Record::from_bytes(&raw).unwrap().content_eq(record));
+    }
+
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .capture_offsets(true)
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+    let offsets: Vec<_> = reader
+        .fetch(&bam::Region::new(0, 25, 1000))
+        .unwrap()
+        .map(|record| record.unwrap().source_offset().unwrap().raw())
+        .collect();
+    assert_eq!(offsets.len(), 3);
+    assert!(offsets.windows(2).all(|pair| pair[1] - pair[0] == u64::from(record_len)));
+}
+
+#[test]
+fn record_overlaps() {
+    let record = mapped_record("r1", 0, 100);
+    assert!(record.overlaps(0, 100, 101));
+    assert!(record.overlaps(0, 103, 200));
+    assert!(record.overlaps(0, 50, 101));
+    assert!(!record.overlaps(0, 104, 200));
+    assert!(!record.overlaps(0, 50, 100));
+    assert!(!record.overlaps(1, 0, 1000));
+
+    let mut unmapped = mapped_record("r2", 0, 100);
+    unmapped.flag_mut().set_mapped(false);
+    assert!(unmapped.overlaps(0, 100, 101));
+    assert!(!unmapped.overlaps(0, 50, 100));
+    assert!(!unmapped.overlaps(0, 101, 200));
+
+    // Same results as fetch.
+    let header = single_ref_header();
+    let records: Vec<_> = (0..20)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 3 * i))
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+    for &(start, end) in &[(0, 1), (5, 6), (10, 20), (31, 40), (57, 100)] {
+        let fetched: Vec<_> = reader
+            .fetch(&bam::Region::new(0, start, end))
+            .unwrap()
+            .map(|record| record.unwrap().name().to_vec())
+            .collect();
+        let expected: Vec<_> = records
+            .iter()
+            .filter(|record| record.overlaps(0, start, end))
+            .map(|record| record.name().to_vec())
+            .collect();
+        assert_eq!(fetched, expected);
+    }
+}
+
+#[test]
+fn indexed_reader_reference_dictionary() {
+    let records = vec![
+        mapped_record("r1", 0, 100),
+        mapped_record("r2", 1, 200),
+        mapped_record("r3", 1, 300),
+    ];
+    let mut raw_header = Vec::new();
+    bam::Header::new().write_bam(&mut raw_header).unwrap();
+    let (bam, bai) = indexed_test_bam_from_raw(raw_header, 2, &records);
+    let dictionary = vec![("chr1".to_string(), 1000), ("chr2".to_string(), 500)];
+
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam.clone()), std::io::Cursor::new(bai.clone()))
+        .unwrap();
+    assert!(reader.fetch(&bam::Region::new(1, 0, 500)).is_err());
+
+    let mut reader = bam::IndexedReader::build()
+        .reference_dictionary(dictionary.clone())
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+    assert_eq!(reader.header().reference_name(1), Some("chr2"));
+    assert_eq!(reader.fetch(&bam::Region::new(1, 0, 500)).unwrap().count(), 2);
+    assert!(reader.fetch(&bam::Region::new(1, 0, 501)).is_err());
+
+    // The dictionary should match non-empty header.
+    let (bam, bai) = indexed_test_bam(&two_ref_header(), &records);
+    let res = bam::IndexedReader::build()
+        .reference_dictionary(dictionary)
+        .from_streams(std::io::Cursor::new(bam.clone()), std::io::Cursor::new(bai.clone()));
+    let err = res.err().unwrap().to_string();
+    assert!(err.contains("Reference #1 (chr2) has length 1000 in the header, but 500 is expected"));
+    let res = bam::IndexedReader::build()
+        .reference_dictionary(vec![("chr1".to_string(), 1000), ("chr2".to_string(), 1000)])
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai));
+    assert!(res.is_ok());
+}
+
+#[test]
+fn pileup_random_coverage() {
+    let cigars = ["4M", "2M2D2M", "1S3M", "2M1I1M", "1M5D3M"];
+    let header = two_ref_header();
+    let mut rng = rand::thread_rng();
+    for _ in 0..20 {
+        let mut records = Vec::new();
+        for ref_id in 0..2 {
+            let mut start = 0;
+            for i in 0..rng.gen_range(0, 100) {
+                start += rng.gen_range(0, 8);
+                let mut record = mapped_record(&format!("r{}_{}", ref_id, i), ref_id, start);
+                record
+                    .set_cigar(cigars[rng.gen_range(0, cigars.len())].bytes())
+                    .unwrap();
+                records.push(record);
+            }
+        }
+        let mut expected: Vec<_> = records
+            .iter()
+            .flat_map(|record| {
+                (record.start()..record.calculate_end())
+                    .map(move |pos| (record.ref_id() as u32, pos as u32, record.name().to_vec()))
+            })
+            .collect();
+        expected.sort();
+
+        let (bam, _) = indexed_test_bam(&header, &records);
+        let mut reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+        let mut observed = Vec::new();
+        let mut last_pos = None;
+        for column in bam::Pileup::new(&mut reader) {
+            let column = column.unwrap();
+            let pos = (column.ref_id(), column.ref_pos());
+            assert!(last_pos < Some(pos), "Pileup columns are unsorted");
+            last_pos = Some(pos);
+            for entry in column.entries() {
+                observed.push((pos.0, pos.1, entry.record().name().to_vec()));
+            }
+        }
+        observed.sort();
+        assert_eq!(observed, expected);
+    }
+}
+
+#[test]
+fn record_reader_drain() {
+    let header = single_ref_header();
+    let records: Vec<_> = (0..10)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 10 * i))
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+
+    let mut reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    Iterator::next(&mut reader).unwrap().unwrap();
+    let rest = reader.drain().unwrap();
+    assert_eq!(rest.len(), 9);
+    assert!(rest.iter().zip(&records[1..]).all(|(a, b)| a.content_eq(b)));
+    assert!(reader.drain().unwrap().is_empty());
+
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam.clone()), std::io::Cursor::new(bai))
+        .unwrap();
+    let mut viewer = reader.fetch(&bam::Region::new(0, 20, 60)).unwrap();
+    assert_eq!(viewer.skip_records(2).unwrap(), 2);
+    let names: Vec<_> = viewer
+        .drain()
+        .unwrap()
+        .iter()
+        .map(|record| record.name().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"r4".to_vec(), b"r5".to_vec()]);
+
+    // The last record is truncated, so drain stops with an error.
+    let mut raw = Vec::new();
+    header.write_bam(&mut raw).unwrap();
+    for record in records.iter() {
+        record.write_bam(&mut raw).unwrap();
+    }
+    raw.truncate(raw.len() - 3);
+    let truncated = compress_raw_records(&raw);
+    let mut reader = bam::BamReader::from_stream(&truncated[..], 0).unwrap();
+    assert!(reader.drain().is_err());
+}
+
+#[test]
+fn seek_reader_block_boundaries() {
+    let eof_len = compress_raw_records(&[]).len();
+    let parts: Vec<Vec<u8>> = vec![b"first block".to_vec(), vec![7; 1000], b"third".to_vec()];
+    let mut bgzip = Vec::new();
+    let mut expected = Vec::new();
+    for part in parts.iter() {
+        expected.push(bgzip.len() as u64);
+        let compressed = compress_raw_records(part);
+        bgzip.extend_from_slice(&compressed[..compressed.len() - eof_len]);
+    }
+    // Empty input is compressed into empty blocks of 28 bytes, keep only the last one.
+    expected.push(bgzip.len() as u64);
+    bgzip.extend_from_slice(&compress_raw_records(&[])[eof_len - 28..]);
+
+    let mut reader =
+        bam::bgzip::SeekReader::from_stream(std::io::Cursor::new(bgzip.clone()), 0).unwrap();
+    reader.make_consecutive();
+    let mut buf = [0_u8; 5];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(reader.block_boundaries().unwrap(), expected);
+    // The reading position is not changed.
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest.len(), parts.iter().map(Vec::len).sum::<usize>() - 5);
+
+    // Reading can start from any boundary.
+    let mut reader = bam::bgzip::SeekReader::from_stream(std::io::Cursor::new(bgzip), 0).unwrap();
+    reader.from_offset(bam::index::VirtualOffset::new(expected[2], 0));
+    let mut last = Vec::new();
+    reader.read_to_end(&mut last).unwrap();
+    assert_eq!(last, b"third");
+}
+
+#[test]
+fn region_viewer_skipped_unmapped() {
+    let header = single_ref_header();
+    let mut records: Vec<_> = (0..10)
+        .map(|i| mapped_record(&format!("r{}", i), 0, 10 * i))
+        .collect();
+    // Placed unmapped mates at 10, 30 and 70.
+    for &i in &[1, 3, 7] {
+        records[i].flag_mut().set_mapped(false);
+    }
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let mut viewer = reader.fetch(&bam::Region::new(0, 25, 60)).unwrap();
+    assert_eq!(viewer.skipped_unmapped(), 0);
+    let names: Vec<_> = viewer
+        .drain()
+        .unwrap()
+        .iter()
+        .map(|record| record.name().to_vec())
+        .collect();
+    assert_eq!(names, vec![b"r3".to_vec(), b"r4".to_vec(), b"r5".to_vec()]);
+    assert_eq!(viewer.skipped_unmapped(), 1);
+
+    // Records rejected by the predicate are not counted.
+    let mut viewer = reader
+        .fetch_by(&bam::Region::new(0, 35, 100), |record| record.flag().is_mapped())
+        .unwrap();
+    assert_eq!(viewer.by_ref().count(), 5);
+    assert_eq!(viewer.skipped_unmapped(), 0);
+    let mut viewer = reader.fetch(&bam::Region::new(0, 35, 100)).unwrap();
+    assert_eq!(viewer.by_ref().count(), 6);
+    assert_eq!(viewer.skipped_unmapped(), 2);
+}
+
+#[test]
+fn record_fragment_midpoint() {
+    let mut record = mapped_record("r1", 0, 100);
+    record.set_template_len(51);
+    assert_eq!(record.fragment_midpoint(), None);
+    {
+        let flag = record.flag_mut();
+        flag.set_paired(true);
+        flag.set_all_segments_aligned(true);
+        flag.set_first_in_pair(true);
+    }
+    assert_eq!(record.fragment_midpoint(), Some(125));
+
+    // Rightmost mate.
+    record.set_template_len(-51);
+    assert_eq!(record.fragment_midpoint(), None);
+    record.set_template_len(0);
+    assert_eq!(record.fragment_midpoint(), None);
+
+    record.set_template_len(200);
+    record.flag_mut().set_all_segments_aligned(false);
+    assert_eq!(record.fragment_midpoint(), None);
+    record.flag_mut().set_all_segments_aligned(true);
+    record.flag_mut().set_first_in_pair(false);
+    record.flag_mut().set_last_in_pair(true);
+    // The leftmost mate is the second in pair.
+    assert_eq!(record.fragment_midpoint(), Some(200));
+    record.set_template_len(-200);
+    assert_eq!(record.fragment_midpoint(), None);
+
+    // Both mates start at the same position: only the first in pair is counted.
+    record.set_mate_start(100);
+    assert_eq!(record.fragment_midpoint(), None);
+    record.set_template_len(200);
+    assert_eq!(record.fragment_midpoint(), None);
+    record.flag_mut().set_last_in_pair(false);
+    record.flag_mut().set_first_in_pair(true);
+    assert_eq!(record.fragment_midpoint(), Some(200));
+    record.set_template_len(-200);
+    assert_eq!(record.fragment_midpoint(), Some(200));
+}
+
+#[test]
+fn indexed_reader_write_bedgraph() {
+    let header = single_ref_header();
+    let records: Vec<_> = [10, 12, 20, 30, 34]
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| mapped_record(&format!("r{}", i), 0, start))
+        .collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let mut out = Vec::new();
+    reader.write_bedgraph(0, 11, 22, &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "chr1\t11\t12\t1\nchr1\t12\t14\t2\nchr1\t14\t16\t1\nchr1\t20\t22\t1\n"
+    );
+
+    // Adjacent records with the same depth are merged.
+    let mut out = Vec::new();
+    reader.write_bedgraph(0, 25, 1000, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "chr1\t30\t38\t1\n");
+
+    let mut out = Vec::new();
+    reader.write_bedgraph(0, 100, 200, &mut out).unwrap();
+    assert!(out.is_empty());
+    assert!(reader.write_bedgraph(1, 0, 100, &mut out).is_err());
+}
+
+#[test]
+fn sequence_packed() {
+    use bam::record::sequence::{base_to_nibble, nibble_to_base};
+
+    let record = mapped_record("r1", 0, 10);
+    assert_eq!(record.sequence().packed(), &[0x12, 0x48]);
+
+    let sequence = bam::record::Sequence::from_text(b"ACGTN".iter().cloned()).unwrap();
+    let packed = sequence.packed();
+    assert_eq!(packed, &[0x12, 0x48, 0xf0]);
+    let decoded: Vec<u8> = (0..sequence.len())
+        .map(|i| nibble_to_base((packed[i / 2] >> (4 * (1 - i % 2))) & 0x0f))
+        .collect();
+    assert_eq!(decoded, sequence.to_vec());
+
+    for nibble in 0..16 {
+        let nt = nibble_to_base(nibble);
+        assert_eq!(base_to_nibble(nt), Ok(nibble));
+        assert_eq!(base_to_nibble(nt.to_ascii_lowercase()), Ok(nibble));
+    }
+    assert!(base_to_nibble(b'X').is_err());
+}
+
+#[test]
+fn bam_reader_max_soft_clip_fraction() {
+    let header = single_ref_header();
+    let records: Vec<_> = ["8M", "2S6M", "3S5M", "2S4M2S", "1S6M1S"]
+        .iter()
+        .enumerate()
+        .map(|(i, cigar)| {
+            let mut record = mapped_record(&format!("r{}", i), 0, 10 * i as i32);
+            record.set_cigar(cigar.bytes()).unwrap();
+            record
+                .set_seq_qual("ACGTACGT".bytes(), std::iter::repeat(30).take(8))
+                .unwrap();
+            record
+        })
+        .collect();
+    let (bam, _) = indexed_test_bam(&header, &records);
+    let names = |max_fraction: f64| -> Vec<Vec<u8>> {
+        bam::BamReader::from_stream(&bam[..], 0)
+            .unwrap()
+            .max_soft_clip_fraction(max_fraction)
+            .map(|record| record.unwrap().name().to_vec())
+            .collect()
+    };
+
+    assert_eq!(names(0.0), vec![b"r0".to_vec()]);
+    // Records with the fraction exactly at the threshold are kept.
+    assert_eq!(names(0.25), vec![b"r0".to_vec(), b"r1".to_vec(), b"r4".to_vec()]);
+    assert_eq!(names(0.4).len(), 4);
+    assert_eq!(names(0.5).len(), 5);
+}
+
+#[test]
+fn tag_array_iter() {
+    let mut record = mapped_record("r1", 0, 10);
+    let tags = record.tags_mut();
+    tags.push_array(b"cD", &[1_u16, 300, 65535]);
+    tags.push_array(b"ZB", &[-5_i8, 0, 127]);
+    tags.push_array(b"ZI", &[i32::MIN, -1, i32::MAX]);
+    tags.push_array(b"ZU", &[u32::MAX]);
+    tags.push_array(b"ZF", &[0.5_f32]);
+    tags.push_num(b"NM", 3_i32);
+
+    let tags = record.tags();
+    let values = |name: &[u8; 2]| tags.tag_array_iter(name).map(|iter| iter.collect::<Vec<_>>());
+    assert_eq!(values(b"cD"), Some(vec![1, 300, 65535]));
+    assert_eq!(values(b"ZB"), Some(vec![-5, 0, 127]));
+    assert_eq!(
+        values(b"ZI"),
+        Some(vec![i64::from(i32::MIN), -1, i64::from(i32::MAX)])
+    );
+    assert_eq!(values(b"ZU"), Some(vec![i64::from(u32::MAX)]));
+    assert_eq!(values(b"ZF"), None);
+    assert_eq!(values(b"NM"), None);
+    assert_eq!(values(b"XX"), None);
+    assert_eq!(tags.tag_array_iter(b"cD").unwrap().rev().next(), Some(65535));
+}
+
+#[test]
+fn record_pair_orientation() {
+    use bam::record::PairOrientation;
+
+    let pair = |start: i32, forward: bool, mate_start: i32, mate_forward: bool| {
+        let mut record = mapped_record("r1", 0, start);
+        record.set_mate_ref_id(0);
+        record.set_mate_start(mate_start);
+        let flag = record.flag_mut();
+        flag.set_paired(true);
+        flag.set_strand(forward);
+        flag.set_mate_strand(mate_forward);
+        record
+    };
+    // Both mates of a pair produce the same orientation.
+    assert_eq!(pair(100, true, 300, false).pair_orientation(), Some(PairOrientation::FR));
+    assert_eq!(pair(300, false, 100, true).pair_orientation(), Some(PairOrientation::FR));
+    assert_eq!(pair(100, false, 300, true).pair_orientation(), Some(PairOrientation::RF));
+    assert_eq!(pair(300, true, 100, false).pair_orientation(), Some(PairOrientation::RF));
+    assert_eq!(pair(100, true, 300, true).pair_orientation(), Some(PairOrientation::FF));
+    assert_eq!(pair(300, false, 100, false).pair_orientation(), Some(PairOrientation::RR));
+    // Mates at the same position.
+    assert_eq!(pair(100, true, 100, false).pair_orientation(), Some(PairOrientation::FR));
+    assert_eq!(pair(100, false, 100, true).pair_orientation(), Some(PairOrientation::FR));
+
+    let mut record = pair(100, true, 300, false);
+    record.flag_mut().set_mate_mapped(false);
+    assert_eq!(record.pair_orientation(), None);
+    let mut record = pair(100, true, 300, false);
+    record.set_mate_ref_id(1);
+    assert_eq!(record.pair_orientation(), None);
+    let mut record = pair(100, true, 300, false);
+    record.flag_mut().set_paired(false);
+    assert_eq!(record.pair_orientation(), None);
+}
+
+#[test]
+fn record_aligned_triples() {
+    use bam::record::cigar::Operation::*;
+
+    let mut record = mapped_record("r1", 0, 100);
+    record.set_cigar("2H3S4M2I3M1D2N2P3=1X2S5H".bytes()).unwrap();
+    record
+        .set_seq_qual("ACGTACGTACGTACGTAC".bytes(), std::iter::repeat(30).take(18))
+        .unwrap();
+    let triples: Vec<_> = record.aligned_triples().collect();
+    let mut expected = Vec::new();
+    for i in 0..4 {
+        expected.push((Some(3 + i), Some(100 + i), AlnMatch));
+    }
+    expected.push((Some(7), None, Insertion));
+    expected.push((Some(8), None, Insertion));
+    for i in 0..3 {
+        expected.push((Some(9 + i), Some(104 + i), AlnMatch));
+    }
+    expected.push((None, Some(107), Deletion));
+    expected.push((None, Some(108), Skip));
+    expected.push((None, Some(109), Skip));
+    for i in 0..3 {
+        expected.push((Some(12 + i), Some(110 + i), SeqMatch));
+    }
+    expected.push((Some(15), Some(113), SeqMismatch));
+    assert_eq!(triples, expected);
+
+    // Same as aligned pairs without soft clipping and padding.
+    let pairs: Vec<_> = record
+        .aligned_pairs()
+        .filter(|&(q_pos, r_pos)| r_pos.is_some() || q_pos.map_or(false, |q| q >= 3 && q < 16))
+        .collect();
+    let triple_pairs: Vec<_> = triples.iter().map(|&(q_pos, r_pos, _)| (q_pos, r_pos)).collect();
+    assert_eq!(pairs, triple_pairs);
+    assert_eq!(triples.last().unwrap().1, Some(record.calculate_end() as u32 - 1));
+
+    // Only soft clipping: nothing is aligned.
+    record.set_cigar("18S".bytes()).unwrap();
+    assert_eq!(record.aligned_triples().count(), 0);
+}
+
+#[test]
+fn indexed_reader_validate_index() {
+    let header = single_ref_header();
+    let records = vec![mapped_record("r1", 0, 10)];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let open = |bai: &[u8], validate: bool| {
+        bam::IndexedReader::build()
+            .validate_index(validate)
+            .from_streams(std::io::Cursor::new(bam.clone()), bai)
+    };
+    assert!(open(&bai, true).is_ok());
+
+    // Single reference with bins `(bin_id, chunks)` and a linear index.
+    let make_bai = |bins: &[(u32, &[(u64, u64)])], linear: &[u64]| {
+        let mut bai = b"BAI\x01".to_vec();
+        bai.extend_from_slice(&1_i32.to_le_bytes());
+        bai.extend_from_slice(&(bins.len() as i32).to_le_bytes());
+        for &(bin_id, chunks) in bins {
+            bai.extend_from_slice(&bin_id.to_le_bytes());
+            bai.extend_from_slice(&(chunks.len() as i32).to_le_bytes());
+            for &(start, end) in chunks {
+                bai.extend_from_slice(&(start << 16).to_le_bytes());
+                bai.extend_from_slice(&(end << 16).to_le_bytes());
+            }
+        }
+        bai.extend_from_slice(&(linear.len() as i32).to_le_bytes());
+        for &offset in linear {
+            bai.extend_from_slice(&(offset << 16).to_le_bytes());
+        }
+        bai
+    };
+    let valid = make_bai(&[(4681, &[(0, 10), (20, 30)]), (0, &[(40, 50)])], &[0, 10, 10, 40]);
+    assert!(open(&valid, true).is_ok());
+
+    let invalid_bin = make_bai(&[(40000, &[(0, 10)])], &[]);
+    assert!(open(&invalid_bin, false).is_ok());
+    let err = open(&invalid_bin, true).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("reference 0 (1 bins, 1 chunks)"));
+
+    let decreasing = make_bai(&[(4681, &[(0, 10)])], &[20, 10]);
+    assert!(open(&decreasing, false).is_ok());
+    assert!(open(&decreasing, true).is_err());
+}
+
+#[test]
+fn indexed_reader_fragment_coverage() {
+    let header = single_ref_header();
+    let mate = |name: &str, start: i32, proper: bool| {
+        let mut record = mapped_record(name, 0, start);
+        record.set_mate_ref_id(0);
+        record.flag_mut().set_paired(true);
+        record.flag_mut().set_all_segments_aligned(proper);
+        record
+    };
+    let mut secondary = mapped_record("s2", 0, 12);
+    secondary.flag_mut().set_secondary(true);
+    let records = vec![
+        mate("p1", 2, true),
+        mate("p1", 4, true),
+        mapped_record("s1", 0, 5),
+        mate("q1", 10, false),
+        mate("q1", 11, false),
+        secondary,
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let depth = reader.fragment_coverage(0, 0, 20).unwrap();
+    let mut expected = vec![0_u32; 20];
+    expected[2..15].copy_from_slice(&[1, 1, 1, 2, 2, 2, 1, 0, 1, 2, 2, 2, 1]);
+    assert_eq!(depth, expected);
+
+    // The first mate does not overlap the region, so only the second mate is counted.
+    assert_eq!(reader.fragment_coverage(0, 6, 9).unwrap(), vec![2, 2, 1]);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn record_json() {
+    let header = two_ref_header();
+    let mut record = mapped_record("read\"1", 0, 99);
+    record.set_mate_ref_id(1);
+    record.set_mate_start(9);
+    record.set_template_len(0);
+    record.flag_mut().set_paired(true);
+    record.flag_mut().set_strand(false);
+    record.tags_mut().push_num(b"NM", 1);
+    record.tags_mut().push_string(b"MD", b"4");
+
+    let json = record.to_json(&header);
+    assert!(json.starts_with("{\"name\":\"read\\\"1\",\"flag\":17,\"flags\":[\"PAIRED\","));
+    assert!(json.contains("\"ref_name\":\"chr1\",\"pos\":100,"));
+    assert!(json.contains("\"mate_ref_name\":\"chr2\",\"mate_pos\":10,"));
+    assert!(json.contains("\"cigar\":\"4M\""));
+    assert!(json.contains("\"seq\":\"ACGT\",\"qual\":\"????\""));
+    assert!(json.ends_with("\"tags\":[\"NM:i:1\",\"MD:Z:4\"]}"));
+    let parsed = bam::Record::from_json(&json, &header).unwrap();
+    assert_eq!(parsed.to_json(&header), json);
+
+    let unmapped = bam::Record::from_json("{\"name\": \"r2\", \"flag\": 4}", &header).unwrap();
+    assert_eq!(unmapped.ref_id(), -1);
+    assert_eq!(unmapped.start(), -1);
+    assert!(unmapped.to_json(&header).contains("\"ref_name\":null,\"pos\":0,"));
+
+    assert!(bam::Record::from_json("{\"flag\": 4}", &header).is_err());
+    assert!(bam::Record::from_json("{\"name\": \"r\\t2\", \"flag\": 4}", &header).is_err());
+    assert!(bam::Record::from_json("{\"name\": \"r2\", \"flag\": 4", &header).is_err());
+    let unknown_ref = "{\"name\": \"r2\", \"flag\": 0, \"ref_name\": \"chr3\", \"pos\": 1}";
+    assert!(bam::Record::from_json(unknown_ref, &header).is_err());
+
+    let same_mate = "{\"name\": \"r3\", \"flag\": 0, \"ref_name\": \"chr2\", \"pos\": 5, \
+        \"cigar\": \"2M\", \"mate_ref_name\": \"=\", \"seq\": \"AC\", \"extra\": [1, {}]}";
+    let same_mate = bam::Record::from_json(same_mate, &header).unwrap();
+    assert_eq!((same_mate.ref_id(), same_mate.mate_ref_id(), same_mate.start()), (1, 1, 4));
+    assert!(!same_mate.qualities().available());
+    let bad_qual = "{\"name\": \"r4\", \"flag\": 4, \"seq\": \"AC\", \"qual\": \" !\"}";
+    assert!(bam::Record::from_json(bad_qual, &header).is_err());
+}
+
+#[test]
+fn indexed_reader_buffer_pool() {
+    let header = single_ref_header();
+    let records: Vec<_> = (0..50).map(|i| mapped_record(&format!("r{}", i), 0, i * 10)).collect();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let open = move |pooled: bool| {
+        bam::IndexedReader::build()
+            .buffer_pool(pooled)
+            .from_streams(std::io::Cursor::new(bam.clone()), std::io::Cursor::new(bai.clone()))
+            .unwrap()
+    };
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let open = open.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    let mut reader = open(true);
+                    let count = reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().count();
+                    assert_eq!(count, 50);
+                    let count = reader.fetch(&bam::Region::new(0, i, i + 20)).unwrap().count();
+                    assert!(count >= 1 && count <= 3);
+                }
+                // Readers were created one after another, so they all reused the same block.
+                assert_eq!(bam::bgzip::pooled_blocks(), 1);
+
+                let readers: Vec<_> = (0..5).map(|_| open(true)).collect();
+                assert_eq!(bam::bgzip::pooled_blocks(), 0);
+                std::mem::drop(readers);
+                assert_eq!(bam::bgzip::pooled_blocks(), 5);
+
+                // Readers without the pool and reclaimed buffers do not change the pool.
+                std::mem::drop(open(false));
+                assert_eq!(bam::bgzip::pooled_blocks(), 5);
+                assert!(open(true).into_buffer().is_some());
+                assert_eq!(bam::bgzip::pooled_blocks(), 4);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn read_tracks() {
+    use bam::pileup::{AlnType, ReadTracks};
+
+    let mut record = mapped_record("r1", 0, 10);
+    record.set_cigar("1S2M2I1M1D1N1M".bytes()).unwrap();
+    record.set_seq_qual("TACGTAC".bytes(), std::iter::empty()).unwrap();
+    let mut unmapped = bam::Record::new();
+    unmapped.set_name("r2".bytes());
+    unmapped.flag_mut().set_mapped(false);
+    let mut records = vec![record, unmapped, mapped_record("r3", 0, 12)]
+        .into_iter()
+        .map(Ok);
+
+    let tracks: Vec<_> = ReadTracks::new(&mut records).map(|track| track.unwrap()).collect();
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[0].record().name(), b"r1");
+    assert_eq!(
+        tracks[0].positions(),
+        &[
+            (10, AlnType::Match, Some(b'A')),
+            (11, AlnType::Insertion(2), Some(b'C')),
+            (12, AlnType::Match, Some(b'A')),
+            (13, AlnType::Deletion, None),
+            (14, AlnType::Deletion, None),
+            (15, AlnType::Match, Some(b'C')),
+        ][..]
+    );
+    assert_eq!(tracks[0].positions_in(12, 14).len(), 2);
+    assert_eq!(tracks[0].positions_in(12, 14)[0].0, 12);
+    assert!(tracks[0].positions_in(20, 30).is_empty());
+    let bases: Vec<_> = tracks[1].positions().iter().map(|(pos, _, base)| (*pos, *base)).collect();
+    assert_eq!(bases, vec![(12, Some(b'A')), (13, Some(b'C')), (14, Some(b'G')), (15, Some(b'T'))]);
+}
+
+#[test]
+fn indexed_reader_fetch_min_mapq() {
+    let header = single_ref_header();
+    let record = |name: &str, start: i32, cigar: &str, mapq: u8| {
+        let mut record = mapped_record(name, 0, start);
+        record.set_cigar(cigar.bytes()).unwrap();
+        record.set_mapq(mapq);
+        record
+    };
+    let records = vec![
+        // Overlaps the region only through the deletion.
+        record("long_high", 10, "2M100D2M", 60),
+        record("long_low", 20, "2M100D2M", 5),
+        record("inside_high", 100, "4M", 30),
+        record("inside_low", 104, "4M", 29),
+        record("outside_high", 300, "4M", 60),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let names = |reader: &mut bam::IndexedReader<_>, min_mapq: u8| -> Vec<String> {
+        reader
+            .fetch_min_mapq(0, 90, 200, min_mapq)
+            .unwrap()
+            .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+            .collect()
+    };
+    assert_eq!(names(&mut reader, 30), vec!["long_high", "inside_high"]);
+    assert_eq!(names(&mut reader, 0), vec!["long_high", "long_low", "inside_high", "inside_low"]);
+    assert_eq!(names(&mut reader, 61), Vec::<String>::new());
+    assert!(reader.fetch_min_mapq(0, 900, 2000, 0).is_err());
+}
+
+#[test]
+fn indexed_reader_read_at() {
+    use bam::index::VirtualOffset;
+
+    let header = two_ref_header();
+    let mut records: Vec<_> = (0..20)
+        .map(|i| mapped_record(&format!("r{}", i), 0, i * 10))
+        .collect();
+    records.push(mapped_record("other", 1, 5));
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .capture_offsets(true)
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+    let offsets: Vec<_> = reader
+        .full()
+        .map(|record| record.unwrap().source_offset().unwrap())
+        .collect();
+    assert_eq!(offsets.len(), records.len());
+
+    for (record, offset) in records.iter().zip(&offsets).rev() {
+        let read = reader.read_at(offset.raw()).unwrap();
+        assert!(read.content_eq(record));
+        assert!(read.source_offset() == Some(*offset));
+    }
+    // Reader can be used as usual afterwards.
+    assert_eq!(reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().count(), 20);
+
+    let first = offsets[0];
+    let misaligned = VirtualOffset::new(first.block_offset(), first.contents_offset() + 2);
+    let err = reader.read_at(misaligned.raw()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let outside = VirtualOffset::new(first.block_offset(), 60000);
+    let err = reader.read_at(outside.raw()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(reader.read_at(VirtualOffset::new(first.block_offset() + 1, 0).raw()).is_err());
+    assert!(reader.read_at(VirtualOffset::new(1 << 40, 0).raw()).is_err());
+}
+
+#[test]
+fn indexed_reader_coverage_stranded() {
+    use bam::bam_reader::StrandAssignment;
+
+    let header = two_ref_header();
+    let mate = |name: &str, start: i32, reverse: bool, first: bool| {
+        let mut record = mapped_record(name, 0, start);
+        record.set_mate_ref_id(0);
+        let flag = record.flag_mut();
+        flag.set_paired(true);
+        flag.set_strand(!reverse);
+        flag.set_mate_strand(reverse);
+        flag.set_first_in_pair(first);
+        flag.set_last_in_pair(!first);
+        record
+    };
+    let mut unpaired_reverse = mapped_record("u2", 0, 12);
+    unpaired_reverse.set_cigar("1M1D3M".bytes()).unwrap();
+    unpaired_reverse.flag_mut().set_strand(false);
+    let mut secondary = mapped_record("s", 0, 0);
+    secondary.flag_mut().set_secondary(true);
+    let records = vec![
+        secondary,
+        mate("p1", 2, false, true),
+        mate("p1", 6, true, false),
+        mapped_record("u1", 0, 10),
+        unpaired_reverse,
+        mapped_record("other", 1, 0),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let (forward, reverse) = reader.coverage_stranded(0).unwrap();
+    assert_eq!(forward.len(), 1000);
+    assert_eq!(reverse.len(), 1000);
+    assert_eq!(&forward[..17], &[0, 0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 0]);
+    assert_eq!(&reverse[..17], &[0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 1, 0, 1, 1, 1]);
+    assert!(forward[17..].iter().chain(&reverse[17..]).all(|&depth| depth == 0));
+
+    let (forward, reverse) = reader.coverage_stranded_by(0, StrandAssignment::FirstInPair).unwrap();
+    assert_eq!(&forward[..17], &[0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0]);
+    assert_eq!(&reverse[..17], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1]);
+
+    let (forward, reverse) = reader.coverage_stranded(1).unwrap();
+    assert_eq!(forward.iter().sum::<u32>(), 4);
+    assert_eq!(reverse.iter().sum::<u32>(), 0);
+    assert!(reader.coverage_stranded(2).is_err());
+}
+
+#[test]
+fn header_check_compatible() {
+    let header = two_ref_header();
+    let refs = |pairs: &[(&str, u32)]| -> Vec<(String, u32)> {
+        pairs.iter().map(|&(name, len)| (name.to_string(), len)).collect()
+    };
+    let lengths = header.reference_lengths().to_vec();
+    let (len1, len2) = (lengths[0], lengths[1]);
+    assert_eq!(header.check_compatible(&refs(&[("chr1", len1), ("chr2", len2)])), Ok(()));
+
+    let err = header.check_compatible(&refs(&[("chr2", len2), ("chr1", len1)])).unwrap_err();
+    assert!(err.contains("#0") && err.contains("chr1") && err.contains("chr2"), "{}", err);
+    let err = header.check_compatible(&refs(&[("chr1", len1), ("chr2", len2 + 1)])).unwrap_err();
+    assert!(err.contains("#1") && err.contains("length"), "{}", err);
+    let err = header.check_compatible(&refs(&[("chr1", len1)])).unwrap_err();
+    assert!(err.contains("unexpected") && err.contains("chr2"), "{}", err);
+    let extra = refs(&[("chr1", len1), ("chr2", len2), ("chr3", 10)]);
+    let err = header.check_compatible(&extra).unwrap_err();
+    assert!(err.contains("missing") && err.contains("chr3"), "{}", err);
+    assert!(bam::Header::new().check_compatible(&[]).is_ok());
+}
+
+#[test]
+fn transform_records() {
+    let header = single_ref_header();
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header.clone()).unwrap();
+    for i in 0..10 {
+        writer.write(&mapped_record(&format!("r{}", i), 0, i * 10)).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let mut reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let mut output = Vec::new();
+    let mut writer = bam::SamWriter::build()
+        .write_header(false)
+        .from_stream(&mut output, header)
+        .unwrap();
+    let mut calls = 0;
+    bam::transform(&mut reader, &mut writer, |record| {
+        calls += 1;
+        record.set_mapq(record.start() as u8);
+        record.tags_mut().push_num(b"XI", calls);
+        record.start() % 20 == 0
+    })
+    .unwrap();
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    assert_eq!(calls, 10);
+    let lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+    assert_eq!(lines.len(), 5);
+    for (i, line) in lines.iter().enumerate() {
+        let fields: Vec<_> = line.split('\t').collect();
+        assert_eq!(fields[0], format!("r{}", 2 * i));
+        assert_eq!(fields[4], (20 * i).to_string());
+        // Tags from previous records are not carried over.
+        assert_eq!(fields[11..].to_vec(), vec![format!("XI:i:{}", 2 * i + 1)]);
+    }
+}
+
+#[test]
+fn header_has_reference() {
+    let header = two_ref_header();
+    assert_eq!(header.n_references(), 2);
+    assert!(header.has_reference(0));
+    assert!(header.has_reference(1));
+    assert!(!header.has_reference(2));
+    assert!(!header.has_reference(u32::MAX));
+    assert!(!bam::Header::new().has_reference(0));
+}
+
+#[test]
+fn pileup_column_read_names() {
+    let header = single_ref_header();
+    let records = vec![
+        mapped_record("r1", 0, 10),
+        mapped_record("r2", 0, 12),
+        mapped_record("r3", 0, 13),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+    let mut viewer = reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap();
+    let columns: Vec<_> = bam::Pileup::new(&mut viewer).map(|column| column.unwrap()).collect();
+    let names = |pos: u32| {
+        let column = columns.iter().find(|column| column.ref_pos() == pos).unwrap();
+        assert_eq!(column.read_names().len(), column.entries().len());
+        let mut names: Vec<_> = column.read_names().collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(10), vec![&b"r1"[..]]);
+    assert_eq!(names(13), vec![&b"r1"[..], b"r2", b"r3"]);
+    assert_eq!(names(16), vec![&b"r3"[..]]);
+}
+
+#[test]
+fn indexed_reader_sort_order() {
+    use bam::bam_reader::SortOrderCheck;
+    use bam::header::{HeaderEntry, SortOrder};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let header_with = |order: Option<&str>| {
+        let mut header = bam::Header::new();
+        let mut line = HeaderEntry::header_line("1.6".to_string());
+        if let Some(order) = order {
+            line.push(b"SO", order.to_string());
+        }
+        header.push_entry(line).unwrap();
+        header
+            .push_entry(HeaderEntry::ref_sequence("chr1".to_string(), 1000))
+            .unwrap();
+        header
+    };
+    assert_eq!(single_ref_header().sort_order(), SortOrder::Unknown);
+    assert_eq!(header_with(None).sort_order(), SortOrder::Unknown);
+    assert_eq!(header_with(Some("unknown")).sort_order(), SortOrder::Unknown);
+    assert_eq!(header_with(Some("Coordinate")).sort_order(), SortOrder::Coordinate);
+    assert_eq!(header_with(Some("queryname")).sort_order(), SortOrder::QueryName);
+    assert_eq!(header_with(Some("unsorted")).sort_order(), SortOrder::Unsorted);
+
+    let records = vec![mapped_record("r1", 0, 10)];
+    let open = |order: Option<&str>, check: SortOrderCheck| {
+        let (bam, bai) = indexed_test_bam(&header_with(order), &records);
+        bam::IndexedReader::build()
+            .sort_order(check)
+            .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+    };
+    assert!(open(Some("coordinate"), SortOrderCheck::Error).is_ok());
+    assert!(open(None, SortOrderCheck::Error).is_ok());
+    let err = open(Some("queryname"), SortOrderCheck::Error).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(open(Some("unsorted"), SortOrderCheck::Error).is_err());
+
+    let mut reader = open(Some("unsorted"), SortOrderCheck::Ignore).unwrap();
+    assert_eq!(reader.fetch(&bam::Region::new(0, 0, 100)).unwrap().count(), 1);
+    let warned = Arc::new(AtomicBool::new(false));
+    let warned_clone = Arc::clone(&warned);
+    let check = SortOrderCheck::warn(move |_| warned_clone.store(true, Ordering::SeqCst));
+    assert!(open(Some("queryname"), check).is_ok());
+    assert!(warned.load(Ordering::SeqCst));
+}
+
+#[test]
+fn qualities_to_ascii_and_mean() {
+    let mut record = mapped_record("r1", 0, 10);
+    record
+        .set_seq_qual("ACGT".bytes(), [0_u8, 10, 20, 40].iter().cloned())
+        .unwrap();
+    let qual = record.qualities();
+    assert_eq!(qual.to_ascii(33), qual.to_readable());
+    assert_eq!(qual.to_ascii(64), vec![64, 74, 84, 104]);
+    assert_eq!(qual.to_ascii(250), vec![250, 255, 255, 255]);
+    assert_eq!(qual.mean(), Some(17.5));
+
+    record.set_seq_qual("ACGT".bytes(), std::iter::empty()).unwrap();
+    assert!(!record.qualities().available());
+    assert!(record.qualities().to_ascii(33).is_empty());
+    assert_eq!(record.qualities().mean(), None);
+    assert_eq!(bam::Record::new().qualities().mean(), None);
+}
+
+#[test]
+fn indexed_reader_fetch_to_reference_end() {
+    let header = two_ref_header();
+    let records = vec![
+        mapped_record("r1", 0, 10),
+        mapped_record("r2", 0, 500),
+        mapped_record("r3", 0, 996),
+        mapped_record("r4", 1, 0),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let names = |viewer: bam::bam_reader::RegionViewer<_>| -> Vec<Vec<u8>> {
+        viewer.map(|record| record.unwrap().name().to_vec()).collect()
+    };
+    let viewer = reader.fetch(&bam::Region::new(0, 100, u32::MAX)).unwrap();
+    assert_eq!(names(viewer), vec![b"r2".to_vec(), b"r3".to_vec()]);
+    let viewer = reader.fetch(&bam::Region::new(0, 1000, u32::MAX)).unwrap();
+    assert!(names(viewer).is_empty());
+    assert!(reader.fetch(&bam::Region::new(0, 1001, u32::MAX)).is_err());
+    // Other ends after the reference end are still errors.
+    assert!(reader.fetch(&bam::Region::new(0, 100, 1001)).is_err());
+    assert!(reader.fetch(&bam::Region::new(0, 100, u32::MAX - 1)).is_err());
+
+    assert!(reader.has_records(0, 997, u32::MAX).unwrap());
+    assert_eq!(reader.fragment_coverage(0, 990, u32::MAX).unwrap().len(), 10);
+}
+
+#[test]
+fn bam_reader_dedup_umi() {
+    let header = single_ref_header();
+    let record = |name: &str, start: i32, umi: Option<&str>, reverse: bool| {
+        let mut record = mapped_record(name, 0, start);
+        record.flag_mut().set_strand(!reverse);
+        if let Some(umi) = umi {
+            record.tags_mut().push_string(b"RX", umi.as_bytes());
+        }
+        record
+    };
+    let mut secondary = record("s", 10, Some("AAA"), false);
+    secondary.flag_mut().set_secondary(true);
+    let records = vec![
+        record("a1", 10, Some("AAA"), false),
+        record("a2", 10, Some("CCC"), false),
+        record("a3", 10, Some("AAA"), false),
+        record("a4", 10, Some("AAA"), true),
+        record("a5", 10, None, false),
+        record("a6", 10, None, false),
+        secondary,
+        record("b1", 20, Some("AAA"), false),
+        record("b2", 20, Some("AAA"), false),
+    ];
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let marked: Vec<_> = reader
+        .dedup_umi(*b"RX")
+        .map(|record| {
+            let record = record.unwrap();
+            (String::from_utf8(record.name().to_vec()).unwrap(), record.flag().is_duplicate())
+        })
+        .collect();
+    let expected = vec![
+        ("a1", false),
+        ("a2", false),
+        ("a3", true),
+        ("a4", false),
+        ("a5", false),
+        ("a6", false),
+        ("s", false),
+        ("b1", false),
+        ("b2", true),
+    ];
+    let expected: Vec<_> = expected
+        .into_iter()
+        .map(|(name, dup)| (name.to_string(), dup))
+        .collect();
+    assert_eq!(marked, expected);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let mut dedup = reader.dedup_umi(*b"RX");
+    dedup.drop_duplicates(true);
+    let names: Vec<_> = dedup
+        .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+        .collect();
+    assert_eq!(names, vec!["a1", "a2", "a4", "a5", "a6", "s", "b1"]);
+}
+
+#[test]
+fn indexed_reader_fetch_pos() {
+    let header = single_ref_header();
+    let record = |name: &str, start: i32, cigar: &str| {
+        let mut record = mapped_record(name, 0, start);
+        record.set_cigar(cigar.bytes()).unwrap();
+        record
+    };
+    let records = vec![
+        record("ends_before", 6, "4M"),
+        record("match", 8, "4M"),
+        record("deletion", 8, "1M3D3M"),
+        record("skip", 9, "1M5N3M"),
+        record("insertion", 9, "1M2I1M"),
+        record("starts_at", 10, "4M"),
+        record("starts_after", 11, "4M"),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), std::io::Cursor::new(bai))
+        .unwrap();
+
+    let names: Vec<_> = reader
+        .fetch_pos(0, 10)
+        .unwrap()
+        .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+        .collect();
+    assert_eq!(names, vec!["match", "deletion", "skip", "insertion", "starts_at"]);
+    assert_eq!(reader.fetch_pos(0, 999).unwrap().count(), 0);
+    assert!(reader.fetch_pos(0, 1000).is_err());
+    assert!(reader.fetch_pos(0, u32::MAX).is_err());
+    assert!(reader.fetch_pos(1, 0).is_err());
+}
+
+#[test]
+fn record_split_read() {
+    use bam::record::SupplementaryAlignment;
+
+    let mut record = mapped_record("r1", 0, 10);
+    assert!(!record.is_split_read());
+    assert!(!record.is_chimeric());
+    assert!(record.supplementary_alignments().is_none());
+    record.flag_mut().set_supplementary(true);
+    assert!(!record.is_split_read());
+    assert!(record.is_chimeric());
+
+    let mut record = mapped_record("r1", 0, 10);
+    record
+        .tags_mut()
+        .push_string(b"SA", b"chr2,101,-,50S50M,60,2;chr1,5,+,bad;chr3,1,+,10M,0,0;");
+    assert!(record.is_split_read());
+    assert!(record.is_chimeric());
+    let alignments = record.supplementary_alignments().unwrap();
+    assert_eq!(
+        alignments,
+        vec![
+            SupplementaryAlignment {
+                ref_name: "chr2".to_string(),
+                start: 100,
+                is_reverse_strand: true,
+                cigar: "50S50M".to_string(),
+                mapq: 60,
+                edit_distance: 2,
+            },
+            SupplementaryAlignment {
+                ref_name: "chr3".to_string(),
+                start: 0,
+                is_reverse_strand: false,
+                cigar: "10M".to_string(),
+                mapq: 0,
+                edit_distance: 0,
+            },
+        ]
+    );
+
+    // A split read always has parsed alignments, even if all of them are malformed.
+    let mut record = mapped_record("r1", 0, 10);
+    record.tags_mut().push_string(b"SA", b"garbage");
+    assert!(record.is_split_read());
+    assert_eq!(record.supplementary_alignments(), Some(vec![]));
+    // Non-string SA tags are ignored.
+    let mut record = mapped_record("r1", 0, 10);
+    record.tags_mut().push_num(b"SA", 1);
+    assert!(!record.is_split_read());
+    assert!(record.supplementary_alignments().is_none());
+}
+
+#[test]
+fn record_end_without_reference_span() {
+    let header = single_ref_header();
+    for cigar in &["50S", "10I", "2S3I2H"] {
+        let mut record = mapped_record("r1", 0, 100);
+        record.set_cigar(cigar.bytes()).unwrap();
+        assert_eq!(record.calculate_end(), 100, "CIGAR {}", cigar);
+        assert_eq!(record.reference_span(), 0);
+        assert!(record.overlaps(0, 100, 101));
+        assert!(record.overlaps(0, 50, 150));
+        assert!(!record.overlaps(0, 101, 200));
+        assert!(!record.overlaps(0, 0, 100));
+    }
+
+    let mut record = mapped_record("r2", 0, 100);
+    record.set_cigar("50S".bytes()).unwrap();
+    let records = vec![mapped_record("r1", 0, 10), record, mapped_record("r3", 0, 200)];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let names = |viewer: bam::bam_reader::RegionViewer<_>| -> Vec<Vec<u8>> {
+        viewer.map(|record| record.unwrap().name().to_vec()).collect()
+    };
+    assert_eq!(names(reader.fetch_pos(0, 100).unwrap()), vec![b"r2".to_vec()]);
+    assert_eq!(names(reader.fetch(&bam::Region::new(0, 90, 110)).unwrap()), vec![b"r2".to_vec()]);
+    assert!(names(reader.fetch(&bam::Region::new(0, 101, 150)).unwrap()).is_empty());
+}
+
+#[test]
+fn bam_reader_fragments() {
+    let header = two_ref_header();
+    let mate = |name: &str, ref_id: i32, start: i32, first: bool| {
+        let mut record = mapped_record(name, ref_id, start);
+        record.flag_mut().set_paired(true);
+        record.flag_mut().set_first_in_pair(first);
+        record.flag_mut().set_last_in_pair(!first);
+        record
+    };
+    let mut secondary = mate("p1", 0, 500, true);
+    secondary.flag_mut().set_secondary(true);
+    let mut unmapped = mate("p3", 0, 300, false);
+    unmapped.flag_mut().set_mapped(false);
+    let records = vec![
+        mate("p1/2", 0, 200, false),
+        secondary,
+        mate("p1/1", 0, 100, true),
+        mapped_record("s1", 1, 50),
+        mate("p2", 0, 10, true),
+        mate("p3", 0, 300, true),
+        unmapped,
+        mate("p4", 0, 400, true),
+        mate("p4", 1, 20, false),
+        mate("orphan", 1, 30, false),
+    ];
+
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let fragments: Vec<_> = reader.fragments().map(|fragment| fragment.unwrap()).collect();
+    let summary: Vec<_> = fragments
+        .iter()
+        .map(|fragment| {
+            (
+                String::from_utf8_lossy(fragment.first().name()).into_owned(),
+                fragment.is_pair(),
+                fragment.ref_id(),
+                fragment.start(),
+                fragment.end(),
+            )
+        })
+        .collect();
+    let expected = vec![
+        ("p1/1", true, 0, 100, 204),
+        ("s1", false, 1, 50, 54),
+        ("p2", false, 0, 10, 14),
+        ("p3", true, 0, 300, 304),
+        ("p4", true, 0, 400, 404),
+        ("orphan", false, 1, 30, 34),
+    ];
+    let expected: Vec<_> = expected
+        .into_iter()
+        .map(|(name, pair, ref_id, start, end)| (name.to_string(), pair, ref_id, start, end))
+        .collect();
+    assert_eq!(summary, expected);
+    assert_eq!(fragments[0].second().unwrap().name(), b"p1/2");
+    let (first, second) = fragments.into_iter().nth(4).unwrap().into_records();
+    assert_eq!((first.ref_id(), second.unwrap().ref_id()), (0, 1));
+}
+
+#[test]
+fn bam_writer_write_header_from() {
+    let text = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@RG\tID:rg1\tSM:x\n\
+        @PG\tID:p1\tPN:tool\tCL:tool  -x\n@CO\tcomment  with spaces \n\0\0";
+    let mut raw = b"BAM\x01".to_vec();
+    raw.extend_from_slice(&(text.len() as i32).to_le_bytes());
+    raw.extend_from_slice(text.as_bytes());
+    raw.extend_from_slice(&1_i32.to_le_bytes());
+    raw.extend_from_slice(&5_i32.to_le_bytes());
+    raw.extend_from_slice(b"chr1\0");
+    raw.extend_from_slice(&1000_i32.to_le_bytes());
+    let header = bam::Header::from_bam(&mut &raw[..]).unwrap();
+
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::build()
+        .write_header(false)
+        .from_stream(&mut stream, bam::Header::new())
+        .unwrap();
+    writer.write_header_from(&header).unwrap();
+    assert_eq!(writer.header().reference_name(0), Some("chr1"));
+    writer.write(&mapped_record("r1", 0, 10)).unwrap();
+    assert!(writer.write_header_from(&header).is_err());
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    assert_eq!(reader.header().text(), text);
+    assert_eq!(reader.header().reference_len(0), Some(1000));
+    let mut copy = Vec::new();
+    let mut writer = bam::BamWriter::build()
+        .write_header(false)
+        .from_stream(&mut copy, bam::Header::new())
+        .unwrap();
+    writer.write_header_from(reader.header()).unwrap();
+    let records: Vec<_> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(records.len(), 1);
+    writer.write(&records[0]).unwrap();
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+    assert_eq!(copy, stream);
+
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, single_ref_header()).unwrap();
+    assert!(writer.write_header_from(&header).is_err());
+}
+
+#[test]
+fn export_fastq_records() {
+    let header = single_ref_header();
+    let mut first = mapped_record("p1/1", 0, 10);
+    first.flag_mut().set_paired(true);
+    first.flag_mut().set_first_in_pair(true);
+    first
+        .set_seq_qual("ACGTAC".bytes(), [30_u8, 30, 30, 30, 5, 2].iter().cloned())
+        .unwrap();
+    first.set_cigar("6M".bytes()).unwrap();
+    let mut second = mapped_record("p1/2", 0, 100);
+    second.flag_mut().set_paired(true);
+    second.flag_mut().set_last_in_pair(true);
+    second.flag_mut().set_strand(false);
+    second
+        .set_seq_qual("AACGTT".bytes(), [2_u8, 5, 30, 30, 30, 40].iter().cloned())
+        .unwrap();
+    second.set_cigar("6M".bytes()).unwrap();
+    let mut secondary = mapped_record("p1", 0, 500);
+    secondary.flag_mut().set_secondary(true);
+    let mut no_qual = mapped_record("s1", 0, 200);
+    no_qual.set_seq_qual("ACGN".bytes(), std::iter::empty()).unwrap();
+
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header).unwrap();
+    for record in &[first, second, secondary, no_qual] {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let mut output = Vec::new();
+    let mut reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    assert_eq!(bam::export_fastq(&mut reader, &mut output, None).unwrap(), 3);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "@p1/1\nACGTAC\n+\n????&#\n@p1/2\nAACGTT\n+\nI???&#\n@s1\nACGN\n+\n!!!!\n"
+    );
+
+    let mut output = Vec::new();
+    let mut reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    assert_eq!(bam::export_fastq(&mut reader, &mut output, Some(20)).unwrap(), 3);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "@p1/1\nACGT\n+\n????\n@p1/2\nAACG\n+\nI???\n@s1\nACGN\n+\n!!!!\n"
+    );
+}
+
+#[test]
+fn record_count_mismatches() {
+    let reference = vec!["ACGTACGTACGTACGTACGT"];
+    let mut record = mapped_record("r1", 0, 2);
+    record.set_cigar("1S4M1I2M1D2M".bytes()).unwrap();
+    record
+        .set_seq_qual("TGTTNAGAC=".bytes(), std::iter::empty())
+        .unwrap();
+    assert_eq!(record.count_mismatches(&reference).unwrap(), 2);
+    assert_eq!(record.count_mismatches_by(&reference, false).unwrap(), 3);
+    let lowercase = vec![reference[0].to_lowercase()];
+    assert_eq!(record.count_mismatches(&lowercase).unwrap(), 2);
+
+    assert!(record.count_mismatches(&vec!["ACGTAC"]).is_err());
+    record.set_ref_id(1);
+    assert!(record.count_mismatches(&reference).is_err());
+    record.flag_mut().set_mapped(false);
+    assert_eq!(record.count_mismatches(&reference).unwrap(), 0);
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn digest_reader() {
+    let header = single_ref_header();
+    let mut record = mapped_record("r1", 0, 10);
+    record.tags_mut().push_num(b"NM", 1_u8);
+    let records = vec![record, mapped_record("r2", 0, 20)];
+
+    let mut digests = Vec::new();
+    for &level in &[0_u8, 9] {
+        let mut stream = Vec::new();
+        let mut writer = bam::BamWriter::build()
+            .compression_level(level)
+            .from_stream(&mut stream, header.clone())
+            .unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+        std::mem::drop(writer);
+
+        let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+        let mut reader = bam::digest::DigestReader::new(reader, header.clone());
+        assert_eq!(reader.by_ref().count(), 2);
+        assert_eq!(reader.n_records(), 2);
+        digests.push(reader.content_digest_hex());
+    }
+    assert_eq!(digests[0], digests[1]);
+    // SHA-256 of "r1\t0\tchr1\t11\t0\t4M\t*\t0\t0\tACGT\t????\tNM:i:1\n"
+    //   + "r2\t0\tchr1\t21\t0\t4M\t*\t0\t0\tACGT\t????\n".
+    assert_eq!(
+        digests[0],
+        "0424964b1bcb122e19e8e2e394f93981243b48b1b3ef8f4b5e8883b727dbe207"
+    );
+
+    let mut stream = Vec::new();
+    bam::BamWriter::from_stream(&mut stream, header.clone())
+        .unwrap()
+        .finish()
+        .unwrap();
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let mut reader = bam::digest::DigestReader::new(reader, header);
+    assert!(reader.next().is_none());
+    assert_eq!(
+        reader.content_digest_hex(),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+}
+
+#[test]
+fn indexed_reader_fetch_bed() {
+    let header = two_ref_header();
+    let mut long = mapped_record("long", 0, 190);
+    long.set_cigar("2M100N2M".bytes()).unwrap();
+    let records = vec![
+        mapped_record("a", 0, 50),
+        mapped_record("b", 0, 105),
+        long,
+        mapped_record("c", 0, 198),
+        mapped_record("d", 0, 250),
+        mapped_record("e", 0, 295),
+        mapped_record("f", 1, 10),
+        mapped_record("g", 1, 500),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+
+    let bed_path = std::env::temp_dir().join(format!("bam_fetch_bed_{}.bed", std::process::id()));
+    std::fs::write(
+        &bed_path,
+        "# targets\ntrack name=t\nchr2\t0\t20\tgene1\nchr1\t100\t150\nchr1 140 200\n\n\
+         chr1\t200\t210\nchr1\t290\t300\nchr2\t30\t30\n",
+    )
+    .unwrap();
+    let viewer = reader.fetch_bed(&bed_path).unwrap();
+    let regions: Vec<_> = viewer
+        .regions()
+        .iter()
+        .map(|region| (region.ref_id(), region.start(), region.end()))
+        .collect();
+    assert_eq!(regions, vec![(0, 100, 210), (0, 290, 300), (1, 0, 20)]);
+    let names: Vec<_> = viewer
+        .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+        .collect();
+    assert_eq!(names, vec!["b", "long", "c", "e", "f"]);
+
+    std::fs::write(&bed_path, "chr3\t0\t10\n").unwrap();
+    assert!(reader.fetch_bed(&bed_path).is_err());
+    std::fs::write(&bed_path, "chr1\t0\n").unwrap();
+    assert!(reader.fetch_bed(&bed_path).is_err());
+    std::fs::remove_file(&bed_path).unwrap();
+
+    // The long record overlaps both regions, but is returned once.
+    let regions = vec![bam::Region::new(0, 293, 297), bam::Region::new(0, 191, 192)];
+    let names: Vec<_> = reader
+        .fetch_regions(&regions)
+        .unwrap()
+        .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+        .collect();
+    assert_eq!(names, vec!["long", "e"]);
+    assert!(reader.fetch_regions(&[bam::Region::new(1, 0, 2000)]).is_err());
+}
+
+#[test]
+fn record_identity() {
+    let reference = vec!["ACGTACGTACGTACGTACGT"];
+    let mut record = mapped_record("r1", 0, 2);
+    // 8 M/=/X bases with 2 mismatches, 1 inserted and 1 deleted base.
+    record.set_cigar("1S4M1I2M1D2M".bytes()).unwrap();
+    record
+        .set_seq_qual("TGTTCAGAC=".bytes(), std::iter::empty())
+        .unwrap();
+    assert_eq!(record.count_mismatches(&reference).unwrap(), 2);
+    assert_eq!(record.identity(&reference).unwrap(), Some(0.6));
+    assert_eq!(record.identity_from_nm(), None);
+    record.tags_mut().push_num(b"NM", 4_u8);
+    assert_eq!(record.identity_from_nm(), Some(0.6));
+    record.tags_mut().remove(b"NM");
+    record.tags_mut().push_num(b"NM", 11_u8);
+    assert_eq!(record.identity_from_nm(), None);
+
+    record.set_cigar("10S".bytes()).unwrap();
+    assert_eq!(record.identity(&reference).unwrap(), None);
+    record.set_cigar("1S9M".bytes()).unwrap();
+    record.flag_mut().set_mapped(false);
+    assert_eq!(record.identity(&reference).unwrap(), None);
+    assert_eq!(record.identity_from_nm(), None);
+}
+
+#[test]
+fn region_viewer_sorted_by_name() {
+    let header = single_ref_header();
+    let records = vec![
+        mapped_record("r2/1", 0, 10),
+        mapped_record("r10", 0, 20),
+        mapped_record("r1", 0, 30),
+        mapped_record("r2/2", 0, 40),
+        mapped_record("r3", 0, 500),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let names: Vec<_> = reader
+        .fetch(&bam::Region::new(0, 0, 100))
+        .unwrap()
+        .sorted_by_name()
+        .unwrap()
+        .map(|record| String::from_utf8(record.name().to_vec()).unwrap())
+        .collect();
+    assert_eq!(names, vec!["r1", "r10", "r2/1", "r2/2"]);
+}
+
+#[test]
+fn pileup_column_indel_summary() {
+    let header = single_ref_header();
+    let record = |name: &str, cigar: &str, seq: &str| {
+        let mut record = mapped_record(name, 0, 10);
+        record.set_cigar(cigar.bytes()).unwrap();
+        record.set_seq_qual(seq.bytes(), std::iter::empty()).unwrap();
+        record
+    };
+    let records = vec![
+        record("a", "2M2I2M", "ACTTGT"),
+        record("b", "2M2I2M", "ACGGGT"),
+        record("c", "2M2I2M", "ACTTGT"),
+        record("d", "1M2D3M", "ACGT"),
+        record("e", "1M5N3M", "ACGT"),
+        record("f", "4M", "ACGT"),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let mut viewer = reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap();
+    let columns: Vec<_> = bam::Pileup::new(&mut viewer).map(|column| column.unwrap()).collect();
+    let summary = |pos: u32| {
+        columns
+            .iter()
+            .find(|column| column.ref_pos() == pos)
+            .unwrap()
+            .indel_summary()
+    };
+
+    let at_11 = summary(11);
+    assert_eq!((at_11.matches, at_11.insertions, at_11.deletions), (1, 3, 2));
+    assert_eq!(at_11.top_insertion, Some((b"TT".to_vec(), 2)));
+    let at_10 = summary(10);
+    assert_eq!((at_10.matches, at_10.insertions, at_10.deletions), (6, 0, 0));
+    assert_eq!(at_10.top_insertion, None);
+}
+
+#[test]
+fn pristine_records() {
+    let header = single_ref_header();
+    let mut records = Vec::new();
+    for i in 0..5 {
+        let mut record = mapped_record(&format!("r{}", i), 0, i * 10);
+        record.tags_mut().push_num(b"NM", i);
+        records.push(record);
+    }
+    let (bam, bai) = indexed_test_bam(&header, &records);
+
+    let mut reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    reader.keep_raw(true);
+    let input: Vec<_> = reader.map(|record| record.unwrap()).collect();
+    assert!(input.iter().all(|record| record.is_pristine()));
+    let mut copy = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut copy, header.clone()).unwrap();
+    for record in &input {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+    let mut encoded = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut encoded, header.clone()).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+    assert_eq!(copy, encoded);
+
+    let mut modified = input.clone();
+    modified[1].set_mapq(7);
+    modified[2].tags_mut().push_char(b"XA", b'x');
+    modified[3].flag_mut();
+    assert!(modified[0].is_pristine());
+    assert!(modified[1..4].iter().all(|record| !record.is_pristine()));
+    let mut output = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut output, header.clone()).unwrap();
+    for record in &modified {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+    let reader = bam::BamReader::from_stream(&output[..], 0).unwrap();
+    let output: Vec<_> = reader.map(|record| record.unwrap()).collect();
+    assert_eq!(output[1].mapq(), 7);
+    assert!(output[2].tags().get(b"XA").is_some());
+    assert!(output.iter().all(|record| !record.is_pristine()));
+
+    let mut reader = bam::IndexedReader::build()
+        .keep_raw(true)
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    let mut record = bam::Record::new();
+    let mut viewer = reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap();
+    assert!(viewer.read_into(&mut record).unwrap());
+    assert!(record.is_pristine());
+    record.clear();
+    assert!(!record.is_pristine());
+}
+
+#[test]
+fn bam_reader_genomic_extent() {
+    let mut header = two_ref_header();
+    header
+        .push_entry(bam::header::HeaderEntry::ref_sequence("chr3".to_string(), 1000))
+        .unwrap();
+    let mut long = mapped_record("long", 0, 100);
+    long.set_cigar("2M300N2M".bytes()).unwrap();
+    let mut unmapped = mapped_record("u", 1, 5);
+    unmapped.flag_mut().set_mapped(false);
+    let records = vec![
+        mapped_record("a", 2, 700),
+        long,
+        mapped_record("b", 0, 50),
+        mapped_record("c", 0, 200),
+        unmapped,
+        mapped_record("d", 2, 10),
+    ];
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    assert_eq!(
+        reader.genomic_extent().unwrap(),
+        vec![Some((50, 404)), None, Some((10, 704))]
+    );
+}
+
+#[test]
+fn format_info() {
+    let header = single_ref_header();
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header.clone()).unwrap();
+    writer.write(&mapped_record("a", 0, 10)).unwrap();
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let text_len = reader.header().text().len() as u32;
+    let expected = bam::FormatInfo {
+        bgzf: true,
+        bam_magic: true,
+        header_text_len: Some(text_len),
+    };
+    assert_eq!(reader.format_info(), expected);
+    assert!(expected.is_bam());
+
+    let reader = bam::BamReader::from_stream_no_header(&stream[..], header.clone(), 0).unwrap();
+    assert!(!reader.format_info().bam_magic);
+    assert_eq!(reader.format_info().header_text_len, None);
+
+    let dir = std::env::temp_dir();
+    let bam_path = dir.join(format!("bam_format_info_{}.bam", std::process::id()));
+    let sam_path = dir.join(format!("bam_format_info_{}.sam", std::process::id()));
+    std::fs::write(&bam_path, &stream).unwrap();
+    std::fs::write(&sam_path, b"@SQ\tSN:chr1\tLN:1000\n").unwrap();
+    let raw_path = dir.join(format!("bam_format_info_{}.raw.bam", std::process::id()));
+    let mut raw_header = Vec::new();
+    header.write_bam(&mut raw_header).unwrap();
+    std::fs::write(&raw_path, &raw_header).unwrap();
+    let bam_info = bam::sniff(&bam_path).unwrap();
+    let sam_info = bam::sniff(&sam_path).unwrap();
+    let raw_info = bam::sniff(&raw_path).unwrap();
+    std::fs::remove_file(&bam_path).unwrap();
+    std::fs::remove_file(&sam_path).unwrap();
+    std::fs::remove_file(&raw_path).unwrap();
+
+    assert_eq!(bam_info, expected);
+    assert_eq!(
+        raw_info,
+        bam::FormatInfo {
+            bgzf: false,
+            bam_magic: true,
+            header_text_len: Some(text_len),
+        }
+    );
+    assert_eq!(
+        sam_info,
+        bam::FormatInfo {
+            bgzf: false,
+            bam_magic: false,
+            header_text_len: None,
+        }
+    );
+}
+
+fn written_names(mode: bam::bam_writer::NameMode) -> Vec<String> {
+    let mut first = mapped_record("a/1", 0, 10);
+    first.flag_mut().set_paired(true);
+    first.flag_mut().set_first_in_pair(true);
+    let mut last = mapped_record("a", 0, 20);
+    last.flag_mut().set_paired(true);
+    last.flag_mut().set_last_in_pair(true);
+    let unpaired = mapped_record("b/1", 0, 30);
+    let mut wrong_suffix = mapped_record("c/1", 0, 40);
+    wrong_suffix.flag_mut().set_paired(true);
+    wrong_suffix.flag_mut().set_last_in_pair(true);
+
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::build()
+        .normalize_names(mode)
+        .from_stream(&mut stream, single_ref_header())
+        .unwrap();
+    for record in &[first, last, unpaired, wrong_suffix] {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    bam::BamReader::from_stream(&stream[..], 0)
+        .unwrap()
+        .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+        .collect()
+}
+
+#[test]
+fn bam_writer_normalize_names_keep() {
+    use bam::bam_writer::NameMode;
+    assert_eq!(written_names(NameMode::Keep), vec!["a/1", "a", "b/1", "c/1"]);
+}
+
+#[test]
+fn bam_writer_normalize_names_strip() {
+    use bam::bam_writer::NameMode;
+    assert_eq!(
+        written_names(NameMode::StripPairSuffix),
+        vec!["a", "a", "b/1", "c/1"]
+    );
+}
+
+#[test]
+fn bam_writer_normalize_names_add() {
+    use bam::bam_writer::NameMode;
+    assert_eq!(
+        written_names(NameMode::AddPairSuffix),
+        vec!["a/1", "a/2", "b/1", "c/1/2"]
+    );
+
+    let mut record = mapped_record(&"x".repeat(253), 0, 10);
+    record.flag_mut().set_paired(true);
+    record.flag_mut().set_first_in_pair(true);
+    let mut writer = bam::BamWriter::build()
+        .normalize_names(NameMode::AddPairSuffix)
+        .from_stream(Vec::new(), single_ref_header())
+        .unwrap();
+    assert!(writer.write(&record).is_err());
+}
+
+#[test]
+fn pileup_region_consensus() {
+    let header = single_ref_header();
+    let record = |name: &str, start: i32, seq: &str| {
+        let mut record = mapped_record(name, 0, start);
+        record.set_seq_qual(seq.bytes(), std::iter::empty()).unwrap();
+        record
+    };
+    let records = vec![
+        record("a", 10, "ACGT"),
+        record("b", 10, "ACCT"),
+        record("c", 12, "GTAA"),
+        record("d", 20, "TTTT"),
+        record("e", 21, "CCGG"),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+
+    let mut viewer = reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap();
+    let (seq, depth) = bam::pileup::region_consensus(bam::Pileup::new(&mut viewer), 2, false)
+        .unwrap();
+    assert_eq!(seq, b"ACGTCCG".to_vec());
+    assert_eq!(depth, vec![2, 2, 3, 3, 2, 2, 2]);
+
+    let mut viewer = reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap();
+    let (seq, depth) = bam::pileup::region_consensus(bam::Pileup::new(&mut viewer), 2, true)
+        .unwrap();
+    assert_eq!(seq, b"ACGTNNNNNNNCCGN".to_vec());
+    assert_eq!(depth, vec![2, 2, 3, 3, 1, 1, 0, 0, 0, 0, 1, 2, 2, 2, 1]);
+}
+
+#[test]
+fn indexed_reader_fetch_by_pos() {
+    let header = single_ref_header();
+    let mut long = mapped_record("long", 0, 90);
+    long.set_cigar("2M100N2M".bytes()).unwrap();
+    let records = vec![
+        long,
+        mapped_record("a", 0, 98),
+        mapped_record("b", 0, 100),
+        mapped_record("c", 0, 140),
+        mapped_record("d", 0, 160),
+        mapped_record("e", 0, 300),
+    ];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+
+    let offsets = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let offsets_clone = offsets.clone();
+    let viewer = reader
+        .fetch_by_pos(0, 100, 200, move |record, offset| {
+            offsets_clone.lock().unwrap().push((record.start(), offset));
+            offset < 50
+        })
+        .unwrap();
+    let names: Vec<_> = viewer
+        .map(|record| String::from_utf8(record.unwrap().name().to_vec()).unwrap())
+        .collect();
+    assert_eq!(names, vec!["long", "a", "b", "c"]);
+    let offsets = offsets.lock().unwrap();
+    assert!(offsets.iter().all(|&(start, offset)| offset == start - 100));
+    assert!(offsets.contains(&(90, -10)));
+}
+
+#[test]
+fn record_query_aligned_fraction() {
+    let mut record = mapped_record("a", 0, 10);
+    assert_eq!(record.query_aligned_fraction(), 1.0);
+
+    record
+        .set_seq_qual("ACGTACGT".bytes(), std::iter::empty())
+        .unwrap();
+    record.set_cigar("4S3M1I".bytes()).unwrap();
+    assert!((record.query_aligned_fraction() - 0.5).abs() < 1e-9);
+
+    record.set_cigar("4H2S3M1I2S".bytes()).unwrap();
+    assert!((record.query_aligned_fraction() - 4.0 / 12.0).abs() < 1e-9);
+
+    record.flag_mut().set_mapped(false);
+    assert_eq!(record.query_aligned_fraction(), 0.0);
+}
+
+#[test]
+fn index_populated_references() {
+    let mut header = two_ref_header();
+    header
+        .push_entry(bam::header::HeaderEntry::ref_sequence("chr3".to_string(), 1000))
+        .unwrap();
+    let records = vec![mapped_record("a", 0, 10), mapped_record("b", 2, 500)];
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    assert_eq!(reader.index().populated_references(), vec![0, 2]);
+}
+
+#[test]
+fn reader_cap_qualities() {
+    let header = single_ref_header();
+    let mut high = mapped_record("high", 0, 10);
+    high.set_seq_qual("ACGT".bytes(), vec![10, 45, 60, 20]).unwrap();
+    let mut missing = mapped_record("missing", 0, 20);
+    missing.set_seq_qual("ACGT".bytes(), std::iter::empty()).unwrap();
+    let records = vec![high, missing];
+
+    let check = |records: Vec<bam::Record>| {
+        assert_eq!(records[0].qualities().raw(), &[10, 40, 40, 20]);
+        assert!(!records[1].qualities().available());
+        assert_eq!(records[1].qualities().raw(), &[0xff; 4]);
+    };
+
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::BamReader::from_stream(&bam[..], 0).unwrap();
+    reader.cap_qualities(40);
+    check(reader.map(|record| record.unwrap()).collect());
+
+    let mut reader = bam::IndexedReader::build()
+        .cap_qualities(40)
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+    check(reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().drain().unwrap());
+}
+
+#[test]
+fn indexed_reader_region_chunks() {
+    let header = two_ref_header();
+    let records = vec![mapped_record("a", 0, 10), mapped_record("b", 0, 500)];
+    let mut raw_header = Vec::new();
+    header.write_bam(&mut raw_header).unwrap();
+    let (bam, bai) = indexed_test_bam(&header, &records);
+    let mut reader = bam::IndexedReader::build()
+        .from_streams(std::io::Cursor::new(bam), &bai[..])
+        .unwrap();
+
+    let chunks = reader.region_chunks(0, 0, 1000).unwrap();
+    let expected: Vec<_> = reader
+        .index()
+        .fetch_chunks(0, 0, 1000)
+        .into_iter()
+        .map(|chunk| (chunk.start().raw(), chunk.end().raw()))
+        .collect();
+    assert_eq!(chunks, expected);
+    assert_eq!(chunks.len(), 1);
+    let begin = bam::index::VirtualOffset::from_raw(chunks[0].0);
+    assert!(begin.equal(0, raw_header.len() as u16));
+    // Everything is stored in a single block.
+    assert_eq!(reader.region_compressed_size(0, 0, 1000).unwrap(), 0);
+
+    assert!(reader.region_chunks(1, 0, 1000).unwrap().is_empty());
+    assert!(reader.region_chunks(0, 0, 2000).is_err());
+    assert_eq!(reader.region_chunks(0, 0, u32::MAX).unwrap(), chunks);
+    assert_eq!(reader.fetch(&bam::Region::new(0, 0, 1000)).unwrap().count(), 2);
+}
+
+#[test]
+fn record_validate_end_overflow() {
+    let mut header = bam::Header::new();
+    header
+        .push_entry(bam::header::HeaderEntry::ref_sequence("chr1".to_string(), i32::MAX as u32))
+        .unwrap();
+    let mut record = mapped_record("a", 0, i32::MAX - 100);
+    record.set_cigar("4M268435455D".bytes()).unwrap();
+    let err = record.validate(&header).unwrap_err();
+    assert!(err.contains("alignment end 2415919006"), "{}", err);
+}
+
+#[test]
+fn bam_reader_truncated_block_size() {
+    let header = single_ref_header();
+    let truncated = |n_extra: usize| {
+        let mut raw = Vec::new();
+        header.write_bam(&mut raw).unwrap();
+        mapped_record("a", 0, 10).write_bam(&mut raw).unwrap();
+        raw.extend_from_slice(&[20, 0, 0][..n_extra]);
+        compress_raw_records(&raw)
+    };
+
+    for n_extra in 0..4 {
+        let stream = truncated(n_extra);
+        let mut reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+        assert!(reader.skip_record().unwrap());
+        let peeked = reader.peek_record_size();
+        let mut reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+        let records: Vec<_> = (&mut reader).collect();
+        assert_eq!(records.len(), if n_extra == 0 { 1 } else { 2 });
+        if n_extra == 0 {
+            assert_eq!(peeked.unwrap(), None);
+        } else {
+            assert_eq!(peeked.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+            assert_eq!(
+                records[1].as_ref().unwrap_err().kind(),
+                std::io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+}
+
+#[test]
+fn record_leading_soft_clip_is_not_long_cigar() {
+    for cigar in &["4S", "4S3D", "4S1P"] {
+        let mut record = mapped_record("a", 0, 10);
+        record.set_cigar(cigar.bytes()).unwrap();
+        let restored = bam::Record::from_bytes(&record.to_bytes()).unwrap();
+        assert_eq!(restored.cigar().to_string(), *cigar);
+    }
+
+    // kSmN with the CG tag is still replaced by the long CIGAR.
+    let mut record = mapped_record("a", 0, 10);
+    record.set_cigar("4S10N".bytes()).unwrap();
+    record.tags_mut().push_array(b"CG", &[2_u32 << 4, (2 << 4) | 1]);
+    let restored = bam::Record::from_bytes(&record.to_bytes()).unwrap();
+    assert_eq!(restored.cigar().to_string(), "2M2I");
+    assert!(restored.tags().get(b"CG").is_none());
+}
+
+#[test]
+fn record_overlaps_large_end() {
+    let record = mapped_record("a", 0, 100);
+    assert!(record.overlaps(0, 0, u32::MAX));
+    assert!(record.overlaps(0, 102, u32::MAX));
+    assert!(!record.overlaps(0, 104, u32::MAX));
+    assert!(!record.overlaps(0, u32::MAX - 1, u32::MAX));
+    assert!(!record.overlaps(1, 0, u32::MAX));
+}
+
+#[test]
+fn bam_reader_dedup_umi_unmapped_tail() {
+    let header = single_ref_header();
+    let mut stream = Vec::new();
+    let mut writer = bam::BamWriter::from_stream(&mut stream, header).unwrap();
+    for name in &["a1", "a2"] {
+        let mut record = mapped_record(name, 0, 10);
+        record.tags_mut().push_string(b"RX", b"AAA");
+        writer.write(&record).unwrap();
+    }
+    let n_unmapped = 100_000;
+    for i in 0..n_unmapped {
+        let mut record = bam::Record::new();
+        record.set_name(format!("u{}", i).bytes());
+        record.flag_mut().set_mapped(false);
+        record.tags_mut().push_string(b"RX", b"AAA");
+        writer.write(&record).unwrap();
+    }
+    writer.finish().unwrap();
+    std::mem::drop(writer);
+
+    let reader = bam::BamReader::from_stream(&stream[..], 0).unwrap();
+    let mut n_records = 0;
+    for record in reader.dedup_umi(*b"RX") {
+        let record = record.unwrap();
+        assert_eq!(record.flag().is_duplicate(), record.name() == b"a2");
+        n_records += 1;
+    }
+    assert_eq!(n_records, n_unmapped + 2);
+
+    // Unmapped records are passed through one at a time: records before a corrupted block
+    // at the end of the file are returned before the error.
+    let truncated = &stream[..stream.len() - 100];
+    let reader = bam::BamReader::from_stream(truncated, 0).unwrap();
+    let mut dedup = reader.dedup_umi(*b"RX");
+    for _ in 0..1000 {
+        assert!(dedup.next().unwrap().is_ok());
+    }
+    assert!(dedup.any(|record| record.is_err()));
+}